@@ -0,0 +1,144 @@
+//! Fan-out throughput: one ingest producer, many receivers.
+//!
+//! Runs the server in-process under both runtime flavors and reports
+//! deliveries per second (messages x receivers over wall time from the
+//! first send until every receiver has every message). Run with
+//! `cargo bench --bench fanout [-- CLIENTS MESSAGES]`.
+
+use std::env;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use tcp_broadcast::{BroadcastServer, Config};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+
+fn main() {
+    let mut args = env::args().skip(1).filter(|a| a != "--bench");
+    let clients: usize = args.next().and_then(|a| a.parse().ok()).unwrap_or(500);
+    let messages: usize = args.next().and_then(|a| a.parse().ok()).unwrap_or(2000);
+
+    let load = Builder::new_multi_thread().enable_all().build().unwrap();
+    let mut results = Vec::new();
+    for flavor in ["current_thread", "multi_thread"] {
+        let server = match flavor {
+            "current_thread" => Builder::new_current_thread().enable_all().build().unwrap(),
+            _ => Builder::new_multi_thread().enable_all().build().unwrap(),
+        };
+        let addr = start_server(server, messages);
+        let elapsed = load.block_on(run(addr, clients, messages));
+        let rate = (clients * messages) as f64 / elapsed.as_secs_f64();
+        results.push(format!(
+            "fanout runtime={flavor} clients={clients} messages={messages} elapsed_ms={} deliveries_per_sec={rate:.0}",
+            elapsed.as_millis()
+        ));
+    }
+    for line in results {
+        println!("{line}");
+    }
+}
+
+/// Serves on a free port from its own thread and runtime.
+fn start_server(runtime: Runtime, messages: usize) -> SocketAddr {
+    // A backlog big enough for every client connecting at once
+    let listener = {
+        let _guard = runtime.enter();
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(([127, 0, 0, 1], 0).into()).unwrap();
+        socket.listen(1024).unwrap()
+    };
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        runtime.block_on(async {
+            // Room for the whole run, so nobody counts as a slow consumer
+            let config = Config { send_queue: messages * 2, ..Config::default() };
+            BroadcastServer::bind(addr).config(config).serve(listener).await
+        })
+    });
+    addr
+}
+
+async fn run(addr: SocketAddr, clients: usize, messages: usize) -> Duration {
+    let mut receivers: Vec<_> = future::join_all((0..clients).map(|_| connect(addr))).await;
+    let (mut producer, producer_id) = connect(addr).await;
+    // Let the presence notices from all those connects drain before timing
+    let joined = format!("JOINED:{producer_id}");
+    future::join_all(receivers.iter_mut().map(|(conn, _)| skip_to(conn, &joined))).await;
+    let receivers: Vec<_> = receivers
+        .into_iter()
+        .map(|(conn, _)| tokio::spawn(receive(conn, messages)))
+        .collect();
+
+    producer.get_mut().write_all(b"INGEST\n").await.unwrap();
+    expect_line(&mut producer, "ACK:INGEST").await;
+
+    let started = Instant::now();
+    let (read_half, write_half) = producer.into_inner().into_split();
+    let sender = tokio::spawn(async move {
+        let mut w = BufWriter::new(write_half);
+        for i in 0..messages {
+            w.write_all(format!("bench message {i}\n").as_bytes()).await.unwrap();
+        }
+        w.flush().await.unwrap();
+        w
+    });
+    // Acks have to be read or the producer's own queue fills up
+    let mut acks = BufReader::new(read_half);
+    let mut line = String::new();
+    while !line.trim_end().ends_with(&format!("-{messages}")) {
+        line.clear();
+        if acks.read_line(&mut line).await.unwrap() == 0 {
+            panic!("producer disconnected");
+        }
+    }
+    for r in receivers {
+        r.await.unwrap();
+    }
+    let elapsed = started.elapsed();
+    drop(sender.await.unwrap());
+    elapsed
+}
+
+/// Connects and waits for `LOGIN`, returning the client id.
+async fn connect(addr: SocketAddr) -> (BufReader<TcpStream>, String) {
+    let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut line = String::new();
+    conn.read_line(&mut line).await.unwrap();
+    let id = line.trim_end().strip_prefix("LOGIN:").unwrap_or_else(|| panic!("unexpected greeting {line:?}"));
+    let id = id.to_string();
+    (conn, id)
+}
+
+/// Reads and discards lines up to and including `want`.
+async fn skip_to(conn: &mut BufReader<TcpStream>, want: &str) {
+    let mut line = String::new();
+    while line.trim_end() != want {
+        line.clear();
+        if conn.read_line(&mut line).await.unwrap() == 0 {
+            panic!("disconnected waiting for {want:?}");
+        }
+    }
+}
+
+async fn expect_line(conn: &mut BufReader<TcpStream>, want: &str) {
+    let mut line = String::new();
+    conn.read_line(&mut line).await.unwrap();
+    assert_eq!(line.trim_end(), want);
+}
+
+async fn receive(mut conn: BufReader<TcpStream>, messages: usize) {
+    let mut line = String::new();
+    let mut seen = 0;
+    while seen < messages {
+        line.clear();
+        if conn.read_line(&mut line).await.unwrap() == 0 {
+            panic!("receiver disconnected after {seen} messages");
+        }
+        if line.starts_with("MESSAGE:") {
+            seen += 1;
+        }
+    }
+}
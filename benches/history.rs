@@ -0,0 +1,99 @@
+//! History replay: many clients arriving at once, each replayed a full
+//! lobby history.
+//!
+//! Fills the history from one ingest producer, then times clients
+//! connecting until every one has its `HISTORY:` lines and `LOGIN`, and
+//! reports replayed lines per second. Run with
+//! `cargo bench --bench history [-- CLIENTS HISTORY]`.
+
+use std::env;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use tcp_broadcast::{BroadcastServer, Config};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::runtime::Builder;
+
+fn main() {
+    let mut args = env::args().skip(1).filter(|a| a != "--bench");
+    let clients: usize = args.next().and_then(|a| a.parse().ok()).unwrap_or(500);
+    let history: usize = args.next().and_then(|a| a.parse().ok()).unwrap_or(1000);
+
+    let addr = start_server(history);
+    let load = Builder::new_multi_thread().enable_all().build().unwrap();
+    let elapsed = load.block_on(run(addr, clients, history));
+    let rate = (clients * history) as f64 / elapsed.as_secs_f64();
+    println!(
+        "history clients={clients} history={history} elapsed_ms={} replayed_per_sec={rate:.0}",
+        elapsed.as_millis()
+    );
+}
+
+/// Serves on a free port from its own thread and runtime.
+fn start_server(history: usize) -> SocketAddr {
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    // A backlog big enough for every client connecting at once
+    let listener = {
+        let _guard = runtime.enter();
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(([127, 0, 0, 1], 0).into()).unwrap();
+        socket.listen(1024).unwrap()
+    };
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        runtime.block_on(async {
+            // History is capped at half the send queue, so it's replayed whole
+            let config = Config { history, send_queue: history * 2 + 64, ..Config::default() };
+            BroadcastServer::bind(addr).config(config).serve(listener).await
+        })
+    });
+    addr
+}
+
+async fn run(addr: SocketAddr, clients: usize, history: usize) -> Duration {
+    fill(addr, history).await;
+    let started = Instant::now();
+    future::join_all((0..clients).map(|_| replayed(addr, history))).await;
+    started.elapsed()
+}
+
+/// Sends `history` messages, and waits until they're all acked.
+async fn fill(addr: SocketAddr, history: usize) {
+    let mut producer = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut line = String::new();
+    producer.read_line(&mut line).await.unwrap();
+    producer.get_mut().write_all(b"INGEST\n").await.unwrap();
+    let mut batch = String::new();
+    for i in 0..history {
+        batch.push_str(&format!("bench message {i}\n"));
+    }
+    producer.get_mut().write_all(batch.as_bytes()).await.unwrap();
+    while !line.trim_end().ends_with(&format!("-{history}")) {
+        line.clear();
+        if producer.read_line(&mut line).await.unwrap() == 0 {
+            panic!("producer disconnected");
+        }
+    }
+}
+
+/// Connects and reads up to `LOGIN`, checking the whole history came first.
+async fn replayed(addr: SocketAddr, history: usize) {
+    let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut line = String::new();
+    let mut seen = 0;
+    loop {
+        line.clear();
+        if conn.read_line(&mut line).await.unwrap() == 0 {
+            panic!("disconnected after {seen} history lines");
+        }
+        if line.starts_with("HISTORY:") {
+            seen += 1;
+        } else if line.starts_with("LOGIN:") {
+            break;
+        }
+    }
+    assert_eq!(seen, history, "replay was cut short");
+}
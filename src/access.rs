@@ -0,0 +1,102 @@
+//! Who may connect, by address.
+//!
+//! A connection from a denied range is closed as soon as it's accepted, as
+//! is one from outside every allowed range when any are given; it never
+//! becomes a client or counts towards churn. The lists can be replaced
+//! while the server runs (the binary rereads them on `SIGHUP`), which only
+//! affects connections accepted from then on. Unix socket clients have no
+//! address and aren't checked.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address range, `10.0.0.0/8` or `2001:db8::/32`; a bare address is a
+/// range of one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => same_prefix(net.to_bits().into(), ip.to_bits().into(), 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => same_prefix(net.to_bits(), ip.to_bits(), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn same_prefix(a: u128, b: u128, bits: u32, prefix: u8) -> bool {
+    let host_bits = bits - u32::from(prefix);
+    host_bits >= bits || (a ^ b) >> host_bits == 0
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("invalid range {s:?}, expected an address or ADDR/PREFIX");
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(addr, prefix)| (addr, Some(prefix)));
+        let addr: IpAddr = addr.parse().map_err(|_| bad())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&prefix| prefix <= max).ok_or_else(bad)?,
+            None => max,
+        };
+        // ::ffff:a.b.c.d/N is the IPv4 range a.b.c.d/(N-96), which is how
+        // clients from that range are seen
+        match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix >= 96 => Ok(Cidr { addr: v4.into(), prefix: prefix - 96 }),
+            _ => Ok(Cidr { addr, prefix }),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Ranges that may and may not connect. Empty lists let everyone in.
+#[derive(Clone, Default)]
+pub struct AccessList {
+    /// If any are given, only these may connect.
+    pub allow: Vec<Cidr>,
+    /// Never let in, even if also allowed.
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let within = |ranges: &[Cidr]| ranges.iter().any(|range| range.contains(ip));
+        !within(&self.deny) && (self.allow.is_empty() || within(&self.allow))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|range| range.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn allow_then_deny() {
+        let access = AccessList { allow: ranges(&["10.0.0.0/8", "2001:db8::/32"]), deny: ranges(&["10.6.6.6"]) };
+        assert!(access.permits("10.1.2.3".parse().unwrap()));
+        assert!(access.permits("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(access.permits("2001:db8::1".parse().unwrap()));
+        assert!(!access.permits("10.6.6.6".parse().unwrap()));
+        assert!(!access.permits("192.168.1.1".parse().unwrap()));
+        assert!(AccessList { allow: ranges(&["0.0.0.0/0"]), deny: Vec::new() }.permits("1.2.3.4".parse().unwrap()));
+        assert_eq!("::ffff:10.0.0.0/104".parse(), "10.0.0.0/8".parse::<Cidr>());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+}
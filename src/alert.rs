@@ -0,0 +1,312 @@
+//! Webhook alerts on server health conditions.
+//!
+//! Meant for small deployments without a monitoring stack: when a condition
+//! trips, a JSON `{"text": ...}` body is POSTed to the configured URL (the
+//! shape Slack, Mattermost and most chat webhooks accept). Each condition
+//! alerts at most once per cooldown so a sustained problem doesn't turn
+//! into a flood of requests. Only plain `http://` URLs are supported, and
+//! only with the `http` feature.
+//!
+//! Operators who live in the chat can name an alert room instead, or as
+//! well: each alert then also goes to the admins in that room as an
+//! `ALERT:` line, under the same cooldown.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "http")]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::time;
+use tracing::warn;
+
+#[cfg(feature = "http")]
+use crate::net;
+use crate::registry::ClientId;
+
+/// Give up on a webhook request after this long.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct AlertConfig {
+    /// Where alerts are POSTed.
+    pub webhook: Option<String>,
+    /// Room whose admins are sent alerts. Alerting is off without this or
+    /// a webhook.
+    pub room: Option<String>,
+    /// Event-loop lag that counts as a problem.
+    pub lag_threshold: Duration,
+    /// Fraction of the open-file limit in use that counts as near it.
+    pub fd_threshold: f64,
+    /// Resident memory, in bytes, that counts as too much; `None` doesn't
+    /// check.
+    pub memory_threshold: Option<u64>,
+    /// Fraction of `Config::send_queue` one client's backlog can fill
+    /// before it counts as deep.
+    pub queue_threshold: f64,
+    /// Minimum time between two alerts for the same condition.
+    pub cooldown: Duration,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            webhook: None,
+            room: None,
+            lag_threshold: Duration::from_millis(250),
+            fd_threshold: 0.8,
+            memory_threshold: None,
+            queue_threshold: 0.8,
+            cooldown: Duration::from_secs(300),
+        }
+    }
+}
+
+pub enum Condition {
+    /// A timer fired this late, so every connection waited as long.
+    EventLoopLag { lag: Duration },
+    FdLimitNear { open: u64, limit: u64 },
+    MemoryHigh { resident: u64, threshold: u64 },
+    /// The client furthest behind has this many broadcast lines waiting.
+    QueueDeep { client_id: ClientId, queued: u64, capacity: u64 },
+}
+
+impl Condition {
+    fn name(&self) -> &'static str {
+        match self {
+            Condition::EventLoopLag { .. } => "event_loop_lag",
+            Condition::FdLimitNear { .. } => "fd_limit_near",
+            Condition::MemoryHigh { .. } => "memory_high",
+            Condition::QueueDeep { .. } => "queue_deep",
+        }
+    }
+
+    fn fields(&self) -> String {
+        match self {
+            Condition::EventLoopLag { lag } => format!("lag_ms={}", lag.as_millis()),
+            Condition::FdLimitNear { open, limit } => format!("open={open} limit={limit}"),
+            Condition::MemoryHigh { resident, threshold } => format!("resident_bytes={resident} threshold_bytes={threshold}"),
+            Condition::QueueDeep { client_id, queued, capacity } => format!("client_id={client_id} queued={queued} capacity={capacity}"),
+        }
+    }
+
+    /// The line for the alert room: `ALERT:{EVENT} {KEY}={VALUE} …`.
+    pub fn line(&self) -> String {
+        format!("ALERT:{} {}\n", self.name(), self.fields())
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "alert event={} {}", self.name(), self.fields())
+    }
+}
+
+pub struct Alerter {
+    config: AlertConfig,
+    /// When each condition last alerted, for the cooldown.
+    last_sent: HashMap<&'static str, Instant>,
+}
+
+impl Alerter {
+    pub fn new(config: AlertConfig) -> Self {
+        Self { config, last_sent: HashMap::new() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.webhook.is_some() || self.config.room.is_some()
+    }
+
+    pub fn room(&self) -> Option<&str> {
+        self.config.room.as_deref()
+    }
+
+    /// Checks how late a timer due at `due` fired. This and the other
+    /// checks return the condition when it alerted, for the alert room.
+    pub fn check_lag(&mut self, due: Instant, now: Instant) -> Option<Condition> {
+        let lag = now.saturating_duration_since(due);
+        if lag < self.config.lag_threshold {
+            return None;
+        }
+        self.fire(Condition::EventLoopLag { lag }, now)
+    }
+
+    pub fn check_fds(&mut self, now: Instant) -> Option<Condition> {
+        let (open, limit) = fd_usage()?;
+        if limit == 0 || (open as f64) < limit as f64 * self.config.fd_threshold {
+            return None;
+        }
+        self.fire(Condition::FdLimitNear { open, limit }, now)
+    }
+
+    pub fn check_memory(&mut self, now: Instant) -> Option<Condition> {
+        let threshold = self.config.memory_threshold?;
+        let resident = resident_memory()?;
+        if resident < threshold {
+            return None;
+        }
+        self.fire(Condition::MemoryHigh { resident, threshold }, now)
+    }
+
+    /// Checks the backlog of the client furthest behind, out of a queue of
+    /// `capacity` lines.
+    pub fn check_queue(&mut self, deepest: Option<(ClientId, u64)>, capacity: u64, now: Instant) -> Option<Condition> {
+        let (client_id, queued) = deepest?;
+        if capacity == 0 || (queued as f64) < capacity as f64 * self.config.queue_threshold {
+            return None;
+        }
+        self.fire(Condition::QueueDeep { client_id, queued, capacity }, now)
+    }
+
+    /// Logs the condition and, outside its cooldown, sends it to the webhook
+    /// in the background; returns it unless it was still cooling down.
+    fn fire(&mut self, condition: Condition, now: Instant) -> Option<Condition> {
+        if let Some(last) = self.last_sent.get(condition.name()) {
+            if now.duration_since(*last) < self.config.cooldown {
+                return None;
+            }
+        }
+        self.last_sent.insert(condition.name(), now);
+
+        let text = condition.to_string();
+        warn!("{text}");
+        let Some(url) = self.config.webhook.clone() else { return Some(condition) };
+        tokio::spawn(async move {
+            let body = format!("{{\"text\":\"{}\"}}", json_escape(&text));
+            match time::timeout(WEBHOOK_TIMEOUT, post(&url, &body)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("alert webhook failed: {e}"),
+                Err(_) => warn!("alert webhook failed: timed out"),
+            }
+        });
+        Some(condition)
+    }
+}
+
+/// POSTs a JSON body to an `http://host[:port]/path` URL and checks for a
+/// 2xx status.
+#[cfg(feature = "http")]
+pub(crate) async fn post(url: &str, body: &str) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported webhook url {url}"));
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(invalid());
+    }
+    let target = if authority.contains(':') { authority.to_string() } else { format!("{authority}:80") };
+
+    let mut stream = net::connect(&target).await?;
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).await?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("webhook answered {:?}", status.trim_end()))),
+    }
+}
+
+/// Built without HTTP, so a webhook is refused at startup.
+#[cfg(not(feature = "http"))]
+pub(crate) async fn post(_url: &str, _body: &str) -> io::Result<()> {
+    Err(crate::info::not_built("http"))
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Open file descriptors and the soft limit on them.
+#[cfg(target_os = "linux")]
+fn fd_usage() -> Option<(u64, u64)> {
+    let open = std::fs::read_dir("/proc/self/fd").ok()?.count() as u64;
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits.lines().find(|l| l.starts_with("Max open files"))?;
+    let limit = line.split_whitespace().nth(3)?.parse().ok()?;
+    Some((open, limit))
+}
+
+/// Resident memory, in bytes.
+#[cfg(target_os = "linux")]
+pub(crate) fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+// Nothing cheap and dependency-free to ask elsewhere; the check is skipped.
+#[cfg(not(target_os = "linux"))]
+fn fd_usage() -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn resident_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alerts_once_per_cooldown() {
+        let mut alerter = Alerter::new(AlertConfig { room: Some("ops".to_string()), ..AlertConfig::default() });
+        let now = Instant::now();
+        let tripped = alerter.check_queue(Some((7, 900)), 1024, now).unwrap();
+        assert_eq!(tripped.line(), "ALERT:queue_deep client_id=7 queued=900 capacity=1024\n");
+        assert!(alerter.check_queue(Some((7, 1000)), 1024, now + Duration::from_secs(1)).is_none());
+        assert!(alerter.check_queue(Some((7, 100)), 1024, now + Duration::from_secs(600)).is_none());
+        assert!(alerter.check_queue(Some((7, 1000)), 1024, now + Duration::from_secs(600)).is_some());
+    }
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn posts_lag_to_the_webhook() {
+        use tokio::io::AsyncReadExt;
+
+        // Up to the end of the body: the alert waits for an answer, so it
+        // doesn't close first
+        async fn request(stream: &mut tokio::net::TcpStream) -> String {
+            let mut request = Vec::new();
+            while !request.ends_with(b"}") {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "closed mid-request");
+                request.extend_from_slice(&buf[..n]);
+            }
+            String::from_utf8(request).unwrap()
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let webhook = format!("http://{}/hooks/ops", listener.local_addr().unwrap());
+        let mut alerter = Alerter::new(AlertConfig { webhook: Some(webhook), ..AlertConfig::default() });
+        let due = Instant::now();
+        assert!(alerter.check_lag(due, due + Duration::from_millis(100)).is_none());
+        assert!(alerter.check_lag(due, due + Duration::from_millis(300)).is_some());
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let request = request(&mut stream).await;
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+        assert!(request.starts_with("POST /hooks/ops HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"text\":\"alert event=event_loop_lag lag_ms=300\"}"));
+        assert!(post("https://example.com/", "{}").await.is_err());
+    }
+}
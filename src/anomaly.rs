@@ -0,0 +1,155 @@
+//! Connection-level anomaly heuristics.
+//!
+//! The detector is fed from the accept path and the read path and returns a
+//! [`SecurityEvent`] whenever something looks abusive. Events are plain data;
+//! the caller decides how to report them. Flagged IPs are greylisted for a
+//! while (see the tarpit module), and if auto-ban is configured
+//! [`AnomalyDetector::is_banned`] also rejects them until the ban expires.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::registry::ClientId;
+
+pub struct AnomalyConfig {
+    /// Connects from one IP inside `churn_window` before it's flagged.
+    pub churn_limit: usize,
+    pub churn_window: Duration,
+    /// How long a flagged IP is refused; `None` only reports.
+    pub ban_for: Option<Duration>,
+    /// How long a flagged IP stays on the greylist.
+    pub greylist_for: Duration,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            churn_limit: 20,
+            churn_window: Duration::from_secs(10),
+            ban_for: None,
+            greylist_for: Duration::from_secs(600),
+        }
+    }
+}
+
+pub enum SecurityEvent {
+    /// Too many connects from one IP in the churn window.
+    ConnectChurn { ip: IpAddr, connects: usize, window: Duration },
+    /// Non-text bytes (invalid UTF-8 or NUL) on the line protocol.
+    BinaryGarbage { ip: IpAddr, client_id: ClientId },
+}
+
+impl fmt::Display for SecurityEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecurityEvent::ConnectChurn { ip, connects, window } => write!(
+                f,
+                "security event=connect_churn ip={ip} connects={connects} window_secs={}",
+                window.as_secs()
+            ),
+            SecurityEvent::BinaryGarbage { ip, client_id } => {
+                write!(f, "security event=binary_garbage ip={ip} client={client_id}")
+            }
+        }
+    }
+}
+
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    connects: HashMap<IpAddr, VecDeque<Instant>>,
+    bans: HashMap<IpAddr, Instant>,
+    greylist: HashMap<IpAddr, Instant>,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: AnomalyConfig) -> Self {
+        Self {
+            config,
+            connects: HashMap::new(),
+            bans: HashMap::new(),
+            greylist: HashMap::new(),
+        }
+    }
+
+    /// Returns true while `ip` is serving an auto-ban.
+    pub fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        match self.bans.get(&ip) {
+            Some(&until) if until > now => true,
+            Some(_) => {
+                self.bans.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Returns true if `ip` was flagged recently.
+    pub fn is_greylisted(&self, ip: IpAddr, now: Instant) -> bool {
+        self.greylist.get(&ip).is_some_and(|&until| until > now)
+    }
+
+    /// Records a connect from `ip`; flags it once the churn limit is crossed.
+    pub fn on_connect(&mut self, ip: IpAddr, now: Instant) -> Option<SecurityEvent> {
+        let window = self.config.churn_window;
+        let recent = self.connects.entry(ip).or_default();
+        while recent.front().is_some_and(|&t| now.duration_since(t) > window) {
+            recent.pop_front();
+        }
+        recent.push_back(now);
+
+        let connects = recent.len();
+        if connects <= self.config.churn_limit {
+            return None;
+        }
+        // Start counting afresh so a sustained flood reports once per window.
+        recent.clear();
+        self.flag(ip, now);
+        Some(SecurityEvent::ConnectChurn { ip, connects, window })
+    }
+
+    /// Records non-text input from a client.
+    pub fn on_garbage(&mut self, ip: IpAddr, client_id: ClientId, now: Instant) -> SecurityEvent {
+        self.flag(ip, now);
+        SecurityEvent::BinaryGarbage { ip, client_id }
+    }
+
+    /// Forgets connect history that has aged out of the window.
+    pub fn prune(&mut self, now: Instant) {
+        let window = self.config.churn_window;
+        self.connects
+            .retain(|_, recent| recent.back().is_some_and(|&t| now.duration_since(t) <= window));
+        self.bans.retain(|_, &mut until| until > now);
+        self.greylist.retain(|_, &mut until| until > now);
+    }
+
+    fn flag(&mut self, ip: IpAddr, now: Instant) {
+        self.greylist.insert(ip, now + self.config.greylist_for);
+        if let Some(ban_for) = self.config.ban_for {
+            self.bans.insert(ip, now + ban_for);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn churn_past_the_limit_bans_and_greylists() {
+        let config = AnomalyConfig { churn_limit: 2, ban_for: Some(Duration::from_secs(60)), ..AnomalyConfig::default() };
+        let mut detector = AnomalyDetector::new(config);
+        let ip: IpAddr = [192, 0, 2, 1].into();
+        let now = Instant::now();
+        assert!(detector.on_connect(ip, now).is_none());
+        assert!(detector.on_connect(ip, now).is_none());
+        assert!(matches!(detector.on_connect(ip, now), Some(SecurityEvent::ConnectChurn { connects: 3, .. })));
+        assert!(detector.is_banned(ip, now) && detector.is_greylisted(ip, now));
+        assert!(!detector.is_banned([192, 0, 2, 2].into(), now));
+
+        // The ban runs out well before the greylisting
+        let later = now + Duration::from_secs(61);
+        assert!(!detector.is_banned(ip, later) && detector.is_greylisted(ip, later));
+    }
+}
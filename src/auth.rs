@@ -0,0 +1,119 @@
+//! Authentication before `LOGIN`.
+//!
+//! With credentials configured, a new connection is greeted with
+//! `AUTH_REQUIRED` instead of `LOGIN`, and has to send
+//! `AUTH:<token>` or `AUTH:<user> <password>` within the timeout. Until
+//! it does, it's invisible: it gets no broadcasts, isn't announced, can't
+//! be messaged, and anything it sends but `AUTH`, `PING` and `PONG` is
+//! refused. A wrong credential disconnects it.
+//!
+//! Users listed as admins may also run operator commands (maintenance
+//! mode and the like) once authenticated. Users listed as bridges relay
+//! between here and elsewhere, and may have their echoes dropped (see
+//! `echo`).
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+pub struct AuthConfig {
+    /// Shared tokens, any of which lets a client in.
+    pub tokens: Vec<String>,
+    /// Passwords by user name.
+    pub users: BTreeMap<String, String>,
+    /// Users who may run operator commands.
+    pub admins: Vec<String>,
+    /// Users who relay to and from somewhere else.
+    pub bridges: Vec<String>,
+    /// How long a new connection has to authenticate.
+    pub timeout: Duration,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            tokens: Vec::new(),
+            users: BTreeMap::new(),
+            admins: Vec::new(),
+            bridges: Vec::new(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Whether any credentials are configured; without any, nobody could
+    /// get in.
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty() || !self.users.is_empty()
+    }
+
+    /// Checks the credentials from an `AUTH:` line, and says who they're
+    /// for.
+    pub fn check<'a>(&self, credentials: &'a str) -> Option<Identity<'a>> {
+        match credentials.split_once(' ') {
+            Some((user, password)) => {
+                let expected = self.users.get(user)?;
+                same(expected.as_bytes(), password.as_bytes()).then_some(Identity::User(user))
+            }
+            None => {
+                // Every token is compared, so timing doesn't tell which one
+                // came close
+                let matched = self.tokens.iter().fold(false, |hit, token| hit | same(token.as_bytes(), credentials.as_bytes()));
+                matched.then_some(Identity::Token)
+            }
+        }
+    }
+
+    pub fn is_admin(&self, identity: &Identity<'_>) -> bool {
+        matches!(identity, Identity::User(user) if self.admins.iter().any(|admin| admin == user))
+    }
+
+    pub fn is_bridge(&self, identity: &Identity<'_>) -> bool {
+        matches!(identity, Identity::User(user) if self.bridges.iter().any(|bridge| bridge == user))
+    }
+}
+
+/// Who a client authenticated as.
+#[derive(Debug, PartialEq)]
+pub enum Identity<'a> {
+    /// One of the shared tokens.
+    Token,
+    User(&'a str),
+}
+
+impl fmt::Display for Identity<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identity::Token => f.write_str("token"),
+            Identity::User(user) => write!(f, "user={user}"),
+        }
+    }
+}
+
+/// Compares in time that depends only on the lengths.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_and_users() {
+        let config = AuthConfig {
+            tokens: vec!["s3cret".into()],
+            users: [("alice".to_string(), "pw for alice".to_string())].into(),
+            admins: vec!["alice".into()],
+            ..AuthConfig::default()
+        };
+        assert_eq!(config.check("s3cret"), Some(Identity::Token));
+        assert_eq!(config.check("s3cre"), None);
+        assert_eq!(config.check("alice pw for alice"), Some(Identity::User("alice")));
+        assert_eq!(config.check("alice s3cret"), None);
+        assert_eq!(config.check("bob pw for alice"), None);
+        assert!(config.is_admin(&Identity::User("alice")));
+        assert!(!config.is_admin(&Identity::Token));
+    }
+}
@@ -0,0 +1,267 @@
+//! Interactive client for the text protocol.
+//!
+//! Lines typed on stdin are sent as they are, so commands (`JOIN:dev`,
+//! `NICK:alice`) work as well as messages. What the server sends is
+//! printed with a timestamp, messages with their sender highlighted and
+//! errors in red; `ACK:MESSAGE`, the reply to every message, isn't shown,
+//! `PING` is answered, and the rest is shown as it came. A lost
+//! connection is retried with exponential backoff, and the room and
+//! nickname the server last acknowledged are taken up again.
+
+use std::io::{self, IsTerminal};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+use futures::SinkExt;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+
+/// Credentials to answer `AUTH_REQUIRED` with, `TOKEN` or `USER PASSWORD`;
+/// from the environment, since other users can see the command line.
+const AUTH_VAR: &str = "TCP_BROADCAST_AUTH";
+/// First wait before reconnecting; it doubles on every failure in a row.
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Chats with a tcp-broadcast server from the terminal.",
+    after_help = "Set TCP_BROADCAST_AUTH to a token, or to a user and password, for servers that require auth."
+)]
+struct Args {
+    /// Server to connect to
+    #[arg(default_value = "127.0.0.1:8888", value_name = "HOST:PORT")]
+    server: String,
+    /// Longest wait between reconnection attempts
+    #[arg(long, default_value_t = 30, value_name = "SECS")]
+    max_backoff: u64,
+    /// Don't reconnect once the connection is lost
+    #[arg(long)]
+    no_reconnect: bool,
+    /// Plain output even on a terminal
+    #[arg(long)]
+    no_color: bool,
+}
+
+/// How a connection ended.
+enum End {
+    /// Stdin closed: we're done.
+    Quit,
+    /// The connection went; worth trying again.
+    Lost,
+    /// The server won't have us back as we are.
+    Refused(String),
+}
+
+/// What carries over from one connection to the next.
+#[derive(Default)]
+struct Session {
+    room: Option<String>,
+    nick: Option<String>,
+    /// The last connection got as far as `LOGIN`.
+    logged_in: bool,
+}
+
+/// Waits between reconnection attempts.
+struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(max: Duration) -> Self {
+        Self { next: FIRST_BACKOFF.min(max), max }
+    }
+
+    /// The next wait, with up to a quarter more at random so clients
+    /// dropped together don't all come back together.
+    fn take(&mut self) -> Duration {
+        let wait = self.next;
+        self.next = (self.next * 2).min(self.max);
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        wait + wait.mul_f64(f64::from(nanos % 1000) / 4000.0)
+    }
+
+    fn reset(&mut self) {
+        self.next = FIRST_BACKOFF.min(self.max);
+    }
+}
+
+/// ANSI colors, or none.
+struct Paint {
+    color: bool,
+}
+
+impl Paint {
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn dim(&self, text: &str) -> String {
+        self.paint("2", text)
+    }
+
+    /// A line from the server, with the time it arrived.
+    fn show(&self, line: &str) {
+        println!("{} {line}", self.dim(&clock()));
+    }
+
+    /// A note from the client itself, on stderr.
+    fn note(&self, text: &str) {
+        eprintln!("{} {}", self.dim(&clock()), self.paint("33", &format!("* {text}")));
+    }
+}
+
+/// The time of day, UTC.
+fn clock() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 86_400;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let paint = Paint { color: !args.no_color && io::stdout().is_terminal() };
+    let auth = std::env::var(AUTH_VAR).ok();
+    let mut input = BufReader::new(tokio::io::stdin()).lines();
+    let mut session = Session::default();
+    let mut backoff = Backoff::new(Duration::from_secs(args.max_backoff));
+    loop {
+        paint.note(&format!("connecting to {}", args.server));
+        match TcpStream::connect(&args.server).await {
+            Ok(stream) => match converse(stream, &mut input, &mut session, auth.as_deref(), &paint).await? {
+                End::Quit => return Ok(()),
+                End::Lost => paint.note("connection lost"),
+                End::Refused(why) => {
+                    paint.note(&why);
+                    std::process::exit(1);
+                }
+            },
+            Err(e) => paint.note(&format!("can't connect: {e}")),
+        }
+        if args.no_reconnect {
+            std::process::exit(1);
+        }
+        if std::mem::take(&mut session.logged_in) {
+            backoff.reset();
+        }
+        let wait = backoff.take();
+        paint.note(&format!("reconnecting in {:.1}s", wait.as_secs_f64()));
+        // Stdin is still read meanwhile, so Ctrl-D doesn't wait on the server
+        let deadline = time::Instant::now() + wait;
+        loop {
+            tokio::select! {
+                _ = time::sleep_until(deadline) => break,
+                line = input.next_line() => match line? {
+                    Some(_) => paint.note("not connected, line not sent"),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+/// Relays between stdin and one connection until either ends.
+async fn converse(
+    stream: TcpStream,
+    input: &mut Lines<BufReader<Stdin>>,
+    session: &mut Session,
+    auth: Option<&str>,
+    paint: &Paint,
+) -> io::Result<End> {
+    let mut conn = Framed::new(stream, LinesCodec::new());
+    loop {
+        tokio::select! {
+            line = input.next_line() => {
+                let Some(line) = line? else { return Ok(End::Quit) };
+                if !line.is_empty() && conn.send(line).await.is_err() {
+                    return Ok(End::Lost);
+                }
+            }
+            line = conn.next() => {
+                let Some(Ok(line)) = line else { return Ok(End::Lost) };
+                match handle(&line, session, auth, paint) {
+                    Reply::None => {}
+                    Reply::Send(lines) => {
+                        for line in lines {
+                            if conn.send(line).await.is_err() {
+                                return Ok(End::Lost);
+                            }
+                        }
+                    }
+                    Reply::Refused(why) => return Ok(End::Refused(why)),
+                }
+            }
+        }
+    }
+}
+
+/// What to do about a line from the server.
+enum Reply {
+    None,
+    Send(Vec<String>),
+    Refused(String),
+}
+
+fn handle(line: &str, session: &mut Session, auth: Option<&str>, paint: &Paint) -> Reply {
+    let (kind, rest) = line.split_once(':').unwrap_or((line, ""));
+    match kind {
+        "MESSAGE" | "HISTORY" => {
+            let (history, rest) = match rest.strip_prefix("MESSAGE:") {
+                Some(rest) if kind == "HISTORY" => (true, rest),
+                _ => (false, rest),
+            };
+            let (from, text) = rest.split_once(' ').unwrap_or((rest, ""));
+            let from = paint.paint("1;36", from);
+            match history {
+                true => paint.show(&paint.dim(&format!("{from} {text}"))),
+                false => paint.show(&format!("{from} {text}")),
+            }
+        }
+        "MSG" => {
+            let (from, text) = rest.split_once(' ').unwrap_or((rest, ""));
+            paint.show(&format!("{} {text}", paint.paint("1;35", &format!("{from} (private)"))));
+        }
+        "ACK" => {
+            let (what, detail) = rest.split_once(' ').unwrap_or((rest, ""));
+            match what {
+                "MESSAGE" => return Reply::None,
+                "JOIN" => session.room = Some(detail.to_string()),
+                "PART" => session.room = None,
+                "NICK" => session.nick = Some(detail.to_string()),
+                _ => {}
+            }
+            paint.show(&paint.paint("32", line));
+        }
+        "ERROR" => {
+            paint.show(&paint.paint("1;31", line));
+            match rest {
+                "AUTH_FAILED" => return Reply::Refused("authentication failed".to_string()),
+                "KICKED" => return Reply::Refused("kicked by an admin".to_string()),
+                _ => {}
+            }
+        }
+        "LOGIN" => {
+            paint.note(&format!("logged in as {rest}"));
+            session.logged_in = true;
+            let mut lines = Vec::new();
+            lines.extend(session.nick.as_ref().map(|nick| format!("NICK:{nick}")));
+            lines.extend(session.room.as_ref().map(|room| format!("JOIN:{room}")));
+            return Reply::Send(lines);
+        }
+        "AUTH_REQUIRED" => match auth {
+            Some(credentials) => return Reply::Send(vec![format!("AUTH:{credentials}")]),
+            None => paint.note(&format!("the server requires auth: set {AUTH_VAR}, or type AUTH:<token>")),
+        },
+        "PING" => return Reply::Send(vec!["PONG".to_string()]),
+        _ => paint.show(&paint.dim(line)),
+    }
+    Reply::None
+}
@@ -0,0 +1,109 @@
+//! Large payloads offloaded to disk, passed around by reference.
+//!
+//! A message longer than the threshold isn't broadcast itself: its payload
+//! is written to a file in the blob directory and everyone gets a short
+//! `BLOBREF:<sender> <id> <size>` instead, then fetches the body with
+//! `FETCH:<id>` if they want it. Fan-out then carries a few dozen bytes
+//! however large the message was.
+//!
+//! Files are written from the event loop, so the reference can't go out
+//! before its blob exists; that's one write to the page cache in place of
+//! one per recipient. The server never deletes them. Without the
+//! `persistence` feature there's no blob store.
+
+#[cfg(feature = "persistence")]
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+#[cfg(feature = "persistence")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct BlobConfig {
+    /// Where blobs are kept; created if missing.
+    pub dir: PathBuf,
+    /// Payloads longer than this many bytes are offloaded.
+    pub threshold: usize,
+}
+
+impl BlobConfig {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into(), threshold: 4096 }
+    }
+}
+
+#[cfg(feature = "persistence")]
+pub struct BlobStore {
+    dir: PathBuf,
+    threshold: usize,
+    /// Distinguishes this run's ids from those of earlier runs, whose
+    /// blobs may still be in the directory.
+    run: String,
+    next: u64,
+}
+
+#[cfg(feature = "persistence")]
+impl BlobStore {
+    pub fn open(config: &BlobConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Ok(Self { dir: config.dir.clone(), threshold: config.threshold, run: format!("{started:x}"), next: 0 })
+    }
+
+    pub fn wants(&self, payload: &str) -> bool {
+        payload.len() > self.threshold
+    }
+
+    /// Stores a payload, returning its id.
+    pub fn put(&mut self, payload: &str) -> io::Result<String> {
+        self.next += 1;
+        let id = format!("{}-{}", self.run, self.next);
+        fs::write(self.dir.join(&id), payload)?;
+        Ok(id)
+    }
+
+    /// A stored payload, or `None` if there's no blob by that id.
+    pub fn get(&self, id: &str) -> io::Result<Option<String>> {
+        // Ids are hex and a dash; anything else could name a path outside
+        // the directory.
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return Ok(None);
+        }
+        match fs::read_to_string(self.dir.join(id)) {
+            Ok(payload) => Ok(Some(payload)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// For the startup log.
+    pub fn describe(&self) -> String {
+        format!("dir={} threshold={}", self.dir.display(), self.threshold)
+    }
+}
+
+/// Built without persistence, so no store can be open.
+#[cfg(not(feature = "persistence"))]
+pub enum BlobStore {}
+
+#[cfg(not(feature = "persistence"))]
+impl BlobStore {
+    pub fn open(_config: &BlobConfig) -> io::Result<Self> {
+        Err(crate::info::not_built("persistence"))
+    }
+
+    pub fn wants(&self, _payload: &str) -> bool {
+        match *self {}
+    }
+
+    pub fn put(&mut self, _payload: &str) -> io::Result<String> {
+        match *self {}
+    }
+
+    pub fn get(&self, _id: &str) -> io::Result<Option<String>> {
+        match *self {}
+    }
+
+    pub fn describe(&self) -> String {
+        match *self {}
+    }
+}
@@ -0,0 +1,164 @@
+//! A blocking client, for programs that aren't async.
+//!
+//! [`Client`] speaks the line protocol over TCP and runs a small Tokio
+//! runtime of its own, so a script or a synchronous application can talk
+//! to a server without taking on an executor:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use tcp_broadcast::blocking::Client;
+//!
+//! let mut client = Client::connect("127.0.0.1:8888")?;
+//! client.send("hello")?;
+//! while let Some(line) = client.recv_timeout(Duration::from_secs(5))? {
+//!     println!("{line}");
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Lines go both ways as they are on the wire, without the newline; see
+//! the README for what they mean. Calling it from inside an async runtime
+//! panics, as blocking there would stall the runtime's other tasks.
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+
+use futures::SinkExt;
+use tokio::net::TcpStream;
+use tokio::runtime::{self, Runtime};
+use tokio::time;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+
+use crate::net;
+
+/// How long to wait for `LOGIN:` before giving up on a connection.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection to a server that has logged in.
+pub struct Client {
+    // Before the runtime, so it's dropped while that's still around
+    conn: Framed<TcpStream, LinesCodec>,
+    runtime: Runtime,
+    id: String,
+    /// Lines that came before `LOGIN:` (history), not yet received.
+    pending: VecDeque<String>,
+}
+
+impl Client {
+    /// Connects to `addr` (`host:port`) and waits for `LOGIN:`.
+    pub fn connect(addr: &str) -> io::Result<Client> {
+        Self::login(addr, None)
+    }
+
+    /// Connects to a server that wants credentials, sending `AUTH:` with
+    /// `credentials` (a token, or `user password`) when asked.
+    pub fn connect_with_auth(addr: &str, credentials: &str) -> io::Result<Client> {
+        Self::login(addr, Some(credentials))
+    }
+
+    fn login(addr: &str, credentials: Option<&str>) -> io::Result<Client> {
+        let runtime = runtime::Builder::new_current_thread().enable_all().build()?;
+        let stream = runtime.block_on(net::connect(addr))?;
+        let mut client = Client { conn: Framed::new(stream, LinesCodec::new()), runtime, id: String::new(), pending: VecDeque::new() };
+        let deadline = time::Instant::now() + LOGIN_TIMEOUT;
+        loop {
+            let line = client.runtime.block_on(async { time::timeout_at(deadline, client.conn.next()).await });
+            let line = match line {
+                Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "no LOGIN from the server")),
+                Ok(line) => read(line)?.ok_or_else(closed)?,
+            };
+            if let Some(id) = line.strip_prefix("LOGIN:") {
+                client.id = id.to_string();
+                return Ok(client);
+            }
+            match (line.as_str(), credentials) {
+                ("AUTH_REQUIRED", Some(credentials)) => client.send(&format!("AUTH:{credentials}"))?,
+                ("AUTH_REQUIRED", None) => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "server wants credentials")),
+                (refused, _) if refused.starts_with("ERROR:") || refused.starts_with("BUSY:") => {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionRefused, refused.to_string()));
+                }
+                _ => client.pending.push_back(line),
+            }
+        }
+    }
+
+    /// The id the server gave this client.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Sends one line: a message, or a command like `JOIN:dev`.
+    pub fn send(&mut self, line: &str) -> io::Result<()> {
+        self.runtime.block_on(self.conn.send(line)).map_err(codec_error)
+    }
+
+    /// Waits for the next line; an error if the server closed the
+    /// connection.
+    pub fn recv(&mut self) -> io::Result<String> {
+        if let Some(line) = self.pending.pop_front() {
+            return Ok(line);
+        }
+        read(self.runtime.block_on(self.conn.next()))?.ok_or_else(closed)
+    }
+
+    /// Waits up to `timeout` for the next line; `None` if none came.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<String>> {
+        if let Some(line) = self.pending.pop_front() {
+            return Ok(Some(line));
+        }
+        match self.runtime.block_on(async { time::timeout(timeout, self.conn.next()).await }) {
+            Ok(line) => read(line)?.ok_or_else(closed).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+fn read(line: Option<Result<String, LinesCodecError>>) -> io::Result<Option<String>> {
+    line.transpose().map_err(codec_error)
+}
+
+fn codec_error(e: LinesCodecError) -> io::Error {
+    match e {
+        LinesCodecError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{BroadcastServer, Config};
+    use crate::LogLevel;
+
+    #[test]
+    fn talks_without_an_async_caller() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        std::thread::spawn(move || {
+            let runtime = runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let config = Config { log_level: LogLevel::Error, ..Config::default() };
+                BroadcastServer::bind(addr).config(config).serve(listener).await
+            })
+        });
+
+        let mut a = Client::connect(&addr.to_string()).unwrap();
+        let mut b = Client::connect(&addr.to_string()).unwrap();
+        a.send("hello").unwrap();
+        assert_eq!(next(&mut a, "ACK:"), Some("ACK:MESSAGE".to_string()));
+        assert_eq!(next(&mut b, "MESSAGE:"), Some(format!("MESSAGE:{} hello", a.id())));
+    }
+
+    /// The next line of a kind, past presence notices.
+    fn next(client: &mut Client, kind: &str) -> Option<String> {
+        std::iter::from_fn(|| client.recv_timeout(Duration::from_secs(2)).unwrap()).find(|line| line.starts_with(kind))
+    }
+}
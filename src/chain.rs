@@ -0,0 +1,133 @@
+//! Hash-chaining the message log, so editing it after the fact shows.
+//!
+//! With `--log-chain`, every entry the server appends carries `prev`, the
+//! SHA-256 of the line before it as written (newline excluded), in hex;
+//! the first line's is all zeros. Changing, inserting or deleting a line
+//! breaks the link from the line after it, and patching that one breaks
+//! the next, all the way to the end. A purge replaces each entry it removes
+//! with a tombstone, `{"purged":"{HASH}"}`, holding the hash of the line it
+//! replaced, so the chain still holds without keeping the message. The
+//! last line's hash, the head, isn't covered by anything after it: an
+//! operator who needs to prove the tail wasn't rewritten either keeps
+//! heads printed by `tcp-broadcast verify-log PATH` somewhere else, or
+//! compares against one kept earlier.
+
+use std::io;
+use std::path::Path;
+
+#[cfg(feature = "persistence")]
+use ring::digest::{digest, SHA256};
+#[cfg(feature = "persistence")]
+use serde_json::{json, Value};
+
+/// The `prev` of a log's first line.
+#[cfg(feature = "persistence")]
+pub(crate) const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The hash the next line links to: the line's own, or for a tombstone
+/// that of the line it replaced.
+#[cfg(feature = "persistence")]
+pub(crate) fn link(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    if line.starts_with(br#"{"purged":"#) {
+        if let Some(hash) = serde_json::from_slice::<Value>(line).ok().and_then(|t| t["purged"].as_str().map(str::to_string)) {
+            return hash;
+        }
+    }
+    digest(&SHA256, line).as_ref().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// What a purged line is replaced with.
+#[cfg(feature = "persistence")]
+pub(crate) fn tombstone(line: &[u8]) -> String {
+    format!("{}\n", json!({ "purged": link(line) }))
+}
+
+/// The head of the log in `contents`: the link of its last line.
+#[cfg(feature = "persistence")]
+pub(crate) fn head(contents: &[u8]) -> String {
+    contents.split(|&b| b == b'\n').rfind(|line| !line.is_empty()).map_or_else(|| GENESIS.to_string(), link)
+}
+
+/// What checking a whole log found.
+#[cfg(feature = "persistence")]
+#[derive(PartialEq, Eq, Debug)]
+struct Report {
+    entries: usize,
+    purged: usize,
+    /// Lines from before chaining was turned on.
+    unchained: usize,
+    head: String,
+}
+
+/// Follows the chain through `contents`, or says where it breaks. Lines
+/// written before chaining was on may come first; after the first chained
+/// line every line must be.
+#[cfg(feature = "persistence")]
+fn check(contents: &[u8]) -> Result<Report, String> {
+    let mut report = Report { entries: 0, purged: 0, unchained: 0, head: GENESIS.to_string() };
+    let mut chained = false;
+    for (i, line) in contents.split(|&b| b == b'\n').enumerate().filter(|(_, line)| !line.is_empty()) {
+        let n = i + 1;
+        let entry: Value = serde_json::from_slice(line).map_err(|e| format!("line {n}: not JSON: {e}"))?;
+        if entry["purged"].is_string() {
+            report.purged += 1;
+        } else {
+            match entry["prev"].as_str() {
+                Some(prev) if prev == report.head => chained = true,
+                Some(_) => return Err(format!("line {n}: prev doesn't match the line before it")),
+                None if chained => return Err(format!("line {n}: not chained")),
+                None => report.unchained += 1,
+            }
+            report.entries += 1;
+        }
+        report.head = link(line);
+    }
+    Ok(report)
+}
+
+/// `verify-log` subcommand: checks the chain through the log at `path`,
+/// prints what it found, and returns whether it held.
+#[cfg(feature = "persistence")]
+pub fn verify(path: &Path) -> io::Result<bool> {
+    let contents = std::fs::read(path)?;
+    match check(&contents) {
+        Ok(Report { entries, purged, unchained, head }) => {
+            println!("OK {} entries={entries} purged={purged} unchained={unchained} head={head}", path.display());
+            Ok(true)
+        }
+        Err(why) => {
+            println!("BROKEN {}: {why}", path.display());
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(not(feature = "persistence"))]
+pub fn verify(_path: &Path) -> io::Result<bool> {
+    Err(crate::info::not_built("persistence"))
+}
+
+#[cfg(all(test, feature = "persistence"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_break_the_chain() {
+        let plain = "{\"text\":\"before\"}\n";
+        let first = format!("{{\"prev\":\"{}\",\"text\":\"one\"}}", link(plain.as_bytes()));
+        let second = format!("{{\"prev\":\"{}\",\"text\":\"two\"}}", link(first.as_bytes()));
+        let log = format!("{plain}{first}\n{second}\n");
+        let report = check(log.as_bytes()).unwrap();
+        assert_eq!((report.entries, report.purged, report.unchained), (3, 0, 1));
+        assert_eq!(report.head, head(log.as_bytes()));
+
+        // A purged line leaves the chain as it was
+        let purged = format!("{plain}{}{second}\n", tombstone(first.as_bytes()));
+        assert_eq!(check(purged.as_bytes()).unwrap().head, report.head);
+        let edited = log.replace("one", "uno");
+        assert_eq!(check(edited.as_bytes()), Err("line 3: prev doesn't match the line before it".to_string()));
+        let unchained = format!("{plain}{first}\n{plain}");
+        assert_eq!(check(unchained.as_bytes()), Err("line 3: not chained".to_string()));
+    }
+}
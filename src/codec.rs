@@ -0,0 +1,172 @@
+//! Line framing for the client protocol.
+//!
+//! Frames are handed out as `Bytes` split off the connection's read buffer,
+//! so a line costs no allocation of its own: the buffer is reused once every
+//! frame taken from it has been dropped, and only a partial line that
+//! straddles the end of the buffer gets copied when it grows.
+//!
+//! Lines have a maximum length, so a client that never sends a newline
+//! can't make the buffer grow without bound.
+//!
+//! Clients on the framed listener send length-prefixed frames instead (a
+//! 4-byte big-endian length, then the payload), which can carry newlines
+//! and binary data. Each frame stands for one line, under the same limit.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Decoder, LengthDelimitedCodec, LengthDelimitedCodecError};
+
+use crate::conn::Transport;
+
+/// Splits input on `\n`, dropping the newline and an optional trailing `\r`.
+pub struct LineDecoder {
+    /// How far into the buffer we've already looked for a newline.
+    scanned: usize,
+    /// Longest line accepted, line ending not included.
+    max_length: usize,
+}
+
+impl LineDecoder {
+    pub fn new(max_length: usize) -> Self {
+        Self { scanned: 0, max_length }
+    }
+}
+
+/// The read error for a line over the maximum length.
+#[derive(Debug)]
+pub struct LineTooLong;
+
+impl fmt::Display for LineTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("line too long")
+    }
+}
+
+impl Error for LineTooLong {}
+
+impl LineTooLong {
+    /// Whether a read error was this one.
+    pub fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<LineTooLong>())
+    }
+}
+
+/// A client's input: lines, or length-prefixed frames on the framed
+/// listener.
+pub enum InputCodec {
+    Lines(LineDecoder),
+    Frames(LengthDelimitedCodec),
+}
+
+impl InputCodec {
+    pub fn new(transport: Transport, max_length: usize) -> Self {
+        match transport {
+            Transport::Framed => InputCodec::Frames(frames(max_length)),
+            Transport::Tcp | Transport::WebSocket | Transport::Unix => InputCodec::Lines(LineDecoder::new(max_length)),
+        }
+    }
+}
+
+/// The length-prefixed codec, for frames of up to `max_length` bytes.
+pub fn frames(max_length: usize) -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder().max_frame_length(max_length).new_codec()
+}
+
+impl Decoder for InputCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        match self {
+            InputCodec::Lines(lines) => lines.decode(buf),
+            InputCodec::Frames(frames) => frames.decode(buf).map(|frame| frame.map(BytesMut::freeze)).map_err(too_long),
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        match self {
+            InputCodec::Lines(lines) => lines.decode_eof(buf),
+            InputCodec::Frames(frames) => {
+                frames.decode_eof(buf).map(|frame| frame.map(BytesMut::freeze)).map_err(too_long)
+            }
+        }
+    }
+}
+
+/// An oversized frame is reported like an oversized line.
+fn too_long(e: io::Error) -> io::Error {
+    if e.get_ref().is_some_and(|e| e.is::<LengthDelimitedCodecError>()) {
+        io::Error::new(io::ErrorKind::InvalidData, LineTooLong)
+    } else {
+        e
+    }
+}
+
+impl Decoder for LineDecoder {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        let Some(pos) = buf[self.scanned..].iter().position(|&b| b == b'\n') else {
+            self.scanned = buf.len();
+            // One more byte may yet turn out to be the `\r` of a `\r\n`
+            if buf.len() > self.max_length.saturating_add(1) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, LineTooLong));
+            }
+            return Ok(None);
+        };
+        let mut line = buf.split_to(self.scanned + pos + 1);
+        self.scanned = 0;
+        line.truncate(line.len() - 1);
+        if line.last() == Some(&b'\r') {
+            line.truncate(line.len() - 1);
+        }
+        if line.len() > self.max_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, LineTooLong));
+        }
+        Ok(Some(line.freeze()))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        if let Some(line) = self.decode(buf)? {
+            return Ok(Some(line));
+        }
+        // Like `lines()`, a final unterminated line still counts.
+        self.scanned = 0;
+        if buf.is_empty() {
+            Ok(None)
+        } else if buf.len() > self.max_length {
+            Err(io::Error::new(io::ErrorKind::InvalidData, LineTooLong))
+        } else {
+            Ok(Some(buf.split().freeze()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_lines_across_reads() {
+        let mut decoder = LineDecoder::new(5);
+        let mut buf = BytesMut::from("one\r\ntw");
+        assert_eq!(decoder.decode(&mut buf).unwrap().as_deref(), Some(&b"one"[..]));
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"o\nfive!\r");
+        assert_eq!(decoder.decode(&mut buf).unwrap().as_deref(), Some(&b"two"[..]));
+        // Five and a `\r` still might be a line of five
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"\nseven!!");
+        assert_eq!(decoder.decode(&mut buf).unwrap().as_deref(), Some(&b"five!"[..]));
+        assert!(decoder.decode(&mut buf).is_err_and(|e| LineTooLong::is(&e)));
+
+        let mut buf = BytesMut::from("last");
+        let mut decoder = LineDecoder::new(5);
+        assert_eq!(decoder.decode_eof(&mut buf).unwrap().as_deref(), Some(&b"last"[..]));
+        assert_eq!(decoder.decode_eof(&mut buf).unwrap(), None);
+    }
+}
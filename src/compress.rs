@@ -0,0 +1,86 @@
+//! Compressed broadcasts, for clients that ask.
+//!
+//! Large payloads (JSON documents, say) cost every recipient the same
+//! bandwidth. With `--compress-min-bytes` set, a client can send
+//! `CAPS:compress=gzip` or `CAPS:compress=zstd` to get messages at least
+//! that long compressed, base64-encoded so they still fit on a line:
+//! `MESSAGE[enc=zstd]:{ID} {BASE64}`. Each message is compressed once per
+//! algorithm some client asked for, not once per client, and clients that
+//! didn't ask get it as usual.
+
+/// A compression algorithm a client can ask for. Compiled out without the
+/// `compression` feature, which leaves none to ask for.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Compression {
+    #[cfg(feature = "compression")]
+    Gzip,
+    #[cfg(feature = "compression")]
+    Zstd,
+}
+
+impl Compression {
+    /// Every algorithm this build can use.
+    #[cfg(feature = "compression")]
+    pub const ALL: &[Compression] = &[Compression::Gzip, Compression::Zstd];
+    #[cfg(not(feature = "compression"))]
+    pub const ALL: &[Compression] = &[];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|alg| alg.name() == name)
+    }
+
+    /// The name clients ask for it by, as in `enc=` tags.
+    #[cfg(feature = "compression")]
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    pub fn name(self) -> &'static str {
+        match self {}
+    }
+
+    /// `data` compressed and base64-encoded.
+    #[cfg(feature = "compression")]
+    pub fn compress(self, data: &[u8]) -> String {
+        use std::io::Write;
+
+        // Writing into memory can't fail
+        let compressed = match self {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).expect("in-memory write");
+                encoder.finish().expect("in-memory write")
+            }
+            Compression::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL).expect("in-memory write"),
+        };
+        data_encoding::BASE64.encode(&compressed)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    pub fn compress(self, _data: &[u8]) -> String {
+        match self {}
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn round_trips_through_base64() {
+        let data = br#"{"items":[1,2,3,1,2,3,1,2,3,1,2,3,1,2,3,1,2,3,1,2,3,1,2,3]}"#;
+        let gzip = data_encoding::BASE64.decode(Compression::Gzip.compress(data).as_bytes()).unwrap();
+        let mut unzipped = Vec::new();
+        flate2::read::GzDecoder::new(&gzip[..]).read_to_end(&mut unzipped).unwrap();
+        assert_eq!(unzipped, data);
+        let zstd = data_encoding::BASE64.decode(Compression::Zstd.compress(data).as_bytes()).unwrap();
+        assert_eq!(zstd::bulk::decompress(&zstd, data.len()).unwrap(), data);
+        assert_eq!(Compression::parse("zstd"), Some(Compression::Zstd));
+        assert_eq!(Compression::parse("brotli"), None);
+    }
+}
@@ -0,0 +1,309 @@
+//! `conformance` subcommand: checks a running endpoint against the
+//! documented protocol and prints a pass/fail report.
+//!
+//! Each check opens its own connections so one failure doesn't cascade
+//! into the rest of the report.
+
+use std::io;
+use std::time::Duration;
+
+use futures::SinkExt;
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::net;
+use crate::stamp;
+
+/// How long to wait for an expected line before failing the check.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to listen when checking that nothing arrives.
+const QUIET_PERIOD: Duration = Duration::from_millis(300);
+
+pub(crate) type CheckResult = Result<(), String>;
+
+/// Runs every check against `target` and returns whether they all passed.
+pub async fn run(target: &str) -> io::Result<bool> {
+    println!("conformance {target}");
+    let checks: [(&str, CheckResult); 11] = [
+        ("handshake", handshake(target).await),
+        ("broadcast and ack", broadcast_and_ack(target).await),
+        ("no echo to sender", no_echo(target).await),
+        ("control characters stripped", control_chars(target).await),
+        ("ephemeral events", events(target).await),
+        ("ingest ack ranges", ingest(target).await),
+        ("rooms", rooms(target).await),
+        ("nicknames", nicknames(target).await),
+        ("private messages", private_messages(target).await),
+        ("presence", presence(target).await),
+        ("ping", ping(target).await),
+    ];
+
+    let mut failed = 0;
+    for (name, result) in &checks {
+        match result {
+            Ok(()) => println!("PASS {name}"),
+            Err(why) => {
+                failed += 1;
+                println!("FAIL {name}: {why}");
+            }
+        }
+    }
+    println!("{} passed, {failed} failed", checks.len() - failed);
+    Ok(failed == 0)
+}
+
+/// A connected test client that has completed the handshake.
+pub(crate) struct Probe {
+    pub(crate) id: String,
+    conn: Framed<TcpStream, LinesCodec>,
+}
+
+impl Probe {
+    pub(crate) async fn connect(target: &str) -> Result<Probe, String> {
+        let stream = net::connect(target)
+            .await
+            .map_err(|e| format!("connect to {target}: {e}"))?;
+        let mut probe = Probe {
+            id: String::new(),
+            conn: Framed::new(stream, LinesCodec::new()),
+        };
+        // A server keeping history replays it first
+        let mut login = probe.recv().await?;
+        while login.starts_with("HISTORY:") {
+            login = probe.recv().await?;
+        }
+        let id = login
+            .strip_prefix("LOGIN:")
+            .ok_or_else(|| format!("expected LOGIN:<id>, got {login:?}"))?;
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("LOGIN id should be numeric, got {id:?}"));
+        }
+        probe.id = id.to_string();
+        Ok(probe)
+    }
+
+    pub(crate) async fn send(&mut self, line: &str) -> CheckResult {
+        self.conn.send(line).await.map_err(|e| format!("send: {e}"))
+    }
+
+    /// Next line, skipping presence notices: other probes come and go
+    /// while a check runs, so those can arrive at any point.
+    async fn recv(&mut self) -> Result<String, String> {
+        loop {
+            let line = self.recv_any().await?;
+            if !is_unsolicited(&line) {
+                return Ok(line);
+            }
+        }
+    }
+
+    async fn recv_any(&mut self) -> Result<String, String> {
+        match time::timeout(REPLY_TIMEOUT, self.conn.next()).await {
+            Ok(Some(Ok(line))) => Ok(line),
+            Ok(Some(Err(e))) => Err(format!("read: {e}")),
+            Ok(None) => Err("connection closed".to_string()),
+            Err(_) => Err("timed out waiting for a reply".to_string()),
+        }
+    }
+
+    /// Waits for one presence notice among any others.
+    pub(crate) async fn expect_presence(&mut self, want: &str) -> CheckResult {
+        loop {
+            let got = self.recv_any().await.map_err(|e| format!("waiting for {want:?}: {e}"))?;
+            if got == want {
+                return Ok(());
+            }
+            if !is_unsolicited(&got) {
+                return Err(format!("expected {want:?}, got {got:?}"));
+            }
+        }
+    }
+
+    pub(crate) async fn expect(&mut self, want: &str) -> CheckResult {
+        let got = self.recv().await?;
+        // Servers stamping messages add an ID and time that can't be predicted
+        if stamp::unstamped(&got) == want {
+            Ok(())
+        } else {
+            Err(format!("expected {want:?}, got {got:?}"))
+        }
+    }
+
+    pub(crate) async fn expect_quiet(&mut self) -> CheckResult {
+        let deadline = time::Instant::now() + QUIET_PERIOD;
+        loop {
+            match time::timeout_at(deadline, self.conn.next()).await {
+                Err(_) => return Ok(()),
+                Ok(Some(Ok(line))) if is_unsolicited(&line) => {}
+                Ok(Some(Ok(line))) => return Err(format!("unexpected {line:?}")),
+                Ok(_) => return Err("connection closed".to_string()),
+            }
+        }
+    }
+}
+
+/// Lines the server may send at any moment: presence notices, liveness
+/// pings and keepalives.
+pub(crate) fn is_unsolicited(line: &str) -> bool {
+    line.starts_with("JOINED:") || line.starts_with("LEFT:") || line == "PING" || line == "NOOP"
+}
+
+async fn handshake(target: &str) -> CheckResult {
+    let a = Probe::connect(target).await?;
+    let b = Probe::connect(target).await?;
+    if a.id == b.id {
+        return Err(format!("two clients got the same id {}", a.id));
+    }
+    Ok(())
+}
+
+async fn broadcast_and_ack(target: &str) -> CheckResult {
+    let mut a = Probe::connect(target).await?;
+    let mut b = Probe::connect(target).await?;
+    let mut c = Probe::connect(target).await?;
+    a.send("conformance hello").await?;
+    a.expect("ACK:MESSAGE").await?;
+    b.expect(&format!("MESSAGE:{} conformance hello", a.id)).await?;
+    c.expect(&format!("MESSAGE:{} conformance hello", a.id)).await
+}
+
+async fn no_echo(target: &str) -> CheckResult {
+    let mut a = Probe::connect(target).await?;
+    let _b = Probe::connect(target).await?;
+    a.send("echo?").await?;
+    a.expect("ACK:MESSAGE").await?;
+    a.expect_quiet().await
+}
+
+async fn control_chars(target: &str) -> CheckResult {
+    let mut a = Probe::connect(target).await?;
+    let mut b = Probe::connect(target).await?;
+    a.send("x\rMESSAGE:1 forged").await?;
+    a.expect("ACK:MESSAGE").await?;
+    b.expect(&format!("MESSAGE:{} xMESSAGE:1 forged", a.id)).await
+}
+
+async fn events(target: &str) -> CheckResult {
+    let mut a = Probe::connect(target).await?;
+    let mut b = Probe::connect(target).await?;
+    let mut muted = Probe::connect(target).await?;
+    muted.send("EVENTS:OFF").await?;
+    muted.expect("ACK:EVENTS").await?;
+    a.send("TYPING").await?;
+    b.expect(&format!("EVENT:{} TYPING", a.id)).await?;
+    // Events are never acknowledged, and opted-out clients don't see them.
+    a.expect_quiet().await?;
+    muted.expect_quiet().await
+}
+
+async fn ingest(target: &str) -> CheckResult {
+    let mut a = Probe::connect(target).await?;
+    a.send("INGEST").await?;
+    a.expect("ACK:INGEST").await?;
+    for i in 0..3 {
+        a.send(&format!("ingest {i}")).await?;
+    }
+    // Ranges may arrive split across flush ticks, but must be contiguous
+    // from 1 and end at the last message.
+    let mut next = 1;
+    while next <= 3 {
+        let line = a.recv().await?;
+        let range = line
+            .strip_prefix("ACK_RANGE:")
+            .and_then(|r| r.split_once('-'))
+            .and_then(|(from, to)| Some((from.parse::<u64>().ok()?, to.parse::<u64>().ok()?)))
+            .ok_or_else(|| format!("expected ACK_RANGE:<from>-<to>, got {line:?}"))?;
+        if range.0 != next || range.1 < range.0 {
+            return Err(format!("expected a range starting at {next}, got {line:?}"));
+        }
+        next = range.1 + 1;
+    }
+    if next != 4 {
+        return Err(format!("acked up to {}, sent 3", next - 1));
+    }
+    Ok(())
+}
+
+async fn rooms(target: &str) -> CheckResult {
+    let room = "conformance-room";
+    let mut a = Probe::connect(target).await?;
+    let mut b = Probe::connect(target).await?;
+    let mut lobby = Probe::connect(target).await?;
+    for p in [&mut a, &mut b] {
+        p.send(&format!("JOIN:{room}")).await?;
+        p.expect(&format!("ACK:JOIN {room}")).await?;
+    }
+    a.send("ROOMS").await?;
+    let rooms = a.recv().await?;
+    let listed = rooms
+        .strip_prefix("ROOMS:")
+        .ok_or_else(|| format!("expected ROOMS:..., got {rooms:?}"))?;
+    if !listed.split(' ').any(|r| r == format!("{room}=2")) {
+        return Err(format!("expected {room}=2 in {rooms:?}"));
+    }
+    // Room traffic stays in the room, lobby traffic stays out of it
+    a.send("in the room").await?;
+    a.expect("ACK:MESSAGE").await?;
+    b.expect(&format!("MESSAGE:{} in the room", a.id)).await?;
+    lobby.expect_quiet().await?;
+    lobby.send("in the lobby").await?;
+    lobby.expect("ACK:MESSAGE").await?;
+    b.expect_quiet().await?;
+    b.send(&format!("PART:{room}")).await?;
+    b.expect(&format!("ACK:PART {room}")).await?;
+    b.send(&format!("PART:{room}")).await?;
+    b.expect(&format!("ERROR:NOT_IN_ROOM {room}")).await
+}
+
+async fn nicknames(target: &str) -> CheckResult {
+    let mut a = Probe::connect(target).await?;
+    let mut b = Probe::connect(target).await?;
+    // Ids aren't reused, so this name is free unless the server is broken
+    let nick = format!("probe-{}", a.id);
+    a.send(&format!("NICK:{nick}")).await?;
+    a.expect(&format!("ACK:NICK {nick}")).await?;
+    b.send(&format!("NICK:{}", nick.to_uppercase())).await?;
+    b.expect(&format!("ERROR:NICK_TAKEN {}", nick.to_uppercase())).await?;
+    a.send("by name").await?;
+    a.expect("ACK:MESSAGE").await?;
+    b.expect(&format!("MESSAGE:{nick} by name")).await
+}
+
+async fn private_messages(target: &str) -> CheckResult {
+    let mut a = Probe::connect(target).await?;
+    let mut b = Probe::connect(target).await?;
+    let mut c = Probe::connect(target).await?;
+    a.send(&format!("MSG:{} just for you", b.id)).await?;
+    a.expect("ACK:MSG").await?;
+    b.expect(&format!("MSG:{} just for you", a.id)).await?;
+    c.expect_quiet().await?;
+    a.send("MSG:0 anyone there").await?;
+    a.expect("ERROR:UNKNOWN_CLIENT 0").await
+}
+
+async fn presence(target: &str) -> CheckResult {
+    let mut a = Probe::connect(target).await?;
+    let b = Probe::connect(target).await?;
+    a.expect_presence(&format!("JOINED:{}", b.id)).await?;
+    a.send("WHO").await?;
+    let who = a.recv().await?;
+    let ids: Vec<&str> = who
+        .strip_prefix("WHO:")
+        .ok_or_else(|| format!("expected WHO:..., got {who:?}"))?
+        .split(' ')
+        .collect();
+    if !ids.contains(&a.id.as_str()) || !ids.contains(&b.id.as_str()) {
+        return Err(format!("expected {} and {} in {who:?}", a.id, b.id));
+    }
+    let gone = b.id.clone();
+    drop(b);
+    a.expect_presence(&format!("LEFT:{gone}")).await
+}
+
+async fn ping(target: &str) -> CheckResult {
+    let mut a = Probe::connect(target).await?;
+    a.send("PING").await?;
+    a.expect("PONG").await
+}
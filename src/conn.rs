@@ -0,0 +1,92 @@
+//! Accepted connections, whatever the transport.
+//!
+//! A socket from any listener may need a TLS handshake, a WebSocket
+//! upgrade or both before it can speak the line protocol. Once it can, it
+//! is split into boxed read and write halves so the rest of the server
+//! doesn't care which it was.
+
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time;
+#[cfg(feature = "tls")]
+use tokio_rustls::server::TlsStream;
+
+use crate::tls::TlsAcceptor;
+use crate::ws::{self, WsStream};
+
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Which listener a socket came in on.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Tcp,
+    WebSocket,
+    /// Plain TCP (or TLS) speaking length-prefixed frames instead of lines.
+    Framed,
+    /// The Unix domain socket, never upgraded.
+    Unix,
+}
+
+/// A connection ready for the line protocol.
+pub enum Conn {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+    WebSocket(Box<WsStream>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Conn {
+    pub fn split(self) -> (ReadHalf, WriteHalf) {
+        match self {
+            Conn::Plain(stream) => {
+                let (read, write) = stream.into_split();
+                (Box::new(read), Box::new(write))
+            }
+            #[cfg(feature = "tls")]
+            Conn::Tls(stream) => {
+                let (read, write) = tokio::io::split(*stream);
+                (Box::new(read), Box::new(write))
+            }
+            Conn::WebSocket(stream) => ws::split(*stream),
+            #[cfg(unix)]
+            Conn::Unix(stream) => {
+                let (read, write) = stream.into_split();
+                (Box::new(read), Box::new(write))
+            }
+        }
+    }
+}
+
+/// Whether a socket needs [`upgrade`] before it can be used.
+pub fn needs_upgrade(transport: Transport, tls: Option<&TlsAcceptor>) -> bool {
+    transport == Transport::WebSocket || tls.is_some()
+}
+
+/// Runs the TLS handshake and/or WebSocket upgrade, bounded by `timeout`.
+pub async fn upgrade(stream: TcpStream, transport: Transport, tls: Option<TlsAcceptor>, timeout: Duration) -> io::Result<Conn> {
+    let handshake = async {
+        match (transport, tls) {
+            (Transport::Tcp | Transport::Framed | Transport::Unix, None) => Ok(Conn::Plain(stream)),
+            #[cfg(feature = "tls")]
+            (Transport::Tcp | Transport::Framed | Transport::Unix, Some(tls)) => Ok(Conn::Tls(Box::new(tls.accept(stream).await?))),
+            (Transport::WebSocket, None) => Ok(Conn::WebSocket(Box::new(ws::accept(Box::new(stream)).await?))),
+            #[cfg(feature = "tls")]
+            (Transport::WebSocket, Some(tls)) => {
+                let stream = tls.accept(stream).await?;
+                Ok(Conn::WebSocket(Box::new(ws::accept(Box::new(stream)).await?)))
+            }
+            #[cfg(not(feature = "tls"))]
+            (_, Some(tls)) => match tls {},
+        }
+    };
+    match time::timeout(timeout, handshake).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")),
+    }
+}
@@ -0,0 +1,91 @@
+//! Collapsing of repeated messages.
+//!
+//! When a sender repeats the exact same line within the window, only the
+//! first copy is broadcast. The rest are acknowledged but held back, and
+//! once the run ends (a different line, or the window closing) the other
+//! clients get a single `REPEATED:<id> <n>` line saying how many copies
+//! they didn't see.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+/// Per-sender run of identical messages.
+pub struct Dedup {
+    window: Duration,
+    /// The last delivered message and when it was delivered.
+    last: Option<(Bytes, Instant)>,
+    /// Copies of `last` suppressed so far.
+    repeated: u32,
+}
+
+/// What to do with a message, and how to close the run it ends.
+pub struct Check {
+    pub deliver: bool,
+    /// Copies suppressed in the run this message ends, if any were.
+    pub ended: Option<u32>,
+    /// Set on the first suppressed copy: when the window closes.
+    pub closes_in: Option<Duration>,
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Self {
+        Self { window, last: None, repeated: 0 }
+    }
+
+    pub fn check(&mut self, frame: &Bytes, now: Instant) -> Check {
+        if let Some((last, since)) = &self.last {
+            let open = now.duration_since(*since) < self.window;
+            if open && last == frame {
+                self.repeated += 1;
+                let closes_in = (self.repeated == 1).then(|| self.window - now.duration_since(*since));
+                return Check { deliver: false, ended: None, closes_in };
+            }
+        }
+        let ended = self.take_repeated();
+        self.last = Some((frame.clone(), now));
+        Check { deliver: true, ended, closes_in: None }
+    }
+
+    /// Ends the current run if its window has closed, returning the number
+    /// of suppressed copies to report.
+    pub fn expire(&mut self, now: Instant) -> Option<u32> {
+        let (_, since) = self.last.as_ref()?;
+        if now.duration_since(*since) < self.window {
+            return None;
+        }
+        self.last = None;
+        self.take_repeated()
+    }
+
+    fn take_repeated(&mut self) -> Option<u32> {
+        let n = std::mem::take(&mut self.repeated);
+        (n > 0).then_some(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collapses_a_run_of_copies() {
+        let window = Duration::from_secs(5);
+        let mut dedup = Dedup::new(window);
+        let (hi, bye) = (Bytes::from("hi"), Bytes::from("bye"));
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        assert!(dedup.check(&hi, now).deliver);
+        let first_copy = dedup.check(&hi, now + second);
+        assert!(!first_copy.deliver && first_copy.closes_in == Some(window - second));
+        assert!(dedup.check(&hi, now + 2 * second).closes_in.is_none());
+
+        let ended = dedup.check(&bye, now + 3 * second);
+        assert!(ended.deliver && ended.ended == Some(2));
+        assert_eq!(dedup.check(&bye, now + 4 * second).ended, None);
+        assert_eq!(dedup.expire(now + 5 * second), None);
+        assert_eq!(dedup.expire(now + 8 * second), Some(1));
+        // The window closed, so the same line goes through again
+        assert!(dedup.check(&bye, now + 8 * second).deliver);
+    }
+}
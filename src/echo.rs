@@ -0,0 +1,75 @@
+//! Echo suppression for bridges that can't tag what they relay.
+//!
+//! A bridge is a client relaying between this server and somewhere else: a
+//! chat gateway, a webhook, a legacy relay. One that can't tell its own
+//! traffic apart sends what it was given straight back, and two of them
+//! facing each other pass a message round forever. With a window set, each
+//! bridge remembers a hash of every message sent out through it; a message
+//! from the bridge matching one of those within the window is its echo,
+//! and is dropped. Each copy sent out excuses one copy coming back, so the
+//! same words said again later still get through.
+
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant};
+
+/// Messages remembered per bridge, however short the window.
+const MAX_REMEMBERED: usize = 1024;
+
+/// What's been sent out through one bridge lately.
+pub(crate) struct Echoes {
+    window: Duration,
+    hasher: RandomState,
+    /// Hashes of the messages, oldest first.
+    sent: VecDeque<(u64, Instant)>,
+}
+
+impl Echoes {
+    pub fn new(window: Duration) -> Self {
+        Self { window, hasher: RandomState::new(), sent: VecDeque::new() }
+    }
+
+    /// Notes a message sent out through the bridge.
+    pub fn sent(&mut self, text: &str, now: Instant) {
+        self.expire(now);
+        if self.sent.len() == MAX_REMEMBERED {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((self.hasher.hash_one(text), now));
+    }
+
+    /// Whether a message from the bridge is one it was sent, which it then
+    /// no longer excuses.
+    pub fn is_echo(&mut self, text: &str, now: Instant) -> bool {
+        self.expire(now);
+        let hash = self.hasher.hash_one(text);
+        let Some(at) = self.sent.iter().position(|&(sent, _)| sent == hash) else { return false };
+        self.sent.remove(at);
+        true
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.sent.front().is_some_and(|&(_, at)| now.duration_since(at) >= self.window) {
+            self.sent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_one_echo_per_copy_within_the_window() {
+        let start = Instant::now();
+        let mut echoes = Echoes::new(Duration::from_secs(5));
+        echoes.sent("hello", start);
+        echoes.sent("hello", start);
+        echoes.sent("bye", start);
+        assert!(echoes.is_echo("hello", start + Duration::from_secs(1)));
+        assert!(echoes.is_echo("hello", start + Duration::from_secs(1)));
+        assert!(!echoes.is_echo("hello", start + Duration::from_secs(1)));
+        assert!(!echoes.is_echo("Hello", start + Duration::from_secs(1)));
+        assert!(!echoes.is_echo("bye", start + Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,351 @@
+//! JSON envelopes, for `--protocol json`.
+//!
+//! The text protocol stays the server's own language: lines are built as
+//! `MESSAGE:3 hi` and friends everywhere, and in JSON mode they're turned
+//! into `{"type":"message","from":3,"body":"hi"}` on their way out, once per
+//! line rather than once per recipient. Inbound envelopes are turned back
+//! into the text command they stand for, except messages, whose body is
+//! never read as a command.
+
+use bytes::Bytes;
+use serde_json::{json, Map, Value};
+
+use crate::protocol;
+
+/// The wire format clients speak.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Newline-delimited text lines (`MESSAGE:3 hi`).
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl Protocol {
+    /// A server line, in this protocol.
+    pub fn encode(self, line: Bytes) -> Bytes {
+        match self {
+            Protocol::Text => line,
+            Protocol::Json => encode(&line),
+        }
+    }
+}
+
+/// What an inbound envelope asks for.
+#[derive(Debug, PartialEq)]
+pub enum Inbound {
+    /// A message to broadcast, taken as is.
+    Message(String),
+    /// The text command line it stands for.
+    Command(String),
+}
+
+/// Reads one inbound envelope. The error is a reason for the violation log.
+pub fn decode(line: &str) -> Result<Inbound, &'static str> {
+    let Ok(Value::Object(envelope)) = serde_json::from_str::<Value>(line) else {
+        return Err("bad json");
+    };
+    let field = |key: &str| envelope.get(key).and_then(Value::as_str).ok_or("bad envelope");
+    let kind = envelope.get("type").and_then(Value::as_str).ok_or("bad envelope")?;
+    let command = match kind {
+        "message" => match (envelope.get("content_type"), envelope.get("seq")) {
+            (None, None) => return Ok(Inbound::Message(field("body")?.to_string())),
+            (Some(_), None) => {
+                // Checked here, since a `]:` in it would end the tag early
+                let content_type = field("content_type")?;
+                if !protocol::valid_content_type(content_type) {
+                    return Err("bad envelope");
+                }
+                format!("PUB[ct={content_type}]:{}", field("body")?)
+            }
+            (None, Some(seq)) => format!("MESSAGE:{} {}", seq.as_u64().ok_or("bad envelope")?, field("body")?),
+            // The text protocol has no line for both
+            (Some(_), Some(_)) => return Err("bad envelope"),
+        },
+        "private" => {
+            let to = field("to")?;
+            if to.contains(' ') {
+                return Err("bad envelope");
+            }
+            format!("MSG:{to} {}", field("body")?)
+        }
+        "join" => format!("JOIN:{}", field("room")?),
+        "part" => format!("PART:{}", field("room")?),
+        "nick" => format!("NICK:{}", field("name")?),
+        "mode" => format!("MODE:{}", field("settings")?),
+        "fetch" => format!("FETCH:{}", field("id")?),
+        "history" => match envelope.get("limit") {
+            None => "HISTORY".to_string(),
+            Some(limit) => format!("HISTORY:{}", limit.as_u64().ok_or("bad envelope")?),
+        },
+        "resume" => format!("RESUME:{}", envelope.get("msg_id").and_then(Value::as_u64).ok_or("bad envelope")?),
+        "approve" | "reject" => {
+            let id = envelope.get("id").and_then(Value::as_u64).ok_or("bad envelope")?;
+            format!("{}:{id}", kind.to_ascii_uppercase())
+        }
+        "direct" => format!("DIRECT:{}", field("to")?),
+        "direct_failed" => format!("DIRECT_FAILED:{}", field("to")?),
+        "auth" => match field("token") {
+            Ok(token) => format!("AUTH:{token}"),
+            Err(_) => {
+                let user = field("user")?;
+                if user.contains(' ') {
+                    return Err("bad envelope");
+                }
+                format!("AUTH:{user} {}", field("password")?)
+            }
+        },
+        "maintenance" => format!("MAINTENANCE:{}", field("mode")?.to_ascii_uppercase()),
+        "accept" => {
+            let types = envelope.get("types").and_then(Value::as_array).ok_or("bad envelope")?;
+            let types: Vec<&str> = types.iter().map(|t| t.as_str().ok_or("bad envelope")).collect::<Result<_, _>>()?;
+            format!("ACCEPT:{}", types.join(","))
+        }
+        "kick" => match field("where") {
+            Ok(selector) => format!("KICK[{selector}]:"),
+            Err(_) => format!("KICK:{}", field("to")?),
+        },
+        "set" => match (field("to"), field("where")) {
+            (Ok(to), _) => format!("SET:{to} {}", field("settings")?),
+            (_, Ok(selector)) => format!("SET[{selector}]:{}", field("settings")?),
+            _ => format!("SET:{}", field("settings")?),
+        },
+        "get" => format!("GET:{}", field("keys").unwrap_or_default()),
+        "broadcast" => match field("where") {
+            Ok(selector) => format!("BROADCAST[{selector}]:{}", field("body")?),
+            Err(_) => format!("BROADCAST:{}", field("body")?),
+        },
+        "purge" => match field("room") {
+            Ok(room) => format!("PURGE:ROOM {room}"),
+            Err(_) => format!("PURGE:USER {}", field("user")?),
+        },
+        "event" => format!("EVENT:{}", field("name")?),
+        "events" => match envelope.get("on").and_then(Value::as_bool).ok_or("bad envelope")? {
+            true => "EVENTS:ON".to_string(),
+            false => "EVENTS:OFF".to_string(),
+        },
+        "receipts" => match envelope.get("on").and_then(Value::as_bool).ok_or("bad envelope")? {
+            true => "RECEIPTS:ON".to_string(),
+            false => "RECEIPTS:OFF".to_string(),
+        },
+        "who" if envelope.get("regions").and_then(Value::as_bool) == Some(true) => "WHO:regions".to_string(),
+        kind @ ("typing" | "stopped_typing" | "who" | "rooms" | "ping" | "pong" | "ingest" | "stats" | "info" | "shutdown" | "barrier") => kind.to_ascii_uppercase(),
+        _ => return Err("unknown envelope type"),
+    };
+    Ok(Inbound::Command(command))
+}
+
+/// Rewrites a text protocol line (with its newline) as a JSON one.
+fn encode(line: &[u8]) -> Bytes {
+    let text = String::from_utf8_lossy(line);
+    let text = text.strip_suffix('\n').unwrap_or(&text);
+    let (history, text) = match text.strip_prefix("HISTORY:") {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let mut envelope = envelope(text);
+    if history {
+        envelope.insert("history".into(), true.into());
+    }
+    let mut out = Value::Object(envelope).to_string();
+    out.push('\n');
+    Bytes::from(out)
+}
+
+fn envelope(text: &str) -> Map<String, Value> {
+    // A tag can hold colons (`ts=10:17:47Z`), so it runs to `]:`
+    let (kind, rest) = match text.split_once(':') {
+        Some((kind, _)) if kind.contains('[') => text.split_once("]:").unwrap_or((text, "")),
+        split => split.unwrap_or((text, "")),
+    };
+    let (kind, attributes) = kind.split_once('[').unwrap_or((kind, ""));
+    let (head, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+    let value = match kind {
+        "MESSAGE" => json!({ "type": "message", "from": name(head), "body": tail }),
+        "MSG" => json!({ "type": "private", "from": name(head), "body": tail }),
+        "BLOBREF" => {
+            let (id, size) = tail.split_once(' ').unwrap_or((tail, ""));
+            json!({ "type": "blobref", "from": name(head), "id": id, "size": number(size) })
+        }
+        "BLOB" => json!({ "type": "blob", "id": head, "body": tail }),
+        "DIRECT" | "DIRECT_FAILED" => json!({ "type": kind.to_ascii_lowercase(), "from": name(rest) }),
+        "HELD" | "APPROVED" | "REJECTED" => json!({ "type": kind.to_ascii_lowercase(), "id": number(rest) }),
+        "PENDING" => {
+            let (from, body) = tail.split_once(' ').unwrap_or((tail, ""));
+            json!({ "type": "pending", "id": number(head), "from": name(from), "body": body })
+        }
+        "FLAGGED" => {
+            let (list, rest) = tail.split_once(' ').unwrap_or((tail, ""));
+            let (from, body) = rest.split_once(' ').unwrap_or((rest, ""));
+            let room = Some(head).filter(|room| *room != "-");
+            json!({ "type": "flagged", "room": room, "list": list, "from": name(from), "body": body })
+        }
+        "REDIRECT" => json!({ "type": "redirect", "addr": rest }),
+        "PUNCH" => json!({ "type": "punch", "peer": name(head), "addr": tail }),
+        "EVENT" => json!({ "type": "event", "from": name(head), "name": tail }),
+        "PRESENCE" => json!({ "type": "presence", "from": name(head), "state": tail }),
+        "REPEATED" => json!({ "type": "repeated", "from": name(head), "count": number(tail) }),
+        "LOGIN" | "JOINED" | "LEFT" => json!({ "type": kind.to_ascii_lowercase(), "id": number(rest) }),
+        "ACK" if !head.is_empty() && head.bytes().all(|b| b.is_ascii_digit()) => json!({ "type": "ack", "seq": number(head) }),
+        "DELIVERED" => json!({ "type": "delivered", "seq": number(rest) }),
+        "ACK" => with_detail(json!({ "type": "ack", "of": head.to_ascii_lowercase() }), tail),
+        "ACK_RANGE" => {
+            let (from, to) = rest.split_once('-').unwrap_or((rest, rest));
+            json!({ "type": "ack_range", "from": number(from), "to": number(to) })
+        }
+        "ERROR" => with_detail(json!({ "type": "error", "code": head }), tail),
+        "WARN" => with_detail(json!({ "type": "warning", "code": head }), tail),
+        "WHO" => json!({ "type": "who", "clients": rest.split(' ').filter(|s| !s.is_empty()).map(who).collect::<Vec<_>>() }),
+        "ROOMS" => {
+            let rooms: Vec<Value> = rest.split(' ').filter(|s| !s.is_empty()).map(room).collect();
+            json!({ "type": "rooms", "rooms": rooms })
+        }
+        "NOTICE" => json!({ "type": "notice", "body": rest }),
+        "ALERT" => {
+            let fields: Map<String, Value> = tail
+                .split(' ')
+                .filter_map(|field| field.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.parse::<u64>().map_or_else(|_| value.into(), Value::from)))
+                .collect();
+            json!({ "type": "alert", "event": head, "fields": fields })
+        }
+        "RESUME" if rest == "GAP" => json!({ "type": "resume_gap" }),
+        "SET" => json!({ "type": "set", "settings": rest }),
+        "GET" => json!({ "type": "get", "settings": rest }),
+        "STATS" => {
+            let counters: Map<String, Value> = rest
+                .split(' ')
+                .filter_map(|counter| counter.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.parse::<u64>().map_or_else(|_| value.into(), Value::from)))
+                .collect();
+            json!({ "type": "stats", "counters": counters })
+        }
+        "INFO" => {
+            let mut info = Map::new();
+            info.insert("type".into(), "info".into());
+            for (key, value) in rest.split(' ').filter_map(|field| field.split_once('=')) {
+                let value = match key {
+                    "features" | "transports" | "protocols" => value.split(',').filter(|s| !s.is_empty()).collect::<Vec<_>>().into(),
+                    _ => value.into(),
+                };
+                info.insert(key.into(), value);
+            }
+            Value::Object(info)
+        }
+        "SERVER" => json!({ "type": "server", "event": rest.to_ascii_lowercase() }),
+        "AUTH_REQUIRED" if rest.is_empty() => json!({ "type": "auth_required" }),
+        "PING" | "PONG" | "NOOP" if rest.is_empty() => json!({ "type": kind.to_ascii_lowercase() }),
+        _ => json!({ "type": "line", "line": text }),
+    };
+    match value {
+        Value::Object(mut map) => {
+            for attribute in attributes.split(',') {
+                match attribute.split_once('=') {
+                    Some(("ct", content_type)) => map.insert("content_type".into(), content_type.into()),
+                    Some(("id", id)) => map.insert("msg_id".into(), number(id)),
+                    Some(("ts", ts)) => map.insert("ts".into(), ts.into()),
+                    _ => None,
+                };
+            }
+            map
+        }
+        _ => unreachable!("envelopes are objects"),
+    }
+}
+
+/// A sender as it appears in text lines: an id, or a nickname (which is
+/// never all digits).
+fn name(s: &str) -> Value {
+    s.parse::<u64>().map_or_else(|_| s.into(), Value::from)
+}
+
+/// A client in `WHO`, with its region after `=` in `WHO:regions`.
+fn who(s: &str) -> Value {
+    match s.split_once('=') {
+        Some((id, region)) => json!({ "id": name(id), "region": region }),
+        None => name(s),
+    }
+}
+
+fn number(s: &str) -> Value {
+    s.parse::<u64>().map_or(Value::Null, Value::from)
+}
+
+fn with_detail(mut value: Value, detail: &str) -> Value {
+    if !detail.is_empty() {
+        value["detail"] = detail.into();
+    }
+    value
+}
+
+/// One `ROOMS:` entry, `{ROOM}={MEMBERS}` with `;`-separated modes.
+fn room(entry: &str) -> Value {
+    let (name, rest) = entry.split_once('=').unwrap_or((entry, ""));
+    let mut parts = rest.split(';');
+    let members = number(parts.next().unwrap_or(""));
+    let modes: Map<String, Value> = parts
+        .filter_map(|mode| mode.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.into()))
+        .collect();
+    json!({ "name": name, "members": members, "modes": modes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(line: &str) -> Value {
+        serde_json::from_slice(&Protocol::Json.encode(Bytes::from(line.to_string()))).unwrap()
+    }
+
+    #[test]
+    fn message_from_id_or_nick() {
+        assert_eq!(encoded("MESSAGE:3 hi there\n"), json!({ "type": "message", "from": 3, "body": "hi there" }));
+        assert_eq!(encoded("MESSAGE:alice hi\n"), json!({ "type": "message", "from": "alice", "body": "hi" }));
+    }
+
+    #[test]
+    fn history_is_flagged() {
+        let replayed = encoded("HISTORY:MESSAGE:3 hi\n");
+        assert_eq!(replayed, json!({ "type": "message", "from": 3, "body": "hi", "history": true }));
+        assert_eq!(decode(r#"{"type":"history","limit":50}"#), Ok(Inbound::Command("HISTORY:50".into())));
+    }
+
+    #[test]
+    fn acks_errors_and_presence() {
+        assert_eq!(encoded("ACK:MESSAGE\n"), json!({ "type": "ack", "of": "message" }));
+        assert_eq!(encoded("ACK:JOIN dev\n"), json!({ "type": "ack", "of": "join", "detail": "dev" }));
+        let error = encoded("ERROR:PROTOCOL_VIOLATION invalid utf-8\n");
+        assert_eq!(error, json!({ "type": "error", "code": "PROTOCOL_VIOLATION", "detail": "invalid utf-8" }));
+        assert_eq!(encoded("JOINED:7\n"), json!({ "type": "joined", "id": 7 }));
+        let flagged = encoded("FLAGGED:books spoilers 3 the ending\n");
+        assert_eq!(flagged, json!({ "type": "flagged", "room": "books", "list": "spoilers", "from": 3, "body": "the ending" }));
+        let rooms = encoded("ROOMS:dev=2;slow=5\n");
+        assert_eq!(rooms, json!({ "type": "rooms", "rooms": [{ "name": "dev", "members": 2, "modes": { "slow": "5" } }] }));
+        assert_eq!(encoded("ACK:42\n"), json!({ "type": "ack", "seq": 42 }));
+        assert_eq!(encoded("DELIVERED:42\n"), json!({ "type": "delivered", "seq": 42 }));
+        let stats = encoded("STATS:clients=2 maintenance=off\n");
+        assert_eq!(stats, json!({ "type": "stats", "counters": { "clients": 2, "maintenance": "off" } }));
+        let info = encoded("INFO:version=0.1.0 features= transports=tcp,unix protocol=text\n");
+        assert_eq!(info, json!({ "type": "info", "version": "0.1.0", "features": [], "transports": ["tcp", "unix"], "protocol": "text" }));
+    }
+
+    #[test]
+    fn content_type_both_ways() {
+        let tagged = encoded("MESSAGE[ct=json]:3 {}\n");
+        assert_eq!(tagged, json!({ "type": "message", "from": 3, "body": "{}", "content_type": "json" }));
+        let stamped = encoded("MESSAGE[ct=json,id=7,ts=2026-10-15T10:17:47.427Z]:3 {}\n");
+        assert_eq!(stamped, json!({ "type": "message", "from": 3, "body": "{}", "content_type": "json", "msg_id": 7, "ts": "2026-10-15T10:17:47.427Z" }));
+        let inbound = decode(r#"{"type":"message","body":"{}","content_type":"json"}"#);
+        assert_eq!(inbound, Ok(Inbound::Command("PUB[ct=json]:{}".into())));
+        assert_eq!(decode(r#"{"type":"accept","types":["json","text"]}"#), Ok(Inbound::Command("ACCEPT:json,text".into())));
+    }
+
+    #[test]
+    fn message_body_is_never_a_command() {
+        assert_eq!(decode(r#"{"type":"message","body":"JOIN:x"}"#), Ok(Inbound::Message("JOIN:x".into())));
+        assert_eq!(decode(r#"{"type":"join","room":"x"}"#), Ok(Inbound::Command("JOIN:x".into())));
+        assert_eq!(decode(r#"{"type":"private","to":"a b","body":"hi"}"#), Err("bad envelope"));
+        assert_eq!(decode("JOIN:x"), Err("bad json"));
+    }
+}
@@ -0,0 +1,108 @@
+//! Round-robin interleaving of inbound lines across senders.
+//!
+//! Lines that are already buffered when the main loop wakes up are queued
+//! per sender and handed out one sender at a time, so a client flooding the
+//! server gets one line through per turn like everybody else instead of
+//! however many the input poll happens to return for it first. The server
+//! also stops reading from a sender with a full share queued (see
+//! `READ_BUDGET` in `server`), so one firehose can't fill the queue and
+//! keep quieter clients' lines unread in their sockets.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use crate::registry::ClientId;
+
+/// How inbound lines are ordered before being handled.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fairness {
+    /// Handle each line as soon as it's read.
+    Off,
+    /// Queue ready lines per sender and take one from each sender in turn.
+    RoundRobin,
+}
+
+#[derive(Default)]
+pub struct FairQueue {
+    queues: HashMap<ClientId, VecDeque<(Bytes, Instant)>>,
+    /// Senders with queued lines, in the order they'll be served.
+    order: VecDeque<ClientId>,
+    len: usize,
+}
+
+impl FairQueue {
+    pub fn push(&mut self, client_id: ClientId, frame: Bytes, received: Instant) {
+        let queue = self.queues.entry(client_id).or_default();
+        if queue.is_empty() {
+            self.order.push_back(client_id);
+        }
+        queue.push_back((frame, received));
+        self.len += 1;
+    }
+
+    /// Next line from the sender whose turn it is.
+    pub fn pop(&mut self) -> Option<(ClientId, Bytes, Instant)> {
+        let client_id = self.order.pop_front()?;
+        let queue = self.queues.get_mut(&client_id)?;
+        let (frame, received) = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queues.remove(&client_id);
+        } else {
+            self.order.push_back(client_id);
+        }
+        self.len -= 1;
+        Some((client_id, frame, received))
+    }
+
+    /// Takes everything queued for a client that has gone away.
+    pub fn remove(&mut self, client_id: ClientId) -> VecDeque<(Bytes, Instant)> {
+        let Some(queue) = self.queues.remove(&client_id) else { return VecDeque::new() };
+        self.len -= queue.len();
+        self.order.retain(|&id| id != client_id);
+        queue
+    }
+
+    /// Lines queued for one sender.
+    pub fn queued(&self, client_id: ClientId) -> usize {
+        self.queues.get(&client_id).map_or(0, VecDeque::len)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_one_line_per_sender_in_turn() {
+        let mut queue = FairQueue::default();
+        let now = Instant::now();
+        for line in ["a1", "a2", "a3"] {
+            queue.push(1, Bytes::from(line), now);
+        }
+        queue.push(2, Bytes::from("b1"), now);
+        queue.push(3, Bytes::from("c1"), now);
+        queue.push(3, Bytes::from("c2"), now);
+        assert_eq!((queue.len(), queue.queued(1)), (6, 3));
+
+        let mut order = Vec::new();
+        while let Some((client_id, line, _)) = queue.pop() {
+            order.push(line);
+            if client_id == 2 {
+                // Whoever goes away takes their turns with them
+                assert_eq!(queue.remove(3).len(), 2);
+            }
+        }
+        assert_eq!(order, ["a1", "b1", "a2", "a3"]);
+        assert!(queue.is_empty());
+    }
+}
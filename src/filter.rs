@@ -0,0 +1,167 @@
+//! Word filters, chosen per room.
+//!
+//! Community standards differ from room to room, so there's no one filter
+//! for the whole server. The operator names word lists, then gives each
+//! room the lists its messages are checked against and what happens on a
+//! match: the words are masked, the message is refused, or it goes out and
+//! the room's moderator and any admins are told. A room without filters of
+//! its own gets those for `*`, which also covers the lobby.
+//!
+//! Matching is by whole word, ignoring case, where a word is a run of
+//! letters and digits; `heck` catches `Heck!` but not `checkers`.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::str::FromStr;
+
+/// The room key for the lobby and every room without filters of its own.
+pub const DEFAULT_ROOM: &str = "*";
+
+/// What to do with a message that has a word from the list.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FilterAction {
+    /// Replace each letter of the word with `*`, and send it on.
+    Mask,
+    /// Don't send it; the sender gets `ERROR:FILTERED`.
+    Reject,
+    /// Send it, and tell the room's moderator and admins with `FLAGGED:`.
+    Flag,
+}
+
+/// One of a room's filters: a word list and what a match on it does.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RoomFilter {
+    pub list: String,
+    pub action: FilterAction,
+}
+
+/// `LIST:mask`, `LIST:reject` or `LIST:flag`.
+impl FromStr for RoomFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (list, action) = s.rsplit_once(':').ok_or_else(|| format!("invalid room filter {s:?}, expected LIST:ACTION"))?;
+        let action = match action {
+            "mask" => FilterAction::Mask,
+            "reject" => FilterAction::Reject,
+            "flag" => FilterAction::Flag,
+            _ => return Err(format!("invalid room filter {s:?}: {action:?} isn't mask, reject or flag")),
+        };
+        Ok(RoomFilter { list: list.to_string(), action })
+    }
+}
+
+/// Word lists, and which rooms use them.
+#[derive(Clone, Debug, Default)]
+pub struct FilterConfig {
+    /// Words by list name.
+    pub lists: BTreeMap<String, Vec<String>>,
+    /// Each room's filters, applied in order, by room name or
+    /// [`DEFAULT_ROOM`].
+    pub rooms: BTreeMap<String, Vec<RoomFilter>>,
+}
+
+/// What the filters made of a message that may go out.
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) struct Checked<'a> {
+    /// The text, masked where it had to be.
+    pub text: Cow<'a, str>,
+    /// Lists with a `flag` action that matched.
+    pub flagged: Vec<String>,
+}
+
+/// The configuration, compiled for checking messages.
+pub(crate) struct Filters {
+    lists: HashMap<String, HashSet<String>>,
+    rooms: HashMap<String, Vec<RoomFilter>>,
+}
+
+impl Filters {
+    /// Fails on a room filter naming a list that isn't there, or a list
+    /// entry that isn't a single word, which could never match.
+    pub fn new(config: &FilterConfig) -> io::Result<Filters> {
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidInput, what);
+        let mut lists = HashMap::new();
+        for (name, words) in &config.lists {
+            if let Some(word) = words.iter().find(|word| word.is_empty() || !word.chars().all(char::is_alphanumeric)) {
+                return Err(invalid(format!("filter list {name}: {word:?} isn't a single word")));
+            }
+            lists.insert(name.clone(), words.iter().map(|word| word.to_lowercase()).collect());
+        }
+        for (room, filters) in &config.rooms {
+            if let Some(filter) = filters.iter().find(|filter| !lists.contains_key(&filter.list)) {
+                return Err(invalid(format!("room filters for {room}: no filter list {}", filter.list)));
+            }
+        }
+        let rooms = config.rooms.iter().map(|(room, filters)| (room.clone(), filters.clone())).collect();
+        Ok(Filters { lists, rooms })
+    }
+
+    /// Checks a message in `room` (the lobby for `None`): `Err` with the
+    /// list that refused it, or what to send.
+    pub fn check<'a>(&self, room: Option<&str>, text: &'a str) -> Result<Checked<'a>, String> {
+        let mut checked = Checked { text: Cow::Borrowed(text), flagged: Vec::new() };
+        let filters = room.and_then(|room| self.rooms.get(room)).or_else(|| self.rooms.get(DEFAULT_ROOM));
+        for filter in filters.into_iter().flatten() {
+            let list = &self.lists[&filter.list];
+            let found = |word: &str| list.contains(&word.to_lowercase());
+            if !words(&checked.text).any(|(_, word)| found(word)) {
+                continue;
+            }
+            match filter.action {
+                FilterAction::Reject => return Err(filter.list.clone()),
+                FilterAction::Flag => checked.flagged.push(filter.list.clone()),
+                FilterAction::Mask => {
+                    let text = &checked.text;
+                    let mut masked = String::with_capacity(text.len());
+                    let mut end = 0;
+                    for (at, word) in words(text).filter(|(_, word)| found(word)) {
+                        masked.push_str(&text[end..at]);
+                        masked.extend(std::iter::repeat_n('*', word.chars().count()));
+                        end = at + word.len();
+                    }
+                    masked.push_str(&text[end..]);
+                    checked.text = Cow::Owned(masked);
+                }
+            }
+        }
+        Ok(checked)
+    }
+}
+
+/// The words in `text`, with their byte offsets.
+fn words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(move |word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_rejects_and_flags_per_room() {
+        let config = FilterConfig {
+            lists: BTreeMap::from([
+                ("mild".to_string(), vec!["heck".to_string(), "Darn".to_string()]),
+                ("spoilers".to_string(), vec!["ending".to_string()]),
+            ]),
+            rooms: BTreeMap::from([
+                ("kids".to_string(), vec!["mild:reject".parse().unwrap()]),
+                ("books".to_string(), vec!["mild:mask".parse().unwrap(), "spoilers:flag".parse().unwrap()]),
+                (DEFAULT_ROOM.to_string(), vec!["mild:mask".parse().unwrap()]),
+            ]),
+        };
+        let filters = Filters::new(&config).unwrap();
+        assert_eq!(filters.check(Some("kids"), "oh HECK"), Err("mild".to_string()));
+        let checked = filters.check(Some("books"), "darn, the ending! checkers").unwrap();
+        assert_eq!((checked.text.as_ref(), checked.flagged), ("****, the ending! checkers", vec!["spoilers".to_string()]));
+        assert_eq!(filters.check(None, "heck").unwrap().text, "****");
+        assert_eq!(filters.check(Some("other"), "fine").unwrap().text, Cow::Borrowed("fine"));
+
+        let missing = FilterConfig { rooms: config.rooms.clone(), ..FilterConfig::default() };
+        assert!(Filters::new(&missing).is_err());
+    }
+}
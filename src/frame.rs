@@ -0,0 +1,78 @@
+//! The server's view of a message on its way from sender to broadcast, and
+//! the filters an embedding application can stop or change it with.
+
+use std::collections::BTreeMap;
+
+use crate::registry::ClientId;
+
+pub struct Frame<'a> {
+    pub sender: ClientId,
+    pub text: &'a str,
+    /// The type it was tagged with by `PUB[ct=...]:`, if any.
+    pub content_type: Option<&'a str>,
+    /// The language its sender said it writes in, with `SET:lang=`.
+    pub lang: Option<&'a str>,
+    /// Verdicts attached by hooks (`spam_score`, `language`, ...), kept in
+    /// key order so anything serializing them produces stable output.
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl<'a> Frame<'a> {
+    pub(crate) fn new(sender: ClientId, text: &'a str, content_type: Option<&'a str>, lang: Option<&'a str>) -> Self {
+        Self { sender, text, content_type, lang, annotations: BTreeMap::new() }
+    }
+
+    /// Sets an annotation, replacing any earlier value under the same key.
+    pub fn annotate(&mut self, key: impl Into<String>, value: impl ToString) {
+        self.annotations.insert(key.into(), value.to_string());
+    }
+
+    pub fn annotation(&self, key: &str) -> Option<&str> {
+        self.annotations.get(key).map(String::as_str)
+    }
+}
+
+/// What a [`MessageFilter`] makes of a message.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum MessageAction {
+    /// Send it on as it is.
+    Pass,
+    /// Don't send it. The sender is acked as usual and never knows.
+    Drop,
+    /// Send this instead; later filters see the new text.
+    Rewrite(String),
+    /// Send it, with an annotation set on its frame.
+    Annotate(String, String),
+}
+
+/// A filter for text messages on their way to broadcast: masking words,
+/// dropping what's too long, tagging what needs a second look. Closures
+/// taking the sender and the text are filters too.
+pub trait MessageFilter {
+    fn on_message(&mut self, from: ClientId, line: &str) -> MessageAction;
+}
+
+impl<F: FnMut(ClientId, &str) -> MessageAction> MessageFilter for F {
+    fn on_message(&mut self, from: ClientId, line: &str) -> MessageAction {
+        self(from, line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_annotation_per_key_in_key_order() {
+        let mut frame = Frame::new(7, "hello", None, None);
+        frame.annotate("spam_score", 0.25);
+        frame.annotate("language", "en");
+        frame.annotate("spam_score", 0.5);
+        assert_eq!(frame.annotation("spam_score"), Some("0.5"));
+        assert_eq!(frame.annotation("missing"), None);
+        assert_eq!(frame.annotations.keys().collect::<Vec<_>>(), ["language", "spam_score"]);
+
+        let mut shout = |_, line: &str| MessageAction::Rewrite(line.to_uppercase());
+        assert_eq!(shout.on_message(frame.sender, frame.text), MessageAction::Rewrite("HELLO".to_string()));
+    }
+}
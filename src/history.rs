@@ -0,0 +1,140 @@
+//! Recent messages, replayed to clients that arrive late.
+//!
+//! The lobby and each room keep their own ring of the last few `MESSAGE:`
+//! lines, exactly as they were broadcast. A client gets the lobby's on
+//! connect and a room's when it joins, each line prefixed with `HISTORY:`.
+//! Only the lobby's can be seeded from the message log (see `journal`);
+//! a room's history goes with the room.
+//!
+//! Lines are numbered as they're pushed, and line `seq` lives in slot
+//! `seq % limit` of a buffer allocated once, so finding a line or the
+//! start of a range is arithmetic, and keeping one costs nothing but the
+//! `Bytes` handle. A purged line leaves an empty slot behind, which is
+//! skipped, so the numbering stays put.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+const PREFIX: &[u8] = b"HISTORY:";
+
+pub struct History {
+    slots: Box<[Bytes]>,
+    /// Sequence number the next line gets; the ring holds the `len` before it.
+    next: u64,
+    len: usize,
+}
+
+impl History {
+    /// A ring of up to `limit` lines; 0 keeps nothing.
+    pub fn new(limit: usize) -> Self {
+        Self { slots: vec![Bytes::new(); limit].into_boxed_slice(), next: 0, len: 0 }
+    }
+
+    pub fn push(&mut self, line: Bytes) {
+        let limit = self.slots.len();
+        if limit == 0 {
+            // Still counted, so it shows as lost
+            self.next += 1;
+            return;
+        }
+        self.slots[(self.next % limit as u64) as usize] = line;
+        self.next += 1;
+        self.len = (self.len + 1).min(limit);
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.fill(Bytes::new());
+        self.len = 0;
+    }
+
+    /// Empties the slots of lines `purged` picks, returning how many.
+    pub fn purge(&mut self, mut purged: impl FnMut(&[u8]) -> bool) -> usize {
+        let limit = self.slots.len() as u64;
+        let mut count = 0;
+        for seq in self.first()..self.next {
+            let slot = &mut self.slots[(seq % limit) as usize];
+            if !slot.is_empty() && purged(slot) {
+                *slot = Bytes::new();
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Whether lines have been overwritten or cleared, so that the ring no
+    /// longer holds everything pushed to it.
+    pub fn wrapped(&self) -> bool {
+        self.first() > 0
+    }
+
+    /// Sequence number of the oldest line still kept.
+    fn first(&self) -> u64 {
+        self.next - self.len as u64
+    }
+
+    /// Lines from `seq` on, oldest first; lines already overwritten or
+    /// purged are skipped.
+    pub fn since(&self, seq: u64) -> impl Iterator<Item = (u64, &Bytes)> {
+        let limit = self.slots.len() as u64;
+        (seq.max(self.first())..self.next)
+            .map(move |seq| (seq, &self.slots[(seq % limit) as usize]))
+            .filter(|(_, line)| !line.is_empty())
+    }
+
+    /// The kept lines, oldest first, as `HISTORY:` lines.
+    pub fn replay(&self) -> Vec<Bytes> {
+        self.since(0)
+            .map(|(_, line)| {
+                let mut out = BytesMut::with_capacity(PREFIX.len() + line.len());
+                out.put_slice(PREFIX);
+                out.put_slice(line);
+                out.freeze()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqs(history: &History, from: u64) -> Vec<u64> {
+        history.since(from).map(|(seq, _)| seq).collect()
+    }
+
+    #[test]
+    fn wraps_and_keeps_numbering() {
+        let mut history = History::new(3);
+        for i in 0..5 {
+            history.push(Bytes::from(format!("MESSAGE:1 {i}\n")));
+        }
+        assert_eq!(seqs(&history, 0), [2, 3, 4]);
+        assert!(history.wrapped());
+        assert_eq!(seqs(&history, 4), [4]);
+        assert_eq!(seqs(&history, 5), [] as [u64; 0]);
+        assert_eq!(history.replay()[0], "HISTORY:MESSAGE:1 2\n");
+        history.clear();
+        assert!(history.replay().is_empty());
+        history.push(Bytes::from_static(b"MESSAGE:1 5\n"));
+        assert_eq!(seqs(&history, 0), [5]);
+    }
+
+    #[test]
+    fn purge_leaves_a_gap() {
+        let mut history = History::new(4);
+        for line in ["MESSAGE:1 a\n", "MESSAGE:2 b\n", "MESSAGE:1 c\n"] {
+            history.push(Bytes::from(line));
+        }
+        assert_eq!(history.purge(|line| line.starts_with(b"MESSAGE:1 ")), 2);
+        assert_eq!(seqs(&history, 0), [1]);
+        history.push(Bytes::from_static(b"MESSAGE:1 d\n"));
+        assert_eq!(seqs(&history, 0), [1, 3]);
+    }
+
+    #[test]
+    fn zero_keeps_nothing() {
+        let mut history = History::new(0);
+        history.push(Bytes::from_static(b"MESSAGE:1 hi\n"));
+        assert!(history.replay().is_empty());
+        assert!(history.wrapped());
+    }
+}
@@ -0,0 +1,159 @@
+//! Reaping quiet connections, with a policy per listener.
+//!
+//! A browser tab on the WebSocket port may sit quiet for hours and is
+//! worth a `PING` now and then, while a raw TCP client that goes quiet is
+//! more likely gone. Each listener has its own [`IdlePolicy`], which a
+//! client keeps for its whole session, and the server applies them all in
+//! one scan of its sessions once a second rather than with a timer per
+//! client. Any line counts as being heard from, `PONG` included.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::conn::Transport;
+
+/// How long a pinged client has to answer when the policy doesn't say.
+const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What happens to a client that has sent nothing for a while.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IdlePolicy {
+    /// Nothing: it stays as long as its connection does.
+    Off,
+    /// It's sent `PING` once quiet for `interval`, and disconnected if
+    /// nothing comes back within `timeout`.
+    Ping { interval: Duration, timeout: Duration },
+    /// It's disconnected once quiet this long, without a `PING` first.
+    Reap(Duration),
+}
+
+/// What's due for one client under its policy.
+#[derive(PartialEq, Eq, Debug)]
+pub enum Verdict {
+    Keep,
+    /// Quiet long enough to be pinged.
+    Ping,
+    /// Pinged and didn't answer in time.
+    NoPong,
+    /// Quiet too long under `IdlePolicy::Reap`.
+    Idle,
+}
+
+impl IdlePolicy {
+    /// What's due for a client last heard from at `last_heard`, with its
+    /// unanswered `PING` sent at `pinged`, if there is one.
+    pub fn check(self, now: Instant, last_heard: Instant, pinged: Option<Instant>) -> Verdict {
+        let quiet = now.duration_since(last_heard);
+        match (self, pinged) {
+            (IdlePolicy::Off, _) => Verdict::Keep,
+            (IdlePolicy::Ping { timeout, .. }, Some(at)) if now.duration_since(at) >= timeout => Verdict::NoPong,
+            (IdlePolicy::Ping { .. }, Some(_)) => Verdict::Keep,
+            (IdlePolicy::Ping { interval, .. }, None) if quiet >= interval => Verdict::Ping,
+            (IdlePolicy::Reap(after), _) if quiet >= after => Verdict::Idle,
+            _ => Verdict::Keep,
+        }
+    }
+}
+
+/// `off`, `ping:SECS` or `ping:SECS:TIMEOUT_SECS`, or `reap:SECS`.
+impl FromStr for IdlePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let secs = |n: &str| match n.parse::<u64>() {
+            Ok(n) if n > 0 => Ok(Duration::from_secs(n)),
+            _ => Err(format!("invalid idle policy {s:?}: {n:?} isn't a positive number of seconds")),
+        };
+        match s.split(':').collect::<Vec<_>>()[..] {
+            ["off"] => Ok(IdlePolicy::Off),
+            ["ping", interval] => Ok(IdlePolicy::Ping { interval: secs(interval)?, timeout: DEFAULT_PING_TIMEOUT }),
+            ["ping", interval, timeout] => Ok(IdlePolicy::Ping { interval: secs(interval)?, timeout: secs(timeout)? }),
+            ["reap", after] => Ok(IdlePolicy::Reap(secs(after)?)),
+            _ => Err(format!("invalid idle policy {s:?}, expected off, ping:SECS[:TIMEOUT_SECS] or reap:SECS")),
+        }
+    }
+}
+
+impl fmt::Display for IdlePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdlePolicy::Off => f.write_str("off"),
+            IdlePolicy::Ping { interval, timeout } => write!(f, "ping:{}:{}", interval.as_secs(), timeout.as_secs()),
+            IdlePolicy::Reap(after) => write!(f, "reap:{}", after.as_secs()),
+        }
+    }
+}
+
+/// The idle policy of each listener. Every listener given to
+/// `also_bind` counts as `tcp`, and TLS doesn't change which is which.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IdleConfig {
+    pub tcp: IdlePolicy,
+    pub websocket: IdlePolicy,
+    pub framed: IdlePolicy,
+    pub unix: IdlePolicy,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self::all(IdlePolicy::Off)
+    }
+}
+
+impl IdleConfig {
+    /// The same policy on every listener.
+    pub fn all(policy: IdlePolicy) -> Self {
+        Self { tcp: policy, websocket: policy, framed: policy, unix: policy }
+    }
+
+    /// Sets the policy of the listener called `listener`: `tcp`,
+    /// `websocket`, `framed` or `unix`.
+    pub fn set(&mut self, listener: &str, policy: IdlePolicy) -> Result<(), String> {
+        let slot = match listener {
+            "tcp" => &mut self.tcp,
+            "websocket" => &mut self.websocket,
+            "framed" => &mut self.framed,
+            "unix" => &mut self.unix,
+            _ => return Err(format!("unknown listener {listener:?}, expected tcp, websocket, framed or unix")),
+        };
+        *slot = policy;
+        Ok(())
+    }
+
+    /// Whether any listener's clients can be reaped, so the scan is worth
+    /// running.
+    pub fn enabled(&self) -> bool {
+        [self.tcp, self.websocket, self.framed, self.unix].iter().any(|&policy| policy != IdlePolicy::Off)
+    }
+
+    pub(crate) fn get(&self, transport: Transport) -> IdlePolicy {
+        match transport {
+            Transport::Tcp => self.tcp,
+            Transport::WebSocket => self.websocket,
+            Transport::Framed => self.framed,
+            Transport::Unix => self.unix,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_or_reap() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let ping: IdlePolicy = "ping:30:5".parse().unwrap();
+        assert_eq!(ping.check(at(29), start, None), Verdict::Keep);
+        assert_eq!(ping.check(at(30), start, None), Verdict::Ping);
+        assert_eq!(ping.check(at(34), start, Some(at(30))), Verdict::Keep);
+        assert_eq!(ping.check(at(35), start, Some(at(30))), Verdict::NoPong);
+        let reap: IdlePolicy = "reap:120".parse().unwrap();
+        assert_eq!(reap.check(at(119), start, None), Verdict::Keep);
+        assert_eq!(reap.check(at(120), start, None), Verdict::Idle);
+        assert!("ping:0".parse::<IdlePolicy>().is_err());
+        assert_eq!("ping:30".parse::<IdlePolicy>().unwrap().to_string(), "ping:30:10");
+    }
+}
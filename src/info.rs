@@ -0,0 +1,71 @@
+//! What's running, for `INFO` and `GET /info` on the metrics port: the
+//! crate version, the commit and cargo features it was built from (see
+//! `build.rs`), the transports clients can connect over, and the protocol
+//! versions spoken. Enough to tell a client built against one release from
+//! a server running another.
+
+use std::io;
+
+use crate::compress::Compression;
+use crate::envelope::Protocol;
+use crate::protocol;
+use crate::server::Config;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short commit hash, `-dirty` with uncommitted changes, or `unknown`
+/// outside a git checkout.
+const GIT_HASH: &str = env!("TCP_BROADCAST_GIT_HASH");
+/// Comma-separated; empty for a `--no-default-features` build.
+const FEATURES: &str = env!("TCP_BROADCAST_FEATURES");
+
+/// The `INFO:` line for a server with `config`. It's the same for the
+/// server's whole life, so it's built once.
+pub fn line(config: &Config) -> String {
+    let mut transports = vec!["tcp"];
+    if config.ws_port.is_some() {
+        transports.push("websocket");
+    }
+    if config.framed_port.is_some() {
+        transports.push("framed");
+    }
+    if config.unix_socket.is_some() {
+        transports.push("unix");
+    }
+    format!(
+        "INFO:version={VERSION} git={GIT_HASH} features={FEATURES} transports={} tls={} protocols=text/{v},json/{v} protocol={} compress={}\n",
+        transports.join(","),
+        if config.tls.is_some() { "on" } else { "off" },
+        match config.protocol {
+            Protocol::Text => "text",
+            Protocol::Json => "json",
+        },
+        match config.compress_min_bytes {
+            Some(_) if config.protocol == Protocol::Text => Compression::ALL.iter().map(|alg| alg.name()).collect::<Vec<_>>().join(","),
+            _ => "off".to_string(),
+        },
+        v = protocol::VERSION,
+    )
+}
+
+/// Refuses a configuration that needs a subsystem this build was compiled
+/// without, before anything is bound.
+pub fn check(config: &Config) -> io::Result<()> {
+    let wanted = [
+        ("tls", cfg!(feature = "tls"), config.tls.is_some()),
+        ("websocket", cfg!(feature = "websocket"), config.ws_port.is_some()),
+        ("http", cfg!(feature = "http"), config.metrics_port.is_some() || config.alert.webhook.is_some() || config.session_webhook.is_some()),
+        ("persistence", cfg!(feature = "persistence"), config.log_file.is_some() || config.blobs.is_some()),
+        ("cluster", cfg!(feature = "cluster"), config.peers.enabled() || config.redis.is_some()),
+        ("compression", cfg!(feature = "compression"), config.compress_min_bytes.is_some()),
+    ];
+    let missing: Vec<&str> = wanted.iter().filter(|(_, built, used)| *used && !built).map(|(feature, ..)| *feature).collect();
+    match missing[..] {
+        [] => Ok(()),
+        _ => Err(not_built(&missing.join(", "))),
+    }
+}
+
+/// The error for using a subsystem compiled out of this build.
+pub fn not_built(features: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("built without the {features} feature(s)"))
+}
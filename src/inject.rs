@@ -0,0 +1,86 @@
+//! Messages published from inside the process: bots, auto-replies, plugins.
+//!
+//! An [`Injector`] speaks for one internal identity, a nickname reserved
+//! for it when the server starts. Publishing only queues the message; the
+//! event loop picks it up between client lines, so calling it from a hook
+//! (which runs in the middle of handling a line) is safe. From there it
+//! takes the same road as a client's message: scrubbed, rate limited per
+//! identity, logged, kept in history and fanned out as `MESSAGE:<name> ...`.
+
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+use crate::protocol;
+
+/// A handle for publishing as one internal identity. Cheap to clone, and
+/// `Send`, so it can be moved into hooks or other tasks.
+#[derive(Clone)]
+pub struct Injector {
+    name: Arc<str>,
+    tx: mpsc::UnboundedSender<Injected>,
+}
+
+impl Injector {
+    /// The nickname messages go out under.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Publishes `text` to the lobby.
+    pub fn publish(&self, text: impl Into<String>) {
+        self.send(None, text.into());
+    }
+
+    /// Publishes `text` to a room. Rooms only exist while they have
+    /// members, so this reaches nobody if the room is empty.
+    pub fn publish_in(&self, room: &str, text: impl Into<String>) {
+        self.send(Some(room.into()), text.into());
+    }
+
+    fn send(&self, room: Option<Arc<str>>, text: String) {
+        // Fails only once the server has stopped
+        let _ = self.tx.send(Injected { name: self.name.clone(), room, text });
+    }
+}
+
+pub(crate) struct Injected {
+    pub name: Arc<str>,
+    pub room: Option<Arc<str>>,
+    pub text: String,
+}
+
+/// The server's end: identities handed out so far, and their queue.
+pub(crate) struct Inbox {
+    tx: mpsc::UnboundedSender<Injected>,
+    pub rx: mpsc::UnboundedReceiver<Injected>,
+    pub names: Vec<Arc<str>>,
+}
+
+impl Default for Inbox {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self { tx, rx, names: Vec::new() }
+    }
+}
+
+impl Inbox {
+    /// An injector for `name`; asking twice for the same name gives two
+    /// handles on one identity.
+    ///
+    /// # Panics
+    ///
+    /// If `name` isn't a valid nickname.
+    pub fn injector(&mut self, name: &str) -> Injector {
+        assert!(protocol::valid_nick(name), "invalid injector name {name:?}");
+        let name = match self.names.iter().find(|n| n.eq_ignore_ascii_case(name)) {
+            Some(known) => known.clone(),
+            None => {
+                let name: Arc<str> = name.into();
+                self.names.push(name.clone());
+                name
+            }
+        };
+        Injector { name, tx: self.tx.clone() }
+    }
+}
@@ -0,0 +1,260 @@
+//! Append-only log of broadcast messages, for durability across restarts.
+//!
+//! Each message is one JSON object per line:
+//! `{"ts_ms":…,"sender":…,"name":…,"room":…,"text":…}`, with `room` null
+//! for the lobby, `sender` null for a message from a linked server (see
+//! `peer`), and `ct` added for a message tagged with a content type.
+//! Writes happen on their own task so a slow disk never stalls the event
+//! loop. On startup the tail of the file can seed the lobby's history; room
+//! messages aren't replayed, since rooms only exist while they have
+//! members.
+//!
+//! A purge is done by the same task, between writes: it rewrites the file
+//! without the purged entries, through a temporary file renamed over it,
+//! and appends an audit entry, `{"ts_ms":…,"audit":"purge","by":…,
+//! "target":…,"removed":…}`, which replay skips.
+//!
+//! With chaining on, the task also links every entry it appends to the
+//! one before (see `chain`), and a purge leaves tombstones in place of the
+//! entries it removes.
+//!
+//! Without the `persistence` feature there's no message log.
+
+#[cfg(feature = "persistence")]
+use std::collections::VecDeque;
+#[cfg(feature = "persistence")]
+use std::fs::{File, OpenOptions};
+use std::io;
+#[cfg(feature = "persistence")]
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+#[cfg(feature = "persistence")]
+use std::path::PathBuf;
+#[cfg(feature = "persistence")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+#[cfg(feature = "persistence")]
+use serde_json::{json, Value};
+#[cfg(feature = "persistence")]
+use tokio::io::{AsyncWriteExt, BufWriter};
+#[cfg(feature = "persistence")]
+use tokio::sync::mpsc;
+#[cfg(feature = "persistence")]
+use tracing::{error, info, warn};
+
+#[cfg(feature = "persistence")]
+use crate::chain;
+use crate::purge::Target;
+use crate::registry::ClientId;
+use crate::stamp::Stamp;
+
+#[cfg(feature = "persistence")]
+pub struct Journal {
+    tx: mpsc::UnboundedSender<Op>,
+}
+
+#[cfg(feature = "persistence")]
+enum Op {
+    Append(Value),
+    Purge { target: Target, by: String },
+}
+
+#[cfg(feature = "persistence")]
+impl Journal {
+    /// Opens `path` for appending, creating it if needed, and starts the
+    /// writer task. With `chain`, entries are hash-chained from whatever
+    /// the file already ends with.
+    pub fn open(path: &Path, chain: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut head = match chain {
+            true => Some(chain::head(&std::fs::read(path)?)),
+            false => None,
+        };
+        let (tx, mut rx) = mpsc::unbounded_channel::<Op>();
+        let path = path.to_path_buf();
+        tokio::spawn(async move {
+            let mut out = BufWriter::new(tokio::fs::File::from_std(file));
+            let result: io::Result<()> = async {
+                while let Some(mut op) = rx.recv().await {
+                    // Whatever else is waiting goes out in the same flush
+                    loop {
+                        match op {
+                            Op::Append(entry) => out.write_all(&linked(entry, &mut head)).await?,
+                            Op::Purge { target, by } => {
+                                out.flush().await?;
+                                out = BufWriter::new(purge(&path, &target, &by, &mut head).await?);
+                            }
+                        }
+                        match rx.try_recv() {
+                            Ok(next) => op = next,
+                            Err(_) => break,
+                        }
+                    }
+                    out.flush().await?;
+                }
+                Ok(())
+            }
+            .await;
+            if let Err(e) = result {
+                error!("message log {} write failed, no longer logging: {e}", path.display());
+            }
+        });
+        Ok(Self { tx })
+    }
+
+    pub fn record(
+        &self,
+        sender: Option<ClientId>,
+        name: &str,
+        room: Option<&str>,
+        text: &str,
+        content_type: Option<&str>,
+        stamp: Option<Stamp>,
+    ) {
+        let ts_ms = match stamp {
+            Some(stamp) => stamp.ts_ms,
+            None => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+        };
+        let mut entry = json!({ "ts_ms": ts_ms, "sender": sender, "name": name, "room": room, "text": text });
+        if let Some(content_type) = content_type {
+            entry["ct"] = content_type.into();
+        }
+        if let Some(stamp) = stamp {
+            entry["id"] = stamp.id.into();
+        }
+        // Fails only once the writer has given up, which it already reported
+        let _ = self.tx.send(Op::Append(entry));
+    }
+
+    /// Has the writer drop every entry `target` matches, once what's
+    /// already queued is written. `by` is recorded in the audit entry.
+    pub fn purge(&self, target: Target, by: &str) {
+        let _ = self.tx.send(Op::Purge { target, by: by.to_string() });
+    }
+}
+
+/// `entry` as a line of the log, linked to `head` if the log is chained,
+/// which it then becomes the head of.
+#[cfg(feature = "persistence")]
+fn linked(mut entry: Value, head: &mut Option<String>) -> Vec<u8> {
+    if let Some(head) = head {
+        entry["prev"] = head.as_str().into();
+    }
+    let line = format!("{entry}\n").into_bytes();
+    if let Some(head) = head {
+        *head = chain::link(&line);
+    }
+    line
+}
+
+/// Rewrites the log at `path` without the entries `target` matches (or,
+/// chained, with tombstones in their place), plus an audit entry, and
+/// reopens it for appending.
+#[cfg(feature = "persistence")]
+async fn purge(path: &Path, target: &Target, by: &str, head: &mut Option<String>) -> io::Result<tokio::fs::File> {
+    let contents = tokio::fs::read(path).await?;
+    let mut kept = Vec::with_capacity(contents.len());
+    let mut removed = 0;
+    for line in contents.split_inclusive(|&b| b == b'\n') {
+        let purged = serde_json::from_slice::<Value>(line).is_ok_and(|entry| {
+            match (entry["name"].as_str(), entry["text"].as_str()) {
+                (Some(name), Some(text)) => target.matches(name, entry["room"].as_str(), text),
+                _ => false,
+            }
+        });
+        if purged {
+            removed += 1;
+            if head.is_some() {
+                kept.extend_from_slice(chain::tombstone(line).as_bytes());
+            }
+        } else {
+            kept.extend_from_slice(line);
+        }
+    }
+    let ts_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+    let audit = json!({ "ts_ms": ts_ms, "audit": "purge", "by": by, "target": target.to_string(), "removed": removed });
+    kept.extend_from_slice(&linked(audit, head));
+
+    let mut temporary = PathBuf::from(path);
+    temporary.as_mut_os_string().push(".purge");
+    tokio::fs::write(&temporary, kept).await?;
+    tokio::fs::rename(&temporary, path).await?;
+    info!(by, target = %target, removed, "message log purged");
+    tokio::fs::OpenOptions::new().append(true).open(path).await
+}
+
+/// The last `limit` lobby messages in the log, as the `MESSAGE:` lines
+/// they were broadcast as. Lines that don't parse are skipped.
+#[cfg(feature = "persistence")]
+pub fn load_lobby(path: &Path, limit: usize) -> io::Result<Vec<Bytes>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut kept = VecDeque::with_capacity(limit);
+    let mut skipped = 0;
+    for line in BufReader::new(file).lines() {
+        let Ok(entry) = serde_json::from_str::<Value>(&line?) else {
+            skipped += 1;
+            continue;
+        };
+        if !entry["audit"].is_null() || !entry["purged"].is_null() {
+            continue;
+        }
+        let (Some(name), Some(text)) = (entry["name"].as_str(), entry["text"].as_str()) else {
+            skipped += 1;
+            continue;
+        };
+        if !entry["room"].is_null() || limit == 0 {
+            continue;
+        }
+        if kept.len() == limit {
+            kept.pop_front();
+        }
+        // Stamped entries keep their ID and time when replayed
+        let mut attributes: Vec<String> = entry["ct"].as_str().map(|content_type| format!("ct={content_type}")).into_iter().collect();
+        if let (Some(id), Some(ts_ms)) = (entry["id"].as_u64(), entry["ts_ms"].as_u64()) {
+            attributes.push(Stamp { id, ts_ms }.attributes());
+        }
+        let tag = if attributes.is_empty() { String::new() } else { format!("[{}]", attributes.join(",")) };
+        kept.push_back(Bytes::from(format!("MESSAGE{tag}:{name} {text}\n")));
+    }
+    if skipped > 0 {
+        warn!("message log {}: skipped {skipped} unreadable lines", path.display());
+    }
+    Ok(kept.into())
+}
+
+/// Built without persistence, so no log can be open.
+#[cfg(not(feature = "persistence"))]
+pub enum Journal {}
+
+#[cfg(not(feature = "persistence"))]
+impl Journal {
+    pub fn open(_path: &Path, _chain: bool) -> io::Result<Self> {
+        Err(crate::info::not_built("persistence"))
+    }
+
+    pub fn record(
+        &self,
+        _sender: Option<ClientId>,
+        _name: &str,
+        _room: Option<&str>,
+        _text: &str,
+        _content_type: Option<&str>,
+        _stamp: Option<Stamp>,
+    ) {
+        match *self {}
+    }
+
+    pub fn purge(&self, _target: Target, _by: &str) {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "persistence"))]
+pub fn load_lobby(_path: &Path, _limit: usize) -> io::Result<Vec<Bytes>> {
+    Err(crate::info::not_built("persistence"))
+}
@@ -0,0 +1,87 @@
+//! TCP broadcast server.
+//!
+//! Every line a client sends is relayed to all other connected clients as
+//! `MESSAGE:<id> <line>` and acknowledged to the sender. [`BroadcastServer`]
+//! is the entry point for embedding; the `tcp-broadcast` binary is a thin
+//! command-line wrapper around it.
+
+mod access;
+mod alert;
+mod anomaly;
+mod auth;
+mod blobs;
+pub mod blocking;
+pub mod chain;
+mod codec;
+mod compress;
+mod conn;
+pub mod conformance;
+mod dedup;
+mod echo;
+mod envelope;
+mod fair;
+mod filter;
+mod frame;
+mod history;
+mod idle;
+mod info;
+mod inject;
+mod journal;
+mod logging;
+mod metrics;
+mod net;
+mod panics;
+mod peer;
+mod presence;
+mod prometheus;
+mod protocol;
+mod proxy;
+mod purge;
+mod redis;
+mod regions;
+mod registry;
+mod replay;
+mod rooms;
+mod sampling;
+pub mod selftest;
+mod selector;
+mod server;
+mod sessions;
+mod shed;
+mod stamp;
+mod systemd;
+mod tarpit;
+pub mod testing;
+mod tls;
+mod udp;
+mod unix;
+pub mod vectors;
+mod violations;
+mod writer;
+mod ws;
+
+pub use access::{AccessList, Cidr};
+pub use alert::AlertConfig;
+pub use anomaly::AnomalyConfig;
+pub use auth::AuthConfig;
+pub use blobs::BlobConfig;
+pub use envelope::Protocol;
+pub use fair::Fairness;
+pub use filter::{FilterAction, FilterConfig, RoomFilter, DEFAULT_ROOM};
+pub use frame::{Frame, MessageAction, MessageFilter};
+pub use idle::{IdleConfig, IdlePolicy};
+pub use inject::Injector;
+pub use logging::{init as init_logging, LogFormat, LogLevel};
+pub use net::SocketOptions;
+pub use peer::PeerConfig;
+pub use presence::PresenceConfig;
+pub use redis::RedisConfig;
+pub use regions::Regions;
+pub use registry::ClientId;
+pub use replay::ReplayConfig;
+pub use server::{Batching, BroadcastServer, Config, RateLimit, Reload, Tuning};
+pub use shed::ShedConfig;
+pub use tarpit::TarpitConfig;
+pub use tls::TlsConfig;
+pub use violations::ViolationPolicy;
+pub use writer::{LatencyBudget, SlowConsumer};
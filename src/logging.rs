@@ -0,0 +1,77 @@
+//! How much the server logs, and in what form.
+//!
+//! Everything is logged through `tracing`: informational events (connects,
+//! messages, summaries) at info, trouble at warn or error. Events about a
+//! client are recorded inside its `client` span, which carries its
+//! `client_id` and `peer`. Unless the application has installed a
+//! subscriber of its own, one is installed when a server starts, from
+//! [`Config::log_level`](crate::Config::log_level) and
+//! [`Config::log_format`](crate::Config::log_format); it writes warnings
+//! and errors to stderr and the rest to stdout. Its level can be changed
+//! later, on a config reload.
+
+use std::io::{self, IsTerminal};
+use std::sync::OnceLock;
+
+use tracing::level_filters::LevelFilter;
+use tracing::Level;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Registry};
+
+/// The level of the subscriber `init` installed, if it did.
+static LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// Which events are written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogLevel {
+    /// Everything.
+    Info,
+    /// Warnings and errors only.
+    Warn,
+    /// Errors only.
+    Error,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Error => LevelFilter::ERROR,
+        }
+    }
+}
+
+/// How events are written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+    /// One readable line per event, with the client span's fields.
+    Text,
+    /// One JSON object per line: `timestamp`, `level`, `message`, the
+    /// event's fields, and the client span's as `span`.
+    Json,
+}
+
+/// Installs the process-wide subscriber, unless there already is one.
+pub fn init(level: LogLevel, format: LogFormat) {
+    let out = io::stderr.with_max_level(Level::WARN).or_else(io::stdout);
+    let (filter, handle) = reload::Layer::new(LevelFilter::from(level));
+    let layer = fmt::layer().with_target(false).with_writer(out);
+    let registry = tracing_subscriber::registry().with(filter);
+    // An error only means a subscriber is installed already, which stays
+    let installed = match format {
+        LogFormat::Text => registry.with(layer.with_ansi(io::stdout().is_terminal())).try_init(),
+        LogFormat::Json => registry.with(layer.json().flatten_event(true).with_span_list(false)).try_init(),
+    };
+    if installed.is_ok() {
+        let _ = LEVEL.set(handle);
+    }
+}
+
+/// Changes which events are written, if the subscriber is the one `init`
+/// installed; returns whether it was.
+pub fn set_level(level: LogLevel) -> bool {
+    LEVEL.get().is_some_and(|handle| handle.reload(LevelFilter::from(level)).is_ok())
+}
@@ -1,137 +1,755 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::env;
+use std::fs;
 use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use clap::Parser;
+use serde::Deserialize;
+use futures::Stream;
+use tcp_broadcast::{
+    chain, conformance, init_logging, selftest, vectors, AccessList, BlobConfig, BroadcastServer, Config, Fairness, IdleConfig, IdlePolicy,
+    LatencyBudget, LogFormat, LogLevel, Protocol, RateLimit, RedisConfig, Reload, SlowConsumer, TlsConfig, Tuning,
+    ViolationPolicy,
+};
+use tracing::warn;
 
-use tokio_stream::wrappers::{LinesStream, TcpListenerStream};
-use tokio_stream::{StreamExt, StreamMap};
+struct Options {
+    addr: SocketAddr,
+    also: Vec<SocketAddr>,
+    config: Config,
+}
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> io::Result<()> {
-    // Choose port: default 8888 or first CLI arg.
-    let port: u16 = env::args()
-        .nth(1)
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(8888);
-
-    let addr = format!("0.0.0.0:{port}");
-    let listener = TcpListener::bind(&addr).await?;
-    println!("listening on port {port}");
-
-    // Stream of incoming connections
-    let mut incoming = TcpListenerStream::new(listener);
-
-    // Map of client_id -> write half
-    let mut writers: HashMap<u16, BufWriter<OwnedWriteHalf>> = HashMap::new();
-
-    // Map of client_id -> stream of input lines
-    let mut inputs: StreamMap<u16, LinesStream<BufReader<OwnedReadHalf>>> = StreamMap::new();
-
-    loop {
-        tokio::select! {
-            // Accept new clients
-            maybe_conn = incoming.next() => {
-                match maybe_conn {
-                    Some(Ok(stream)) => {
-                        if let Err(e) = handle_new_client(stream, &mut writers, &mut inputs).await {
-                            eprintln!("error on accept: {e}");
-                        }
-                    }
-                    Some(Err(e)) => {
-                        eprintln!("accept error: {e}");
-                    }
-                    None => {
-                        break;
-                    }
-                }
+/// A `--bind` value: an address to listen on, with or without a port.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+enum Bind {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+impl Bind {
+    fn with_default_port(self, port: u16) -> SocketAddr {
+        match self {
+            Bind::Ip(ip) => SocketAddr::new(ip, port),
+            Bind::Socket(addr) => addr,
+        }
+    }
+}
+
+impl FromStr for Bind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        s.parse()
+            .map(Bind::Socket)
+            .or_else(|_| s.parse().map(Bind::Ip))
+            .map_err(|_| format!("invalid address {s:?}, expected an IP or IP:PORT ([::1]:8888 for IPv6)"))
+    }
+}
+
+impl TryFrom<String> for Bind {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+/// Server settings, from the command line and optionally a TOML file.
+///
+/// The file's keys are the long flags' names (`max-clients = 100`,
+/// `tls-cert = "server.pem"`), so the two can't drift apart; anything given
+/// on the command line overrides the file.
+#[derive(Parser, Deserialize, Default, Clone)]
+#[command(
+    version,
+    long_about = None,
+    about = "Broadcasts every line a client sends to all other clients.",
+    after_help = "Run `tcp-broadcast conformance [HOST:PORT]` to check a running server, `tcp-broadcast selftest [OPTIONS]` \
+                  to try these settings on a server of its own, `tcp-broadcast vectors [HOST:PORT] [--file PATH]` to run \
+                  protocol test vectors, or `tcp-broadcast verify-log PATH` to check a chained message log."
+)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Settings {
+    /// Port to listen on [default: 8888]
+    port: Option<u16>,
+    /// Read settings from this TOML file; flags override it
+    #[arg(long, value_name = "PATH")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Address to listen on, as IP or IP:PORT; repeat for more. WebSocket
+    /// and framed ports use the first [default: 0.0.0.0]
+    #[arg(long, value_name = "ADDR")]
+    bind: Vec<Bind>,
+    /// `warn` leaves out everything but warnings and errors, `error` all
+    /// but errors [default: info]
+    #[arg(long, value_name = "LEVEL", value_parser = ["info", "warn", "error"])]
+    log_level: Option<String>,
+    /// `json` writes one JSON object per event [default: text]
+    #[arg(long, value_name = "FORMAT", value_parser = ["text", "json"])]
+    log_format: Option<String>,
+
+    /// Flush every line at once, with TCP_NODELAY
+    #[arg(long, conflicts_with = "throughput")]
+    low_latency: bool,
+    /// Batch writes for throughput
+    #[arg(long)]
+    throughput: bool,
+    /// Send broadcasts in rooms of up to N members straight to each member
+    #[arg(long, value_name = "N")]
+    small_room: Option<usize>,
+    #[arg(long)]
+    nodelay: bool,
+    #[arg(long, value_name = "SECS")]
+    keepalive: Option<u64>,
+    #[arg(long)]
+    reuse_port: bool,
+    #[arg(long)]
+    no_reuse_addr: bool,
+    #[arg(long, value_name = "N")]
+    backlog: Option<u32>,
+    #[arg(long, value_name = "N")]
+    accept_batch: Option<usize>,
+    /// Read a PROXY protocol header from every client connection and use
+    /// the address in it; only behind a load balancer that sends one
+    #[arg(long)]
+    proxy_protocol: bool,
+
+    #[arg(long, value_name = "PORT")]
+    ws_port: Option<u16>,
+    #[arg(long, value_name = "PORT")]
+    framed_port: Option<u16>,
+    /// Publish each line of the datagrams arriving on this UDP port
+    #[arg(long, value_name = "PORT")]
+    udp_port: Option<u16>,
+    #[arg(long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+    #[arg(long, value_name = "PATH")]
+    unix_socket: Option<PathBuf>,
+    /// Accept links from other servers on this port
+    #[arg(long, value_name = "PORT")]
+    peer_port: Option<u16>,
+    /// Link to the server with its peer port here; repeat for more
+    #[arg(long, value_name = "HOST:PORT")]
+    peer: Vec<String>,
+    /// This server's name among its peers [default: random]
+    #[arg(long, value_name = "ID")]
+    server_id: Option<String>,
+    /// This server's number in message IDs, 0-1023, different on every
+    /// server in a cluster [default: derived from the server id]
+    #[arg(long, value_name = "N")]
+    node_id: Option<u16>,
+    /// Where clients can reach this server, for front doors to send them
+    #[arg(long, value_name = "HOST:PORT")]
+    advertise: Option<String>,
+    /// Send every new client to the least loaded linked server
+    #[arg(long)]
+    front_door: bool,
+    /// Share messages with other instances through this Redis server
+    #[arg(long, value_name = "HOST:PORT")]
+    redis: Option<String>,
+    /// The Redis channel instances share [default: tcp-broadcast]
+    #[arg(long, value_name = "NAME")]
+    redis_channel: Option<String>,
+    /// Password for Redis AUTH (config file only)
+    #[arg(skip)]
+    redis_password: Option<String>,
+    #[arg(long, value_name = "PEM")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, value_name = "PEM")]
+    tls_key: Option<PathBuf>,
+    #[arg(long, value_name = "PEM")]
+    tls_client_ca: Option<PathBuf>,
+    #[arg(long, value_name = "FORMAT", value_parser = ["text", "json"])]
+    protocol: Option<String>,
+    #[arg(long)]
+    direct: bool,
+
+    #[arg(long, value_name = "N")]
+    history: Option<usize>,
+    /// Replay history at most this many lines a second, across clients
+    #[arg(long, value_name = "PER_SEC")]
+    replay_rate: Option<f64>,
+    /// Lines of a replay sent to one client before the next client's turn
+    /// [default: 100]
+    #[arg(long, value_name = "N")]
+    replay_page: Option<usize>,
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// Hash-chain the message log, for `tcp-broadcast verify-log PATH`
+    #[arg(long)]
+    log_chain: bool,
+    #[arg(long, value_name = "PATH")]
+    blob_dir: Option<PathBuf>,
+    #[arg(long, value_name = "BYTES")]
+    blob_threshold: Option<usize>,
+    /// Words by list name, for room filters (config file only)
+    #[arg(skip)]
+    filter_lists: BTreeMap<String, Vec<String>>,
+    /// LIST:ACTION filters by room, `*` for the rest (config file only)
+    #[arg(skip)]
+    room_filters: BTreeMap<String, Vec<String>>,
+
+    #[arg(long, value_name = "N")]
+    max_clients: Option<usize>,
+    #[arg(long, value_name = "N")]
+    max_line_bytes: Option<usize>,
+    #[arg(long, value_name = "PER_SEC")]
+    rate_limit: Option<f64>,
+    #[arg(long, value_name = "N")]
+    rate_burst: Option<f64>,
+    #[arg(long, value_name = "N")]
+    send_queue: Option<usize>,
+    #[arg(long, value_name = "POLICY", value_parser = ["drop-oldest", "drop-newest", "drop", "disconnect", "latency"])]
+    slow_consumer: Option<String>,
+    /// Under `--slow-consumer latency`, how long a line may take to be
+    /// delivered [default: 1000]
+    #[arg(long, value_name = "MS")]
+    latency_budget_ms: Option<u64>,
+    /// Under `--slow-consumer latency`, how long a client may stay over
+    /// budget before it's disconnected [default: 10]
+    #[arg(long, value_name = "SECS")]
+    latency_grace_secs: Option<u64>,
+    #[arg(long, value_name = "MODE", value_parser = ["off", "round-robin"])]
+    fairness: Option<String>,
+    #[arg(long, value_name = "SECS")]
+    dedup_window: Option<u64>,
+    /// Drop a message from a bridge user that was sent out through it
+    /// within this long
+    #[arg(long, value_name = "SECS")]
+    echo_window: Option<u64>,
+    /// Compress messages at least this long for clients that send
+    /// CAPS:compress=gzip|zstd
+    #[arg(long, value_name = "BYTES")]
+    compress_min_bytes: Option<usize>,
+    /// Give every message an ID and the time it was sent, in its tag
+    #[arg(long)]
+    stamp_messages: bool,
+    #[arg(long, value_name = "SECS")]
+    drain_timeout: Option<u64>,
+    /// Drop a connection that hasn't finished its PROXY header, TLS
+    /// handshake or WebSocket upgrade in this long (default 10)
+    #[arg(long, value_name = "SECS")]
+    handshake_timeout: Option<u64>,
+    /// Disconnect a client when a write to it takes longer than this
+    #[arg(long, value_name = "SECS")]
+    write_timeout: Option<u64>,
+    /// Send clients their own messages back, unless they send SET:echo=off
+    #[arg(long)]
+    echo_to_sender: bool,
+    #[arg(long, value_name = "SECS")]
+    ping_interval: Option<u64>,
+    #[arg(long, value_name = "SECS")]
+    ping_timeout: Option<u64>,
+    /// One listener's idle policy, overriding --ping-interval for it:
+    /// off, ping:SECS[:TIMEOUT_SECS] or reap:SECS; repeat for more
+    #[arg(long, value_name = "LISTENER=POLICY")]
+    idle: Vec<String>,
+    /// Send NOOP (a ping on WebSockets) to clients sent nothing this long,
+    /// to keep NAT and firewall mappings open
+    #[arg(long, value_name = "SECS")]
+    noop_interval: Option<u64>,
+    /// Shared tokens clients may authenticate with (config file only)
+    #[arg(skip)]
+    auth_tokens: Vec<String>,
+    /// Passwords by user name (config file only)
+    #[arg(skip)]
+    auth_users: BTreeMap<String, String>,
+    /// Users who may run operator commands (config file only)
+    #[arg(skip)]
+    admin_users: Vec<String>,
+    /// Users who relay to and from elsewhere (config file only)
+    #[arg(skip)]
+    bridge_users: Vec<String>,
+    #[arg(long, value_name = "SECS")]
+    auth_timeout: Option<u64>,
+    /// Address ranges that may connect (config file only; reread on SIGHUP)
+    #[arg(skip)]
+    allow: Vec<String>,
+    /// Address ranges that may not connect (config file only; reread on
+    /// SIGHUP)
+    #[arg(skip)]
+    deny: Vec<String>,
+    #[arg(long, value_name = "SECS")]
+    idle_after: Option<u64>,
+    #[arg(long, value_name = "SECS")]
+    away_after: Option<u64>,
+
+    #[arg(long, value_name = "N")]
+    churn_limit: Option<usize>,
+    #[arg(long, value_name = "SECS")]
+    churn_window: Option<u64>,
+    #[arg(long, value_name = "SECS")]
+    auto_ban: Option<u64>,
+    #[arg(long, value_name = "SECS")]
+    greylist: Option<u64>,
+    #[arg(long, value_name = "SECS")]
+    tarpit: Option<u64>,
+    #[arg(long, value_name = "MS")]
+    tarpit_read_ms: Option<u64>,
+    #[arg(long, value_name = "N")]
+    violation_budget: Option<u32>,
+    #[arg(long, value_name = "URL")]
+    alert_webhook: Option<String>,
+    #[arg(long, value_name = "MS")]
+    alert_lag_ms: Option<u64>,
+    /// Also send alerts to the admins in this room
+    #[arg(long, value_name = "ROOM")]
+    alert_room: Option<String>,
+    /// Alert when resident memory reaches this many MiB
+    #[arg(long, value_name = "MB")]
+    alert_memory_mb: Option<u64>,
+    /// Shed load once this many broadcast lines wait across all clients
+    #[arg(long, value_name = "LINES")]
+    shed_queue: Option<u64>,
+    /// Shed load once resident memory reaches this many MiB
+    #[arg(long, value_name = "MB")]
+    shed_memory_mb: Option<u64>,
+    /// Time each client's round trip at login and sort it into latency
+    /// regions, e.g. local=5,near=50,far (milliseconds; the last may be
+    /// unbounded)
+    #[arg(long, value_name = "REGIONS")]
+    rtt_regions: Option<String>,
+    /// POST a JSON summary of each client's session when it ends
+    #[arg(long, value_name = "URL")]
+    session_webhook: Option<String>,
+}
+
+impl Settings {
+    /// Reads a settings file.
+    fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| invalid(format!("{}: {e}", path.display())))
+    }
+
+    /// The `allow` and `deny` lists.
+    fn access(&self) -> io::Result<AccessList> {
+        let ranges = |key: &str, list: &[String]| {
+            list.iter().map(|range| range.parse().map_err(|e| invalid(format!("{key}: {e}")))).collect::<io::Result<_>>()
+        };
+        Ok(AccessList { allow: ranges("allow", &self.allow)?, deny: ranges("deny", &self.deny)? })
+    }
+
+    /// These settings, with whatever they leave unset taken from `file`.
+    fn or(self, file: Settings) -> Settings {
+        // The latency/throughput presets are one choice, made in one place
+        let preset = self.low_latency || self.throughput;
+        Settings {
+            port: self.port.or(file.port),
+            config: self.config,
+            bind: if self.bind.is_empty() { file.bind } else { self.bind },
+            log_level: self.log_level.or(file.log_level),
+            log_format: self.log_format.or(file.log_format),
+            low_latency: if preset { self.low_latency } else { file.low_latency },
+            throughput: if preset { self.throughput } else { file.throughput },
+            small_room: self.small_room.or(file.small_room),
+            nodelay: self.nodelay || file.nodelay,
+            keepalive: self.keepalive.or(file.keepalive),
+            reuse_port: self.reuse_port || file.reuse_port,
+            no_reuse_addr: self.no_reuse_addr || file.no_reuse_addr,
+            backlog: self.backlog.or(file.backlog),
+            accept_batch: self.accept_batch.or(file.accept_batch),
+            proxy_protocol: self.proxy_protocol || file.proxy_protocol,
+            ws_port: self.ws_port.or(file.ws_port),
+            framed_port: self.framed_port.or(file.framed_port),
+            udp_port: self.udp_port.or(file.udp_port),
+            metrics_port: self.metrics_port.or(file.metrics_port),
+            unix_socket: self.unix_socket.or(file.unix_socket),
+            peer_port: self.peer_port.or(file.peer_port),
+            peer: if self.peer.is_empty() { file.peer } else { self.peer },
+            server_id: self.server_id.or(file.server_id),
+            node_id: self.node_id.or(file.node_id),
+            advertise: self.advertise.or(file.advertise),
+            front_door: self.front_door || file.front_door,
+            redis: self.redis.or(file.redis),
+            redis_channel: self.redis_channel.or(file.redis_channel),
+            redis_password: file.redis_password,
+            tls_cert: self.tls_cert.or(file.tls_cert),
+            tls_key: self.tls_key.or(file.tls_key),
+            tls_client_ca: self.tls_client_ca.or(file.tls_client_ca),
+            protocol: self.protocol.or(file.protocol),
+            direct: self.direct || file.direct,
+            history: self.history.or(file.history),
+            replay_rate: self.replay_rate.or(file.replay_rate),
+            replay_page: self.replay_page.or(file.replay_page),
+            log_file: self.log_file.or(file.log_file),
+            log_chain: self.log_chain || file.log_chain,
+            blob_dir: self.blob_dir.or(file.blob_dir),
+            blob_threshold: self.blob_threshold.or(file.blob_threshold),
+            filter_lists: file.filter_lists,
+            room_filters: file.room_filters,
+            max_clients: self.max_clients.or(file.max_clients),
+            max_line_bytes: self.max_line_bytes.or(file.max_line_bytes),
+            rate_limit: self.rate_limit.or(file.rate_limit),
+            rate_burst: self.rate_burst.or(file.rate_burst),
+            send_queue: self.send_queue.or(file.send_queue),
+            slow_consumer: self.slow_consumer.or(file.slow_consumer),
+            latency_budget_ms: self.latency_budget_ms.or(file.latency_budget_ms),
+            latency_grace_secs: self.latency_grace_secs.or(file.latency_grace_secs),
+            fairness: self.fairness.or(file.fairness),
+            dedup_window: self.dedup_window.or(file.dedup_window),
+            echo_window: self.echo_window.or(file.echo_window),
+            compress_min_bytes: self.compress_min_bytes.or(file.compress_min_bytes),
+            stamp_messages: self.stamp_messages || file.stamp_messages,
+            drain_timeout: self.drain_timeout.or(file.drain_timeout),
+            handshake_timeout: self.handshake_timeout.or(file.handshake_timeout),
+            write_timeout: self.write_timeout.or(file.write_timeout),
+            echo_to_sender: self.echo_to_sender || file.echo_to_sender,
+            ping_interval: self.ping_interval.or(file.ping_interval),
+            ping_timeout: self.ping_timeout.or(file.ping_timeout),
+            idle: if self.idle.is_empty() { file.idle } else { self.idle },
+            noop_interval: self.noop_interval.or(file.noop_interval),
+            auth_tokens: file.auth_tokens,
+            auth_users: file.auth_users,
+            admin_users: file.admin_users,
+            bridge_users: file.bridge_users,
+            auth_timeout: self.auth_timeout.or(file.auth_timeout),
+            allow: file.allow,
+            deny: file.deny,
+            idle_after: self.idle_after.or(file.idle_after),
+            away_after: self.away_after.or(file.away_after),
+            churn_limit: self.churn_limit.or(file.churn_limit),
+            churn_window: self.churn_window.or(file.churn_window),
+            auto_ban: self.auto_ban.or(file.auto_ban),
+            greylist: self.greylist.or(file.greylist),
+            tarpit: self.tarpit.or(file.tarpit),
+            tarpit_read_ms: self.tarpit_read_ms.or(file.tarpit_read_ms),
+            violation_budget: self.violation_budget.or(file.violation_budget),
+            alert_webhook: self.alert_webhook.or(file.alert_webhook),
+            alert_lag_ms: self.alert_lag_ms.or(file.alert_lag_ms),
+            alert_room: self.alert_room.or(file.alert_room),
+            alert_memory_mb: self.alert_memory_mb.or(file.alert_memory_mb),
+            shed_queue: self.shed_queue.or(file.shed_queue),
+            shed_memory_mb: self.shed_memory_mb.or(file.shed_memory_mb),
+            rtt_regions: self.rtt_regions.or(file.rtt_regions),
+            session_webhook: self.session_webhook.or(file.session_webhook),
+        }
+    }
+
+    /// Checks the settings as a whole and turns them into a server config.
+    fn into_options(self) -> io::Result<Options> {
+        let mut config = Config { access: self.access()?, ..Config::default() };
+        if self.low_latency && self.throughput {
+            return Err(invalid("low-latency and throughput can't both be set"));
+        }
+        if self.low_latency {
+            config.socket.nodelay = true;
+            config.tuning = Tuning::low_latency();
+        }
+        if self.throughput {
+            config.tuning = Tuning::throughput();
+        }
+        set(&mut config.tuning.small_room, self.small_room);
+        config.socket.nodelay |= self.nodelay;
+        config.socket.keepalive = self.keepalive.map(Duration::from_secs);
+        config.socket.reuse_port = self.reuse_port;
+        config.socket.reuse_address = !self.no_reuse_addr;
+        set(&mut config.socket.backlog, self.backlog);
+        set(&mut config.accept_batch, self.accept_batch);
+        config.proxy_protocol = self.proxy_protocol;
+        config.log_level = match self.log_level.as_deref() {
+            None | Some("info") => LogLevel::Info,
+            Some("warn") => LogLevel::Warn,
+            Some("error") => LogLevel::Error,
+            Some(_) => return Err(invalid("invalid value for log-level")),
+        };
+        config.log_format = match self.log_format.as_deref() {
+            None | Some("text") => LogFormat::Text,
+            Some("json") => LogFormat::Json,
+            Some(_) => return Err(invalid("invalid value for log-format")),
+        };
+
+        config.ws_port = self.ws_port;
+        config.framed_port = self.framed_port;
+        config.udp_port = self.udp_port;
+        config.metrics_port = self.metrics_port;
+        config.unix_socket = self.unix_socket;
+        config.peers.port = self.peer_port;
+        config.peers.peers = self.peer;
+        config.peers.server_id = self.server_id;
+        config.peers.node_id = self.node_id;
+        if let Some(advertise) = &self.advertise {
+            if !advertise.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) {
+                return Err(invalid(format!("invalid --advertise {advertise:?}, expected HOST:PORT")));
             }
+        }
+        config.peers.advertise = self.advertise;
+        config.peers.front_door = self.front_door;
+        if (config.peers.front_door || config.peers.advertise.is_some()) && !config.peers.enabled() {
+            return Err(invalid("--front-door and --advertise need --peer or --peer-port"));
+        }
+        config.redis = match (self.redis, self.redis_channel) {
+            (Some(addr), channel) => {
+                let default = RedisConfig::new(addr);
+                Some(RedisConfig { channel: channel.unwrap_or(default.channel), password: self.redis_password, ..default })
+            }
+            (None, None) if self.redis_password.is_none() => None,
+            _ => return Err(invalid("--redis-channel and redis-password need --redis")),
+        };
+        config.tls = match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { client_ca: self.tls_client_ca, ..TlsConfig::new(cert, key) }),
+            (None, None) if self.tls_client_ca.is_none() => None,
+            _ => return Err(invalid("--tls-cert and --tls-key go together (and --tls-client-ca needs both)")),
+        };
+        config.protocol = match self.protocol.as_deref() {
+            None | Some("text") => Protocol::Text,
+            Some("json") => Protocol::Json,
+            Some(_) => return Err(invalid("invalid value for protocol")),
+        };
+        config.direct = self.direct;
 
-            // Any line from any client
-            maybe_item = inputs.next(), if !inputs.is_empty() => {
-                match maybe_item {
-                    Some((client_id, Ok(line))) => {
-
-                        println!("message {client_id} {line}");
-
-                        // Broadcast to all other clients
-                        let mut dead: Vec<u16> = Vec::new();
-                        let msg = format!("MESSAGE:{client_id} {line}\n");
-                        for (&other_id, w) in writers.iter_mut() {
-                            if other_id == client_id { continue; }
-                            if let Err(e) = w.write_all(msg.as_bytes()).await {
-                                eprintln!("write error to {other_id}: {e}");
-                                dead.push(other_id);
-                                continue;
-                            }
-                            if let Err(e) = w.flush().await {
-                                eprintln!("flush error to {other_id}: {e}");
-                                dead.push(other_id);
-                            }
-                        }
-                        // Remove any failed writers
-                        for id in dead {
-                            writers.remove(&id);
-                            inputs.remove(&id);
-                        }
-
-                        // ACK to sender
-                        if let Some(w) = writers.get_mut(&client_id) {
-                            if let Err(e) = w.write_all(b"ACK:MESSAGE\n").await {
-                                eprintln!("ack write error to {client_id}: {e}");
-                                writers.remove(&client_id);
-                                inputs.remove(&client_id);
-                            } else if let Err(e) = w.flush().await {
-                                eprintln!("ack flush error to {client_id}: {e}");
-                                writers.remove(&client_id);
-                                inputs.remove(&client_id);
-                            }
-                        }
-                    }
-                    Some((client_id, Err(e))) => {
-                        eprintln!("read error from {client_id}: {e}");
-                        writers.remove(&client_id);
-                        inputs.remove(&client_id);
-                    }
-                    None => {
-                        // No more input streams (all clients gone) — keep accepting
-                        // (the accept branch above will continue to fire).
-                    }
-                }
+        set(&mut config.history, self.history);
+        config.replay.rate = match (self.replay_rate, self.replay_page) {
+            (Some(rate), _) if rate > 0.0 => Some(rate),
+            (None, None) => None,
+            _ => return Err(invalid("--replay-rate needs a positive rate (and --replay-page needs --replay-rate)")),
+        };
+        set(&mut config.replay.page, self.replay_page);
+        if self.log_chain && self.log_file.is_none() {
+            return Err(invalid("--log-chain needs --log-file"));
+        }
+        config.log_file = self.log_file;
+        config.log_chain = self.log_chain;
+        config.blobs = match (self.blob_dir, self.blob_threshold) {
+            (Some(dir), threshold) => {
+                let default = BlobConfig::new(dir);
+                Some(BlobConfig { threshold: threshold.unwrap_or(default.threshold), ..default })
             }
+            (None, None) => None,
+            (None, Some(_)) => return Err(invalid("--blob-threshold needs --blob-dir")),
+        };
+        config.filters.lists = self.filter_lists;
+        for (room, filters) in self.room_filters {
+            let filters = filters.iter().map(|filter| filter.parse()).collect::<Result<_, _>>();
+            config.filters.rooms.insert(room, filters.map_err(invalid)?);
         }
+
+        config.max_clients = self.max_clients;
+        set(&mut config.max_line, self.max_line_bytes);
+        config.rate_limit = match (self.rate_limit, self.rate_burst) {
+            (Some(rate), burst) if rate > 0.0 => {
+                let default = RateLimit::per_second(rate);
+                Some(RateLimit { burst: burst.unwrap_or(default.burst).max(1.0), ..default })
+            }
+            (None, None) => None,
+            _ => return Err(invalid("--rate-limit needs a positive rate (and --rate-burst needs --rate-limit)")),
+        };
+        set(&mut config.send_queue, self.send_queue);
+        match self.slow_consumer.as_deref() {
+            None => {}
+            Some("disconnect") => config.slow_consumer = SlowConsumer::Disconnect,
+            Some("drop-oldest") => config.slow_consumer = SlowConsumer::DropOldest,
+            // `drop` predates the choice of end
+            Some("drop-newest" | "drop") => config.slow_consumer = SlowConsumer::DropNewest,
+            Some("latency") => {
+                let mut limit = LatencyBudget::default();
+                set(&mut limit.budget, self.latency_budget_ms.map(Duration::from_millis));
+                set(&mut limit.grace, self.latency_grace_secs.map(Duration::from_secs));
+                config.slow_consumer = SlowConsumer::Latency(limit);
+            }
+            Some(_) => return Err(invalid("invalid value for slow-consumer")),
+        }
+        if !matches!(config.slow_consumer, SlowConsumer::Latency(_))
+            && (self.latency_budget_ms.is_some() || self.latency_grace_secs.is_some())
+        {
+            return Err(invalid("--latency-budget-ms and --latency-grace-secs need --slow-consumer latency"));
+        }
+        match self.fairness.as_deref() {
+            None => {}
+            Some("round-robin") => config.fairness = Fairness::RoundRobin,
+            Some("off") => config.fairness = Fairness::Off,
+            Some(_) => return Err(invalid("invalid value for fairness")),
+        }
+        config.dedup_window = self.dedup_window.map(Duration::from_secs);
+        config.echo_window = self.echo_window.map(Duration::from_secs);
+        config.compress_min_bytes = self.compress_min_bytes;
+        config.stamp_messages = self.stamp_messages;
+        set(&mut config.drain_timeout, self.drain_timeout.map(Duration::from_secs));
+        if self.handshake_timeout == Some(0) || self.write_timeout == Some(0) {
+            return Err(invalid("--handshake-timeout and --write-timeout need a positive number of seconds"));
+        }
+        set(&mut config.handshake_timeout, self.handshake_timeout.map(Duration::from_secs));
+        config.write_timeout = self.write_timeout.map(Duration::from_secs);
+        config.echo_to_sender = self.echo_to_sender;
+        // --ping-interval is every listener's policy, unless --idle says otherwise
+        if let Some(interval) = self.ping_interval.map(Duration::from_secs) {
+            let timeout = Duration::from_secs(self.ping_timeout.unwrap_or(10));
+            config.idle = IdleConfig::all(IdlePolicy::Ping { interval, timeout });
+        }
+        for entry in &self.idle {
+            let (listener, policy) =
+                entry.split_once('=').ok_or_else(|| invalid(format!("invalid --idle {entry:?}, expected LISTENER=POLICY")))?;
+            config.idle.set(listener, policy.parse().map_err(invalid)?).map_err(invalid)?;
+        }
+        config.noop_interval = match self.noop_interval {
+            Some(0) => return Err(invalid("--noop-interval needs a positive number of seconds")),
+            secs => secs.map(Duration::from_secs),
+        };
+        config.auth.tokens = self.auth_tokens;
+        if let Some(unknown) = self.admin_users.iter().find(|admin| !self.auth_users.contains_key(*admin)) {
+            return Err(invalid(format!("admin-users: {unknown} isn't in auth-users")));
+        }
+        if let Some(unknown) = self.bridge_users.iter().find(|bridge| !self.auth_users.contains_key(*bridge)) {
+            return Err(invalid(format!("bridge-users: {unknown} isn't in auth-users")));
+        }
+        config.auth.users = self.auth_users;
+        config.auth.admins = self.admin_users;
+        config.auth.bridges = self.bridge_users;
+        set(&mut config.auth.timeout, self.auth_timeout.map(Duration::from_secs));
+        config.presence.idle_after = self.idle_after.map(Duration::from_secs);
+        config.presence.away_after = self.away_after.map(Duration::from_secs);
+
+        set(&mut config.anomaly.churn_limit, self.churn_limit);
+        set(&mut config.anomaly.churn_window, self.churn_window.map(Duration::from_secs));
+        config.anomaly.ban_for = self.auto_ban.map(Duration::from_secs);
+        set(&mut config.anomaly.greylist_for, self.greylist.map(Duration::from_secs));
+        config.tarpit.delay = self.tarpit.map(Duration::from_secs);
+        set(&mut config.tarpit.read_interval, self.tarpit_read_ms.map(Duration::from_millis));
+        if let Some(budget) = self.violation_budget {
+            config.violations = ViolationPolicy::with_budget(budget);
+        }
+        config.alert.webhook = self.alert_webhook;
+        config.alert.room = self.alert_room;
+        config.alert.memory_threshold = self.alert_memory_mb.map(|mb| mb * 1024 * 1024);
+        config.session_webhook = self.session_webhook;
+        set(&mut config.alert.lag_threshold, self.alert_lag_ms.map(Duration::from_millis));
+        if self.shed_queue == Some(0) || self.shed_memory_mb == Some(0) {
+            return Err(invalid("--shed-queue and --shed-memory-mb need a positive threshold"));
+        }
+        config.shed.queue_threshold = self.shed_queue;
+        config.shed.memory_threshold = self.shed_memory_mb.map(|mb| mb * 1024 * 1024);
+        if let Some(regions) = self.rtt_regions {
+            config.regions = regions.parse().map_err(|e| invalid(format!("--rtt-regions: {e}")))?;
+        }
+
+        let port = self.port.unwrap_or(8888);
+        let mut addrs = self.bind.iter().map(|bind| bind.with_default_port(port));
+        let addr = addrs.next().unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port));
+        Ok(Options { addr, also: addrs.collect(), config })
     }
+}
 
-    Ok(())
+/// Overrides a default, if a value was given.
+fn set<T>(field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+    }
 }
 
-async fn handle_new_client(
-    stream: TcpStream,
-    writers: &mut HashMap<u16, BufWriter<OwnedWriteHalf>>,
-    inputs: &mut StreamMap<u16, LinesStream<BufReader<OwnedReadHalf>>>,
-) -> io::Result<()> {
-    let peer = stream.peer_addr()?;
-    let client_id: u16 = peer.port(); // use peer port as CLIENT_ID to match examples
+fn invalid(e: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.into())
+}
 
-    println!("connected {} {}", peer.ip(), client_id);
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    if env::args().nth(1).as_deref() == Some("conformance") {
+        let target = env::args().nth(2).unwrap_or_else(|| "127.0.0.1:8888".to_string());
+        let passed = conformance::run(&target).await?;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    if env::args().nth(1).as_deref() == Some("vectors") {
+        let (mut target, mut file) = (None, None);
+        let mut args = env::args().skip(2);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--file" => file = Some(args.next().ok_or_else(|| invalid("--file needs a path"))?),
+                _ => target = Some(arg),
+            }
+        }
+        let text = match file {
+            Some(path) => fs::read_to_string(path)?,
+            None => vectors::BUILTIN.to_string(),
+        };
+        let vectors = vectors::parse(&text)?;
+        // Without a target, against a server of its own with the default config
+        let passed = match target {
+            Some(target) => vectors::run(&target, &vectors).await,
+            None => vectors::run_local(&vectors).await?,
+        };
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
-    let (read_half, write_half) = stream.into_split();
+    if env::args().nth(1).as_deref() == Some("verify-log") {
+        let path = env::args().nth(2).ok_or_else(|| invalid("verify-log needs the message log's path"))?;
+        let held = chain::verify(Path::new(&path))?;
+        std::process::exit(if held { 0 } else { 1 });
+    }
 
-    // Prepare the reader as a stream of lines
-    let reader = BufReader::new(read_half);
-    let lines = reader.lines();
-    let lines_stream = LinesStream::new(lines);
+    // Takes the same settings as the server it stands in for
+    let selftest = env::args().nth(1).as_deref() == Some("selftest");
+    let args = env::args().enumerate().filter(|&(i, _)| !(selftest && i == 1)).map(|(_, arg)| arg);
+    let flags = Settings::parse_from(args);
+    let settings = match &flags.config {
+        Some(path) => {
+            let file = Settings::load(path)?;
+            flags.clone().or(file)
+        }
+        None => flags.clone(),
+    };
+    let path = settings.config.clone();
+    let Options { addr, also, config } = settings.into_options()?;
+    if selftest {
+        let passed = selftest::run(config).await?;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    // Before anything that might warn, not when the server starts
+    init_logging(config.log_level, config.log_format);
 
-    // Prepare writer
-    let mut writer = BufWriter::new(write_half);
+    let server = also.into_iter().fold(BroadcastServer::bind(addr), BroadcastServer::also_bind);
+    let server = server.config(config).shutdown_on(shutdown_signal());
+    match path {
+        Some(path) => server.reloads(reloads(flags, path)).run().await,
+        None => server.run().await,
+    }
+}
 
-    writer.write_all(format!("LOGIN:{client_id}\n").as_bytes()).await?;
-    writer.flush().await?;
+/// The reloadable settings, from the config file reread on every SIGHUP
+/// under the same flags. A file that no longer parses, or has an invalid
+/// setting, is reported and the settings in force are kept.
+#[cfg(unix)]
+fn reloads(flags: Settings, path: PathBuf) -> impl Stream<Item = Reload> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let hangups = signal(SignalKind::hangup())
+        .inspect_err(|e| warn!("can't listen for SIGHUP, config won't be reloaded: {e}"))
+        .ok();
+    futures::stream::unfold((hangups, flags, path), |(mut hangups, flags, path)| async move {
+        loop {
+            hangups.as_mut()?.recv().await?;
+            match Settings::load(&path).and_then(|file| flags.clone().or(file).into_options()) {
+                Ok(options) => return Some((Reload::from_config(&options.config), (hangups, flags, path))),
+                Err(e) => warn!("config not reloaded: {e}"),
+            }
+        }
+    })
+}
 
-    writers.insert(client_id, writer);
-    inputs.insert(client_id, lines_stream);
+#[cfg(not(unix))]
+fn reloads(_flags: Settings, _path: PathBuf) -> impl Stream<Item = Reload> {
+    futures::stream::empty()
+}
 
-    Ok(())
-}
\ No newline at end of file
+/// Completes on Ctrl-C, or on SIGTERM where there is such a thing.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("can't listen for SIGTERM: {e}"),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("can't listen for Ctrl-C, graceful shutdown disabled: {e}");
+        std::future::pending::<()>().await;
+    }
+}
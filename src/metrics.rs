@@ -0,0 +1,123 @@
+//! In-process latency accounting.
+
+use std::time::Duration;
+
+/// Power-of-two microsecond buckets: bucket `i` holds samples below `2^i` µs.
+const BUCKETS: usize = 32;
+
+/// Delivery latency histogram, reported and reset on the housekeeping tick.
+#[derive(Default)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum_us: u64,
+    max_us: u64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let us = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+        self.max_us = self.max_us.max(us);
+    }
+
+    /// Upper bound (in µs) of the bucket holding the `q` quantile.
+    fn quantile(&self, q: f64) -> u64 {
+        let rank = ((self.count as f64) * q).ceil() as u64;
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return (1u64 << i).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+
+    /// Summary line for the log, or `None` if nothing was recorded.
+    pub fn take_summary(&mut self) -> Option<String> {
+        if self.count == 0 {
+            return None;
+        }
+        let line = format!(
+            "latency deliveries={} mean_us={} p50_us={} p99_us={} max_us={}",
+            self.count,
+            self.sum_us / self.count,
+            self.quantile(0.50),
+            self.quantile(0.99),
+            self.max_us,
+        );
+        *self = Self::default();
+        Some(line)
+    }
+}
+
+/// Messages below this many bytes count as tiny...
+const TINY_MAX: usize = 64;
+/// ...and below this as small; anything bigger is large.
+const SMALL_MAX: usize = 1024;
+
+/// Inbound message volume by size bucket, reported and reset on the
+/// housekeeping tick.
+#[derive(Default)]
+pub struct SizeStats {
+    tiny: u64,
+    small: u64,
+    large: u64,
+    bytes: u64,
+}
+
+impl SizeStats {
+    pub fn record(&mut self, len: usize) {
+        match len {
+            n if n < TINY_MAX => self.tiny += 1,
+            n if n < SMALL_MAX => self.small += 1,
+            _ => self.large += 1,
+        }
+        self.bytes += len as u64;
+    }
+
+    /// Summary line for the log, or `None` if nothing was recorded.
+    pub fn take_summary(&mut self) -> Option<String> {
+        let messages = self.tiny + self.small + self.large;
+        if messages == 0 {
+            return None;
+        }
+        let line = format!(
+            "traffic messages={messages} bytes={} tiny={} small={} large={}",
+            self.bytes, self.tiny, self.small, self.large,
+        );
+        *self = Self::default();
+        Some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_latency_by_power_of_two_buckets() {
+        let mut latency = LatencyHistogram::default();
+        assert_eq!(latency.take_summary(), None);
+        for us in [3, 3, 3, 100, 5000] {
+            latency.record(Duration::from_micros(us));
+        }
+        // 3µs is under 4, and no quantile reads past the max
+        assert_eq!(latency.take_summary().unwrap(), "latency deliveries=5 mean_us=1021 p50_us=4 p99_us=5000 max_us=5000");
+        assert_eq!(latency.take_summary(), None);
+    }
+    #[test]
+    fn buckets_traffic_by_size() {
+        let mut sizes = SizeStats::default();
+        assert_eq!(sizes.take_summary(), None);
+        for len in [0, 63, 64, 1023, 1024] {
+            sizes.record(len);
+        }
+        assert_eq!(sizes.take_summary().unwrap(), "traffic messages=5 bytes=2174 tiny=2 small=2 large=1");
+        assert_eq!(sizes.take_summary(), None);
+    }
+}
@@ -0,0 +1,278 @@
+//! Platform-specific socket tuning.
+//!
+//! Everything that differs per OS lives here: which options exist, what
+//! they're called and how they behave. An option the platform lacks is
+//! reported as unsupported at startup and otherwise skipped, and one that
+//! fails to apply is logged without failing the listener or connection.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{self, TcpListener, TcpStream};
+use tokio::time;
+use tracing::warn;
+
+/// Probe spacing once keepalive kicks in, where the platform lets us set it.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Unanswered probes before the connection is declared dead, where settable.
+const KEEPALIVE_RETRIES: u32 = 3;
+/// Head start each outbound attempt gets before the next address is tried.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// Give up on a single outbound attempt after this long.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm on client connections.
+    pub nodelay: bool,
+    /// Idle time before TCP keepalive probes start; `None` leaves it off.
+    pub keepalive: Option<Duration>,
+    /// Rebind the port while old connections linger in `TIME_WAIT`
+    /// (`SO_REUSEADDR`; never set on Windows, where it allows port theft).
+    pub reuse_address: bool,
+    /// Let several processes share the port (Linux `SO_REUSEPORT`).
+    pub reuse_port: bool,
+    /// Pending-connection queue passed to `listen(2)`; the kernel may cap it
+    /// (`net.core.somaxconn` on Linux).
+    pub backlog: u32,
+    /// For an IPv6 listener, whether to refuse IPv4-mapped connections;
+    /// `None` leaves the OS default.
+    pub only_v6: Option<bool>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: false,
+            keepalive: None,
+            reuse_address: true,
+            reuse_port: false,
+            backlog: 1024,
+            only_v6: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// One line saying what will actually be applied on this platform.
+    pub fn describe(&self) -> String {
+        let on_off = |b: bool| if b { "on" } else { "off" };
+        let or_unsupported = |available: bool, value: String| {
+            if available { value } else { "unsupported".to_string() }
+        };
+
+        let mut out = format!("nodelay={}", on_off(self.nodelay));
+        match self.keepalive {
+            Some(time) => {
+                let interval = format!("{}s", KEEPALIVE_INTERVAL.as_secs());
+                let retries = KEEPALIVE_RETRIES.to_string();
+                out += &format!(
+                    " keepalive={}s keepalive_interval={} keepalive_retries={}",
+                    time.as_secs(),
+                    or_unsupported(KEEPALIVE_HAS_INTERVAL, interval),
+                    or_unsupported(KEEPALIVE_HAS_RETRIES, retries),
+                );
+            }
+            None => out += " keepalive=off",
+        }
+        if self.reuse_address {
+            out += &format!(" reuseaddr={}", or_unsupported(!cfg!(windows), "on".to_string()));
+        } else {
+            out += " reuseaddr=off";
+        }
+        if self.reuse_port {
+            out += &format!(" reuseport={}", or_unsupported(HAS_REUSE_PORT, "on".to_string()));
+        }
+        out += &format!(" backlog={}", self.backlog);
+        if let Some(only_v6) = self.only_v6 {
+            out += &format!(" v6only={}", on_off(only_v6));
+        }
+        out
+    }
+}
+
+const HAS_REUSE_PORT: bool = cfg!(any(target_os = "linux", target_os = "android"));
+const KEEPALIVE_HAS_INTERVAL: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "windows",
+));
+const KEEPALIVE_HAS_RETRIES: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+));
+
+/// Binds a listening socket with the listener-level options applied.
+pub fn bind(addr: SocketAddr, opts: &SocketOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    // On Windows SO_REUSEADDR lets another process steal a bound port; the
+    // default exclusive behaviour is the safe one there.
+    #[cfg(not(windows))]
+    degrade("reuseaddr", socket.set_reuse_address(opts.reuse_address));
+
+    if opts.reuse_port {
+        set_reuse_port(&socket);
+    }
+    // Has to be decided before bind; meaningless for an IPv4 address
+    if let (Some(only_v6), true) = (opts.only_v6, addr.is_ipv6()) {
+        socket.set_only_v6(only_v6)?;
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(opts.backlog.min(i32::MAX as u32) as i32)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Applies the per-connection options to an accepted stream.
+pub fn tune(stream: &TcpStream, opts: &SocketOptions) {
+    let sock = SockRef::from(stream);
+    if opts.nodelay {
+        degrade("nodelay", sock.set_nodelay(true));
+    }
+    if let Some(time) = opts.keepalive {
+        degrade("keepalive", sock.set_tcp_keepalive(&keepalive_params(time)));
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_reuse_port(socket: &Socket) {
+    degrade("reuseport", socket.set_reuse_port(true));
+}
+
+// BSD-style SO_REUSEPORT doesn't load-balance accepts, and Windows has no
+// equivalent, so elsewhere the option is left alone.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_reuse_port(_socket: &Socket) {}
+
+fn keepalive_params(time: Duration) -> TcpKeepalive {
+    let params = TcpKeepalive::new().with_time(time);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "windows",
+    ))]
+    let params = params.with_interval(KEEPALIVE_INTERVAL);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+    ))]
+    let params = params.with_retries(KEEPALIVE_RETRIES);
+    params
+}
+
+fn degrade(option: &str, result: io::Result<()>) {
+    if let Err(e) = result {
+        warn!("socket option {option} not applied: {e}");
+    }
+}
+
+/// Dials `target` (`host:port`) happy-eyeballs style.
+///
+/// Every resolved address is tried, alternating IPv6 and IPv4. Attempts are
+/// started `ATTEMPT_DELAY` apart (or as soon as the previous one fails) and
+/// race each other; the first to connect wins. A broken address family
+/// therefore costs a quarter second rather than a full connect timeout.
+pub async fn connect(target: &str) -> io::Result<TcpStream> {
+    let mut addrs = interleave_families(net::lookup_host(target).await?.collect()).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if attempts.is_empty() {
+            match addrs.next() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => {
+                    return Err(last_err.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, format!("{target} did not resolve"))
+                    }))
+                }
+            }
+        }
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            },
+            _ = time::sleep(ATTEMPT_DELAY), if !addrs.as_slice().is_empty() => {
+                attempts.extend(addrs.next().map(attempt));
+            }
+        }
+    }
+}
+
+async fn attempt(addr: SocketAddr) -> io::Result<TcpStream> {
+    match time::timeout(ATTEMPT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("connect to {addr} timed out"))),
+    }
+}
+
+/// Orders addresses IPv6, IPv4, IPv6, ... keeping resolver order per family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut out = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return out,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn applies_the_options_it_describes() {
+        let opts = SocketOptions { nodelay: true, keepalive: Some(Duration::from_secs(30)), ..SocketOptions::default() };
+        assert!(opts.describe().starts_with("nodelay=on keepalive=30s "));
+        let listener = bind(([127, 0, 0, 1], 0).into(), &opts).unwrap();
+        assert!(SockRef::from(&listener).reuse_address().unwrap() || cfg!(windows));
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        tune(&stream, &opts);
+        let sock = SockRef::from(&stream);
+        assert!(sock.nodelay().unwrap() && sock.keepalive().unwrap());
+        assert!(!SockRef::from(&client).nodelay().unwrap());
+    }
+    #[tokio::test]
+    async fn dials_whichever_family_answers() {
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:1", "[::1]:1", "10.0.0.3:1", "[::2]:1"].map(|a| a.parse().unwrap()).into();
+        let order: Vec<String> = interleave_families(addrs).iter().map(SocketAddr::to_string).collect();
+        assert_eq!(order, ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "10.0.0.3:1"]);
+
+        // Only IPv4 listens, whatever localhost resolves to first
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stream = connect(&format!("localhost:{port}")).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+    #[tokio::test]
+    async fn listens_with_the_listener_options() {
+        let opts = SocketOptions { reuse_address: false, backlog: 16, only_v6: Some(true), ..SocketOptions::default() };
+        assert!(opts.describe().ends_with(" reuseaddr=off backlog=16 v6only=on"));
+        // v6only is left out for an IPv4 address rather than failing it
+        let listener = bind(([127, 0, 0, 1], 0).into(), &opts).unwrap();
+        assert!(!SockRef::from(&listener).reuse_address().unwrap());
+        TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        listener.accept().await.unwrap();
+    }
+}
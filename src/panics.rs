@@ -0,0 +1,118 @@
+//! Containing panics to the connection they happen on.
+//!
+//! A bug in a codec, a transport or a hook shouldn't take the whole server
+//! down. Reading a client's input, running a hook for it and writing to it
+//! all catch unwinding panics; the server logs what happened, counts it,
+//! and drops only that client. State touched by the panicking code belongs
+//! to that client alone, which is what makes carrying on safe.
+
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+
+/// The read error a client's input ends with after panicking.
+#[derive(Debug)]
+pub struct Panicked(String);
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "panicked: {}", self.0)
+    }
+}
+
+impl Error for Panicked {}
+
+impl Panicked {
+    pub fn new(payload: &(dyn Any + Send)) -> Self {
+        Self(message(payload).to_string())
+    }
+
+    /// Whether a read error was a panic.
+    pub fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<Panicked>())
+    }
+}
+
+/// The message a panic was raised with, if it was a string.
+pub fn message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "(non-string payload)"
+    }
+}
+
+/// A stream of reads that turns a panic while polling into a final
+/// [`Panicked`] error, and ends there.
+pub struct CatchUnwind<S> {
+    inner: S,
+    panicked: bool,
+}
+
+impl<S> CatchUnwind<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, panicked: false }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S, T> Stream for CatchUnwind<S>
+where
+    S: Stream<Item = io::Result<T>> + Unpin,
+{
+    type Item = io::Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.panicked {
+            return Poll::Ready(None);
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| Pin::new(&mut self.inner).poll_next(cx))) {
+            Ok(poll) => poll,
+            Err(payload) => {
+                self.panicked = true;
+                Poll::Ready(Some(Err(io::Error::other(Panicked::new(&*payload)))))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, StreamExt};
+
+    #[tokio::test]
+    async fn panic_becomes_a_final_error() {
+        let mut n = 0;
+        let reads = stream::poll_fn(move |_| {
+            n += 1;
+            if n == 2 {
+                panic!("decoder bug");
+            }
+            Poll::Ready(Some(Ok::<_, io::Error>(n)))
+        });
+        let mut reads = CatchUnwind::new(Box::pin(reads));
+        assert_eq!(reads.next().await.unwrap().unwrap(), 1);
+        let e = reads.next().await.unwrap().unwrap_err();
+        assert!(Panicked::is(&e));
+        assert_eq!(e.to_string(), "panicked: decoder bug");
+        assert!(reads.next().await.is_none());
+    }
+
+    #[test]
+    fn message_of_formatted_panic() {
+        let payload = panic::catch_unwind(|| panic!("client {}", 7)).unwrap_err();
+        assert_eq!(message(&*payload), "client 7");
+    }
+}
@@ -0,0 +1,691 @@
+//! Links between servers, so a broadcast on one reaches clients on all.
+//!
+//! Servers link over a protocol of their own, on a port of its own: each
+//! end of a new connection sends `PEER:<server-id> <version>` and reads
+//! the other's, then messages travel as
+//! `RELAY:<origin> <seq> <hops> <room|-> <content-type|-> <name> <text>`,
+//! with a `PING` every few seconds so a link that dies quietly is noticed.
+//! A server dials the `--peer` addresses it was given and keeps dialing,
+//! with backoff, whenever a link is lost; links it accepts are the other
+//! end's to restore.
+//!
+//! Messages are forwarded across links, so servers needn't all be linked
+//! to each other. Every message carries the id of the server it was sent
+//! to and a number unique on that server; a server drops one it has seen
+//! already (it came round another way), one from itself, and stops
+//! forwarding once it has crossed `max_hops` links. Only messages are
+//! shared: clients, nicknames, rooms, presence and everything else stay on
+//! the server they belong to, and a remote sender is shown as
+//! `<name>@<server-id>`.
+//!
+//! Every few seconds each server also tells the others how many clients it
+//! has, as `LOAD:<origin> <seq> <hops> <clients> <host:port|->`, forwarded
+//! the same way and kept by the newest number per server. A front door
+//! uses them to send new clients to the least loaded server at the address
+//! it advertises.
+
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "cluster")]
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+#[cfg(feature = "cluster")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "cluster")]
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "cluster")]
+use futures::SinkExt;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(feature = "cluster")]
+use tokio::sync::mpsc;
+#[cfg(feature = "cluster")]
+use tokio::task::JoinSet;
+#[cfg(feature = "cluster")]
+use tokio::time::{self, Instant};
+#[cfg(feature = "cluster")]
+use tokio_stream::StreamExt;
+#[cfg(feature = "cluster")]
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+#[cfg(feature = "cluster")]
+use tracing::{info, warn};
+
+use crate::protocol;
+
+/// Version of the peer protocol; both ends of a link must speak the same.
+#[cfg(feature = "cluster")]
+const PEER_VERSION: u32 = 1;
+/// How long the other end has to introduce itself.
+#[cfg(feature = "cluster")]
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a link sends `PING`...
+#[cfg(feature = "cluster")]
+const HEARTBEAT: Duration = Duration::from_secs(5);
+/// ...and how long it may hear nothing before it's given up on.
+#[cfg(feature = "cluster")]
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(20);
+/// First wait before redialing a peer; it doubles on every failure in a
+/// row, up to `MAX_BACKOFF`.
+#[cfg(feature = "cluster")]
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+#[cfg(feature = "cluster")]
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Relays that may wait for a slow link before more are dropped.
+#[cfg(feature = "cluster")]
+const LINK_QUEUE: usize = 4096;
+/// Messages remembered per server, to drop copies that arrive by another
+/// route.
+#[cfg(feature = "cluster")]
+const SEEN_LIMIT: usize = 4096;
+/// What a relay line adds to the message, at most.
+#[cfg(feature = "cluster")]
+const RELAY_OVERHEAD: usize = 1024;
+/// How often a server reports its load...
+pub(crate) const LOAD_INTERVAL: Duration = Duration::from_secs(5);
+/// ...and how long a report counts for, so a server that's gone stops
+/// getting clients.
+#[cfg(feature = "cluster")]
+const LOAD_TTL: Duration = Duration::from_secs(15);
+
+/// Links to other servers.
+#[derive(Clone, Debug)]
+pub struct PeerConfig {
+    /// This server's name among its peers and on the Redis bridge; a
+    /// random one when `None`. It follows the rules for nicknames.
+    pub server_id: Option<String>,
+    /// This server's number in message IDs (`Config::stamp_messages`), up
+    /// to 1023, different on every server so their IDs are too; derived
+    /// from `server_id` when `None`.
+    pub node_id: Option<u16>,
+    /// Accept links from other servers on this port, same address.
+    pub port: Option<u16>,
+    /// Peer ports of other servers to link to, as `HOST:PORT`.
+    pub peers: Vec<String>,
+    /// Most links a message crosses on its way from the server it was sent
+    /// to.
+    pub max_hops: u8,
+    /// Where clients can reach this server, as `HOST:PORT`, for front doors
+    /// to send them to; `None` keeps it out of their choice.
+    pub advertise: Option<String>,
+    /// Be a front door: answer every new client with `REDIRECT:` to the
+    /// least loaded server that advertises an address, and close.
+    pub front_door: bool,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self { server_id: None, node_id: None, port: None, peers: Vec::new(), max_hops: 8, advertise: None, front_door: false }
+    }
+}
+
+impl PeerConfig {
+    /// Whether this server links to any other, or lets others link to it.
+    pub fn enabled(&self) -> bool {
+        self.port.is_some() || !self.peers.is_empty()
+    }
+}
+
+/// A message on its way between servers.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Relay {
+    /// The server it was sent to.
+    pub origin: String,
+    pub seq: u64,
+    /// Links crossed so far.
+    pub hops: u8,
+    pub room: Option<String>,
+    pub content_type: Option<String>,
+    /// The sender's name on its own server.
+    pub name: String,
+    pub text: String,
+}
+
+impl Relay {
+    /// The sender as clients here see it.
+    pub fn sender(&self) -> String {
+        format!("{}@{}", self.name, self.origin)
+    }
+
+    #[cfg(feature = "cluster")]
+    fn line(&self) -> String {
+        format!(
+            "RELAY:{} {} {} {} {} {} {}",
+            self.origin,
+            self.seq,
+            self.hops,
+            self.room.as_deref().unwrap_or("-"),
+            self.content_type.as_deref().unwrap_or("-"),
+            self.name,
+            self.text
+        )
+    }
+
+    #[cfg(feature = "cluster")]
+    fn parse(line: &str) -> Option<Relay> {
+        let mut parts = line.strip_prefix("RELAY:")?.splitn(7, ' ');
+        let origin = parts.next().filter(|origin| protocol::valid_nick(origin))?;
+        let seq = parts.next()?.parse().ok()?;
+        let hops = parts.next()?.parse().ok()?;
+        let room = match parts.next()? {
+            "-" => None,
+            room => Some(room).filter(|room| protocol::valid_room(room))?.into(),
+        };
+        let content_type = match parts.next()? {
+            "-" => None,
+            content_type => Some(content_type).filter(|ct| protocol::valid_content_type(ct))?.into(),
+        };
+        let name = parts.next().filter(|name| !name.is_empty())?;
+        let text = parts.next().unwrap_or_default();
+        Some(Relay {
+            origin: origin.to_string(),
+            seq,
+            hops,
+            room: room.map(str::to_string),
+            content_type: content_type.map(str::to_string),
+            name: name.to_string(),
+            text: text.to_string(),
+        })
+    }
+}
+
+/// How many clients a server has, and where new ones can reach it.
+#[cfg(feature = "cluster")]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Load {
+    pub origin: String,
+    pub seq: u64,
+    pub hops: u8,
+    pub clients: usize,
+    pub addr: Option<String>,
+}
+
+#[cfg(feature = "cluster")]
+impl Load {
+    fn line(&self) -> String {
+        format!("LOAD:{} {} {} {} {}", self.origin, self.seq, self.hops, self.clients, self.addr.as_deref().unwrap_or("-"))
+    }
+
+    fn parse(line: &str) -> Option<Load> {
+        let mut parts = line.strip_prefix("LOAD:")?.split(' ');
+        let origin = parts.next().filter(|origin| protocol::valid_nick(origin))?;
+        let load = Load {
+            origin: origin.to_string(),
+            seq: parts.next()?.parse().ok()?,
+            hops: parts.next()?.parse().ok()?,
+            clients: parts.next()?.parse().ok()?,
+            addr: match parts.next()? {
+                "-" => None,
+                addr => Some(addr.to_string()),
+            },
+        };
+        parts.next().is_none().then_some(load)
+    }
+}
+
+/// What the link tasks tell the server.
+#[cfg(feature = "cluster")]
+pub(crate) enum Event {
+    Up { link: u64, server: String, addr: SocketAddr, tx: mpsc::Sender<Arc<str>> },
+    Relay { link: u64, relay: Relay },
+    Load { link: u64, load: Load },
+    Down { link: u64, why: String },
+}
+
+/// The last load a server reported.
+#[cfg(feature = "cluster")]
+struct Reported {
+    seq: u64,
+    clients: usize,
+    addr: Option<String>,
+    at: Instant,
+}
+
+/// A link that's up.
+#[cfg(feature = "cluster")]
+struct Link {
+    server: String,
+    addr: SocketAddr,
+    tx: mpsc::Sender<Arc<str>>,
+    /// Relays dropped since the link last kept up.
+    dropped: u64,
+}
+
+/// What the link tasks share.
+#[cfg(feature = "cluster")]
+struct Shared {
+    id: String,
+    events: mpsc::UnboundedSender<Event>,
+    next_link: AtomicU64,
+    max_line: usize,
+}
+
+/// The server's side of its links: which are up, what's been seen, and
+/// the tasks dialing and running them, which stop when it's dropped.
+#[cfg(feature = "cluster")]
+pub(crate) struct Cluster {
+    shared: Arc<Shared>,
+    events: mpsc::UnboundedReceiver<Event>,
+    max_hops: u8,
+    /// Number for the next message sent here.
+    next_seq: u64,
+    links: HashMap<u64, Link>,
+    seen: Seen,
+    /// Every server's last load report, by id
+    loads: HashMap<String, Reported>,
+    advertise: Option<String>,
+    tasks: JoinSet<()>,
+}
+
+#[cfg(feature = "cluster")]
+impl Cluster {
+    /// Starts dialing `config.peers` as the server called `id`. Lines on
+    /// links may be up to `max_line` plus what a relay adds.
+    pub fn start(config: &PeerConfig, id: String, max_line: usize) -> Cluster {
+        let (tx, events) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared { id, events: tx, next_link: AtomicU64::new(1), max_line: max_line + RELAY_OVERHEAD });
+        let mut tasks = JoinSet::new();
+        for addr in &config.peers {
+            tasks.spawn(dial(shared.clone(), addr.clone()));
+        }
+        // Numbers carry on from a restart under the same id, so peers
+        // that remember the old ones don't take new messages for copies
+        let next_seq = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |d| d.as_millis() as u64);
+        Cluster {
+            shared,
+            events,
+            max_hops: config.max_hops.max(1),
+            next_seq,
+            links: HashMap::new(),
+            seen: Seen::default(),
+            loads: HashMap::new(),
+            advertise: config.advertise.clone(),
+            tasks,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.shared.id
+    }
+
+    /// Runs a link another server opened.
+    pub fn accept(&mut self, stream: TcpStream, addr: SocketAddr) {
+        while self.tasks.try_join_next().is_some() {}
+        let shared = self.shared.clone();
+        self.tasks.spawn(async move {
+            match link(&shared, stream, addr).await {
+                Ok(()) => {}
+                Err(Refused::Itself) => warn!("peer link from {addr} refused: it's this server"),
+                Err(Refused::Other(why)) => warn!("peer link from {addr} refused: {why}"),
+            }
+        });
+    }
+
+    /// Sends a message just published here to every linked server.
+    pub fn originate(&mut self, room: Option<&str>, content_type: Option<&str>, name: &str, text: &str) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let relay = Relay {
+            origin: self.shared.id.clone(),
+            seq,
+            hops: 1,
+            room: room.map(str::to_string),
+            content_type: content_type.map(str::to_string),
+            name: name.to_string(),
+            text: text.to_string(),
+        };
+        self.forward(&relay, None);
+    }
+
+    /// Tells every linked server how many clients this one has.
+    pub fn report(&mut self, clients: usize) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let load = Load { origin: self.shared.id.clone(), seq, hops: 1, clients, addr: self.advertise.clone() };
+        self.send(load.line().into(), &load.origin, None);
+    }
+
+    /// Where to send a new client: the advertised address of the server
+    /// with the fewest clients lately, this one aside. It's counted as one
+    /// more there, so clients spread out until its next report.
+    pub fn least_loaded(&mut self) -> Option<String> {
+        let now = Instant::now();
+        self.loads.retain(|_, load| now.duration_since(load.at) < LOAD_TTL);
+        let load = self.loads.values_mut().filter(|load| load.addr.is_some()).min_by_key(|load| (load.clients, load.addr.clone()))?;
+        load.clients += 1;
+        load.addr.clone()
+    }
+
+    /// Keeps track of links coming and going, and forwards messages from
+    /// them. Returns a message to publish here, if the event brought a new
+    /// one.
+    pub fn handle(&mut self, event: Event) -> Option<Relay> {
+        match event {
+            Event::Up { link, server, addr, tx } => {
+                info!("peer link up {server} {addr} links={}", self.links.len() + 1);
+                self.links.insert(link, Link { server, addr, tx, dropped: 0 });
+                None
+            }
+            Event::Down { link, why } => {
+                let gone = self.links.remove(&link)?;
+                info!("peer link down {} {} dropped={} links={}: {why}", gone.server, gone.addr, gone.dropped, self.links.len());
+                None
+            }
+            Event::Relay { link, relay } => {
+                if relay.origin == self.shared.id || !self.seen.insert(&relay.origin, relay.seq) {
+                    return None;
+                }
+                if relay.hops < self.max_hops {
+                    self.forward(&Relay { hops: relay.hops + 1, ..relay.clone() }, Some(link));
+                }
+                Some(relay)
+            }
+            Event::Load { link, load } => {
+                let newer = self.loads.get(&load.origin).is_none_or(|known| load.seq > known.seq);
+                if load.origin == self.shared.id || !newer {
+                    return None;
+                }
+                if load.hops < self.max_hops {
+                    let line = Load { hops: load.hops + 1, ..load.clone() }.line();
+                    self.send(line.into(), &load.origin, Some(link));
+                }
+                let Load { origin, seq, clients, addr, .. } = load;
+                self.loads.insert(origin, Reported { seq, clients, addr, at: Instant::now() });
+                None
+            }
+        }
+    }
+
+    /// Sends `relay` over every link but the one it came from and those to
+    /// the server it started on.
+    fn forward(&mut self, relay: &Relay, from: Option<u64>) {
+        self.send(relay.line().into(), &relay.origin, from);
+    }
+
+    /// Sends a line over every link but `from` and those to `origin`.
+    fn send(&mut self, line: Arc<str>, origin: &str, from: Option<u64>) {
+        for (&id, link) in &mut self.links {
+            if Some(id) == from || link.server == origin {
+                continue;
+            }
+            match link.tx.try_send(line.clone()) {
+                Ok(()) => link.dropped = 0,
+                Err(_) => {
+                    if link.dropped == 0 {
+                        warn!("peer link {} {} falling behind, dropping messages", link.server, link.addr);
+                    }
+                    link.dropped += 1;
+                }
+            }
+        }
+    }
+}
+
+/// The next connection on the peer port, if there is one; never completes
+/// otherwise.
+pub(crate) async fn accept(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The next event from the links, if there are any; never completes
+/// otherwise.
+#[cfg(feature = "cluster")]
+pub(crate) async fn next(cluster: Option<&mut Cluster>) -> Option<Event> {
+    match cluster {
+        Some(cluster) => cluster.events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Messages seen lately, by the server they started on and their number.
+#[cfg(feature = "cluster")]
+#[derive(Default)]
+struct Seen {
+    keys: HashSet<(String, u64)>,
+    order: VecDeque<(String, u64)>,
+}
+
+#[cfg(feature = "cluster")]
+impl Seen {
+    /// Remembers a message; false if it already was.
+    fn insert(&mut self, origin: &str, seq: u64) -> bool {
+        let key = (origin.to_string(), seq);
+        if !self.keys.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > SEEN_LIMIT {
+            if let Some(old) = self.order.pop_front() {
+                self.keys.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+/// Why a connection never became a link.
+#[cfg(feature = "cluster")]
+enum Refused {
+    /// It looped back to this server.
+    Itself,
+    Other(String),
+}
+
+/// Dials `addr` until the server stops, relinking whenever the link goes.
+#[cfg(feature = "cluster")]
+async fn dial(shared: Arc<Shared>, addr: String) {
+    let mut backoff = FIRST_BACKOFF;
+    loop {
+        let connected = match TcpStream::connect(&addr).await {
+            Ok(stream) => {
+                let peer = stream.peer_addr().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+                link(&shared, stream, peer).await
+            }
+            Err(e) => Err(Refused::Other(e.to_string())),
+        };
+        match connected {
+            // It was up, so it's worth trying again soon
+            Ok(()) => backoff = FIRST_BACKOFF,
+            Err(Refused::Itself) => {
+                warn!("peer {addr} is this server, not linking to it");
+                return;
+            }
+            Err(Refused::Other(why)) => warn!("peer {addr} unavailable, retrying in {}ms: {why}", backoff.as_millis()),
+        }
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Introduces this server over `stream` and, if the other end turns out to
+/// be a peer, relays until the link goes.
+#[cfg(feature = "cluster")]
+async fn link(shared: &Shared, stream: TcpStream, addr: SocketAddr) -> Result<(), Refused> {
+    let mut conn = Framed::new(stream, LinesCodec::new_with_max_length(shared.max_line));
+    let hello = format!("PEER:{} {PEER_VERSION}", shared.id);
+    conn.send(hello).await.map_err(|e| Refused::Other(e.to_string()))?;
+    let hello = match time::timeout(HELLO_TIMEOUT, conn.next()).await {
+        Ok(Some(Ok(line))) => line,
+        Ok(Some(Err(e))) => return Err(Refused::Other(e.to_string())),
+        Ok(None) => return Err(Refused::Other("closed".to_string())),
+        Err(_) => return Err(Refused::Other("no PEER introduction".to_string())),
+    };
+    let Some((server, version)) = hello.strip_prefix("PEER:").and_then(|rest| rest.split_once(' ')) else {
+        return Err(Refused::Other(format!("not a peer, it sent {hello:?}")));
+    };
+    if version != PEER_VERSION.to_string() {
+        return Err(Refused::Other(format!("peer protocol version {version}, expected {PEER_VERSION}")));
+    }
+    if !protocol::valid_nick(server) {
+        return Err(Refused::Other(format!("invalid server id {server:?}")));
+    }
+    if server == shared.id {
+        return Err(Refused::Itself);
+    }
+
+    let link = shared.next_link.fetch_add(1, Ordering::Relaxed);
+    let (tx, mut rx) = mpsc::channel(LINK_QUEUE);
+    let _ = shared.events.send(Event::Up { link, server: server.to_string(), addr, tx });
+    let mut heartbeat = time::interval(HEARTBEAT);
+    let mut heard = Instant::now();
+    let why = loop {
+        tokio::select! {
+            line = rx.recv() => {
+                // The server has stopped when there's no sender
+                let Some(line) = line else { break "server stopping".to_string() };
+                if let Err(e) = conn.send(line).await {
+                    break e.to_string();
+                }
+            }
+            _ = heartbeat.tick() => {
+                if let Err(e) = conn.send("PING").await {
+                    break e.to_string();
+                }
+            }
+            _ = time::sleep_until(heard + SILENCE_TIMEOUT) => break "silent too long".to_string(),
+            line = conn.next() => {
+                heard = Instant::now();
+                match line {
+                    Some(Ok(line)) if line == "PING" => {}
+                    Some(Ok(line)) => {
+                        let event = match Relay::parse(&line) {
+                            Some(relay) => Some(Event::Relay { link, relay }),
+                            None => Load::parse(&line).map(|load| Event::Load { link, load }),
+                        };
+                        match event {
+                            Some(event) => {
+                                let _ = shared.events.send(event);
+                            }
+                            None => warn!("peer {server} {addr} sent an unreadable line"),
+                        }
+                    }
+                    Some(Err(LinesCodecError::MaxLineLengthExceeded)) => warn!("peer {server} {addr} sent a line too long, dropped"),
+                    Some(Err(e)) => break e.to_string(),
+                    None => break "closed".to_string(),
+                }
+            }
+        }
+    };
+    let _ = shared.events.send(Event::Down { link, why });
+    Ok(())
+}
+
+/// The server's id: the one configured, checked, or a random one.
+pub(crate) fn server_id(config: &PeerConfig) -> io::Result<String> {
+    match &config.server_id {
+        Some(id) if protocol::valid_nick(id) => Ok(id.clone()),
+        Some(id) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid server id {id:?}"))),
+        None => Ok(random_id()),
+    }
+}
+
+/// A name for a server that wasn't given one; not all digits, so it's a
+/// valid nickname.
+fn random_id() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+    hasher.write_u32(std::process::id());
+    format!("srv-{:08x}", hasher.finish() as u32)
+}
+
+/// Built without clustering, so no links can exist.
+#[cfg(not(feature = "cluster"))]
+pub(crate) enum Cluster {}
+
+#[cfg(not(feature = "cluster"))]
+pub(crate) enum Event {}
+
+#[cfg(not(feature = "cluster"))]
+impl Cluster {
+    /// Never called: `info::check` refuses peers in a build like this.
+    pub fn start(_config: &PeerConfig, _id: String, _max_line: usize) -> Cluster {
+        unreachable!("peers configured without the cluster feature")
+    }
+
+    pub fn id(&self) -> &str {
+        match *self {}
+    }
+
+    pub fn accept(&mut self, _stream: TcpStream, _addr: SocketAddr) {
+        match *self {}
+    }
+
+    pub fn originate(&mut self, _room: Option<&str>, _content_type: Option<&str>, _name: &str, _text: &str) {
+        match *self {}
+    }
+
+    pub fn report(&mut self, _clients: usize) {
+        match *self {}
+    }
+
+    pub fn least_loaded(&mut self) -> Option<String> {
+        match *self {}
+    }
+
+    pub fn handle(&mut self, event: Event) -> Option<Relay> {
+        match event {}
+    }
+}
+
+#[cfg(not(feature = "cluster"))]
+pub(crate) async fn next(cluster: Option<&mut Cluster>) -> Option<Event> {
+    match cluster {
+        Some(cluster) => match *cluster {},
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(all(test, feature = "cluster"))]
+mod tests {
+    use super::*;
+
+    fn relay(origin: &str, seq: u64, hops: u8) -> Relay {
+        let line = format!("RELAY:{origin} {seq} {hops} dev - alice hello there");
+        Relay::parse(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn drops_copies_and_stops_at_max_hops() {
+        let config = PeerConfig { max_hops: 2, ..PeerConfig::default() };
+        let mut cluster = Cluster::start(&config, "b".into(), 1024);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut links = Vec::new();
+        for (link, server) in [(1, "a"), (2, "c")] {
+            let (tx, rx) = mpsc::channel(8);
+            cluster.handle(Event::Up { link, server: server.into(), addr, tx });
+            links.push(rx);
+        }
+
+        let first = relay("a", 7, 1);
+        assert_eq!(first.sender(), "alice@a");
+        assert_eq!(first.line(), "RELAY:a 7 1 dev - alice hello there");
+        assert_eq!(cluster.handle(Event::Relay { link: 1, relay: first.clone() }), Some(first));
+        assert_eq!(&*links[1].try_recv().unwrap(), "RELAY:a 7 2 dev - alice hello there");
+        assert!(links[0].try_recv().is_err());
+
+        // The same message by another route, and one that's gone far enough
+        assert_eq!(cluster.handle(Event::Relay { link: 2, relay: relay("a", 7, 2) }), None);
+        assert!(cluster.handle(Event::Relay { link: 1, relay: relay("d", 1, 2) }).is_some());
+        assert!(links[1].try_recv().is_err());
+        assert_eq!(cluster.handle(Event::Relay { link: 1, relay: relay("b", 1, 1) }), None);
+        assert!(Relay::parse("RELAY:a 1 1 - - alice").is_some());
+        assert!(Relay::parse("RELAY:a x 1 - - alice hi").is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_clients_to_the_least_loaded() {
+        let mut cluster = Cluster::start(&PeerConfig::default(), "door".into(), 1024);
+        assert_eq!(cluster.least_loaded(), None);
+        for (origin, seq, clients, addr) in [("a", 2, 3, "a:8888"), ("b", 1, 4, "b:8888"), ("c", 1, 0, "-"), ("a", 1, 0, "a:8888")] {
+            let load = Load::parse(&format!("LOAD:{origin} {seq} 1 {clients} {addr}")).unwrap();
+            cluster.handle(Event::Load { link: 1, load });
+        }
+        // a's stale report doesn't count, c has no address, and a fills up
+        let picks: Vec<_> = (0..3).filter_map(|_| cluster.least_loaded()).collect();
+        assert_eq!(picks, ["a:8888", "a:8888", "b:8888"]);
+    }
+}
@@ -0,0 +1,72 @@
+//! Presence derived from inactivity.
+//!
+//! A client that has sent nothing for a while is marked idle, and later
+//! away, without having to say so; the next line it sends makes it active
+//! again. Transitions are announced to the client's room as
+//! `PRESENCE:<id> <state>`. Answering the server's `PING` doesn't count as
+//! activity, since client libraries do that on their own.
+
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Default)]
+pub struct PresenceConfig {
+    /// Quiet time after which a client is idle; `None` skips the state.
+    pub idle_after: Option<Duration>,
+    /// Quiet time after which a client is away; `None` skips the state.
+    pub away_after: Option<Duration>,
+}
+
+impl PresenceConfig {
+    pub fn enabled(&self) -> bool {
+        self.idle_after.is_some() || self.away_after.is_some()
+    }
+
+    /// The state of a client that has been quiet for `quiet`.
+    pub fn state(&self, quiet: Duration) -> Presence {
+        if self.away_after.is_some_and(|after| quiet >= after) {
+            Presence::Away
+        } else if self.idle_after.is_some_and(|after| quiet >= after) {
+            Presence::Idle
+        } else {
+            Presence::Active
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Presence {
+    Active,
+    Idle,
+    Away,
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Presence::Active => "active",
+            Presence::Idle => "idle",
+            Presence::Away => "away",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_then_away() {
+        let config = PresenceConfig { idle_after: Some(Duration::from_secs(60)), away_after: Some(Duration::from_secs(300)) };
+        assert_eq!(config.state(Duration::from_secs(59)), Presence::Active);
+        assert_eq!(config.state(Duration::from_secs(60)), Presence::Idle);
+        assert_eq!(config.state(Duration::from_secs(300)), Presence::Away);
+    }
+
+    #[test]
+    fn away_only() {
+        let config = PresenceConfig { idle_after: None, away_after: Some(Duration::from_secs(300)) };
+        assert_eq!(config.state(Duration::from_secs(299)), Presence::Active);
+        assert_eq!(config.state(Duration::from_secs(300)), Presence::Away);
+    }
+}
@@ -0,0 +1,178 @@
+//! Metrics over HTTP, for Prometheus to scrape.
+//!
+//! `GET /metrics` on the metrics port answers with the server's counters
+//! and gauges in the Prometheus text format, and `GET /info` with what's
+//! running (see `info`), as JSON. The text is taken when the
+//! connection is accepted, on the server's own thread, so it needs no
+//! locking; the request is read and answered in a task of its own. Each
+//! connection gets one response and is closed. Without the `http` feature
+//! there's no metrics port.
+
+use std::fmt::Write as _;
+use std::io;
+#[cfg(feature = "http")]
+use std::time::Duration;
+
+#[cfg(feature = "http")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(feature = "http")]
+use tokio::time;
+
+/// A scraper gets this long to send its request.
+#[cfg(feature = "http")]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest request head read; the request line is all that's looked at.
+#[cfg(feature = "http")]
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// Values at one moment. Totals count since startup.
+#[derive(Default)]
+pub struct Snapshot {
+    pub clients: u64,
+    pub rooms: u64,
+    pub handshaking: u64,
+    pub connections: u64,
+    pub disconnects: u64,
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub broadcasts: u64,
+    pub bytes_out: u64,
+    pub write_errors: u64,
+    pub slow_consumers: u64,
+    pub dropped_lines: u64,
+    pub panics: u64,
+    /// 1 while shedding load, else 0.
+    pub shedding: u64,
+    pub shed_connections: u64,
+    pub shed_lines: u64,
+    /// With latency regions configured.
+    pub latency: Option<Latency>,
+}
+
+/// Round-trip times measured as clients logged in, and where that put them.
+pub struct Latency {
+    /// Each region's bound, in seconds, with how many RTTs were within it.
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: f64,
+    pub count: u64,
+    /// Connected clients in each region, `-` for none.
+    pub regions: Vec<(String, u64)>,
+}
+
+impl Snapshot {
+    /// The text format: a `HELP` and `TYPE` line, then the sample, for each.
+    pub fn render(&self) -> String {
+        let metrics = [
+            ("clients", "gauge", "Connected clients.", self.clients),
+            ("rooms", "gauge", "Rooms with at least one member.", self.rooms),
+            ("handshaking", "gauge", "Connections still in a TLS or WebSocket handshake.", self.handshaking),
+            ("connections_total", "counter", "Clients connected.", self.connections),
+            ("disconnects_total", "counter", "Clients gone, for whatever reason.", self.disconnects),
+            ("messages_received_total", "counter", "Messages received from clients.", self.messages_in),
+            ("received_bytes_total", "counter", "Bytes of messages received from clients.", self.bytes_in),
+            ("broadcasts_total", "counter", "Lines broadcast.", self.broadcasts),
+            ("sent_bytes_total", "counter", "Bytes written to clients.", self.bytes_out),
+            ("write_errors_total", "counter", "Clients dropped after a failed write.", self.write_errors),
+            ("slow_consumers_total", "counter", "Clients dropped for falling behind.", self.slow_consumers),
+            ("dropped_lines_total", "counter", "Lines slow clients missed under a dropping policy.", self.dropped_lines),
+            ("panics_total", "counter", "Panics caught in a client's reader, writer or hooks.", self.panics),
+            ("shedding", "gauge", "1 while the server is shedding load.", self.shedding),
+            ("shed_connections_total", "counter", "Connections turned away while shedding load.", self.shed_connections),
+            ("shed_lines_total", "counter", "Events and presence notices not sent while shedding load.", self.shed_lines),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP tcp_broadcast_{name} {help}");
+            let _ = writeln!(out, "# TYPE tcp_broadcast_{name} {kind}");
+            let _ = writeln!(out, "tcp_broadcast_{name} {value}");
+        }
+        if let Some(latency) = &self.latency {
+            let _ = writeln!(out, "# HELP tcp_broadcast_rtt_seconds Round-trip times to clients, measured as they logged in.");
+            let _ = writeln!(out, "# TYPE tcp_broadcast_rtt_seconds histogram");
+            for (le, n) in &latency.buckets {
+                let _ = writeln!(out, "tcp_broadcast_rtt_seconds_bucket{{le=\"{le}\"}} {n}");
+            }
+            let _ = writeln!(out, "tcp_broadcast_rtt_seconds_bucket{{le=\"+Inf\"}} {}", latency.count);
+            let _ = writeln!(out, "tcp_broadcast_rtt_seconds_sum {}", latency.sum);
+            let _ = writeln!(out, "tcp_broadcast_rtt_seconds_count {}", latency.count);
+            let _ = writeln!(out, "# HELP tcp_broadcast_region_clients Connected clients by latency region.");
+            let _ = writeln!(out, "# TYPE tcp_broadcast_region_clients gauge");
+            for (region, n) in &latency.regions {
+                let _ = writeln!(out, "tcp_broadcast_region_clients{{region=\"{region}\"}} {n}");
+            }
+        }
+        out
+    }
+}
+
+/// The next connection on the metrics port, if there is one; never
+/// completes otherwise.
+pub async fn accept(listener: Option<&TcpListener>) -> io::Result<TcpStream> {
+    match listener {
+        Some(listener) => Ok(listener.accept().await?.0),
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads one request and answers it with `metrics` if it's for
+/// `/metrics`, or `info` if it's for `/info`.
+#[cfg(feature = "http")]
+pub async fn respond(mut stream: TcpStream, metrics: String, info: String) {
+    let Ok(Ok(head)) = time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await else { return };
+    let mut parts = head.split(' ');
+    let response = match (parts.next(), parts.next().map(|target| target.split('?').next())) {
+        (Some("GET"), Some(Some("/metrics"))) => response("200 OK", "text/plain; version=0.0.4", &metrics),
+        (Some("GET"), Some(Some("/info"))) => response("200 OK", "application/json", &info),
+        (Some("GET"), _) => response("404 Not Found", "text/plain", "not found\n"),
+        _ => response("405 Method Not Allowed", "text/plain", "method not allowed\n"),
+    };
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Built without HTTP, there's no metrics port to have been connected to.
+#[cfg(not(feature = "http"))]
+pub async fn respond(_stream: TcpStream, _metrics: String, _info: String) {}
+
+/// The request line, once the whole head has arrived.
+#[cfg(feature = "http")]
+async fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || buf.len() + n > MAX_REQUEST {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let head = String::from_utf8_lossy(&buf);
+    Ok(head.lines().next().unwrap_or_default().to_string())
+}
+
+#[cfg(feature = "http")]
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_format() {
+        let text = Snapshot { clients: 3, broadcasts: 42, ..Snapshot::default() }.render();
+        assert!(text.contains("# TYPE tcp_broadcast_clients gauge\ntcp_broadcast_clients 3\n"));
+        assert!(text.contains("# TYPE tcp_broadcast_broadcasts_total counter\ntcp_broadcast_broadcasts_total 42\n"));
+        assert_eq!(text.lines().count(), 16 * 3);
+
+        let latency = Latency { buckets: vec![(0.005, 2)], sum: 0.4, count: 3, regions: vec![("local".to_string(), 2)] };
+        let text = Snapshot { latency: Some(latency), ..Snapshot::default() }.render();
+        assert!(text.contains("tcp_broadcast_rtt_seconds_bucket{le=\"0.005\"} 2\ntcp_broadcast_rtt_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("tcp_broadcast_region_clients{region=\"local\"} 2\n"));
+    }
+}
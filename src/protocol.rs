@@ -0,0 +1,456 @@
+//! Client commands recognised on the line protocol.
+//!
+//! Any line that isn't a command is a message to broadcast.
+
+use std::borrow::Cow;
+
+pub enum Command<'a> {
+    /// `INGEST`: switch to batched `ACK_RANGE` acknowledgements.
+    Ingest,
+    /// `TYPING`, `STOPPED_TYPING` or `EVENT:<name>`: an ephemeral event.
+    Event(&'a str),
+    /// `EVENTS:ON` / `EVENTS:OFF`: opt in or out of receiving events.
+    Events(bool),
+    /// `JOIN:<room>`: move to a room, leaving the current one.
+    Join(&'a str),
+    /// `PART:<room>`: leave the room, back to the lobby.
+    Part(&'a str),
+    /// `JOIN:`/`PART:` with a name that can't be a room.
+    BadRoom(&'a str),
+    /// `ROOMS`: list rooms with members.
+    Rooms,
+    /// `WHO`: list connected client ids.
+    Who,
+    /// `WHO:regions`: an admin listing clients with their latency regions.
+    WhoRegions,
+    /// `MODE:<key>=<value> ...`: change the current room's modes.
+    Mode(&'a str),
+    /// `NICK:<name>`: go by a name instead of the numeric id.
+    Nick(&'a str),
+    /// `NICK:` with a name that can't be a nickname.
+    BadNick(&'a str),
+    /// `MSG:<id or nick> <text>`: a private message to one client.
+    Msg { to: &'a str, text: &'a str },
+    /// `DIRECT:<id or nick>`: offer (or accept) a direct connection.
+    Direct(&'a str),
+    /// `DIRECT_FAILED:<id or nick>`: the direct connection didn't work out.
+    DirectFailed(&'a str),
+    /// `APPROVE:<id>`: publish a message held in a moderated room.
+    Approve(&'a str),
+    /// `REJECT:<id>`: drop a message held in a moderated room.
+    Reject(&'a str),
+    /// `FETCH:<id>`: the payload behind a `BLOBREF`.
+    Fetch(&'a str),
+    /// `AUTH:<token>` or `AUTH:<user> <password>`: credentials, before
+    /// `LOGIN`.
+    Auth(&'a str),
+    /// `MAINTENANCE:ON`, `MAINTENANCE:READ_ONLY` or `MAINTENANCE:OFF`:
+    /// an admin switching maintenance mode.
+    Maintenance(Maintenance),
+    /// `KICK:<id or nick>`: an admin disconnecting a client.
+    Kick(&'a str),
+    /// `KICK[<selector>]:`: an admin disconnecting every client that
+    /// matches.
+    KickWhere(&'a str),
+    /// `SET:<id or nick> <key>=<value> ...`: an admin overriding a
+    /// client's room and subscriptions.
+    Set { target: &'a str, settings: &'a str },
+    /// `SET[<selector>]:<key>=<value> ...`: the same, for every client that
+    /// matches.
+    SetWhere { selector: &'a str, settings: &'a str },
+    /// `SET:<key>=<value> ...`: the client changing its own settings.
+    SetOwn(&'a str),
+    /// `GET:<key> ...`, or `GET:` for all: the client's settings.
+    Get(&'a str),
+    /// `BROADCAST:<text>`: an admin's notice to every client.
+    Broadcast(&'a str),
+    /// `BROADCAST[<selector>]:<text>`: the same, to every client that
+    /// matches.
+    BroadcastWhere { selector: &'a str, text: &'a str },
+    /// `STATS`: anyone asking how the server is doing.
+    Stats,
+    /// `INFO`: anyone asking what's running: version, build, transports.
+    Info,
+    /// `HISTORY` or `HISTORY:<n>`: the current room's kept messages, or
+    /// the last `n` of them, again.
+    History(&'a str),
+    /// `RESUME:<id>`: a reconnecting client asking for the messages after
+    /// the last one it saw.
+    Resume(&'a str),
+    /// `SHUTDOWN`: an admin stopping the server, gracefully.
+    Shutdown,
+    /// `PURGE:USER <id or nick>`: an admin deleting the stored messages
+    /// from or mentioning someone.
+    PurgeUser(&'a str),
+    /// `PURGE:ROOM <room>`: an admin deleting a room's stored messages.
+    PurgeRoom(&'a str),
+    /// `MESSAGE:<seq> <text>`: a message the client numbered, acked as
+    /// `ACK:<seq>`.
+    Sequenced { seq: u64, text: &'a str },
+    /// `MESSAGE:` without a sequence number.
+    BadSequence(&'a str),
+    /// `RECEIPTS:ON|OFF`: whether the client wants `DELIVERED:<seq>` for
+    /// its numbered messages.
+    Receipts(bool),
+    /// `BARRIER`: answered once everything broadcast before it has been
+    /// flushed to every client.
+    Barrier,
+    /// `PUB[ct=<type>]:<text>`: a message tagged with a content type.
+    Pub { content_type: &'a str, text: &'a str },
+    /// `PUB[ct=...]:` with a type that can't be a content type.
+    BadContentType(&'a str),
+    /// `ACCEPT:<type>,<type>...` or `ACCEPT:*`: the content types of
+    /// messages the client wants.
+    Accept(&'a str),
+    /// `CAPS:compress=<algorithm>`: what the client can handle beyond
+    /// plain lines.
+    Caps(&'a str),
+    /// `PING`: asks the server for a `PONG`.
+    Ping,
+    /// `PONG`: answers the server's `PING`.
+    Pong,
+}
+
+impl<'a> Command<'a> {
+    pub fn parse(line: &'a str) -> Option<Self> {
+        match line {
+            "INGEST" => return Some(Command::Ingest),
+            "TYPING" | "STOPPED_TYPING" => return Some(Command::Event(line)),
+            "EVENTS:ON" => return Some(Command::Events(true)),
+            "EVENTS:OFF" => return Some(Command::Events(false)),
+            "ROOMS" => return Some(Command::Rooms),
+            "WHO" => return Some(Command::Who),
+            "WHO:regions" => return Some(Command::WhoRegions),
+            "PING" => return Some(Command::Ping),
+            "PONG" => return Some(Command::Pong),
+            "MAINTENANCE:ON" => return Some(Command::Maintenance(Maintenance::On)),
+            "MAINTENANCE:READ_ONLY" => return Some(Command::Maintenance(Maintenance::ReadOnly)),
+            "MAINTENANCE:OFF" => return Some(Command::Maintenance(Maintenance::Off)),
+            "STATS" => return Some(Command::Stats),
+            "INFO" => return Some(Command::Info),
+            "HISTORY" => return Some(Command::History("")),
+            "SHUTDOWN" => return Some(Command::Shutdown),
+            "RECEIPTS:ON" => return Some(Command::Receipts(true)),
+            "RECEIPTS:OFF" => return Some(Command::Receipts(false)),
+            "BARRIER" => return Some(Command::Barrier),
+            _ => {}
+        }
+        if let Some(room) = line.strip_prefix("JOIN:") {
+            return Some(if valid_room(room) { Command::Join(room) } else { Command::BadRoom(room) });
+        }
+        if let Some(rest) = line.strip_prefix("MSG:") {
+            let (to, text) = rest.split_once(' ').unwrap_or((rest, ""));
+            return Some(Command::Msg { to, text });
+        }
+        if let Some(nick) = line.strip_prefix("NICK:") {
+            return Some(if valid_nick(nick) { Command::Nick(nick) } else { Command::BadNick(nick) });
+        }
+        if let Some(peer) = line.strip_prefix("DIRECT:") {
+            return Some(Command::Direct(peer));
+        }
+        if let Some(peer) = line.strip_prefix("DIRECT_FAILED:") {
+            return Some(Command::DirectFailed(peer));
+        }
+        if let Some(id) = line.strip_prefix("APPROVE:") {
+            return Some(Command::Approve(id));
+        }
+        if let Some(id) = line.strip_prefix("REJECT:") {
+            return Some(Command::Reject(id));
+        }
+        if let Some(credentials) = line.strip_prefix("AUTH:") {
+            return Some(Command::Auth(credentials));
+        }
+        if let Some((content_type, text)) = line.strip_prefix("PUB[ct=").and_then(|rest| rest.split_once("]:")) {
+            return Some(match valid_content_type(content_type) {
+                true => Command::Pub { content_type, text },
+                false => Command::BadContentType(content_type),
+            });
+        }
+        if let Some(rest) = line.strip_prefix("MESSAGE:") {
+            let (number, text) = rest.split_once(' ').unwrap_or((rest, ""));
+            return Some(match number.parse() {
+                Ok(seq) if number.bytes().all(|b| b.is_ascii_digit()) => Command::Sequenced { seq, text },
+                _ => Command::BadSequence(number),
+            });
+        }
+        if let Some(types) = line.strip_prefix("ACCEPT:") {
+            return Some(Command::Accept(types));
+        }
+        if let Some(caps) = line.strip_prefix("CAPS:") {
+            return Some(Command::Caps(caps));
+        }
+        // Nothing comes after `KICK[...]:`; anything that does is left in
+        // the selector, which then doesn't parse
+        if let Some(rest) = line.strip_prefix("KICK[") {
+            return Some(Command::KickWhere(rest.strip_suffix("]:").unwrap_or(rest)));
+        }
+        if let Some((selector, settings)) = line.strip_prefix("SET[").and_then(|rest| rest.split_once("]:")) {
+            return Some(Command::SetWhere { selector, settings });
+        }
+        if let Some((selector, text)) = line.strip_prefix("BROADCAST[").and_then(|rest| rest.split_once("]:")) {
+            return Some(Command::BroadcastWhere { selector, text });
+        }
+        if let Some(peer) = line.strip_prefix("KICK:") {
+            return Some(Command::Kick(peer));
+        }
+        if let Some(rest) = line.strip_prefix("SET:") {
+            // No nickname has an '=', so settings first are the client's own
+            if rest.split(' ').next().is_some_and(|first| first.contains('=')) {
+                return Some(Command::SetOwn(rest));
+            }
+            let (target, settings) = rest.split_once(' ').unwrap_or((rest, ""));
+            return Some(Command::Set { target, settings });
+        }
+        if let Some(keys) = line.strip_prefix("GET:") {
+            return Some(Command::Get(keys));
+        }
+        if let Some(who) = line.strip_prefix("PURGE:USER ") {
+            return Some(Command::PurgeUser(who));
+        }
+        if let Some(room) = line.strip_prefix("PURGE:ROOM ") {
+            return Some(if valid_room(room) { Command::PurgeRoom(room) } else { Command::BadRoom(room) });
+        }
+        if let Some(text) = line.strip_prefix("BROADCAST:") {
+            return Some(Command::Broadcast(text));
+        }
+        if let Some(limit) = line.strip_prefix("HISTORY:") {
+            return Some(Command::History(limit));
+        }
+        if let Some(last) = line.strip_prefix("RESUME:") {
+            return Some(Command::Resume(last));
+        }
+        if let Some(id) = line.strip_prefix("FETCH:") {
+            return Some(Command::Fetch(id));
+        }
+        if let Some(settings) = line.strip_prefix("MODE:") {
+            return Some(Command::Mode(settings));
+        }
+        if let Some(room) = line.strip_prefix("PART:") {
+            return Some(if valid_room(room) { Command::Part(room) } else { Command::BadRoom(room) });
+        }
+        let name = line.strip_prefix("EVENT:")?;
+        let bad_char = |c: char| c.is_whitespace() || c.is_control();
+        if name.is_empty() || name.len() > MAX_EVENT_NAME || name.contains(bad_char) {
+            return None;
+        }
+        Some(Command::Event(name))
+    }
+}
+
+/// Maintenance mode, which turns new connections away.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Maintenance {
+    Off,
+    /// Clients already connected carry on as usual.
+    On,
+    /// Clients already connected can read, but only admins can send.
+    ReadOnly,
+}
+
+/// Version of the line protocol, reported by `INFO`; bumped when a change
+/// would break existing clients. JSON envelopes mirror the text lines, so
+/// they share it.
+pub const VERSION: u32 = 1;
+
+/// Longest accepted custom event name; longer lines are treated as messages.
+const MAX_EVENT_NAME: usize = 32;
+/// Longest accepted room name.
+const MAX_ROOM_NAME: usize = 32;
+/// Longest accepted nickname.
+const MAX_NICK: usize = 24;
+/// Longest accepted content type.
+const MAX_CONTENT_TYPE: usize = 64;
+/// Longest accepted language tag.
+const MAX_LANG: usize = 35;
+
+/// What an untagged message counts as when filtering by content type.
+pub const UNTAGGED: &str = "text";
+
+/// Room names are short and limited to characters that read unambiguously
+/// in `ROOMS` output.
+pub(crate) fn valid_room(name: &str) -> bool {
+    let ok = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '#');
+    !name.is_empty() && name.len() <= MAX_ROOM_NAME && name.chars().all(ok)
+}
+
+/// Nicknames take the place of ids in broadcast lines, so they can't be
+/// all digits (that would pass for another client's id) or contain spaces.
+pub(crate) fn valid_nick(nick: &str) -> bool {
+    let ok = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    !nick.is_empty()
+        && nick.len() <= MAX_NICK
+        && nick.chars().all(ok)
+        && !nick.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Content types are a name (`json`, `application/cbor`), so they can't
+/// end the tag early or be mistaken for the list separator in `ACCEPT`.
+pub(crate) fn valid_content_type(content_type: &str) -> bool {
+    let ok = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+' | '/');
+    !content_type.is_empty() && content_type.len() <= MAX_CONTENT_TYPE && content_type.chars().all(ok)
+}
+
+/// Language tags (`de`, `pt-BR`) are letters, digits and hyphens.
+fn valid_lang(lang: &str) -> bool {
+    let ok = |c: char| c.is_ascii_alphanumeric() || c == '-';
+    !lang.is_empty() && lang.len() <= MAX_LANG && !lang.starts_with('-') && lang.chars().all(ok)
+}
+
+/// One `key=value` of `SET:`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Setting<'a> {
+    /// `room=<name>`, or `room=-` for the lobby. Admins only.
+    Room(Option<&'a str>),
+    /// `accept=<type>,<type>...`, or `accept=*` for all.
+    Accept(Option<&'a str>),
+    Events(bool),
+    /// Gets its own messages back.
+    Echo(bool),
+    /// Gets `ACK:MESSAGE` (or `ACK:<seq>`) for what it sends.
+    Ack(bool),
+    Receipts(bool),
+    /// `lang=<tag>`, or `lang=-` for none: the language it writes in,
+    /// for message hooks.
+    Lang(Option<&'a str>),
+    /// Can't change its room or subscriptions itself. Admins only.
+    Lock(bool),
+}
+
+/// Every setting's key, in the order `GET:` lists them.
+pub const SETTINGS: &[&str] = &["room", "accept", "events", "echo", "ack", "receipts", "lang", "lock"];
+
+impl<'a> Setting<'a> {
+    pub fn parse(setting: &'a str) -> Option<Self> {
+        let on = |value| match value {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        };
+        Some(match setting.split_once('=')? {
+            ("room", "-") => Setting::Room(None),
+            ("room", name) if valid_room(name) => Setting::Room(Some(name)),
+            ("accept", "*") => Setting::Accept(None),
+            ("accept", types) if types.split(',').all(valid_content_type) => Setting::Accept(Some(types)),
+            ("events", value) => Setting::Events(on(value)?),
+            ("echo", value) => Setting::Echo(on(value)?),
+            ("ack", value) => Setting::Ack(on(value)?),
+            ("receipts", value) => Setting::Receipts(on(value)?),
+            ("lang", "-") => Setting::Lang(None),
+            ("lang", tag) if valid_lang(tag) => Setting::Lang(Some(tag)),
+            ("lock", value) => Setting::Lock(on(value)?),
+            _ => return None,
+        })
+    }
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            Setting::Room(_) => "room",
+            Setting::Accept(_) => "accept",
+            Setting::Events(_) => "events",
+            Setting::Echo(_) => "echo",
+            Setting::Ack(_) => "ack",
+            Setting::Receipts(_) => "receipts",
+            Setting::Lang(_) => "lang",
+            Setting::Lock(_) => "lock",
+        }
+    }
+
+    /// Whether only an admin may set it, for someone else or themselves.
+    pub fn admin_only(&self) -> bool {
+        matches!(self, Setting::Room(_) | Setting::Lock(_))
+    }
+}
+
+/// The settings in a `SET:`, all valid and no key twice; otherwise the
+/// first one that isn't (empty when there are none at all).
+pub fn parse_settings(settings: &str) -> Result<Vec<Setting<'_>>, &str> {
+    let mut parsed: Vec<Setting<'_>> = Vec::new();
+    for setting in settings.split_whitespace() {
+        match Setting::parse(setting) {
+            Some(s) if !parsed.iter().any(|p| p.key() == s.key()) => parsed.push(s),
+            _ => return Err(setting),
+        }
+    }
+    if parsed.is_empty() {
+        return Err("");
+    }
+    Ok(parsed)
+}
+
+/// The content type of a `MESSAGE:` or `BLOBREF:` line (`MESSAGE[ct=json]:`
+/// when tagged, among any other attributes); `None` for any other line.
+pub fn content_type(line: &[u8]) -> Option<&[u8]> {
+    let rest = line.strip_prefix(b"MESSAGE").or_else(|| line.strip_prefix(b"BLOBREF"))?;
+    let Some(tag) = rest.strip_prefix(b"[") else { return rest.starts_with(b":").then_some(UNTAGGED.as_bytes()) };
+    let tag = &tag[..tag.iter().position(|&b| b == b']')?];
+    Some(tag.split(|&b| b == b',').find_map(|attribute| attribute.strip_prefix(b"ct=")).unwrap_or(UNTAGGED.as_bytes()))
+}
+
+/// Strips control characters (except tab) from a client payload.
+///
+/// Framing is newline based, but a bare `\r` or other terminal control in the
+/// middle of a line would let a client render text that looks like a separate
+/// `MESSAGE:<id> ...` frame from someone else.
+pub fn sanitize_payload(line: &str) -> Cow<'_, str> {
+    if line.chars().any(|c| c.is_control() && c != '\t') {
+        Cow::Owned(line.chars().filter(|&c| !c.is_control() || c == '\t').collect())
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_types() {
+        assert!(matches!(
+            Command::parse("PUB[ct=json]:{\"a\":1}"),
+            Some(Command::Pub { content_type: "json", text: "{\"a\":1}" })
+        ));
+        assert!(matches!(Command::parse("PUB[ct=a b]:hi"), Some(Command::BadContentType("a b"))));
+        assert_eq!(content_type(b"MESSAGE[ct=json]:3 {}\n"), Some(&b"json"[..]));
+        assert_eq!(content_type(b"BLOBREF:3 1 9000\n"), Some(&b"text"[..]));
+        assert_eq!(content_type(b"MESSAGE[id=1,ct=json,ts=x]:3 {}\n"), Some(&b"json"[..]));
+        assert_eq!(content_type(b"MESSAGE[id=1,ts=x]:3 hi\n"), Some(&b"text"[..]));
+        assert_eq!(content_type(b"MESSAGES:3\n"), None);
+        assert_eq!(content_type(b"JOINED:3\n"), None);
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        assert!(matches!(Command::parse("MESSAGE:7 hi there"), Some(Command::Sequenced { seq: 7, text: "hi there" })));
+        assert!(matches!(Command::parse("MESSAGE:+7 hi"), Some(Command::BadSequence("+7"))));
+        assert!(matches!(Command::parse("MESSAGE:hi"), Some(Command::BadSequence("hi"))));
+    }
+
+    #[test]
+    fn settings() {
+        assert!(matches!(Command::parse("SET:echo=on ack=off"), Some(Command::SetOwn("echo=on ack=off"))));
+        assert!(matches!(Command::parse("SET:alice echo=on"), Some(Command::Set { target: "alice", settings: "echo=on" })));
+        assert_eq!(parse_settings("ack=off  lang=pt-BR"), Ok(vec![Setting::Ack(false), Setting::Lang(Some("pt-BR"))]));
+        assert_eq!(parse_settings("accept=*"), Ok(vec![Setting::Accept(None)]));
+        assert_eq!(parse_settings("echo=on echo=off"), Err("echo=off"));
+        assert_eq!(parse_settings("lang=-de"), Err("lang=-de"));
+        assert_eq!(parse_settings("ack=maybe"), Err("ack=maybe"));
+        assert_eq!(parse_settings(" "), Err(""));
+    }
+
+    #[test]
+    fn plain_payload_is_untouched() {
+        assert!(matches!(sanitize_payload("hello\tworld"), Cow::Borrowed("hello\tworld")));
+    }
+
+    #[test]
+    fn carriage_return_cannot_forge_a_frame() {
+        let forged = sanitize_payload("hi\rMESSAGE:1 I am someone else");
+        assert_eq!(forged, "hiMESSAGE:1 I am someone else");
+        assert!(!forged.contains('\r'));
+    }
+
+    #[test]
+    fn other_controls_are_stripped() {
+        assert_eq!(sanitize_payload("a\x1b[2Kb\x00c"), "a[2Kbc");
+    }
+}
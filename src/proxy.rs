@@ -0,0 +1,135 @@
+//! The PROXY protocol, so clients behind a load balancer keep their own
+//! addresses.
+//!
+//! HAProxy, AWS NLB and others can start every connection they pass on
+//! with a header naming the client's address, in version 1 (a text line)
+//! or version 2 (binary). With `--proxy-protocol` every connection on a
+//! client listener must start with one, in either version; it's read
+//! before anything else, TLS included, and the address in it stands in
+//! for the socket's everywhere: access lists, abuse heuristics, client
+//! records and logs. A header with no address (`UNKNOWN`, or a version 2
+//! `LOCAL` health check) leaves the socket's address in place.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// The longest version 1 header, `\r\n` included.
+const V1_MAX: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads the header off `stream`, bounded by `timeout`, and returns
+/// the client's address: the one in the header, or `peer` if it has none.
+/// Nothing after the header is read.
+pub async fn read_header(stream: &mut TcpStream, peer: SocketAddr, timeout: Duration) -> io::Result<SocketAddr> {
+    let header = time::timeout(timeout, read(stream)).await.map_err(|_| io::ErrorKind::TimedOut)??;
+    Ok(header.unwrap_or(peer))
+}
+
+async fn read(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    // Both versions are longer than this, so it can't eat into the
+    // client's own bytes
+    let mut start = [0; 8];
+    stream.read_exact(&mut start).await?;
+    if start.starts_with(b"PROXY ") {
+        // Byte by byte, since the line's end is only known once it's read;
+        // it arrives in one packet, so these are cheap
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX {
+                return Err(invalid("PROXY header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("PROXY header isn't text"))?;
+        return parse_v1(line);
+    }
+    if start[..] != V2_SIGNATURE[..8] {
+        return Err(invalid("no PROXY header"));
+    }
+    let mut header = [0; 16];
+    header[..8].copy_from_slice(&start);
+    stream.read_exact(&mut header[8..]).await?;
+    let mut addresses = vec![0; u16::from_be_bytes([header[14], header[15]]) as usize];
+    stream.read_exact(&mut addresses).await?;
+    parse_v2(&header, &addresses)
+}
+
+/// `PROXY TCP4|TCP6 SRC DST SRC_PORT DST_PORT\r\n`, or `PROXY UNKNOWN…`.
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.trim_end_matches("\r\n").split(' ').collect();
+    let (ip, port) = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => return Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad address in PROXY header"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("PROXY header address doesn't match its family"));
+            }
+            (ip, src_port.parse().map_err(|_| invalid("bad port in PROXY header"))?)
+        }
+        _ => return Err(invalid("malformed PROXY header")),
+    };
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// The 16-byte fixed part, then the addresses and any TLVs, which are
+/// skipped.
+fn parse_v2(header: &[u8; 16], addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if &header[..12] != V2_SIGNATURE {
+        return Err(invalid("no PROXY header"));
+    }
+    match header[12] {
+        // LOCAL: the balancer's own connection, a health check
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => return Err(invalid("unsupported PROXY header version or command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    // The high half is the address family, the low half TCP or UDP
+    match header[13] >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        0x1 | 0x2 => Err(invalid("PROXY header addresses cut short")),
+        // Unspecified or a Unix socket: nothing to stand in for the peer's
+        _ => Ok(None),
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_both_versions() {
+        let addr = |s: &str| Some(s.parse::<SocketAddr>().unwrap());
+        assert_eq!(parse_v1("PROXY TCP4 203.0.113.7 10.0.0.1 51234 8888\r\n").unwrap(), addr("203.0.113.7:51234"));
+        assert_eq!(parse_v1("PROXY TCP6 2001:db8::1 ::1 4000 8888\r\n").unwrap(), addr("[2001:db8::1]:4000"));
+        assert_eq!(parse_v1("PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1("PROXY TCP6 203.0.113.7 10.0.0.1 51234 8888\r\n").is_err());
+
+        let mut header = [0; 16];
+        header[..12].copy_from_slice(V2_SIGNATURE);
+        header[12] = 0x21;
+        header[13] = 0x11;
+        let addresses = [203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x22, 0xb8, 0x03, 0x00];
+        assert_eq!(parse_v2(&header, &addresses).unwrap(), addr("203.0.113.7:51234"));
+        header[12] = 0x20;
+        assert_eq!(parse_v2(&header, &[]).unwrap(), None);
+        header[12] = 0x21;
+        assert!(parse_v2(&header, &addresses[..6]).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//! Deleting stored messages on request: everything from or mentioning
+//! someone, or everything said in a room.
+//!
+//! Messages are stored in two places: the history rings, which are purged
+//! at once, and the message log, which the log's own task rewrites without
+//! the purged entries (see `journal`). A message is matched by the name it
+//! went out under, so a client known by its id and later by a nickname
+//! needs both purged; the server adds a connected client's current names
+//! itself.
+
+use std::fmt;
+
+/// What to purge.
+pub enum Target {
+    /// Messages sent under any of these names, or mentioning one that is
+    /// a nickname (ids are just numbers, so aren't looked for in text).
+    Identity(Vec<String>),
+    /// Messages sent in this room.
+    Room(String),
+}
+
+impl Target {
+    /// Whether a message sent by `name` in `room` (`None` for the lobby)
+    /// goes.
+    pub fn matches(&self, name: &str, room: Option<&str>, text: &str) -> bool {
+        match self {
+            Target::Identity(names) => names.iter().any(|n| n == name || (!is_id(n) && mentions(text, n))),
+            Target::Room(target) => room == Some(target.as_str()),
+        }
+    }
+
+    /// Like [`matches`](Self::matches), for a `MESSAGE:` or `BLOBREF:` line
+    /// as it was broadcast.
+    pub fn matches_line(&self, room: Option<&str>, line: &[u8]) -> bool {
+        let line = String::from_utf8_lossy(line);
+        let Some((_, rest)) = line.trim_end_matches('\n').split_once(':') else { return false };
+        let (name, text) = rest.split_once(' ').unwrap_or((rest, ""));
+        self.matches(name, room, text)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Identity(names) => write!(f, "user {}", names.join(",")),
+            Target::Room(room) => write!(f, "room {room}"),
+        }
+    }
+}
+
+fn is_id(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Whether `nick` appears in `text` as a word of its own, in any case.
+fn mentions(text: &str, nick: &str) -> bool {
+    text.split(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+        .any(|word| word.trim_matches('.').eq_ignore_ascii_case(nick))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_or_mentioning() {
+        let alice = Target::Identity(vec!["alice".into(), "3".into()]);
+        assert!(alice.matches_line(None, b"MESSAGE:alice hi\n"));
+        assert!(alice.matches_line(Some("dev"), b"MESSAGE[ct=json]:3 {}\n"));
+        assert!(alice.matches_line(None, b"MESSAGE:bob thanks, Alice.\n"));
+        assert!(!alice.matches_line(None, b"MESSAGE:bob 3 apples for malice\n"));
+        let dev = Target::Room("dev".into());
+        assert!(dev.matches("bob", Some("dev"), "hi"));
+        assert!(!dev.matches("bob", None, "dev"));
+    }
+}
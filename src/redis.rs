@@ -0,0 +1,347 @@
+//! Redis pub/sub bridge, so instances behind a load balancer share rooms.
+//!
+//! Every message published here is also `PUBLISH`ed to one Redis channel,
+//! as `{"origin":…,"room":…,"ct":…,"name":…,"text":…}`, and every instance
+//! `SUBSCRIBE`s to the same channel and publishes what the others sent to
+//! its own clients. Redis sends a publisher its own messages back too, so
+//! an instance skips those by their `origin`, its server id.
+//!
+//! Publishing and subscribing each have a connection, and a task that
+//! reconnects with backoff whenever it's lost; messages published while
+//! the publisher is down are dropped, not queued. Only as much of the
+//! Redis protocol (RESP) as that takes is spoken here: `AUTH`, `PUBLISH`
+//! and `SUBSCRIBE`, over plain TCP. The bridge comes with the `cluster`
+//! feature.
+
+#[cfg(feature = "cluster")]
+use std::io;
+#[cfg(feature = "cluster")]
+use std::time::Duration;
+
+#[cfg(feature = "cluster")]
+use serde_json::{json, Value};
+#[cfg(feature = "cluster")]
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+#[cfg(feature = "cluster")]
+use tokio::net::TcpStream;
+#[cfg(feature = "cluster")]
+use tokio::sync::mpsc;
+#[cfg(feature = "cluster")]
+use tokio::task::JoinSet;
+#[cfg(feature = "cluster")]
+use tokio::time;
+#[cfg(feature = "cluster")]
+use tracing::{info, warn};
+
+use crate::peer::Relay;
+#[cfg(feature = "cluster")]
+use crate::protocol;
+
+/// First wait before reconnecting; it doubles on every failure in a row,
+/// up to `MAX_BACKOFF`.
+#[cfg(feature = "cluster")]
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+#[cfg(feature = "cluster")]
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Messages that may wait for the publisher before more are dropped.
+#[cfg(feature = "cluster")]
+const PUBLISH_QUEUE: usize = 4096;
+/// What the JSON around a message adds to it, at most.
+#[cfg(feature = "cluster")]
+const ENVELOPE_OVERHEAD: usize = 1024;
+
+/// Where the bridge meets the other instances.
+#[derive(Clone, Debug)]
+pub struct RedisConfig {
+    /// The Redis server, as `HOST:PORT`.
+    pub addr: String,
+    pub channel: String,
+    /// Sent with `AUTH` on connecting, when set.
+    pub password: Option<String>,
+}
+
+impl RedisConfig {
+    /// `addr` on the default channel, `tcp-broadcast`, without a password.
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self { addr: addr.into(), channel: "tcp-broadcast".to_string(), password: None }
+    }
+}
+
+/// The server's side of the bridge; its tasks stop when it's dropped.
+#[cfg(feature = "cluster")]
+pub(crate) struct Bridge {
+    id: String,
+    outbox: mpsc::Sender<String>,
+    inbox: mpsc::UnboundedReceiver<Relay>,
+    /// Messages dropped since the publisher last kept up.
+    dropped: u64,
+    _tasks: JoinSet<()>,
+}
+
+#[cfg(feature = "cluster")]
+impl Bridge {
+    /// Starts the publisher and subscriber for the server called `id`.
+    /// Messages from Redis may be up to `max_line` plus their envelope.
+    pub fn start(config: &RedisConfig, id: String, max_line: usize) -> Bridge {
+        let (outbox, queued) = mpsc::channel(PUBLISH_QUEUE);
+        let (tx, inbox) = mpsc::unbounded_channel();
+        let mut tasks = JoinSet::new();
+        tasks.spawn(publisher(config.clone(), queued));
+        tasks.spawn(subscriber(config.clone(), id.clone(), max_line + ENVELOPE_OVERHEAD, tx));
+        Bridge { id, outbox, inbox, dropped: 0, _tasks: tasks }
+    }
+
+    /// Publishes a message sent here to the other instances.
+    pub fn publish(&mut self, room: Option<&str>, content_type: Option<&str>, name: &str, text: &str) {
+        let message = json!({ "origin": self.id, "room": room, "ct": content_type, "name": name, "text": text });
+        match self.outbox.try_send(message.to_string()) {
+            Ok(()) => self.dropped = 0,
+            Err(_) => {
+                if self.dropped == 0 {
+                    warn!("redis bridge falling behind, dropping messages");
+                }
+                self.dropped += 1;
+            }
+        }
+    }
+}
+
+/// The next message from another instance, if the bridge is on; never
+/// completes otherwise.
+#[cfg(feature = "cluster")]
+pub(crate) async fn next(bridge: Option<&mut Bridge>) -> Option<Relay> {
+    match bridge {
+        Some(bridge) => bridge.inbox.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads a message off the channel; `None` if it isn't one, or is this
+/// server's own.
+#[cfg(feature = "cluster")]
+fn parse(payload: &[u8], id: &str) -> Option<Relay> {
+    let message: Value = serde_json::from_slice(payload).ok()?;
+    let origin = message["origin"].as_str().filter(|origin| protocol::valid_nick(origin) && *origin != id)?;
+    let room = match &message["room"] {
+        Value::Null => None,
+        room => Some(room.as_str().filter(|room| protocol::valid_room(room))?.to_string()),
+    };
+    let content_type = match &message["ct"] {
+        Value::Null => None,
+        ct => Some(ct.as_str().filter(|ct| protocol::valid_content_type(ct))?.to_string()),
+    };
+    let name = message["name"].as_str().filter(|name| !name.is_empty() && !name.contains(' '))?;
+    Some(Relay {
+        origin: origin.to_string(),
+        seq: 0,
+        hops: 0,
+        room,
+        content_type,
+        name: name.to_string(),
+        text: message["text"].as_str()?.to_string(),
+    })
+}
+
+/// Publishes what's queued, reconnecting whenever the connection goes.
+#[cfg(feature = "cluster")]
+async fn publisher(config: RedisConfig, mut queued: mpsc::Receiver<String>) {
+    let mut backoff = FIRST_BACKOFF;
+    loop {
+        let lost: io::Result<()> = async {
+            let mut conn = connect(&config).await?;
+            info!("redis bridge publishing to {} channel={}", config.addr, config.channel);
+            backoff = FIRST_BACKOFF;
+            // The server has stopped when there's no sender
+            while let Some(first) = queued.recv().await {
+                // Whatever else is waiting goes out in the same write
+                let mut batch = vec![first];
+                while let Ok(next) = queued.try_recv() {
+                    batch.push(next);
+                }
+                for message in &batch {
+                    write_command(conn.get_mut(), &["PUBLISH", &config.channel, message]).await?;
+                }
+                for _ in &batch {
+                    if let Resp::Error(e) = read(&mut conn, 64).await? {
+                        warn!("redis bridge publish refused: {e}");
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await;
+        match lost {
+            Ok(()) => return,
+            Err(e) => warn!("redis bridge publisher {} unavailable, retrying in {}ms: {e}", config.addr, backoff.as_millis()),
+        }
+        // What was published meanwhile is gone anyway
+        while queued.try_recv().is_ok() {}
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Passes on what other instances publish, resubscribing whenever the
+/// connection goes.
+#[cfg(feature = "cluster")]
+async fn subscriber(config: RedisConfig, id: String, max_len: usize, tx: mpsc::UnboundedSender<Relay>) {
+    let mut backoff = FIRST_BACKOFF;
+    loop {
+        let lost: io::Result<()> = async {
+            let mut conn = connect(&config).await?;
+            write_command(conn.get_mut(), &["SUBSCRIBE", &config.channel]).await?;
+            info!("redis bridge subscribed to {} channel={}", config.addr, config.channel);
+            backoff = FIRST_BACKOFF;
+            loop {
+                let Resp::Array(parts) = read(&mut conn, max_len).await? else { continue };
+                // Anything else is a subscription being confirmed
+                let [Resp::Bulk(kind), _, Resp::Bulk(payload)] = &parts[..] else { continue };
+                if kind != b"message" {
+                    continue;
+                }
+                if let Some(relay) = parse(payload, &id) {
+                    if tx.send(relay).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        .await;
+        match lost {
+            Ok(()) => return,
+            Err(e) => warn!("redis bridge subscriber {} unavailable, retrying in {}ms: {e}", config.addr, backoff.as_millis()),
+        }
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Connects and authenticates.
+#[cfg(feature = "cluster")]
+async fn connect(config: &RedisConfig) -> io::Result<BufReader<TcpStream>> {
+    let mut conn = BufReader::new(TcpStream::connect(&config.addr).await?);
+    if let Some(password) = &config.password {
+        write_command(conn.get_mut(), &["AUTH", password]).await?;
+        if let Resp::Error(e) = read(&mut conn, 64).await? {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, e));
+        }
+    }
+    Ok(conn)
+}
+
+/// A reply, or a message pushed to a subscriber.
+#[cfg(feature = "cluster")]
+#[derive(Debug, PartialEq)]
+enum Resp {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    /// A null bulk string.
+    Null,
+    Array(Vec<Resp>),
+}
+
+#[cfg(feature = "cluster")]
+async fn write_command(out: &mut (impl AsyncWrite + Unpin), args: &[&str]) -> io::Result<()> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg.as_bytes());
+        command.extend_from_slice(b"\r\n");
+    }
+    out.write_all(&command).await
+}
+
+/// Reads one value. Bulk strings over `max_len` bytes are refused, so a
+/// stray huge message can't take all the memory.
+#[cfg(feature = "cluster")]
+async fn read(conn: &mut (impl AsyncBufRead + Unpin), max_len: usize) -> io::Result<Resp> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("redis sent {what}"));
+    let header = read_line(conn).await?;
+    let (kind, rest) = header.split_at_checked(1).ok_or_else(|| invalid("an empty line"))?;
+    match kind {
+        "+" => Ok(Resp::Simple(rest.to_string())),
+        "-" => Ok(Resp::Error(rest.to_string())),
+        ":" => rest.parse().map(Resp::Integer).map_err(|_| invalid("a bad integer")),
+        "$" => {
+            let len: i64 = rest.parse().map_err(|_| invalid("a bad length"))?;
+            if len < 0 {
+                return Ok(Resp::Null);
+            }
+            if len as usize > max_len {
+                return Err(invalid(&format!("a value of {len} bytes, over {max_len}")));
+            }
+            let mut value = vec![0; len as usize + 2];
+            conn.read_exact(&mut value).await?;
+            value.truncate(len as usize);
+            Ok(Resp::Bulk(value))
+        }
+        "*" => {
+            let len: i64 = rest.parse().map_err(|_| invalid("a bad length"))?;
+            let mut items = Vec::new();
+            for _ in 0..len.max(0) {
+                items.push(Box::pin(read(conn, max_len)).await?);
+            }
+            Ok(Resp::Array(items))
+        }
+        _ => Err(invalid(&format!("an unknown type {kind:?}"))),
+    }
+}
+
+#[cfg(feature = "cluster")]
+async fn read_line(conn: &mut (impl AsyncBufRead + Unpin)) -> io::Result<String> {
+    let mut line = String::new();
+    // Headers are short; a line this long isn't RESP
+    if (&mut *conn).take(1024).read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Built without the bridge, so none can exist.
+#[cfg(not(feature = "cluster"))]
+pub(crate) enum Bridge {}
+
+#[cfg(not(feature = "cluster"))]
+impl Bridge {
+    /// Never called: `info::check` refuses Redis in a build like this.
+    pub fn start(_config: &RedisConfig, _id: String, _max_line: usize) -> Bridge {
+        unreachable!("redis configured without the cluster feature")
+    }
+
+    pub fn publish(&mut self, _room: Option<&str>, _content_type: Option<&str>, _name: &str, _text: &str) {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "cluster"))]
+pub(crate) async fn next(bridge: Option<&mut Bridge>) -> Option<Relay> {
+    match bridge {
+        Some(bridge) => match *bridge {},
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(all(test, feature = "cluster"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn speaks_resp() {
+        let mut out = Vec::new();
+        write_command(&mut out, &["PUBLISH", "chan", "hi"]).await.unwrap();
+        assert_eq!(out, b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nchan\r\n$2\r\nhi\r\n");
+
+        let payload = r#"{"origin":"b","room":"dev","ct":null,"name":"alice","text":"hi there"}"#;
+        let pushed = format!("*3\r\n$7\r\nmessage\r\n$4\r\nchan\r\n${}\r\n{payload}\r\n:1\r\n", payload.len());
+        let mut conn = BufReader::new(pushed.as_bytes());
+        let Resp::Array(parts) = read(&mut conn, 1024).await.unwrap() else { panic!("not an array") };
+        let Resp::Bulk(body) = &parts[2] else { panic!("not a bulk string") };
+        let relay = parse(body, "a").unwrap();
+        assert_eq!((relay.sender().as_str(), relay.room.as_deref(), relay.text.as_str()), ("alice@b", Some("dev"), "hi there"));
+        assert_eq!(parse(body, "b"), None);
+        assert_eq!(read(&mut conn, 1024).await.unwrap(), Resp::Integer(1));
+        assert!(read(&mut BufReader::new(&b"$5000\r\n"[..]), 1024).await.is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! Latency regions: clients sorted by how far away they are on the network,
+//! to see where another node would serve them better.
+//!
+//! With `--rtt-regions local=5,near=50,far`, each client is sent a `PING`
+//! as it logs in, and the time until its `PONG` is its round-trip time.
+//! A client is in the first region its RTT is within, in milliseconds; a
+//! region without a bound takes everyone slower, and a client past every
+//! bound, or yet to answer, is in none (`-`). The metrics endpoint has the
+//! RTTs as a histogram over the bounds, and the clients in each region.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Longest region name.
+const MAX_NAME: usize = 32;
+
+struct Region {
+    name: String,
+    /// RTTs up to this are in it; `None` for all the rest.
+    up_to: Option<Duration>,
+}
+
+/// Regions in order of distance; none means RTTs aren't measured.
+#[derive(Default)]
+pub struct Regions {
+    regions: Vec<Region>,
+}
+
+/// A region list that doesn't parse, with why.
+#[derive(PartialEq, Eq, Debug)]
+pub struct InvalidRegions(String);
+
+impl fmt::Display for InvalidRegions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidRegions {}
+
+impl Regions {
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// The region a client with this RTT is in, if any.
+    pub fn region(&self, rtt: Duration) -> Option<&str> {
+        self.regions.iter().find(|r| r.up_to.is_none_or(|up_to| rtt <= up_to)).map(|r| r.name.as_str())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.regions.iter().map(|r| r.name.as_str())
+    }
+
+    /// The regions' bounds, nearest first, for the histogram.
+    pub(crate) fn bounds(&self) -> Vec<Duration> {
+        self.regions.iter().filter_map(|r| r.up_to).collect()
+    }
+}
+
+impl FromStr for Regions {
+    type Err = InvalidRegions;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut regions: Vec<Region> = Vec::new();
+        for region in s.split(',').map(str::trim) {
+            let bad = || InvalidRegions(format!("bad region {region:?}; want NAME=MS, or NAME for the rest"));
+            let (name, up_to) = match region.split_once('=') {
+                Some((name, ms)) => (name, Some(Duration::from_millis(ms.parse().map_err(|_| bad())?))),
+                None => (region, None),
+            };
+            let named = !name.is_empty() && name != "-" && name.len() <= MAX_NAME;
+            if !named || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-') {
+                return Err(bad());
+            }
+            if regions.iter().any(|r| r.name == name) {
+                return Err(InvalidRegions(format!("region {name} given twice")));
+            }
+            match regions.last() {
+                Some(Region { up_to: None, .. }) => return Err(InvalidRegions("only the last region can go without a bound".to_string())),
+                Some(Region { up_to: Some(last), .. }) if up_to.is_some_and(|up_to| up_to <= *last) => {
+                    return Err(InvalidRegions("region bounds must go up".to_string()));
+                }
+                _ => {}
+            }
+            regions.push(Region { name: name.to_string(), up_to });
+        }
+        Ok(Regions { regions })
+    }
+}
+
+/// RTTs measured since startup, bucketed by the regions' bounds.
+pub(crate) struct Rtts {
+    bounds: Vec<Duration>,
+    /// How many were within each bound.
+    within: Vec<u64>,
+    pub sum: Duration,
+    pub count: u64,
+}
+
+impl Rtts {
+    pub fn new(regions: &Regions) -> Self {
+        let bounds = regions.bounds();
+        Self { within: vec![0; bounds.len()], bounds, sum: Duration::ZERO, count: 0 }
+    }
+
+    pub fn record(&mut self, rtt: Duration) {
+        for (bound, within) in self.bounds.iter().zip(&mut self.within) {
+            if rtt <= *bound {
+                *within += 1;
+            }
+        }
+        self.sum += rtt;
+        self.count += 1;
+    }
+
+    /// Each bound, with how many RTTs were within it.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.bounds.iter().copied().zip(self.within.iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_region_within_wins() {
+        let regions: Regions = "local=5, near=50,far".parse().unwrap();
+        let ms = Duration::from_millis;
+        assert_eq!(regions.region(ms(5)), Some("local"));
+        assert_eq!(regions.region(ms(6)), Some("near"));
+        assert_eq!(regions.region(ms(900)), Some("far"));
+        let bounded: Regions = "local=5,near=50".parse().unwrap();
+        assert_eq!(bounded.region(ms(51)), None);
+
+        let mut rtts = Rtts::new(&regions);
+        [ms(1), ms(20), ms(300)].into_iter().for_each(|rtt| rtts.record(rtt));
+        assert_eq!(rtts.buckets().collect::<Vec<_>>(), [(ms(5), 1), (ms(50), 2)]);
+        assert_eq!((rtts.count, rtts.sum), (3, ms(321)));
+
+        assert!("far,local=5".parse::<Regions>().is_err());
+        assert!("near=50,local=5".parse::<Regions>().is_err());
+        assert!("local=5,local=6".parse::<Regions>().is_err());
+        assert!("lo cal=5".parse::<Regions>().is_err());
+        assert!("local=soon".parse::<Regions>().is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Server-assigned client ids and nicknames.
+//!
+//! Ids count up from 1 and are never reused while the server runs, so two
+//! clients behind the same NAT, or a client that reconnects from a recycled
+//! port, can't be mistaken for each other. A client may also claim a
+//! nickname, unique ignoring case, which then stands in for its id in
+//! broadcast lines.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+pub type ClientId = u64;
+
+#[derive(Default)]
+pub struct ClientRegistry {
+    /// Last id handed out; 0 means none yet.
+    last: ClientId,
+    peers: HashMap<ClientId, SocketAddr>,
+    nicks: HashMap<ClientId, Arc<str>>,
+    /// Lowercased nicknames in use, and who holds them
+    holders: HashMap<String, ClientId>,
+}
+
+/// The nickname belongs to another client.
+pub struct NickTaken;
+
+impl ClientRegistry {
+    /// Assigns the next id to a newly connected peer.
+    pub fn register(&mut self, peer: SocketAddr) -> ClientId {
+        self.last += 1;
+        self.peers.insert(self.last, peer);
+        self.last
+    }
+
+    /// Assigns the next id to an identity inside the process (see
+    /// `inject`), which holds `nick` for as long as the server runs. It has
+    /// no peer, so it can't be found with [`resolve`](Self::resolve).
+    pub fn register_internal(&mut self, nick: &str) -> Result<ClientId, NickTaken> {
+        let key = nick.to_ascii_lowercase();
+        if self.holders.contains_key(&key) {
+            return Err(NickTaken);
+        }
+        self.last += 1;
+        self.holders.insert(key, self.last);
+        self.nicks.insert(self.last, nick.into());
+        Ok(self.last)
+    }
+
+    /// Forgets the client, releasing its nickname.
+    pub fn unregister(&mut self, id: ClientId) -> Option<SocketAddr> {
+        self.release_nick(id);
+        self.peers.remove(&id)
+    }
+
+    /// Gives the client a nickname, releasing any previous one.
+    pub fn set_nick(&mut self, id: ClientId, nick: &str) -> Result<(), NickTaken> {
+        let key = nick.to_ascii_lowercase();
+        if self.holders.get(&key).is_some_and(|holder| *holder != id) {
+            return Err(NickTaken);
+        }
+        self.release_nick(id);
+        self.holders.insert(key, id);
+        self.nicks.insert(id, nick.into());
+        Ok(())
+    }
+
+    fn release_nick(&mut self, id: ClientId) {
+        if let Some(old) = self.nicks.remove(&id) {
+            self.holders.remove(&old.to_ascii_lowercase());
+        }
+    }
+
+    /// How the client appears in broadcast lines: its nickname, or its id.
+    pub fn name(&self, id: ClientId) -> String {
+        self.nicks.get(&id).map_or_else(|| id.to_string(), |nick| nick.to_string())
+    }
+
+    /// Finds a connected client by id or nickname.
+    pub fn resolve(&self, name: &str) -> Option<ClientId> {
+        match name.parse() {
+            Ok(id) => self.peers.contains_key(&id).then_some(id),
+            Err(_) => self.holders.get(&name.to_ascii_lowercase()).copied().filter(|id| self.peers.contains_key(id)),
+        }
+    }
+
+    pub fn peer(&self, id: ClientId) -> Option<SocketAddr> {
+        self.peers.get(&id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_reuses_an_id() {
+        let mut registry = ClientRegistry::default();
+        let peer: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let first = registry.register(peer);
+        assert_eq!(registry.unregister(first), Some(peer));
+        // The same peer again, as after a reconnect from a recycled port
+        let second = registry.register(peer);
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(registry.resolve("1"), None);
+        assert_eq!(registry.resolve("2"), Some(second));
+        assert_eq!(registry.name(second), "2");
+    }
+}
@@ -0,0 +1,119 @@
+//! Pacing history replay, so a mass reconnect can't crowd out live traffic.
+//!
+//! Every client that connects, joins a room or sends `HISTORY` is owed
+//! some kept lines. With a replay rate set, those lines aren't written at
+//! once: each client's replay waits its turn in a queue, is sent a page at
+//! a time, and all replays together go no faster than the rate, while live
+//! messages keep flowing around them. A client whose replay is cut short
+//! (it left, or moved to another room) simply drops out of the queue.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use crate::registry::ClientId;
+
+/// How history replay is paced.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayConfig {
+    /// Replayed lines per second across all clients; `None` sends each
+    /// replay in full straight away.
+    pub rate: Option<f64>,
+    /// Lines one client is sent in a turn before the next one's.
+    pub page: usize,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self { rate: None, page: 100 }
+    }
+}
+
+/// Replays waiting for their next page, in turn order.
+pub(crate) struct Replays {
+    rate: f64,
+    page: usize,
+    tokens: f64,
+    last: Instant,
+    pending: HashMap<ClientId, VecDeque<Bytes>>,
+    turns: VecDeque<ClientId>,
+}
+
+impl Replays {
+    pub fn new(rate: f64, page: usize, now: Instant) -> Self {
+        let page = page.max(1);
+        Self { rate, page, tokens: page as f64, last: now, pending: HashMap::new(), turns: VecDeque::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queues `lines` for a client, in place of any replay it was owed.
+    pub fn start(&mut self, client_id: ClientId, lines: Vec<Bytes>) {
+        if lines.is_empty() {
+            self.cancel(client_id);
+            return;
+        }
+        if self.pending.insert(client_id, lines.into()).is_none() {
+            self.turns.push_back(client_id);
+        }
+    }
+
+    pub fn cancel(&mut self, client_id: ClientId) {
+        if self.pending.remove(&client_id).is_some() {
+            self.turns.retain(|&id| id != client_id);
+        }
+    }
+
+    /// The pages the rate allows by `now`: up to a page per client in
+    /// turn, until the allowance runs out.
+    pub fn due(&mut self, now: Instant) -> Vec<(ClientId, Vec<Bytes>)> {
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.page as f64);
+        self.last = now;
+        let mut pages = Vec::new();
+        while self.tokens >= 1.0 {
+            let Some(client_id) = self.turns.pop_front() else { break };
+            let Some(lines) = self.pending.get_mut(&client_id) else { continue };
+            let n = lines.len().min(self.page).min(self.tokens as usize);
+            self.tokens -= n as f64;
+            pages.push((client_id, lines.drain(..n).collect()));
+            if lines.is_empty() {
+                self.pending.remove(&client_id);
+            } else {
+                self.turns.push_back(client_id);
+            }
+        }
+        pages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn lines(n: usize) -> Vec<Bytes> {
+        (0..n).map(|i| Bytes::from(format!("HISTORY:MESSAGE:1 {i}\n"))).collect()
+    }
+
+    #[test]
+    fn pages_in_turn_within_the_rate() {
+        let start = Instant::now();
+        let mut replays = Replays::new(10.0, 4, start);
+        replays.start(1, lines(6));
+        replays.start(2, lines(3));
+        let sizes = |pages: Vec<(ClientId, Vec<Bytes>)>| pages.into_iter().map(|(id, p)| (id, p.len())).collect::<Vec<_>>();
+        assert_eq!(sizes(replays.due(start)), [(1, 4)]);
+        assert_eq!(sizes(replays.due(start + Duration::from_millis(500))), [(2, 3), (1, 1)]);
+        assert_eq!(sizes(replays.due(start + Duration::from_millis(600))), [(1, 1)]);
+        assert!(replays.is_empty());
+
+        replays.start(3, lines(2));
+        replays.cancel(3);
+        assert!(replays.due(start + Duration::from_secs(5)).is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+//! Room state: members, recent messages and per-room overrides
+//! of server policy.
+//!
+//! Rooms exist while they have members; when the last member leaves, the
+//! room, its history, its modes and any messages held for moderation are
+//! forgotten.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use crate::history::History;
+use crate::registry::ClientId;
+
+/// Longest slow-mode interval a room can ask for.
+const MAX_SLOW: Duration = Duration::from_secs(3600);
+/// Most messages a moderated room holds before turning new ones away.
+pub const MAX_HELD: usize = 100;
+
+pub struct Room {
+    pub members: BTreeSet<ClientId>,
+    /// Its broadcasts go straight to its members' queues rather than
+    /// through the feed, while it's small enough (`Tuning::small_room`).
+    pub direct: bool,
+    pub modes: RoomModes,
+    pub history: History,
+    /// Who turned moderation on: the one member who may approve or reject
+    /// held messages and change modes while it's on.
+    pub moderator: Option<ClientId>,
+    /// Messages waiting for the moderator, by id.
+    pub held: BTreeMap<u64, Held>,
+}
+
+impl Room {
+    pub fn new(history: usize) -> Self {
+        Self {
+            members: BTreeSet::new(),
+            direct: false,
+            modes: RoomModes::default(),
+            history: History::new(history),
+            moderator: None,
+            held: BTreeMap::new(),
+        }
+    }
+}
+
+/// A message held for approval.
+pub struct Held {
+    pub sender: ClientId,
+    /// The sender's name when it was sent.
+    pub name: String,
+    pub text: String,
+    pub content_type: Option<String>,
+}
+
+/// Overrides set with `MODE:`, applying to messages sent in the room.
+#[derive(Clone)]
+pub struct RoomModes {
+    /// Acknowledge messages with `ACK:MESSAGE` (ingest ack ranges are unaffected).
+    pub acks: bool,
+    /// Minimum time between two messages from the same member.
+    pub slow: Option<Duration>,
+    /// Keep recent messages for members who join later (when the server
+    /// keeps history at all).
+    pub history: bool,
+    /// Hold messages from everyone but the moderator until approved.
+    pub moderated: bool,
+}
+
+impl Default for RoomModes {
+    fn default() -> Self {
+        Self { acks: true, slow: None, history: true, moderated: false }
+    }
+}
+
+impl RoomModes {
+    /// Applies one `key=value` setting (`acks=on|off`, `slow=<secs>`,
+    /// `history=on|off`, `moderated=on|off`).
+    pub fn apply(&mut self, setting: &str) -> Result<(), ()> {
+        match setting.split_once('=').ok_or(())? {
+            ("acks", "on") => self.acks = true,
+            ("acks", "off") => self.acks = false,
+            ("history", "on") => self.history = true,
+            ("history", "off") => self.history = false,
+            ("moderated", "on") => self.moderated = true,
+            ("moderated", "off") => self.moderated = false,
+            ("slow", secs) => {
+                let secs: u64 = secs.parse().map_err(|_| ())?;
+                let slow = Duration::from_secs(secs);
+                if slow > MAX_SLOW {
+                    return Err(());
+                }
+                self.slow = (secs > 0).then_some(slow);
+            }
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.acks && self.slow.is_none() && self.history && !self.moderated
+    }
+
+    /// `acks=on slow=0 history=on moderated=off` style, the same settings
+    /// `apply` takes.
+    pub fn describe(&self) -> String {
+        let on_off = |b: bool| if b { "on" } else { "off" };
+        let slow = self.slow.map_or(0, |d| d.as_secs());
+        format!(
+            "acks={} slow={slow} history={} moderated={}",
+            on_off(self.acks),
+            on_off(self.history),
+            on_off(self.moderated)
+        )
+    }
+}
@@ -0,0 +1,79 @@
+//! Sampling of per-connection log lines.
+//!
+//! Every connect, disconnect and rejection normally gets a log line, which
+//! under a connection flood makes logging itself the bottleneck (and fills
+//! the disk). Within each second the first few lines are logged as usual;
+//! past that only one in N is, with N doubling as the rate climbs. What was
+//! left out is counted and reported on the housekeeping tick.
+
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+/// Lines per window logged before sampling kicks in.
+const UNSAMPLED: u64 = 50;
+
+pub struct LogSampler {
+    window_start: Instant,
+    /// Lines offered in the current window.
+    seen: u64,
+    /// Lines left out since the last summary.
+    suppressed: u64,
+    /// Highest 1-in-N rate since the last summary.
+    peak_every: u64,
+}
+
+impl LogSampler {
+    pub fn new(now: Instant) -> Self {
+        Self { window_start: now, seen: 0, suppressed: 0, peak_every: 1 }
+    }
+
+    /// Whether the next line should be logged.
+    pub fn sample(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= WINDOW {
+            self.window_start = now;
+            self.seen = 0;
+        }
+        self.seen += 1;
+        if self.seen <= UNSAMPLED {
+            return true;
+        }
+        let every = (self.seen / UNSAMPLED).next_power_of_two();
+        self.peak_every = self.peak_every.max(every);
+        if self.seen.is_multiple_of(every) {
+            return true;
+        }
+        self.suppressed += 1;
+        false
+    }
+
+    /// Summary line for the log, or `None` if nothing was left out.
+    pub fn take_summary(&mut self) -> Option<String> {
+        if self.suppressed == 0 {
+            return None;
+        }
+        let line = format!("connection log sampled suppressed={} peak_rate=1/{}", self.suppressed, self.peak_every);
+        self.suppressed = 0;
+        self.peak_every = 1;
+        Some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thins_out_a_flood_and_counts_what_it_left_out() {
+        let now = Instant::now();
+        let mut sampler = LogSampler::new(now);
+        let logged = (0..200).filter(|_| sampler.sample(now)).count();
+        // All of the first 99, then one in two, then one in four
+        assert_eq!(logged, 99 + 25 + 13);
+        assert_eq!(sampler.take_summary().as_deref(), Some("connection log sampled suppressed=63 peak_rate=1/4"));
+        assert_eq!(sampler.take_summary(), None);
+
+        // A new window starts over
+        assert!(sampler.sample(now + WINDOW));
+        assert_eq!(sampler.take_summary(), None);
+    }
+}
@@ -0,0 +1,202 @@
+//! Picking clients by what the server knows of them, for admin commands
+//! that act on many at once: `KICK[idle>3600 && room==-]:`.
+//!
+//! A selector is conditions joined by `&&`, each a field, an operator and
+//! a value. The number fields (`id`, `idle` and `age` in seconds,
+//! `messages` received, `queued` broadcast lines, `rtt` in milliseconds,
+//! which no client yet to be timed meets) take `==`, `!=`, `<`, `<=`, `>`
+//! and `>=`; the rest (`name`, `room`, `lang`, `region`, with `-` for the
+//! lobby or none, and `admin`, `on` or `off`) take `==` and `!=`.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::registry::ClientId;
+
+/// What a selector can look at for one client.
+pub(crate) struct Session<'a> {
+    pub id: ClientId,
+    /// Its nickname, or its id without one.
+    pub name: &'a str,
+    pub room: Option<&'a str>,
+    pub lang: Option<&'a str>,
+    pub admin: bool,
+    /// Since it last sent anything but a `PONG`.
+    pub idle: Duration,
+    /// Since it connected.
+    pub age: Duration,
+    pub messages: u64,
+    pub queued: u64,
+    /// Its round-trip time, once measured.
+    pub rtt: Option<Duration>,
+    /// Its latency region, if it's in one.
+    pub region: Option<&'a str>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Field {
+    Id,
+    Idle,
+    Age,
+    Messages,
+    Queued,
+    Rtt,
+    Name,
+    Room,
+    Lang,
+    Region,
+    Admin,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Where two start at the same place the first wins, so `<=` isn't read
+/// as `<` followed by a value of `=...`.
+const OPS: [(&str, Op); 6] = [("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)];
+
+#[derive(PartialEq, Eq, Debug)]
+enum Value {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(PartialEq, Eq, Debug)]
+struct Condition {
+    field: Field,
+    op: Op,
+    value: Value,
+}
+
+/// Conditions a client must all meet.
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) struct Selector {
+    conditions: Vec<Condition>,
+}
+
+/// A selector that doesn't parse, with the condition that didn't.
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) struct InvalidSelector(pub String);
+
+impl Condition {
+    fn parse(condition: &str) -> Option<Condition> {
+        let (at, symbol, op) =
+            OPS.iter().filter_map(|&(symbol, op)| Some((condition.find(symbol)?, symbol, op))).min_by_key(|&(at, ..)| at)?;
+        let (field, value) = (condition[..at].trim(), condition[at + symbol.len()..].trim());
+        let field = match field {
+            "id" => Field::Id,
+            "idle" => Field::Idle,
+            "age" => Field::Age,
+            "messages" => Field::Messages,
+            "queued" => Field::Queued,
+            "rtt" => Field::Rtt,
+            "name" => Field::Name,
+            "room" => Field::Room,
+            "lang" => Field::Lang,
+            "region" => Field::Region,
+            "admin" => Field::Admin,
+            _ => return None,
+        };
+        let value = match field {
+            Field::Id | Field::Idle | Field::Age | Field::Messages | Field::Queued | Field::Rtt => Value::Number(value.parse().ok()?),
+            Field::Admin if !matches!(value, "on" | "off") => return None,
+            _ if matches!(op, Op::Eq | Op::Ne) && !value.is_empty() && !value.contains(char::is_whitespace) => {
+                Value::Text(value.to_string())
+            }
+            _ => return None,
+        };
+        Some(Condition { field, op, value })
+    }
+
+    fn matches(&self, session: &Session<'_>) -> bool {
+        let number = match self.field {
+            Field::Id => session.id,
+            Field::Idle => session.idle.as_secs(),
+            Field::Age => session.age.as_secs(),
+            Field::Messages => session.messages,
+            Field::Queued => session.queued,
+            Field::Rtt => match session.rtt {
+                Some(rtt) => u64::try_from(rtt.as_millis()).unwrap_or(u64::MAX),
+                None => return false,
+            },
+            Field::Name => return self.text(session.name),
+            Field::Room => return self.text(session.room.unwrap_or("-")),
+            Field::Lang => return self.text(session.lang.unwrap_or("-")),
+            Field::Region => return self.text(session.region.unwrap_or("-")),
+            Field::Admin => return self.text(if session.admin { "on" } else { "off" }),
+        };
+        let Value::Number(value) = self.value else { return false };
+        match self.op {
+            Op::Eq => number == value,
+            Op::Ne => number != value,
+            Op::Lt => number < value,
+            Op::Le => number <= value,
+            Op::Gt => number > value,
+            Op::Ge => number >= value,
+        }
+    }
+
+    fn text(&self, text: &str) -> bool {
+        let Value::Text(value) = &self.value else { return false };
+        (text == value) == (self.op == Op::Eq)
+    }
+}
+
+impl Selector {
+    pub fn matches(&self, session: &Session<'_>) -> bool {
+        self.conditions.iter().all(|condition| condition.matches(session))
+    }
+}
+
+impl FromStr for Selector {
+    type Err = InvalidSelector;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let conditions = s
+            .split("&&")
+            .map(|condition| Condition::parse(condition).ok_or_else(|| InvalidSelector(condition.trim().to_string())))
+            .collect::<Result<_, _>>()?;
+        Ok(Selector { conditions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditions_all_apply() {
+        let selector: Selector = "idle>3600 && room==-".parse().unwrap();
+        let mut session = Session {
+            id: 7,
+            name: "7",
+            room: None,
+            lang: None,
+            admin: false,
+            idle: Duration::from_secs(4000),
+            age: Duration::from_secs(5000),
+            messages: 0,
+            queued: 0,
+            rtt: None,
+            region: None,
+        };
+        assert!(selector.matches(&session));
+        session.room = Some("ops");
+        assert!(!selector.matches(&session));
+        assert!("room!=-".parse::<Selector>().unwrap().matches(&session));
+        assert!("idle<=4000&&age>=5000".parse::<Selector>().unwrap().matches(&session));
+        assert!(!"rtt<100".parse::<Selector>().unwrap().matches(&session));
+        session.rtt = Some(Duration::from_millis(80));
+        assert!("rtt<100 && region==-".parse::<Selector>().unwrap().matches(&session));
+        assert_eq!("idle>soon".parse::<Selector>(), Err(InvalidSelector("idle>soon".to_string())));
+        assert_eq!("room==- && room<ops".parse::<Selector>(), Err(InvalidSelector("room<ops".to_string())));
+        assert_eq!("mood==on".parse::<Selector>(), Err(InvalidSelector("mood==on".to_string())));
+    }
+}
@@ -0,0 +1,337 @@
+//! `selftest` subcommand: starts the server in-process with the active
+//! configuration, puts a few clients through it, and prints a pass/fail
+//! report, so operators can check a config change before restarting.
+//!
+//! The server is the real one, with the operator's settings, except for
+//! what would reach outside the process: it listens on an ephemeral
+//! loopback port only (no TLS, WebSocket, framed, metrics or Unix socket
+//! listeners), expects no PROXY headers, links to no other servers or
+//! Redis, lets everyone in regardless of the access lists, keeps no message
+//! log or blobs, and logs errors only. TLS is checked by loading the
+//! certificates. The clients speak the configured protocol and
+//! authenticate with the first configured credential.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::SinkExt;
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::access::AccessList;
+use crate::envelope::Protocol;
+use crate::logging::LogLevel;
+use crate::peer::PeerConfig;
+use crate::server::{BroadcastServer, Config};
+use crate::stamp;
+
+/// How long to wait for an expected line before failing the check.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to listen when checking that nothing arrives.
+const QUIET_PERIOD: Duration = Duration::from_millis(300);
+/// The room the rooms check uses.
+const ROOM: &str = "selftest";
+
+/// What passed, failed, or didn't apply to this config.
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(&'static str),
+}
+
+impl From<Result<(), String>> for Outcome {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Outcome::Pass,
+            Err(why) => Outcome::Fail(why),
+        }
+    }
+}
+
+/// What the clients need to know about the server they're testing.
+struct Plan {
+    addr: SocketAddr,
+    protocol: Protocol,
+    /// What to send as `AUTH:`, when auth is on.
+    credentials: Option<String>,
+}
+
+/// Runs every check against a server with `config` and returns whether
+/// they all passed. Fails outright if the server can't start.
+pub async fn run(mut config: Config) -> io::Result<bool> {
+    let tls = match &config.tls {
+        Some(tls) => tls.acceptor().map(|_| ()).map_err(|e| format!("certificates don't load: {e}")).into(),
+        None => Outcome::Skip("tls is off"),
+    };
+    let credentials = config.auth.tokens.first().cloned().or_else(|| {
+        let (user, password) = config.auth.users.iter().next()?;
+        Some(format!("{user} {password}"))
+    });
+    config.tls = None;
+    config.ws_port = None;
+    config.framed_port = None;
+    config.udp_port = None;
+    config.metrics_port = None;
+    config.unix_socket = None;
+    config.proxy_protocol = false;
+    config.peers = PeerConfig::default();
+    config.redis = None;
+    config.access = AccessList::default();
+    config.log_file = None;
+    config.blobs = None;
+    config.log_level = LogLevel::Error;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let plan = Plan { addr: listener.local_addr()?, protocol: config.protocol, credentials };
+    println!(
+        "selftest {} protocol={} auth={}",
+        plan.addr,
+        if plan.protocol == Protocol::Json { "json" } else { "text" },
+        if plan.credentials.is_some() { "on" } else { "off" },
+    );
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = BroadcastServer::bind(plan.addr).config(config).shutdown_on(async {
+        let _ = stopped.await;
+    });
+    let server = server.serve(listener);
+    tokio::pin!(server);
+    let checks = tokio::select! {
+        served = &mut server => {
+            served?;
+            return Err(io::Error::other("server stopped during the selftest"));
+        }
+        checks = checks(&plan, tls) => checks,
+    };
+    let _ = stop.send(());
+    server.await?;
+
+    let mut failed = 0;
+    for (name, outcome) in &checks {
+        match outcome {
+            Outcome::Pass => println!("PASS {name}"),
+            Outcome::Fail(why) => {
+                failed += 1;
+                println!("FAIL {name}: {why}");
+            }
+            Outcome::Skip(why) => println!("SKIP {name}: {why}"),
+        }
+    }
+    let passed = checks.iter().filter(|(_, outcome)| matches!(outcome, Outcome::Pass)).count();
+    println!("{passed} passed, {failed} failed");
+    Ok(failed == 0)
+}
+
+async fn checks(plan: &Plan, tls: Outcome) -> [(&'static str, Outcome); 6] {
+    let refused = match plan.credentials {
+        Some(_) => auth_refused(plan).await.into(),
+        None => Outcome::Skip("auth is off"),
+    };
+    [
+        ("tls", tls),
+        ("login", login(plan).await.into()),
+        ("wrong credentials refused", refused),
+        ("broadcast and ack", broadcast_and_ack(plan).await.into()),
+        ("rooms", rooms(plan).await.into()),
+        ("ping", ping(plan).await.into()),
+    ]
+}
+
+/// A test client.
+struct Probe {
+    id: String,
+    protocol: Protocol,
+    conn: Framed<TcpStream, LinesCodec>,
+}
+
+impl Probe {
+    /// Connects and logs in, authenticating first if auth is on.
+    async fn connect(plan: &Plan) -> Result<Probe, String> {
+        let mut probe = Probe::greeted(plan).await?;
+        if let Some(credentials) = &plan.credentials {
+            probe.send(&format!("AUTH:{credentials}")).await?;
+        }
+        let login = probe.recv().await?;
+        probe.id = probe
+            .text(&login)
+            .and_then(|line| Some(line.strip_prefix("LOGIN:")?.to_string()))
+            .ok_or_else(|| format!("expected LOGIN, got {login:?}"))?;
+        Ok(probe)
+    }
+
+    /// Connects and waits for `AUTH_REQUIRED` if auth is on, or just
+    /// connects if it's off.
+    async fn greeted(plan: &Plan) -> Result<Probe, String> {
+        let stream = TcpStream::connect(plan.addr).await.map_err(|e| format!("connect to {}: {e}", plan.addr))?;
+        let mut probe = Probe { id: String::new(), protocol: plan.protocol, conn: Framed::new(stream, LinesCodec::new()) };
+        if plan.credentials.is_some() {
+            probe.expect("AUTH_REQUIRED").await?;
+        }
+        Ok(probe)
+    }
+
+    /// Sends a command, written as its text line.
+    async fn send(&mut self, line: &str) -> Result<(), String> {
+        let line = match self.protocol {
+            Protocol::Text => line.to_string(),
+            Protocol::Json => envelope(line).to_string(),
+        };
+        self.conn.send(line).await.map_err(|e| format!("send: {e}"))
+    }
+
+    /// Sends a message to broadcast.
+    async fn say(&mut self, text: &str) -> Result<(), String> {
+        match self.protocol {
+            Protocol::Text => self.send(text).await,
+            Protocol::Json => {
+                let line = json!({"type": "message", "body": text}).to_string();
+                self.conn.send(line).await.map_err(|e| format!("send: {e}"))
+            }
+        }
+    }
+
+    /// Next line, skipping presence notices, pings and history replay,
+    /// which can arrive at any point.
+    async fn recv(&mut self) -> Result<String, String> {
+        loop {
+            let line = match time::timeout(REPLY_TIMEOUT, self.conn.next()).await {
+                Ok(Some(Ok(line))) => line,
+                Ok(Some(Err(e))) => return Err(format!("read: {e}")),
+                Ok(None) => return Err("connection closed".to_string()),
+                Err(_) => return Err("timed out waiting for a reply".to_string()),
+            };
+            if !self.unsolicited(&line) {
+                return Ok(line);
+            }
+        }
+    }
+
+    /// Waits for `want`, given as a text line, in the probe's protocol.
+    async fn expect(&mut self, want: &str) -> Result<(), String> {
+        let got = self.recv().await?;
+        let got = self.unstamped(&got);
+        if got == self.wire(want) {
+            Ok(())
+        } else {
+            Err(format!("expected {:?}, got {got:?}", self.wire(want)))
+        }
+    }
+
+    async fn expect_quiet(&mut self) -> Result<(), String> {
+        let deadline = time::Instant::now() + QUIET_PERIOD;
+        loop {
+            match time::timeout_at(deadline, self.conn.next()).await {
+                Err(_) => return Ok(()),
+                Ok(Some(Ok(line))) if self.unsolicited(&line) => {}
+                Ok(Some(Ok(line))) => return Err(format!("unexpected {line:?}")),
+                Ok(_) => return Err("connection closed".to_string()),
+            }
+        }
+    }
+
+    /// A line without the ID and time a server stamping messages gives
+    /// them, which can't be predicted.
+    fn unstamped(&self, line: &str) -> String {
+        match self.protocol {
+            Protocol::Text => stamp::unstamped(line).into_owned(),
+            Protocol::Json => match serde_json::from_str::<Value>(line) {
+                Ok(Value::Object(mut envelope)) => {
+                    envelope.remove("msg_id");
+                    envelope.remove("ts");
+                    Value::Object(envelope).to_string()
+                }
+                _ => line.to_string(),
+            },
+        }
+    }
+
+    /// A text line as the server sends it in the probe's protocol.
+    fn wire(&self, line: &str) -> String {
+        let line = self.protocol.encode(Bytes::from(format!("{line}\n")));
+        String::from_utf8_lossy(&line).trim_end().to_string()
+    }
+
+    /// The text of a `LOGIN` line in either protocol.
+    fn text(&self, line: &str) -> Option<String> {
+        match self.protocol {
+            Protocol::Text => Some(line.to_string()),
+            Protocol::Json => {
+                let envelope: Value = serde_json::from_str(line).ok()?;
+                (envelope["type"] == "login").then(|| format!("LOGIN:{}", envelope["id"]))
+            }
+        }
+    }
+
+    fn unsolicited(&self, line: &str) -> bool {
+        match self.protocol {
+            Protocol::Text => ["JOINED:", "LEFT:", "PRESENCE:", "HISTORY:"].iter().any(|p| line.starts_with(p)) || line == "PING",
+            Protocol::Json => serde_json::from_str::<Value>(line).is_ok_and(|envelope| {
+                envelope["history"] == true || ["joined", "left", "presence", "ping"].iter().any(|t| envelope["type"] == *t)
+            }),
+        }
+    }
+}
+
+/// The JSON envelope for one of the commands the checks send.
+fn envelope(line: &str) -> Value {
+    match line.split_once(':') {
+        Some(("JOIN", room)) => json!({"type": "join", "room": room}),
+        Some(("AUTH", credentials)) => match credentials.split_once(' ') {
+            Some((user, password)) => json!({"type": "auth", "user": user, "password": password}),
+            None => json!({"type": "auth", "token": credentials}),
+        },
+        _ => json!({"type": line.to_ascii_lowercase()}),
+    }
+}
+
+async fn login(plan: &Plan) -> Result<(), String> {
+    let a = Probe::connect(plan).await?;
+    let b = Probe::connect(plan).await?;
+    if a.id == b.id {
+        return Err(format!("two clients got the same id {}", a.id));
+    }
+    Ok(())
+}
+
+async fn auth_refused(plan: &Plan) -> Result<(), String> {
+    let mut a = Probe::greeted(plan).await?;
+    a.send("AUTH:selftest-wrong-credentials").await?;
+    a.expect("ERROR:AUTH_FAILED").await
+}
+
+async fn broadcast_and_ack(plan: &Plan) -> Result<(), String> {
+    let mut a = Probe::connect(plan).await?;
+    let mut b = Probe::connect(plan).await?;
+    let mut c = Probe::connect(plan).await?;
+    a.say("selftest hello").await?;
+    a.expect("ACK:MESSAGE").await?;
+    b.expect(&format!("MESSAGE:{} selftest hello", a.id)).await?;
+    c.expect(&format!("MESSAGE:{} selftest hello", a.id)).await?;
+    a.expect_quiet().await
+}
+
+async fn rooms(plan: &Plan) -> Result<(), String> {
+    let mut a = Probe::connect(plan).await?;
+    let mut b = Probe::connect(plan).await?;
+    let mut lobby = Probe::connect(plan).await?;
+    for p in [&mut a, &mut b] {
+        p.send(&format!("JOIN:{ROOM}")).await?;
+        p.expect(&format!("ACK:JOIN {ROOM}")).await?;
+    }
+    a.say("in the room").await?;
+    a.expect("ACK:MESSAGE").await?;
+    b.expect(&format!("MESSAGE:{} in the room", a.id)).await?;
+    lobby.expect_quiet().await
+}
+
+async fn ping(plan: &Plan) -> Result<(), String> {
+    let mut a = Probe::connect(plan).await?;
+    a.send("PING").await?;
+    a.expect("PONG").await
+}
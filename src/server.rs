@@ -0,0 +1,3034 @@
+//! The broadcast server: connection state and the select loop driving it.
+//! Commands other than messages, operator commands, receipts and ingestion
+//! from outside client connections live in the submodules.
+
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{FutureExt, Stream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{self, MissedTickBehavior};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::{StreamExt, StreamMap};
+use tokio_util::codec::FramedRead;
+use tokio_util::time::DelayQueue;
+use tracing::{error, info, info_span, warn, Span};
+
+use crate::access::AccessList;
+use crate::alert::{AlertConfig, Alerter, Condition};
+use crate::anomaly::{AnomalyConfig, AnomalyDetector};
+use crate::auth::AuthConfig;
+use crate::blobs::{BlobConfig, BlobStore};
+use crate::codec::{InputCodec, LineTooLong};
+use crate::compress::Compression;
+use crate::conn::{self, Conn, ReadHalf, Transport};
+use crate::dedup::Dedup;
+use crate::echo::Echoes;
+use crate::envelope::{self, Inbound, Protocol};
+use crate::fair::{FairQueue, Fairness};
+use crate::filter::{FilterConfig, Filters};
+use crate::frame::{Frame, MessageAction, MessageFilter};
+use crate::history::History;
+use crate::idle::{IdleConfig, IdlePolicy, Verdict};
+use crate::info;
+use crate::inject::{Inbox, Injector};
+use crate::journal::{self, Journal};
+use crate::logging::{self, LogFormat, LogLevel};
+use crate::metrics::{LatencyHistogram, SizeStats};
+use crate::net::{self, SocketOptions};
+use crate::panics::{self, CatchUnwind, Panicked};
+use crate::peer::{self, Cluster, PeerConfig, Relay};
+use crate::presence::{Presence, PresenceConfig};
+use crate::prometheus::{self, Latency, Snapshot};
+use crate::protocol::{self, sanitize_payload, Command, Maintenance, Setting};
+use crate::proxy;
+use crate::redis::{self, Bridge, RedisConfig};
+use crate::regions::{Regions, Rtts};
+use crate::registry::{ClientId, ClientRegistry};
+use crate::replay::{ReplayConfig, Replays};
+use crate::rooms::{Held, Room, MAX_HELD};
+use crate::sampling::LogSampler;
+use crate::sessions::{Reason, Summary, Webhook};
+use crate::shed::{self, Change, ShedConfig, Shedder};
+use crate::stamp::{self, Stamper};
+use crate::systemd;
+use crate::tarpit::{TarpitConfig, TarpitStats, Throttled};
+use crate::tls::{TlsAcceptor, TlsConfig};
+use crate::udp::{self, UdpIngest};
+use crate::unix::{self, UnixSocket, UNIX_PEER};
+use crate::violations::{Response, ViolationPolicy};
+use crate::writer::{Audience, ClientWriter, Fanout, LatencyBudget, Output, SendError, SlowConsumer};
+
+mod commands;
+mod ingest;
+mod maintenance;
+mod receipts;
+
+use ingest::{Internal, UDP_BURST, UDP_RATE};
+use receipts::{IngestState, Receipt};
+
+/// How often writers are checked for having fallen a whole feed behind.
+const CONSUMER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often writers' progress is checked against messages awaiting a
+/// delivery receipt.
+const RECEIPT_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often clients are checked for having gone quiet, when some
+/// listener's idle policy isn't off.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often paced history replays are given their next pages.
+const REPLAY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often clients are checked for having gone idle or away.
+const PRESENCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the event loop checks its own responsiveness.
+const LAG_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lines queued for fair scheduling before the loop stops reading more.
+const FAIR_QUEUE_LIMIT: usize = 256;
+/// Lines one sender may have queued before the loop stops reading from
+/// it until it's been served, leaving the rest of the queue to others.
+const READ_BUDGET: usize = 16;
+
+/// Which writes may sit in buffers until the flush tick instead of being
+/// flushed straight away.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Batching {
+    Never,
+    /// Only broadcasts from (and acks to) ingest-mode producers.
+    Ingest,
+    /// Every broadcast and ack.
+    All,
+}
+
+/// Per-client limit on inbound lines, messages and commands alike (`PONG`
+/// isn't counted).
+#[derive(Clone)]
+pub struct RateLimit {
+    /// Sustained lines per second.
+    pub rate: f64,
+    /// Lines that may arrive at once after a quiet spell.
+    pub burst: f64,
+    /// Rejected lines at which the client is disconnected; one is
+    /// forgiven per housekeeping tick.
+    pub disconnect_after: u32,
+}
+
+impl RateLimit {
+    /// `rate` lines a second, in bursts of up to twice that.
+    pub fn per_second(rate: f64) -> Self {
+        Self { rate, burst: rate * 2.0, disconnect_after: 50 }
+    }
+}
+
+/// Buffering and batching knobs that trade latency against throughput.
+pub struct Tuning {
+    pub batching: Batching,
+    pub flush_interval: Duration,
+    pub read_buffer: usize,
+    pub write_buffer: usize,
+    /// Log a delivery-latency summary on the housekeeping tick.
+    pub report_latency: bool,
+    /// Rooms with at most this many members have their broadcasts queued
+    /// for each member directly, rather than put on the feed every writer
+    /// reads; 0 never does.
+    pub small_room: usize,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            batching: Batching::Ingest,
+            flush_interval: Duration::from_millis(20),
+            read_buffer: 8 * 1024,
+            write_buffer: 8 * 1024,
+            report_latency: false,
+            small_room: 0,
+        }
+    }
+}
+
+impl Tuning {
+    /// `--low-latency`: flush every write, settle ack ranges almost
+    /// immediately, keep per-connection buffers small and send small
+    /// rooms' broadcasts straight to their members.
+    pub fn low_latency() -> Self {
+        Self {
+            batching: Batching::Never,
+            flush_interval: Duration::from_millis(1),
+            read_buffer: 1024,
+            write_buffer: 1024,
+            report_latency: true,
+            small_room: 8,
+        }
+    }
+
+    /// `--throughput`: big buffers, and every write left for the flush tick
+    /// so each socket sees a few large writes rather than many small ones.
+    pub fn throughput() -> Self {
+        Self {
+            batching: Batching::All,
+            flush_interval: Duration::from_millis(5),
+            read_buffer: 64 * 1024,
+            write_buffer: 64 * 1024,
+            report_latency: false,
+            small_room: 0,
+        }
+    }
+}
+
+/// Everything configurable about a server.
+pub struct Config {
+    pub socket: SocketOptions,
+    pub tuning: Tuning,
+    pub anomaly: AnomalyConfig,
+    pub tarpit: TarpitConfig,
+    pub violations: ViolationPolicy,
+    pub fairness: Fairness,
+    /// Window for collapsing repeated messages; `None` relays every copy.
+    pub dedup_window: Option<Duration>,
+    /// How long a message sent out through a bridge (see
+    /// `AuthConfig::bridges`) is dropped if the bridge sends it back;
+    /// `None` lets echoes through.
+    pub echo_window: Option<Duration>,
+    /// Messages at least this many bytes long are compressed for clients
+    /// that ask with `CAPS:compress=`; `None` doesn't offer compression.
+    /// Line protocol only.
+    pub compress_min_bytes: Option<usize>,
+    /// Gives every message an ID and a timestamp, in its tag. Off by
+    /// default, as it changes the format of `MESSAGE:` lines.
+    pub stamp_messages: bool,
+    pub alert: AlertConfig,
+    /// When to start shedding load; off by default.
+    pub shed: ShedConfig,
+    /// Latency regions to sort clients into by their round-trip time,
+    /// measured as they log in; none measures nothing.
+    pub regions: Regions,
+    /// Where a JSON summary of each client's session is POSTed when it
+    /// ends; `None` sends none.
+    pub session_webhook: Option<String>,
+    /// Lines that may wait in a client's send queue.
+    pub send_queue: usize,
+    /// What happens to a client whose send queue is full, or whose lines
+    /// take too long to deliver.
+    pub slow_consumer: SlowConsumer,
+    /// Most connections accepted per wakeup of the accept arm, so a burst
+    /// of connects is taken in a few turns without starving client input.
+    pub accept_batch: usize,
+    /// Every connection on a client listener starts with a PROXY protocol
+    /// header (version 1 or 2) from a load balancer, whose client address
+    /// stands in for the socket's. Connections without one are dropped.
+    pub proxy_protocol: bool,
+    /// Serve TLS instead of plain TCP (on the WebSocket port too).
+    pub tls: Option<TlsConfig>,
+    /// Also accept WebSocket clients on this port, same address.
+    pub ws_port: Option<u16>,
+    /// Also accept clients speaking length-prefixed frames on this port,
+    /// same address.
+    pub framed_port: Option<u16>,
+    /// Publish each line of the datagrams arriving on this UDP port, same
+    /// address, to the lobby.
+    pub udp_port: Option<u16>,
+    /// Serve Prometheus metrics at `/metrics` over HTTP on this port, same
+    /// address.
+    pub metrics_port: Option<u16>,
+    /// Also accept line-protocol clients on a Unix domain socket at this
+    /// path, removed again on shutdown.
+    pub unix_socket: Option<PathBuf>,
+    /// Links to other servers, which messages are shared with.
+    pub peers: PeerConfig,
+    /// Share messages with other instances through a Redis channel.
+    pub redis: Option<RedisConfig>,
+    /// Recent messages kept per room (and for the lobby) to replay to
+    /// newcomers; 0 keeps none. Capped at half the send queue so a replay
+    /// can't overflow it.
+    pub history: usize,
+    /// How fast history is replayed, so replays after a mass reconnect
+    /// leave room for live messages.
+    pub replay: ReplayConfig,
+    /// Append every broadcast message to this file as JSON lines, and seed
+    /// the lobby's history from its tail on startup.
+    pub log_file: Option<PathBuf>,
+    /// Hash-chain the message log's entries, so edits to it show; see
+    /// `tcp-broadcast verify-log`.
+    pub log_chain: bool,
+    /// Word lists, and the rooms whose messages are checked against them.
+    pub filters: FilterConfig,
+    /// Offload payloads over a size threshold to disk and broadcast a
+    /// `BLOBREF` in their place.
+    pub blobs: Option<BlobConfig>,
+    /// On shutdown, how long clients get to receive what was already sent
+    /// before they're cut off.
+    pub drain_timeout: Duration,
+    /// How long a new connection gets to send its PROXY header, and to
+    /// finish a TLS handshake or WebSocket upgrade, before it's dropped.
+    pub handshake_timeout: Duration,
+    /// Disconnect a client when one write to it (or flush) takes longer
+    /// than this; writes may take as long as they like when `None`.
+    pub write_timeout: Option<Duration>,
+    /// Send clients their own messages back, as well as the ack, until
+    /// they send `SET:echo=off`.
+    pub echo_to_sender: bool,
+    /// What happens to clients that go quiet, per listener: pinged,
+    /// reaped, or left alone.
+    pub idle: IdleConfig,
+    /// Send a client that has been sent nothing this long a `NOOP` (a
+    /// WebSocket ping on that listener), so middleboxes keep its
+    /// connection open.
+    pub noop_interval: Option<Duration>,
+    /// Mark quiet clients idle or away, and tell their rooms.
+    pub presence: PresenceConfig,
+    /// Credentials clients must present before `LOGIN`; without any,
+    /// everyone is let in.
+    pub auth: AuthConfig,
+    /// Address ranges that may connect; everyone, by default.
+    pub access: AccessList,
+    /// Most clients at once, counting connections still in a handshake or
+    /// tarpit; more are sent `ERROR:SERVER_FULL` and closed.
+    pub max_clients: Option<usize>,
+    /// Reject lines over this rate with `ERROR:RATE_LIMITED`.
+    pub rate_limit: Option<RateLimit>,
+    /// Longest line a client may send, in bytes; a longer one gets
+    /// `ERROR:LINE_TOO_LONG` and a disconnect.
+    pub max_line: usize,
+    /// Wire format for every client: text lines, or JSON envelopes.
+    pub protocol: Protocol,
+    /// Broker direct connections: two clients that both send `DIRECT:`
+    /// for each other are told each other's address.
+    pub direct: bool,
+    /// Events written by the subscriber installed when the server starts;
+    /// neither this nor `log_format` applies if the application installed
+    /// its own.
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            socket: SocketOptions::default(),
+            tuning: Tuning::default(),
+            anomaly: AnomalyConfig::default(),
+            tarpit: TarpitConfig::default(),
+            violations: ViolationPolicy::default(),
+            fairness: Fairness::RoundRobin,
+            dedup_window: None,
+            echo_window: None,
+            compress_min_bytes: None,
+            stamp_messages: false,
+            alert: AlertConfig::default(),
+            shed: ShedConfig::default(),
+            regions: Regions::default(),
+            session_webhook: None,
+            send_queue: 1024,
+            slow_consumer: SlowConsumer::Disconnect,
+            accept_batch: 16,
+            proxy_protocol: false,
+            tls: None,
+            ws_port: None,
+            framed_port: None,
+            udp_port: None,
+            metrics_port: None,
+            unix_socket: None,
+            peers: PeerConfig::default(),
+            redis: None,
+            history: 0,
+            replay: ReplayConfig::default(),
+            log_file: None,
+            log_chain: false,
+            blobs: None,
+            filters: FilterConfig::default(),
+            drain_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(10),
+            write_timeout: None,
+            echo_to_sender: false,
+            idle: IdleConfig::default(),
+            noop_interval: None,
+            presence: PresenceConfig::default(),
+            auth: AuthConfig::default(),
+            access: AccessList::default(),
+            max_clients: None,
+            rate_limit: None,
+            max_line: 1024 * 1024,
+            protocol: Protocol::Text,
+            direct: false,
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+/// The settings a running server can take new values of, all at once;
+/// see [`BroadcastServer::reloads`]. The rest of [`Config`] only changes
+/// on a restart.
+pub struct Reload {
+    pub access: AccessList,
+    pub max_clients: Option<usize>,
+    pub rate_limit: Option<RateLimit>,
+    pub log_level: LogLevel,
+}
+
+impl Reload {
+    /// The reloadable part of `config`.
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            access: config.access.clone(),
+            max_clients: config.max_clients,
+            rate_limit: config.rate_limit.clone(),
+            log_level: config.log_level,
+        }
+    }
+}
+
+/// Ephemeral events (typing indicators etc.) a client may burst...
+const EVENT_BURST: f64 = 5.0;
+/// ...and the sustained rate it may send them at, per second.
+const EVENT_RATE: f64 = 1.0;
+
+/// Presence changes a client may have announced in a burst...
+const PRESENCE_BURST: f64 = 4.0;
+/// ...and the sustained rate, per second. A change over budget is
+/// announced once the budget allows, if it still holds by then.
+const PRESENCE_RATE: f64 = 0.1;
+
+/// Token bucket that limits how often a sender may do something: a
+/// client's ephemeral events, an internal identity's messages.
+struct Budget {
+    tokens: f64,
+    last: Instant,
+    burst: f64,
+    rate: f64,
+}
+
+impl Budget {
+    fn new(now: Instant, burst: f64, rate: f64) -> Self {
+        Self { tokens: burst, last: now, burst, rate }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Map of client_id -> stream of input lines, polled together by the main loop.
+type Inputs = StreamMap<ClientId, Input>;
+
+/// A client's input lines, then one `None` once the client closes its end;
+/// `StreamMap` would otherwise drop the finished stream without a word.
+struct Input {
+    lines: CatchUnwind<Throttled<FramedRead<ReadHalf, InputCodec>>>,
+    ended: bool,
+}
+
+impl Stream for Input {
+    type Item = Option<io::Result<Bytes>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+        let item = futures::ready!(Pin::new(&mut self.lines).poll_next(cx));
+        self.ended = item.is_none();
+        Poll::Ready(Some(item))
+    }
+}
+
+/// A PROXY protocol header read, or not, reported back from its task.
+struct Proxied {
+    stream: TcpStream,
+    /// The socket's own peer, the load balancer
+    peer: SocketAddr,
+    transport: Transport,
+    /// The client's address
+    result: io::Result<SocketAddr>,
+}
+
+/// A finished TLS handshake or WebSocket upgrade, reported back from its task.
+struct Handshake {
+    peer: SocketAddr,
+    transport: Transport,
+    throttle: Option<Duration>,
+    result: io::Result<Conn>,
+}
+
+/// Server-wide counters that client removal has to keep in step.
+#[derive(Default)]
+struct Counters {
+    /// Clients in ingest mode; the flush tick only runs while this is non-zero.
+    ingesting: usize,
+    tarpit: TarpitStats,
+    /// Panics caught in a client's reader, writer or hooks, since startup.
+    panics: u64,
+    /// Totals since startup for the metrics endpoint; a client's own are
+    /// added in when it goes.
+    connections: u64,
+    disconnects: u64,
+    messages_in: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    write_errors: u64,
+    slow_consumers: u64,
+    dropped_lines: u64,
+    /// Broadcasts sent straight to a small room's members, not counted in
+    /// `fed`.
+    direct: u64,
+}
+
+/// A connected client's outbound side and bookkeeping.
+struct Client {
+    writer: ClientWriter,
+    /// Has its `LOGIN`: authenticated, or auth is off.
+    authed: bool,
+    /// Authenticated as an admin user, so may run operator commands.
+    admin: bool,
+    /// Speaks length-prefixed frames, so can send and receive binary messages.
+    framed: bool,
+    /// Came in on the WebSocket listener, so keepalives are pings.
+    websocket: bool,
+    /// Bytes its writer had sent when last looked at, and since when.
+    sent: (u64, Instant),
+    /// Broadcasts already sent when the client subscribed.
+    fed_before: u64,
+    /// Set once the client negotiated ingest mode.
+    ingest: Option<IngestState>,
+    /// Ephemeral events over budget are dropped silently; they're
+    /// advisory anyway.
+    event_budget: Budget,
+    /// Reads are throttled because the connection was tarpitted.
+    tarpitted: bool,
+    /// Outstanding protocol violations, forgiven one per housekeeping tick.
+    violations: u32,
+    /// Inbound message budget, when rate limiting is on.
+    rate: Option<Budget>,
+    /// Messages rejected by the rate limit, forgiven one per housekeeping
+    /// tick.
+    rate_strikes: u32,
+    /// Run of repeated messages, when collapsing is on.
+    dedup: Option<Dedup>,
+    /// What was sent out through the client, if it's a bridge and echoes
+    /// are suppressed.
+    echoes: Option<Echoes>,
+    /// How the client asked for large messages to be compressed.
+    compression: Option<Compression>,
+    /// An admin locked its room and subscriptions with `SET:`.
+    locked: bool,
+    /// Gets its own messages back (`SET:echo=on`).
+    echo: bool,
+    /// Gets its messages acked (`SET:ack=off` to stop).
+    acks: bool,
+    /// The language it writes in (`SET:lang=`), for message hooks.
+    lang: Option<Arc<str>>,
+    /// How it authenticated, for its session summary.
+    identity: Option<String>,
+    connected: Instant,
+    /// Current room; `None` is the lobby.
+    room: Option<Arc<str>>,
+    /// The client this one has sent `DIRECT:` to, waiting for it to answer.
+    direct_offer: Option<ClientId>,
+    /// When the client last sent a message, for slow mode.
+    last_message: Option<Instant>,
+    /// When the client last sent anything, commands and `PONG` included.
+    last_heard: Instant,
+    /// When it was sent a `PING` it hasn't answered yet.
+    pinged: Option<Instant>,
+    /// When it was sent the `PING` timing its round trip, until answered.
+    rtt_probe: Option<Instant>,
+    /// Its round-trip time, once measured.
+    rtt: Option<Duration>,
+    /// Its listener's idle policy.
+    idle: IdlePolicy,
+    /// When the client last sent anything but a `PONG`.
+    last_active: Instant,
+    /// Presence its room was last told about.
+    presence: Presence,
+    presence_budget: Budget,
+    /// Inbound totals, logged when the client goes away.
+    messages_in: u64,
+    bytes_in: u64,
+    /// Its `client` span, entered while the client is being dealt with.
+    span: Span,
+    /// Since when its lines have been over the latency budget, under
+    /// `SlowConsumer::Latency`.
+    late_since: Option<Instant>,
+    /// Sequence number of its last numbered message; the next must be
+    /// higher.
+    last_seq: u64,
+    /// Sent `RECEIPTS:ON`.
+    receipts: bool,
+    /// Has a `BARRIER` waiting for its answer.
+    barrier: bool,
+    /// Lines it had missed to the slow-consumer policy when that was last
+    /// logged.
+    dropped_reported: u64,
+}
+
+impl Client {
+    /// A setting from `SET:`, but `room`: moving rooms takes more than
+    /// the client's own state.
+    fn apply(&mut self, setting: Setting<'_>) {
+        match setting {
+            Setting::Room(_) => {}
+            Setting::Accept(types) => {
+                self.writer.set_accept(types.map(|types| types.split(',').map(|t| Bytes::copy_from_slice(t.as_bytes())).collect()))
+            }
+            Setting::Events(on) => self.writer.set_events(on),
+            Setting::Echo(on) => self.echo = on,
+            Setting::Ack(on) => self.acks = on,
+            Setting::Receipts(on) => self.receipts = on,
+            Setting::Lang(tag) => self.lang = tag.map(Into::into),
+            Setting::Lock(on) => self.locked = on,
+        }
+    }
+
+    /// A setting's value as `GET:` shows it, for a key in
+    /// [`protocol::SETTINGS`].
+    fn setting(&self, key: &str) -> String {
+        let on = |on: bool| if on { "on" } else { "off" }.to_string();
+        match key {
+            "room" => self.room.as_deref().unwrap_or("-").to_string(),
+            "accept" => self.writer.accept(),
+            "events" => on(self.writer.events()),
+            "echo" => on(self.echo),
+            "ack" => on(self.acks),
+            "receipts" => on(self.receipts),
+            "lang" => self.lang.as_deref().unwrap_or("-").to_string(),
+            _ => on(self.locked),
+        }
+    }
+}
+
+type ConnectHook = Box<dyn FnMut(ClientId, SocketAddr)>;
+type MessageHook = Box<dyn FnMut(&mut Frame<'_>)>;
+type ShutdownSignal = Pin<Box<dyn Future<Output = ()>>>;
+type AccessUpdates = Pin<Box<dyn Stream<Item = AccessList>>>;
+type Reloads = Pin<Box<dyn Stream<Item = Reload>>>;
+
+/// Callbacks an embedding application can hook into the server with.
+#[derive(Default)]
+struct Hooks {
+    on_connect: Option<ConnectHook>,
+    on_message: Option<MessageHook>,
+    filters: Vec<Box<dyn MessageFilter>>,
+    inbox: Inbox,
+    shutdown: Option<ShutdownSignal>,
+    access_updates: Option<AccessUpdates>,
+    reloads: Option<Reloads>,
+}
+
+/// A broadcast server, configured builder-style and then `run()`.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use tcp_broadcast::BroadcastServer;
+///
+/// BroadcastServer::bind(([127, 0, 0, 1], 8888))
+///     .on_connect(|id, peer| println!("{id} joined from {peer}"))
+///     .on_message(|frame| println!("{} said {}", frame.sender, frame.text))
+///     .run()
+///     .await
+/// # }
+/// ```
+pub struct BroadcastServer {
+    addr: SocketAddr,
+    /// More addresses for line-protocol clients, in the same broadcast
+    /// domain.
+    also: Vec<SocketAddr>,
+    config: Config,
+    hooks: Hooks,
+}
+
+impl BroadcastServer {
+    /// A server that will listen on `addr` with the default configuration.
+    pub fn bind(addr: impl Into<SocketAddr>) -> Self {
+        Self { addr: addr.into(), also: Vec::new(), config: Config::default(), hooks: Hooks::default() }
+    }
+
+    /// Also listens on `addr` (another interface, an IPv6 address, another
+    /// port). Clients from every listener share rooms, presence and
+    /// history as if they'd all connected to the same one. WebSocket and
+    /// framed ports stay on the address given to `bind`.
+    pub fn also_bind(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.also.push(addr.into());
+        self
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Called with the client id and peer address once a client has its
+    /// `LOGIN`. Unix socket clients have `0.0.0.0:0` for an address.
+    pub fn on_connect(mut self, hook: impl FnMut(ClientId, SocketAddr) + 'static) -> Self {
+        self.hooks.on_connect = Some(Box::new(hook));
+        self
+    }
+
+    /// Called for every message received (commands excluded) before it's
+    /// broadcast. Hooks may annotate the frame; annotations stay with it
+    /// for the rest of its way through the server.
+    pub fn on_message(mut self, hook: impl FnMut(&mut Frame<'_>) + 'static) -> Self {
+        self.hooks.on_message = Some(Box::new(hook));
+        self
+    }
+
+    /// Adds a filter that may drop, rewrite or annotate each text message
+    /// after `on_message` hooks and before the room's word filters.
+    /// Filters run in the order they were added, each seeing the text as
+    /// the ones before left it; the first to drop a message ends it.
+    ///
+    /// ```no_run
+    /// # async fn example() -> std::io::Result<()> {
+    /// use tcp_broadcast::{BroadcastServer, MessageAction};
+    ///
+    /// BroadcastServer::bind(([127, 0, 0, 1], 8888))
+    ///     .filter(|_, line: &str| if line.len() > 200 { MessageAction::Drop } else { MessageAction::Pass })
+    ///     .filter(|_, line: &str| MessageAction::Rewrite(line.replace("darn", "****")))
+    ///     .run()
+    ///     .await
+    /// # }
+    /// ```
+    pub fn filter(mut self, filter: impl MessageFilter + 'static) -> Self {
+        self.hooks.filters.push(Box::new(filter));
+        self
+    }
+
+    /// A handle for publishing messages as `name`, an identity of the
+    /// server's own (a bot, say) rather than a connected client. The name
+    /// is reserved as a nickname when the server starts. Messages get the
+    /// same treatment as a client's, including a rate limit of their own,
+    /// except that they don't go through `on_message`: a hook that replies
+    /// with an injector can't end up answering itself.
+    ///
+    /// ```no_run
+    /// # async fn example() -> std::io::Result<()> {
+    /// use tcp_broadcast::BroadcastServer;
+    ///
+    /// let mut server = BroadcastServer::bind(([127, 0, 0, 1], 8888));
+    /// let greeter = server.injector("greeter");
+    /// server
+    ///     .on_connect(move |id, _| greeter.publish(format!("welcome, {id}")))
+    ///     .run()
+    ///     .await
+    /// # }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `name` isn't a valid nickname.
+    pub fn injector(&mut self, name: &str) -> Injector {
+        self.hooks.inbox.injector(name)
+    }
+
+    /// Shuts down gracefully once `signal` completes: stops accepting,
+    /// handles lines already read, sends every client `SERVER:SHUTDOWN`
+    /// and gives writers up to `Config::drain_timeout` to deliver what
+    /// they have, then `run` returns. Without one the server runs until
+    /// the process is killed.
+    pub fn shutdown_on(mut self, signal: impl Future<Output = ()> + 'static) -> Self {
+        self.hooks.shutdown = Some(Box::pin(signal));
+        self
+    }
+
+    /// Replaces `Config::access` with each list `updates` yields, to
+    /// reload it without a restart. Clients already connected stay.
+    pub fn access_updates(mut self, updates: impl Stream<Item = AccessList> + 'static) -> Self {
+        self.hooks.access_updates = Some(Box::pin(updates));
+        self
+    }
+
+    /// Applies each [`Reload`] `reloads` yields, to change limits, access
+    /// lists and the log level without a restart. Clients already
+    /// connected stay, even over a lower `max_clients` or outside the new
+    /// access lists; a new rate limit applies to them straight away, each
+    /// starting with a full burst.
+    pub fn reloads(mut self, reloads: impl Stream<Item = Reload> + 'static) -> Self {
+        self.hooks.reloads = Some(Box::pin(reloads));
+        self
+    }
+
+    /// Binds the listeners and serves clients until they fail for good.
+    /// Under systemd socket activation the listeners it passed are served
+    /// instead, the first in place of `bind`'s and the rest as `also_bind`
+    /// ones, and the addresses given to those are ignored.
+    ///
+    /// Connection state lives in this future, which isn't `Send` (hooks
+    /// needn't be), so drive it with `block_on`/`#[tokio::main]` rather than
+    /// spawning it. Client writers are spawned onto the runtime's workers.
+    pub async fn run(self) -> io::Result<()> {
+        if let Some(mut inherited) = systemd::listeners()? {
+            let listener = inherited.remove(0);
+            return self.serve_all(listener, inherited, true).await;
+        }
+        let listener = net::bind(self.addr, &self.config.socket)?;
+        let also = self.also.iter().map(|&addr| net::bind(addr, &self.config.socket)).collect::<io::Result<_>>()?;
+        self.serve_all(listener, also, false).await
+    }
+
+    /// Like [`run`](Self::run), on a listener bound elsewhere (port 0 in
+    /// tests and benchmarks, say). The addresses given to `bind` and
+    /// `also_bind` are ignored.
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        self.serve_all(listener, Vec::new(), false).await
+    }
+
+    async fn serve_all(mut self, listener: TcpListener, also: Vec<TcpListener>, inherited: bool) -> io::Result<()> {
+        logging::init(self.config.log_level, self.config.log_format);
+        info::check(&self.config)?;
+        if self.config.alert.room.as_deref().is_some_and(|room| !protocol::valid_room(room)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid alert room"));
+        }
+        let from = if inherited { " (from systemd)" } else { "" };
+        info!("listening on {}{from}", listener.local_addr()?);
+        for listener in &also {
+            info!("also listening on {}{from}", listener.local_addr()?);
+        }
+        info!("socket options {}", self.config.socket.describe());
+        let tls = self.config.tls.as_ref().map(TlsConfig::acceptor).transpose()?;
+        if let Some(config) = &self.config.tls {
+            info!("tls {}", config.describe());
+        }
+        let mut listeners = Vec::new();
+        listeners.extend(also.into_iter().map(|also| (Transport::Tcp, also)));
+        if let Some(port) = self.config.ws_port {
+            let ws = net::bind((listener.local_addr()?.ip(), port).into(), &self.config.socket)?;
+            info!("websocket listening on port {}", ws.local_addr()?.port());
+            listeners.push((Transport::WebSocket, ws));
+        }
+        if let Some(port) = self.config.framed_port {
+            let framed = net::bind((listener.local_addr()?.ip(), port).into(), &self.config.socket)?;
+            info!("framed listening on port {}", framed.local_addr()?.port());
+            listeners.push((Transport::Framed, framed));
+        }
+        let udp = match self.config.udp_port {
+            Some(port) => {
+                let udp = UdpIngest::bind((listener.local_addr()?.ip(), port).into()).await?;
+                info!("udp listening on port {}", udp.local_addr()?.port());
+                Some(udp)
+            }
+            None => None,
+        };
+        let unix = self.config.unix_socket.as_deref().map(UnixSocket::bind).transpose()?;
+        if let Some(unix) = &unix {
+            info!("unix socket listening on {}", unix.path().display());
+        }
+        let metrics = match self.config.metrics_port {
+            Some(port) => {
+                let metrics = net::bind((listener.local_addr()?.ip(), port).into(), &self.config.socket)?;
+                info!("metrics listening on port {}", metrics.local_addr()?.port());
+                Some(metrics)
+            }
+            None => None,
+        };
+        let server_id = peer::server_id(&self.config.peers)?;
+        if self.config.peers.node_id.is_some_and(|node| node > stamp::MAX_NODE) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("node id over {}", stamp::MAX_NODE)));
+        }
+        let node = self.config.peers.node_id.unwrap_or_else(|| stamp::node(&server_id));
+        if self.config.stamp_messages {
+            // A derived node number can be another server's too
+            let clustered = self.config.peers.enabled() || self.config.redis.is_some();
+            match self.config.peers.node_id {
+                None if clustered => warn!("message ids node={node} from the server id; give each server its own node id"),
+                _ => info!("message ids node={node}"),
+            }
+        }
+        self.config.peers.node_id = Some(node);
+        let cluster = match self.config.peers.enabled() {
+            true => Some(Cluster::start(&self.config.peers, server_id.clone(), self.config.max_line)),
+            false => None,
+        };
+        let bridge = self.config.redis.as_ref().map(|redis| {
+            info!("redis bridge {} channel={} server_id={server_id}", redis.addr, redis.channel);
+            Bridge::start(redis, server_id, self.config.max_line)
+        });
+        let peers = match (&cluster, self.config.peers.port) {
+            (Some(cluster), Some(port)) => {
+                let peers = net::bind((listener.local_addr()?.ip(), port).into(), &self.config.socket)?;
+                info!("peers listening on port {} server_id={}", peers.local_addr()?.port(), cluster.id());
+                Some(peers)
+            }
+            (Some(cluster), None) => {
+                info!("peering server_id={}", cluster.id());
+                None
+            }
+            _ => None,
+        };
+        let filters = Filters::new(&self.config.filters)?;
+        if !self.config.filters.rooms.is_empty() {
+            info!("room filters lists={} rooms={}", self.config.filters.lists.len(), self.config.filters.rooms.len());
+        }
+        let blobs = self.config.blobs.as_ref().map(BlobStore::open).transpose()?;
+        if let Some(blobs) = &blobs {
+            info!("blobs {}", blobs.describe());
+        }
+        let journal = match &self.config.log_file {
+            Some(path) => {
+                let recent = journal::load_lobby(path, self.config.history.min(self.config.send_queue / 2))?;
+                info!("message log {} replayed={}", path.display(), recent.len());
+                Some((Journal::open(path, self.config.log_chain)?, recent))
+            }
+            None => None,
+        };
+        let server = Server::new(self.config, self.hooks, tls, listeners, journal, blobs, filters, cluster, bridge);
+        server.run(listener, unix, udp, metrics, peers).await
+    }
+}
+
+struct Server {
+    socket: SocketOptions,
+    tuning: Tuning,
+    tarpit: TarpitConfig,
+    violations: ViolationPolicy,
+    fairness: Fairness,
+    dedup_window: Option<Duration>,
+    echo_window: Option<Duration>,
+    compress_min_bytes: Option<usize>,
+    /// Clients that asked for compression, by algorithm.
+    compressing: HashMap<Compression, usize>,
+    stamper: Option<Stamper>,
+    /// Clients authenticated as bridges, when echoes are suppressed.
+    bridges: HashSet<ClientId>,
+    send_queue: usize,
+    slow_consumer: SlowConsumer,
+    protocol: Protocol,
+    direct: bool,
+    accept_batch: usize,
+    proxy_protocol: bool,
+    /// Handshakes accepted sockets before they become clients, when set
+    tls: Option<TlsAcceptor>,
+    /// Listeners besides the main one: more TCP addresses, WebSocket, framed
+    listeners: Vec<(Transport, TcpListener)>,
+    /// Handshake and upgrade tasks report here
+    handshake_tx: mpsc::UnboundedSender<Handshake>,
+    handshake_rx: mpsc::UnboundedReceiver<Handshake>,
+    /// Tasks reading PROXY headers report here
+    proxied_tx: mpsc::UnboundedSender<Proxied>,
+    proxied_rx: mpsc::UnboundedReceiver<Proxied>,
+    /// Abuse heuristics, with their state aged out once per churn window
+    detector: AnomalyDetector,
+    alerter: Alerter,
+    shedder: Shedder,
+    regions: Regions,
+    rtts: Rtts,
+    sessions: Option<Webhook>,
+    housekeeping_interval: Duration,
+
+    /// Ids of connected clients and where they connected from
+    registry: ClientRegistry,
+    /// Map of client_id -> write half and per-client state
+    clients: HashMap<ClientId, Client>,
+    /// Map of client_id -> stream of input lines
+    inputs: Inputs,
+    /// Rooms with at least one member
+    rooms: BTreeMap<Arc<str>, Room>,
+    /// Lines kept per room
+    history_limit: usize,
+    /// Recent lobby messages
+    lobby_history: History,
+    /// Replays waiting for their next page, when replay is paced
+    replays: Option<Replays>,
+    journal: Option<Journal>,
+    blobs: Option<BlobStore>,
+    filters: Filters,
+    /// Links to other servers, when peering is on
+    cluster: Option<Cluster>,
+    /// The Redis bridge to other instances, when it's on
+    bridge: Option<Bridge>,
+    drain_timeout: Duration,
+    handshake_timeout: Duration,
+    write_timeout: Option<Duration>,
+    echo_to_sender: bool,
+    idle: IdleConfig,
+    noop_interval: Option<Duration>,
+    presence: PresenceConfig,
+    auth: AuthConfig,
+    access: AccessList,
+    /// Set by an admin; turns new connections away
+    maintenance: Maintenance,
+    /// Set by an admin's `SHUTDOWN`; the loop stops after this turn
+    stopping: bool,
+    /// Sends new clients on to other servers, when there are any
+    front_door: bool,
+    started: Instant,
+    /// The `INFO:` line, the same for the server's whole life.
+    info: Bytes,
+    max_clients: Option<usize>,
+    rate_limit: Option<RateLimit>,
+    max_line: usize,
+    /// Handshakes, upgrades and PROXY headers being read in their own
+    /// tasks.
+    handshaking: usize,
+    /// Thins out connect/disconnect/reject lines during floods.
+    conn_log: LogSampler,
+    /// Internal identities by name.
+    internal: HashMap<Arc<str>, Internal>,
+    /// Messages from the UDP port.
+    udp_budget: Budget,
+    /// Lines read but not yet handled, when fair scheduling is on
+    fair: FairQueue,
+    /// Inputs taken out of `inputs` while their sender is over its
+    /// `READ_BUDGET`.
+    parked: HashMap<ClientId, Input>,
+    /// Senders whose run of repeated messages is due to be reported
+    dedup_expiry: DelayQueue<ClientId>,
+    /// Connections whose time to authenticate runs out
+    auth_expiry: DelayQueue<ClientId>,
+    /// Broadcasts, read by every client's writer task
+    feed: broadcast::Sender<Fanout>,
+    /// Broadcasts went out without a flush since the last flush tick
+    feed_unflushed: bool,
+    /// A small room's broadcast went straight to its members since the
+    /// last line on the feed
+    fed_direct: bool,
+    /// Broadcasts sent so far, to compare writers' progress against
+    fed: u64,
+    /// Numbered messages whose senders want a receipt, and barriers, in
+    /// the order they were broadcast.
+    receipts: VecDeque<Receipt>,
+    /// Last id given to a message held for moderation
+    last_held: u64,
+    /// Writer tasks report clients they gave up on here
+    closed_tx: mpsc::UnboundedSender<ClientId>,
+    closed_rx: mpsc::UnboundedReceiver<ClientId>,
+
+    hooks: Hooks,
+    counters: Counters,
+    latency: LatencyHistogram,
+    sizes: SizeStats,
+}
+
+impl Server {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        config: Config,
+        hooks: Hooks,
+        tls: Option<TlsAcceptor>,
+        listeners: Vec<(Transport, TcpListener)>,
+        journal: Option<(Journal, Vec<Bytes>)>,
+        blobs: Option<BlobStore>,
+        filters: Filters,
+        cluster: Option<Cluster>,
+        bridge: Option<Bridge>,
+    ) -> Self {
+        let info = Bytes::from(info::line(&config));
+        let (closed_tx, closed_rx) = mpsc::unbounded_channel();
+        let (handshake_tx, handshake_rx) = mpsc::unbounded_channel();
+        let (proxied_tx, proxied_rx) = mpsc::unbounded_channel();
+        let history_limit = config.history.min(config.send_queue / 2);
+        let mut lobby_history = History::new(history_limit);
+        let journal = journal.map(|(journal, recent)| {
+            recent.into_iter().for_each(|line| lobby_history.push(line));
+            journal
+        });
+        let mut registry = ClientRegistry::default();
+        let internal = hooks
+            .inbox
+            .names
+            .iter()
+            .filter_map(|name| {
+                let id = registry.register_internal(name).ok()?;
+                info!("internal {id} {name}");
+                Some((name.clone(), Internal::new(id)))
+            })
+            .collect();
+        Self {
+            socket: config.socket,
+            tuning: config.tuning,
+            tarpit: config.tarpit,
+            violations: config.violations,
+            fairness: config.fairness,
+            dedup_window: config.dedup_window,
+            echo_window: config.echo_window,
+            compress_min_bytes: config.compress_min_bytes.filter(|_| config.protocol == Protocol::Text),
+            compressing: HashMap::new(),
+            stamper: config.stamp_messages.then(|| Stamper::new(config.peers.node_id.unwrap_or_default())),
+            bridges: HashSet::new(),
+            send_queue: config.send_queue,
+            slow_consumer: config.slow_consumer,
+            protocol: config.protocol,
+            direct: config.direct,
+            accept_batch: config.accept_batch.max(1),
+            proxy_protocol: config.proxy_protocol,
+            tls,
+            listeners,
+            handshake_tx,
+            handshake_rx,
+            proxied_tx,
+            proxied_rx,
+            housekeeping_interval: config.anomaly.churn_window,
+            detector: AnomalyDetector::new(config.anomaly),
+            alerter: Alerter::new(config.alert),
+            shedder: Shedder::new(config.shed),
+            rtts: Rtts::new(&config.regions),
+            regions: config.regions,
+            sessions: config.session_webhook.map(Webhook::start),
+            registry,
+            clients: HashMap::new(),
+            inputs: StreamMap::new(),
+            rooms: BTreeMap::new(),
+            history_limit,
+            lobby_history,
+            replays: config.replay.rate.map(|rate| Replays::new(rate, config.replay.page, Instant::now())),
+            journal,
+            blobs,
+            filters,
+            cluster,
+            bridge,
+            drain_timeout: config.drain_timeout,
+            handshake_timeout: config.handshake_timeout,
+            write_timeout: config.write_timeout,
+            echo_to_sender: config.echo_to_sender,
+            idle: config.idle,
+            noop_interval: config.noop_interval,
+            presence: config.presence,
+            auth: config.auth,
+            access: config.access,
+            maintenance: Maintenance::Off,
+            front_door: config.peers.front_door,
+            stopping: false,
+            started: Instant::now(),
+            info,
+            max_clients: config.max_clients,
+            rate_limit: config.rate_limit,
+            max_line: config.max_line,
+            handshaking: 0,
+            conn_log: LogSampler::new(Instant::now()),
+            internal,
+            udp_budget: Budget::new(Instant::now(), UDP_BURST, UDP_RATE),
+            fair: FairQueue::default(),
+            parked: HashMap::new(),
+            dedup_expiry: DelayQueue::new(),
+            auth_expiry: DelayQueue::new(),
+            feed: broadcast::channel(config.send_queue).0,
+            feed_unflushed: false,
+            fed_direct: false,
+            fed: 0,
+            receipts: VecDeque::new(),
+            last_held: 0,
+            closed_tx,
+            closed_rx,
+            hooks,
+            counters: Counters::default(),
+            latency: LatencyHistogram::default(),
+            sizes: SizeStats::default(),
+        }
+    }
+
+    async fn run(
+        mut self,
+        listener: TcpListener,
+        unix: Option<UnixSocket>,
+        mut udp: Option<UdpIngest>,
+        metrics: Option<TcpListener>,
+        peers: Option<TcpListener>,
+    ) -> io::Result<()> {
+        // Streams of incoming connections, by listener
+        let mut incoming = StreamMap::new();
+        incoming.insert((Transport::Tcp, 0), TcpListenerStream::new(listener));
+        for (i, (transport, listener)) in self.listeners.drain(..).enumerate() {
+            incoming.insert((transport, i + 1), TcpListenerStream::new(listener));
+        }
+
+        // Tick that settles ingest-mode clients and batched writes
+        let mut flush_tick = time::interval(self.tuning.flush_interval);
+        flush_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // Its first tick a whole interval in, so nothing is forgiven as it starts
+        let mut housekeeping = time::interval_at(time::Instant::now() + self.housekeeping_interval, self.housekeeping_interval);
+
+        // A timer that fires late means everything else waited as long
+        let mut lag_probe = time::interval(LAG_PROBE_INTERVAL);
+        lag_probe.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut consumer_check = time::interval(CONSUMER_CHECK_INTERVAL);
+        let mut receipt_check = time::interval(RECEIPT_CHECK_INTERVAL);
+        let mut idle_check = time::interval(IDLE_CHECK_INTERVAL);
+        let mut load_check = time::interval(shed::CHECK_INTERVAL);
+        let mut load_report = time::interval(peer::LOAD_INTERVAL);
+        let mut presence_check = time::interval(PRESENCE_CHECK_INTERVAL);
+        let mut replay_tick = time::interval(REPLAY_INTERVAL);
+        replay_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let check_consumers = !matches!(self.slow_consumer, SlowConsumer::DropOldest | SlowConsumer::DropNewest);
+
+        // Greylisted connections waiting out their handshake delay
+        let mut tarpitted: DelayQueue<(TcpStream, SocketAddr, Transport)> = DelayQueue::new();
+
+        let mut shutdown = self.hooks.shutdown.take().unwrap_or_else(|| Box::pin(std::future::pending()));
+        let mut access_updates = self.hooks.access_updates.take().unwrap_or_else(|| Box::pin(futures::stream::pending()));
+        let mut reloads = self.hooks.reloads.take().unwrap_or_else(|| Box::pin(futures::stream::pending()));
+
+        while !self.stopping {
+            let batching_all = self.tuning.batching == Batching::All;
+            tokio::select! {
+                // Accept new clients, plus any others already waiting, up to
+                // the batch size
+                maybe_conn = incoming.next() => {
+                    match maybe_conn {
+                        Some(((transport, _), Ok(stream))) => self.accept(stream, transport, &mut tarpitted),
+                        Some((_, Err(e))) => {
+                            error!("accept error: {e}");
+                        }
+                        None => {
+                            break;
+                        }
+                    }
+                    for _ in 1..self.accept_batch {
+                        match incoming.next().now_or_never() {
+                            Some(Some(((transport, _), Ok(stream)))) => self.accept(stream, transport, &mut tarpitted),
+                            Some(Some((_, Err(e)))) => error!("accept error: {e}"),
+                            _ => break,
+                        }
+                    }
+                }
+
+                // Unix socket clients are local, and need no upgrade
+                conn = unix::accept(unix.as_ref()) => {
+                    match conn {
+                        Ok(conn) => self.accept_unix(conn),
+                        Err(e) => error!("unix accept error: {e}"),
+                    }
+                }
+
+                // A device fired a datagram at the UDP port
+                received = udp::recv(udp.as_mut()) => {
+                    match received {
+                        Ok((peer, text)) => self.ingest_datagram(peer, &text),
+                        Err(e) => error!("udp receive error: {e}"),
+                    }
+                }
+
+                // A scrape; the numbers are taken now, the request is
+                // answered on the side
+                conn = prometheus::accept(metrics.as_ref()) => {
+                    match conn {
+                        Ok(stream) if stream.peer_addr().is_ok_and(|peer| self.access.permits(peer.ip())) => {
+                            let info = String::from_utf8_lossy(&Protocol::Json.encode(self.info.clone())).into_owned();
+                            tokio::spawn(prometheus::respond(stream, self.snapshot().render(), info));
+                        }
+                        Ok(_) => {}
+                        Err(e) => error!("metrics accept error: {e}"),
+                    }
+                }
+
+                // Another server linking to this one
+                conn = peer::accept(peers.as_ref()) => {
+                    match conn {
+                        Ok((stream, addr)) if self.access.permits(addr.ip()) => {
+                            if let Some(cluster) = &mut self.cluster {
+                                cluster.accept(stream, addr);
+                            }
+                        }
+                        Ok((_, addr)) => info!("rejected peer {addr} not allowed"),
+                        Err(e) => error!("peer accept error: {e}"),
+                    }
+                }
+
+                // A link to another server came or went, or brought a message
+                Some(event) = peer::next(self.cluster.as_mut()) => {
+                    let relay = self.cluster.as_mut().and_then(|cluster| cluster.handle(event));
+                    if let Some(relay) = relay {
+                        self.publish_relay(relay);
+                    }
+                }
+
+                // Another instance published a message through Redis
+                Some(relay) = redis::next(self.bridge.as_mut()) => self.publish_relay(relay),
+
+                // A tarpitted connection has waited long enough for its LOGIN
+                Some(expired) = tarpitted.next(), if !tarpitted.is_empty() => {
+                    let (stream, peer, transport) = expired.into_inner();
+                    self.counters.tarpit.pending -= 1;
+                    // The lists may have been reloaded in the meantime
+                    if !self.access.permits(peer.ip()) {
+                        continue;
+                    }
+                    if self.maintenance != Maintenance::Off {
+                        self.turn_away(stream, transport, b"BUSY:MAINTENANCE\n");
+                        continue;
+                    }
+                    self.add_client(stream, peer, transport, Some(self.tarpit.read_interval));
+                }
+
+                // A PROXY header was read, or wasn't
+                Some(Proxied { stream, peer, transport, result }) = self.proxied_rx.recv() => {
+                    self.handshaking -= 1;
+                    match result {
+                        Ok(client) => self.admit(stream, client, transport, &mut tarpitted),
+                        Err(e) if self.conn_log.sample(Instant::now()) => warn!("proxy header from {peer} failed: {e}"),
+                        Err(_) => {}
+                    }
+                }
+
+                // A handshake or upgrade finished, one way or the other
+                Some(Handshake { peer, transport, throttle, result }) = self.handshake_rx.recv() => {
+                    self.handshaking -= 1;
+                    match result {
+                        Ok(conn) => self.start_session(conn, peer, transport, throttle),
+                        Err(e) if self.conn_log.sample(Instant::now()) => warn!("handshake with {peer} failed: {e}"),
+                        Err(_) => {}
+                    }
+                }
+
+                // Any line from any client
+                maybe_item = self.inputs.next(), if !self.inputs.is_empty() && self.fair.len() < FAIR_QUEUE_LIMIT => {
+                    match maybe_item {
+                        Some((client_id, Some(Ok(frame)))) => {
+                            if self.fairness == Fairness::Off {
+                                self.handle_frame(client_id, frame, Instant::now());
+                                continue;
+                            }
+                            // Queue this line and everything else already buffered,
+                            // so the next turns cover every sender that is ready.
+                            self.queue_fair(client_id, frame);
+                            while self.fair.len() < FAIR_QUEUE_LIMIT {
+                                match self.inputs.next().now_or_never() {
+                                    Some(Some((id, Some(Ok(frame))))) => self.queue_fair(id, frame),
+                                    Some(Some((id, Some(Err(e))))) => self.read_failed(id, e),
+                                    Some(Some((id, None))) => self.client_closed(id),
+                                    _ => break,
+                                }
+                            }
+                        }
+                        Some((client_id, Some(Err(e)))) => self.read_failed(client_id, e),
+                        Some((client_id, None)) => self.client_closed(client_id),
+                        None => {
+                            // No more input streams (all clients gone) — keep accepting
+                            // (the accept branch above will continue to fire).
+                        }
+                    }
+                }
+
+                // Handle the next queued line, one sender per turn
+                _ = std::future::ready(()), if !self.fair.is_empty() => {
+                    self.handle_next_fair();
+                }
+
+                // A sender's repeat window closed; report what was held back
+                Some(expired) = self.dedup_expiry.next(), if !self.dedup_expiry.is_empty() => {
+                    self.expire_repeats(expired.into_inner());
+                }
+
+                // A connection's time to authenticate ran out
+                Some(expired) = self.auth_expiry.next(), if !self.auth_expiry.is_empty() => {
+                    self.auth_expired(expired.into_inner());
+                }
+
+                // A message published from inside the process
+                Some(injected) = self.hooks.inbox.rx.recv() => {
+                    self.inject(injected);
+                }
+
+                // A writer task gave up on its client
+                Some(client_id) = self.closed_rx.recv() => {
+                    self.remove_client(client_id, Reason::Writer);
+                }
+
+                // Periodically settle ingest producers: send outstanding ack ranges
+                // and flush whatever batched writes left buffered.
+                _ = flush_tick.tick(), if self.counters.ingesting > 0 || batching_all => {
+                    self.flush_batched();
+                }
+
+                _ = housekeeping.tick() => {
+                    self.housekeeping();
+                }
+
+                due = lag_probe.tick(), if self.alerter.enabled() => {
+                    let tripped = self.alerter.check_lag(due.into_std(), Instant::now());
+                    self.alert(tripped);
+                }
+
+                _ = consumer_check.tick(), if check_consumers => {
+                    match self.slow_consumer {
+                        SlowConsumer::Latency(limit) => self.disconnect_late(limit),
+                        _ => self.disconnect_stalled(),
+                    }
+                }
+
+                _ = receipt_check.tick(), if !self.receipts.is_empty() => {
+                    self.send_receipts();
+                }
+
+                _ = idle_check.tick(), if self.idle.enabled() || self.noop_interval.is_some() => {
+                    self.reap_idle();
+                    self.send_keepalives();
+                }
+
+                _ = load_check.tick(), if self.shedder.enabled() => self.check_load(),
+
+                _ = load_report.tick(), if self.cluster.is_some() => {
+                    let clients = self.clients.len();
+                    if let Some(cluster) = &mut self.cluster {
+                        cluster.report(clients);
+                    }
+                }
+
+                _ = presence_check.tick(), if self.presence.enabled() => {
+                    self.check_presence();
+                }
+
+                _ = replay_tick.tick(), if self.replays.as_ref().is_some_and(|replays| !replays.is_empty()) => {
+                    self.send_replay_pages();
+                }
+
+                Some(access) = access_updates.next() => {
+                    info!("access lists updated allow={} deny={}", access.allow.len(), access.deny.len());
+                    self.access = access;
+                }
+
+                Some(reload) = reloads.next() => self.reload(reload),
+
+                _ = &mut shutdown => break,
+            }
+        }
+
+        // Closes the listeners (removing the socket file), and turns away
+        // anyone still waiting
+        drop(incoming);
+        drop(unix);
+        drop(metrics);
+        drop(peers);
+        drop(tarpitted);
+        // Links close; peers carry on without this server
+        self.cluster = None;
+        self.bridge = None;
+        self.drain().await;
+        Ok(())
+    }
+
+    /// Finishes what clients already sent, tells them the server is going
+    /// and waits for their writers to deliver everything, up to the drain
+    /// timeout.
+    async fn drain(&mut self) {
+        info!("shutting down clients={} drain_timeout_secs={}", self.clients.len(), self.drain_timeout.as_secs());
+        let deadline = time::Instant::now() + self.drain_timeout;
+
+        // Lines already queued or sitting in read buffers, but nothing
+        // that would mean waiting on a socket
+        while self.handle_next_fair() {}
+        while time::Instant::now() < deadline {
+            match self.inputs.next().now_or_never() {
+                Some(Some((client_id, Some(Ok(frame))))) => self.handle_frame(client_id, frame, Instant::now()),
+                Some(Some((client_id, _))) => self.client_closed(client_id),
+                _ => break,
+            }
+        }
+        self.flush_batched();
+        self.publish(None, Audience::All, Bytes::from_static(b"SERVER:SHUTDOWN\n"), true, false);
+        // Without a sender, each writer stops once it has caught up
+        self.feed = broadcast::channel(1).0;
+
+        for (&client_id, c) in &self.clients {
+            self.report_session(client_id, c, Reason::Shutdown);
+        }
+        let writers = self.clients.drain().map(|(_, c)| c.writer.drain(deadline));
+        let drained = futures::future::join_all(writers).await;
+        let cut_off = drained.iter().filter(|done| !**done).count();
+        info!("shut down drained={} cut_off={cut_off}", drained.len() - cut_off);
+        if let Some(sessions) = self.sessions.take() {
+            sessions.close(deadline).await;
+        }
+    }
+
+    /// A new connection on a client listener. With the PROXY protocol on,
+    /// its header is read first, in a task of its own.
+    fn accept(&mut self, stream: TcpStream, transport: Transport, tarpitted: &mut DelayQueue<(TcpStream, SocketAddr, Transport)>) {
+        let Ok(peer) = stream.peer_addr() else { return };
+        if !self.proxy_protocol {
+            self.admit(stream, peer, transport, tarpitted);
+            return;
+        }
+        // Who the client is isn't known until its header has been read
+        let done = self.proxied_tx.clone();
+        let timeout = self.handshake_timeout;
+        self.handshaking += 1;
+        tokio::spawn(async move {
+            let mut stream = stream;
+            let result = proxy::read_header(&mut stream, peer, timeout).await;
+            let _ = done.send(Proxied { stream, peer, transport, result });
+        });
+    }
+
+    /// Lets a connection from `peer` in, or tarpits or turns it away.
+    fn admit(
+        &mut self,
+        stream: TcpStream,
+        peer: SocketAddr,
+        transport: Transport,
+        tarpitted: &mut DelayQueue<(TcpStream, SocketAddr, Transport)>,
+    ) {
+        let now = Instant::now();
+        if !self.access.permits(peer.ip()) {
+            if self.conn_log.sample(now) {
+                info!("rejected {peer} not allowed");
+            }
+            return;
+        }
+        net::tune(&stream, &self.socket);
+        // Banned IPs still count towards churn, so a sustained
+        // flood keeps its ban fresh.
+        if let Some(event) = self.detector.on_connect(peer.ip(), now) {
+            warn!("{event}");
+        }
+        if self.detector.is_banned(peer.ip(), now) {
+            return;
+        }
+        if self.maintenance != Maintenance::Off {
+            if self.conn_log.sample(now) {
+                info!("rejected {peer} maintenance");
+            }
+            self.turn_away(stream, transport, b"BUSY:MAINTENANCE\n");
+            return;
+        }
+        if self.max_clients.is_some_and(|max| self.connections() >= max) {
+            if self.conn_log.sample(now) {
+                info!("rejected {peer} server full clients={}", self.clients.len());
+            }
+            self.turn_away(stream, transport, b"ERROR:SERVER_FULL\n");
+            return;
+        }
+        if self.shedder.shedding() {
+            self.shedder.connections += 1;
+            if self.conn_log.sample(now) {
+                info!("rejected {peer} overloaded");
+            }
+            self.turn_away(stream, transport, b"ERROR:OVERLOADED\n");
+            return;
+        }
+        let greylisted = self.detector.is_greylisted(peer.ip(), now);
+        if let Some(delay) = self.tarpit.delay.filter(|_| greylisted) {
+            if self.conn_log.sample(now) {
+                info!("tarpit {} {} delay_secs={}", peer.ip(), peer.port(), delay.as_secs());
+            }
+            tarpitted.insert((stream, peer, transport), delay);
+            self.counters.tarpit.pending += 1;
+            self.counters.tarpit.total += 1;
+            return;
+        }
+        self.add_client(stream, peer, transport, None);
+    }
+
+    /// Closes a connection that won't become a client, telling it why.
+    ///
+    /// A new socket's send buffer is empty, so a plain non-blocking write
+    /// goes through (tokio's would wait for the reactor to report it
+    /// writable). TLS and WebSocket clients couldn't read the line without
+    /// a handshake, which isn't worth doing for a connection that's turned
+    /// away, so they're just closed.
+    fn turn_away(&self, stream: TcpStream, transport: Transport, line: &'static [u8]) {
+        if conn::needs_upgrade(transport, self.tls.as_ref()) {
+            return;
+        }
+        let Ok(mut stream) = stream.into_std() else { return };
+        let line = self.protocol.encode(Bytes::from_static(line));
+        let line = match transport {
+            Transport::Framed => {
+                let payload = line.strip_suffix(b"\n").unwrap_or(&line);
+                [&(payload.len() as u32).to_be_bytes(), payload].concat()
+            }
+            Transport::Tcp | Transport::WebSocket | Transport::Unix => line.to_vec(),
+        };
+        let _ = io::Write::write(&mut stream, &line);
+    }
+
+    /// A Unix socket client: no per-IP heuristics, since they'd all share
+    /// one, and nothing to tell it if the server is full, the same as TLS.
+    fn accept_unix(&mut self, conn: Conn) {
+        if self.maintenance != Maintenance::Off {
+            if self.conn_log.sample(Instant::now()) {
+                info!("rejected unix maintenance");
+            }
+            return;
+        }
+        if self.max_clients.is_some_and(|max| self.connections() >= max) {
+            if self.conn_log.sample(Instant::now()) {
+                info!("rejected unix server full clients={}", self.clients.len());
+            }
+            return;
+        }
+        if self.shedder.shedding() {
+            self.shedder.connections += 1;
+            if self.conn_log.sample(Instant::now()) {
+                info!("rejected unix overloaded");
+            }
+            return;
+        }
+        self.start_session(conn, UNIX_PEER, Transport::Unix, None);
+    }
+
+    /// Connections holding a socket: clients, plus those still in a
+    /// handshake or tarpit.
+    fn connections(&self) -> usize {
+        self.clients.len() + self.handshaking + self.counters.tarpit.pending
+    }
+
+    /// The client's span, or a disabled one if it's gone.
+    fn span(&self, client_id: ClientId) -> Span {
+        self.clients.get(&client_id).map_or_else(Span::none, |c| c.span.clone())
+    }
+
+    /// Starts the client's session, after a TLS handshake and/or WebSocket
+    /// upgrade if it needs one.
+    fn add_client(&mut self, stream: TcpStream, peer: SocketAddr, transport: Transport, throttle: Option<Duration>) {
+        if !conn::needs_upgrade(transport, self.tls.as_ref()) {
+            self.start_session(Conn::Plain(stream), peer, transport, throttle);
+            return;
+        }
+        let tls = self.tls.clone();
+        let done = self.handshake_tx.clone();
+        let timeout = self.handshake_timeout;
+        self.handshaking += 1;
+        tokio::spawn(async move {
+            let result = conn::upgrade(stream, transport, tls, timeout).await;
+            let _ = done.send(Handshake { peer, transport, throttle, result });
+        });
+    }
+
+    fn start_session(&mut self, conn: Conn, peer: SocketAddr, transport: Transport, throttle: Option<Duration>) {
+        let client_id = self.registry.register(peer);
+        let span = info_span!("client", client_id, peer = %unix::describe(peer));
+        let _entered = span.enter();
+
+        if self.conn_log.sample(Instant::now()) {
+            info!(clients = self.clients.len() + 1, "connected");
+        }
+        if throttle.is_some() {
+            self.counters.tarpit.active += 1;
+        }
+        self.counters.connections += 1;
+
+        let (read_half, write_half) = conn.split();
+
+        // Prepare the reader as a stream of lines
+        let codec = InputCodec::new(transport, self.max_line);
+        let framed = FramedRead::with_capacity(read_half, codec, self.tuning.read_buffer);
+        let input = Input { lines: CatchUnwind::new(Throttled::new(framed, throttle)), ended: false };
+
+        // Prepare writer
+        let writer = ClientWriter::spawn(
+            client_id,
+            Output::new(write_half, transport, self.tuning.write_buffer),
+            self.write_timeout,
+            self.send_queue,
+            self.feed.subscribe(),
+            self.slow_consumer,
+            self.closed_tx.clone(),
+            span.clone(),
+        );
+
+        // A front door keeps a client only when there's nowhere to send it,
+        // and one it sends on never gets in
+        let redirect = match self.cluster.as_mut() {
+            Some(cluster) if self.front_door && transport != Transport::Unix => cluster.least_loaded(),
+            _ => None,
+        };
+
+        // Until it authenticates, the client gets nothing from the feed
+        let authed = !self.auth.enabled() && redirect.is_none();
+        if !authed {
+            writer.skip_feed(u64::MAX);
+        }
+
+        self.clients.insert(
+            client_id,
+            Client {
+                writer,
+                authed,
+                admin: false,
+                framed: transport == Transport::Framed,
+                websocket: transport == Transport::WebSocket,
+                sent: (0, Instant::now()),
+                fed_before: self.fed,
+                ingest: None,
+                event_budget: Budget::new(Instant::now(), EVENT_BURST, EVENT_RATE),
+                tarpitted: throttle.is_some(),
+                violations: 0,
+                rate: self.rate_limit.as_ref().map(|l| Budget::new(Instant::now(), l.burst, l.rate)),
+                rate_strikes: 0,
+                dedup: self.dedup_window.map(Dedup::new),
+                echoes: None,
+                compression: None,
+                locked: false,
+                echo: self.echo_to_sender,
+                acks: true,
+                lang: None,
+                identity: None,
+                connected: Instant::now(),
+                room: None,
+                direct_offer: None,
+                last_message: None,
+                last_heard: Instant::now(),
+                pinged: None,
+                rtt_probe: None,
+                rtt: None,
+                idle: self.idle.get(transport),
+                last_active: Instant::now(),
+                presence: Presence::Active,
+                presence_budget: Budget::new(Instant::now(), PRESENCE_BURST, PRESENCE_RATE),
+                messages_in: 0,
+                bytes_in: 0,
+                span: span.clone(),
+                late_since: None,
+                dropped_reported: 0,
+                last_seq: 0,
+                receipts: false,
+                barrier: false,
+            },
+        );
+        self.inputs.insert(client_id, input);
+        if let Some(addr) = redirect {
+            info!("redirect {addr}");
+            self.disconnect_with(client_id, format!("REDIRECT:{addr}\n"), Reason::Redirected);
+            return;
+        }
+        if authed {
+            self.welcome(client_id, peer);
+        } else {
+            self.auth_expiry.insert(client_id, self.auth.timeout);
+            self.reply(client_id, "AUTH_REQUIRED\n");
+        }
+    }
+
+    /// Lets a client in: history, `LOGIN`, and telling everyone else.
+    fn welcome(&mut self, client_id: ClientId, peer: SocketAddr) {
+        self.replay(client_id, None);
+        self.reply(client_id, format!("LOGIN:{client_id}\n"));
+        if !self.regions.is_empty() {
+            if let Some(c) = self.clients.get_mut(&client_id) {
+                c.rtt_probe = Some(Instant::now());
+            }
+            self.reply(client_id, "PING\n");
+        }
+        self.announce(client_id, format!("JOINED:{client_id}\n"));
+        if let Some(hook) = self.hooks.on_connect.as_mut() {
+            if let Err(payload) = std::panic::catch_unwind(AssertUnwindSafe(|| hook(client_id, peer))) {
+                self.hook_panicked(client_id, "on_connect", &*payload);
+            }
+        }
+    }
+
+    /// A line from a client that hasn't authenticated: `AUTH`, or keeping
+    /// the connection alive, and nothing else.
+    fn authenticate(&mut self, client_id: ClientId, line: &str) {
+        let inbound;
+        let command = match self.protocol {
+            Protocol::Text => Command::parse(line),
+            Protocol::Json => {
+                inbound = envelope::decode(line);
+                match &inbound {
+                    Ok(Inbound::Command(cmd)) => Command::parse(cmd),
+                    _ => None,
+                }
+            }
+        };
+        let credentials = match command {
+            Some(Command::Auth(credentials)) => credentials,
+            Some(Command::Ping) => return self.reply(client_id, "PONG\n"),
+            Some(Command::Pong) => return,
+            _ => return self.reply(client_id, "ERROR:AUTH_REQUIRED\n"),
+        };
+        let Some(peer) = self.registry.peer(client_id) else { return };
+        let Some(who) = self.auth.check(credentials) else {
+            warn!("auth failed");
+            self.disconnect_with(client_id, "ERROR:AUTH_FAILED\n", Reason::AuthFailed);
+            return;
+        };
+        let admin = self.auth.is_admin(&who);
+        let bridge = self.auth.is_bridge(&who);
+        info!("auth {client_id} {who}{}{}", if admin { " admin" } else { "" }, if bridge { " bridge" } else { "" });
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        c.authed = true;
+        c.admin = admin;
+        c.identity = Some(who.to_string());
+        if let (true, Some(window)) = (bridge, self.echo_window) {
+            c.echoes = Some(Echoes::new(window));
+            self.bridges.insert(client_id);
+        }
+        // Broadcasts from before now stay unseen
+        c.writer.skip_feed(self.fed - c.fed_before);
+        c.last_active = Instant::now();
+        self.welcome(client_id, peer);
+    }
+
+    fn auth_expired(&mut self, client_id: ClientId) {
+        if self.clients.get(&client_id).is_some_and(|c| !c.authed) {
+            info!("auth timeout {client_id}");
+            self.disconnect_with(client_id, "ERROR:AUTH_TIMEOUT\n", Reason::AuthTimeout);
+        }
+    }
+
+    /// Everything the metrics endpoint reports, as of now.
+    fn snapshot(&self) -> Snapshot {
+        let counters = &self.counters;
+        Snapshot {
+            clients: self.clients.len() as u64,
+            rooms: self.rooms.len() as u64,
+            handshaking: self.handshaking as u64,
+            connections: counters.connections,
+            disconnects: counters.disconnects,
+            messages_in: counters.messages_in,
+            bytes_in: counters.bytes_in,
+            broadcasts: self.fed + counters.direct,
+            bytes_out: counters.bytes_out + self.clients.values().map(|c| c.writer.sent_bytes()).sum::<u64>(),
+            write_errors: counters.write_errors,
+            slow_consumers: counters.slow_consumers,
+            dropped_lines: counters.dropped_lines + self.clients.values().map(|c| c.writer.dropped()).sum::<u64>(),
+            panics: counters.panics,
+            shedding: u64::from(self.shedder.shedding()),
+            shed_connections: self.shedder.connections,
+            shed_lines: self.shedder.lines,
+            latency: (!self.regions.is_empty()).then(|| self.latency()),
+        }
+    }
+
+    fn latency(&self) -> Latency {
+        let region = |c: &Client| c.rtt.and_then(|rtt| self.regions.region(rtt)).unwrap_or("-");
+        let regions = self
+            .regions
+            .names()
+            .chain(["-"])
+            .map(|name| (name.to_string(), self.clients.values().filter(|c| region(c) == name).count() as u64))
+            .collect();
+        Latency {
+            buckets: self.rtts.buckets().map(|(bound, n)| (bound.as_secs_f64(), n)).collect(),
+            sum: self.rtts.sum.as_secs_f64(),
+            count: self.rtts.count,
+            regions,
+        }
+    }
+
+    /// Takes new values of the reloadable settings.
+    fn reload(&mut self, reload: Reload) {
+        let now = Instant::now();
+        for c in self.clients.values_mut() {
+            c.rate = reload.rate_limit.as_ref().map(|l| Budget::new(now, l.burst, l.rate));
+        }
+        let level_set = logging::set_level(reload.log_level);
+        let off = || "off".to_string();
+        info!(
+            "config reloaded max_clients={} rate_limit={} log_level={} allow={} deny={}",
+            reload.max_clients.map_or_else(off, |max| max.to_string()),
+            reload.rate_limit.as_ref().map_or_else(off, |l| format!("{}/s", l.rate)),
+            match (level_set, reload.log_level) {
+                (false, _) => "unchanged",
+                (true, LogLevel::Info) => "info",
+                (true, LogLevel::Warn) => "warn",
+                (true, LogLevel::Error) => "error",
+            },
+            reload.access.allow.len(),
+            reload.access.deny.len(),
+        );
+        self.access = reload.access;
+        self.max_clients = reload.max_clients;
+        self.rate_limit = reload.rate_limit;
+    }
+
+    /// Sends a parting line and drops the client.
+    fn disconnect_with(&mut self, client_id: ClientId, line: impl Into<Bytes>, reason: Reason) {
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        let _ = enqueue(client_id, c, line.into(), true, self.slow_consumer, self.protocol);
+        self.remove_client(client_id, reason);
+    }
+
+    /// Drops the client a hook panicked over; the hook itself stays.
+    fn hook_panicked(&mut self, client_id: ClientId, hook: &str, payload: &(dyn Any + Send)) {
+        self.counters.panics += 1;
+        error!(hook, error = %panics::message(payload), "hook panicked");
+        self.remove_client(client_id, Reason::Panicked);
+    }
+
+    /// Handles one line from a client: a command, or a message to broadcast.
+    fn handle_frame(&mut self, client_id: ClientId, frame: Bytes, received: Instant) {
+        let _entered = self.span(client_id).entered();
+        // Any line at all shows the client is still there
+        if let Some(c) = self.clients.get_mut(&client_id) {
+            c.last_heard = received;
+            c.pinged = None;
+        }
+
+        // A framed client's frame that couldn't be sent as a line (binary,
+        // or with control characters) is a binary message, relayed as is
+        // to framed clients only. In text mode, that is; JSON can carry
+        // any text already.
+        let framed = self.clients.get(&client_id).is_some_and(|c| c.framed) && self.protocol == Protocol::Text;
+        let binary = framed && !std::str::from_utf8(&frame).is_ok_and(|line| sanitize_payload(line) == line);
+        let lossy;
+
+        // Binary garbage is flagged and dropped rather than relayed
+        let line = match std::str::from_utf8(&frame) {
+            _ if binary => {
+                lossy = String::from_utf8_lossy(&frame);
+                &*lossy
+            }
+            Ok(line) if !line.contains('\0') => line,
+            bad => {
+                let reason = if bad.is_ok() { "nul byte" } else { "invalid utf-8" };
+                if let Some(peer) = self.registry.peer(client_id).filter(|&peer| peer != UNIX_PEER) {
+                    warn!("{}", self.detector.on_garbage(peer.ip(), client_id, Instant::now()));
+                }
+                self.record_violation(client_id, reason);
+                return;
+            }
+        };
+
+        // Nothing but AUTH gets through before the client is let in
+        if self.clients.get(&client_id).is_some_and(|c| !c.authed) {
+            return self.authenticate(client_id, line);
+        }
+
+        // In JSON mode the envelope says whether it's a command; a message
+        // body is never parsed as one
+        let inbound;
+        let (line, command) = match self.protocol {
+            Protocol::Text if binary => match frame.strip_prefix(b"MSG:") {
+                Some(rest) => {
+                    self.mark_active(client_id, received);
+                    if self.rate_limited(client_id, received) {
+                        return;
+                    }
+                    return self.relay_private_binary(client_id, frame.slice_ref(rest));
+                }
+                None => (line, None),
+            },
+            Protocol::Text => (line, Command::parse(line)),
+            Protocol::Json => {
+                inbound = envelope::decode(line);
+                match &inbound {
+                    Ok(Inbound::Message(text)) => (text.as_str(), None),
+                    Ok(Inbound::Command(cmd)) => match Command::parse(cmd) {
+                        Some(command) => (cmd.as_str(), Some(command)),
+                        None => return self.record_violation(client_id, "bad command"),
+                    },
+                    Err(reason) => return self.record_violation(client_id, reason),
+                }
+            }
+        };
+
+        // Anything but a PONG shows someone is using the client, and counts
+        // against its rate limit; PONGs are answered by client libraries on
+        // their own
+        if !matches!(command, Some(Command::Pong)) {
+            self.mark_active(client_id, received);
+            if self.rate_limited(client_id, received) {
+                return;
+            }
+        }
+
+        // Read-only maintenance: nothing reaches anyone else, except from
+        // admins
+        if matches!(command, None | Some(Command::Pub { .. } | Command::Sequenced { .. } | Command::Msg { .. } | Command::Event(_)))
+            && self.read_only(client_id)
+        {
+            return;
+        }
+
+        // A tagged or numbered message goes the same way as any other, with
+        // its type or number
+        let (line, content_type, seq) = match command {
+            Some(Command::Pub { content_type, text }) => (text, Some(content_type), None),
+            Some(Command::Sequenced { seq, text }) => (text, None, Some(seq)),
+            _ => (line, None, None),
+        };
+
+        match command {
+            Some(Command::Pub { .. } | Command::Sequenced { .. }) | None => {}
+            Some(command) => return self.command(client_id, command, received),
+        }
+
+        // Numbers only go up, so a resent message isn't relayed twice
+        if let Some(seq) = seq {
+            let Some(c) = self.clients.get_mut(&client_id) else { return };
+            if seq <= c.last_seq {
+                self.reply(client_id, format!("ERROR:OUT_OF_SEQUENCE {seq}\n"));
+                return;
+            }
+            c.last_seq = seq;
+        }
+
+        // Room modes: slow mode turns away messages sent too soon
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        let modes = c.room.as_ref().and_then(|r| self.rooms.get(r)).map(|r| r.modes.clone()).unwrap_or_default();
+        let now = Instant::now();
+        if let (Some(slow), Some(last)) = (modes.slow, c.last_message) {
+            let wait = slow.saturating_sub(now.duration_since(last));
+            if !wait.is_zero() {
+                self.reply(client_id, format!("ERROR:SLOW_MODE {}\n", wait.as_secs_f64().ceil() as u64));
+                return;
+            }
+        }
+        c.last_message = Some(now);
+
+        // Ingest traffic skips per-message logging and per-recipient
+        // flushes; the flush tick pushes it out in batches.
+        self.sizes.record(frame.len());
+        c.messages_in += 1;
+        c.bytes_in += frame.len() as u64;
+        self.counters.messages_in += 1;
+        self.counters.bytes_in += frame.len() as u64;
+        let ingest = c.ingest.is_some();
+        let batched = match self.tuning.batching {
+            Batching::Never => false,
+            Batching::Ingest => ingest,
+            Batching::All => true,
+        };
+        if !ingest && binary {
+            info!(bytes = frame.len(), "binary message");
+        } else if !ingest {
+            info!(text = line, content_type, "message");
+        }
+        let lang = c.lang.clone();
+        let mut message = Frame::new(client_id, line, content_type, lang.as_deref());
+        if let Some(hook) = self.hooks.on_message.as_mut() {
+            if let Err(payload) = std::panic::catch_unwind(AssertUnwindSafe(|| hook(&mut message))) {
+                self.hook_panicked(client_id, "on_message", &*payload);
+                return;
+            }
+        }
+
+        // Repeats of the last line are held back and reported as a count
+        let check = c.dedup.as_mut().map(|d| d.check(&frame, Instant::now()));
+        if let Some(n) = check.as_ref().and_then(|check| check.ended) {
+            self.report_repeats(client_id, n, !batched);
+        }
+        if let Some(closes_in) = check.as_ref().and_then(|check| check.closes_in) {
+            self.dedup_expiry.insert(client_id, closes_in);
+        }
+
+        // The application's filters may drop, rewrite or annotate it
+        let mut text = Cow::Borrowed(message.text);
+        let mut dropped = false;
+        for filter in self.hooks.filters.iter_mut().filter(|_| !binary) {
+            let verdict = match std::panic::catch_unwind(AssertUnwindSafe(|| filter.on_message(client_id, &text))) {
+                Ok(verdict) => verdict,
+                Err(payload) => {
+                    self.hook_panicked(client_id, "filter", &*payload);
+                    return;
+                }
+            };
+            match verdict {
+                MessageAction::Pass => {}
+                MessageAction::Drop => {
+                    info!("dropped by filter {client_id}");
+                    dropped = true;
+                    break;
+                }
+                MessageAction::Rewrite(line) => text = Cow::Owned(line),
+                MessageAction::Annotate(key, value) => message.annotate(key, value),
+            }
+        }
+
+        // The room's word filters may mask the message, refuse it, or flag
+        // it to whoever moderates
+        if !binary && !dropped {
+            let room = self.clients.get(&client_id).and_then(|c| c.room.clone());
+            match self.filters.check(room.as_deref(), &text) {
+                Ok(checked) => {
+                    let checked_text = checked.text.into_owned();
+                    for list in checked.flagged {
+                        self.flag(client_id, &list, &checked_text);
+                    }
+                    text = Cow::Owned(checked_text);
+                }
+                Err(list) => {
+                    info!("filtered {client_id} {list}");
+                    self.reply(client_id, format!("ERROR:FILTERED {list}\n"));
+                    return;
+                }
+            }
+        }
+
+        // A bridge sending back what it was just given is echoing it
+        let echo = !binary
+            && !dropped
+            && self
+                .clients
+                .get_mut(&client_id)
+                .and_then(|c| c.echoes.as_mut())
+                .is_some_and(|echoes| echoes.is_echo(&sanitize_payload(&text), Instant::now()));
+        if echo {
+            info!("echo {client_id} dropped");
+        }
+
+        // Broadcast to all other clients, unless the room holds it for its
+        // moderator. The origin id is always stamped here; the payload is
+        // scrubbed so it can't pose as another frame on the receiving side.
+        let held = modes.moderated && !self.moderates(client_id) && !echo && !dropped;
+        let deliver = check.is_none_or(|check| check.deliver) && !echo && !dropped;
+        if deliver && held {
+            if binary {
+                self.reply(client_id, "ERROR:BINARY_IN_MODERATED_ROOM\n");
+            } else {
+                self.hold(client_id, sanitize_payload(&text).into_owned(), content_type);
+            }
+        } else if deliver {
+            if binary {
+                self.relay_binary(client_id, &frame, !batched);
+            } else {
+                let payload = sanitize_payload(&text);
+                let name = self.registry.name(client_id);
+                let room = self.clients.get(&client_id).and_then(|c| c.room.clone());
+                self.publish_message(client_id, &name, room, &payload, content_type, !batched);
+            }
+
+            // Batched writes are only delivered on the next tick
+            if !batched {
+                self.latency.record(received.elapsed());
+            }
+            if let Some(seq) = seq.filter(|_| self.clients.get(&client_id).is_some_and(|c| c.receipts)) {
+                self.receipts.push_back(Receipt { client_id, seq: Some(seq), index: self.fed });
+            }
+        }
+
+        // ACK to sender, either immediately or as part of a range
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        let ack = match c.ingest.as_mut() {
+            Some(state) => state.received(),
+            // A held message was answered with HELD: instead
+            None if modes.acks && c.acks && !held => Some(match seq {
+                Some(seq) => format!("ACK:{seq}\n"),
+                None => "ACK:MESSAGE\n".to_string(),
+            }),
+            None => None,
+        };
+        if let Some(ack) = ack {
+            if !enqueue(client_id, c, Bytes::from(ack), !batched, self.slow_consumer, self.protocol) {
+                self.remove_client(client_id, Reason::Writer);
+            }
+        }
+    }
+
+    /// Sends `msg` to everyone else in the sender's room, or to every client
+    /// for server lines; each writer task picks it up from the shared feed.
+    fn fan_out(&mut self, from: Option<ClientId>, msg: impl Into<Bytes>, flush: bool, event: bool) {
+        let to = match from {
+            Some(id) => Audience::Room(self.clients.get(&id).and_then(|c| c.room.clone())),
+            None => Audience::All,
+        };
+        self.publish(from, to, msg.into(), flush, event);
+    }
+
+    /// Tells every client but `subject` about it, whatever room they're in,
+    /// unless the server is shedding load.
+    fn announce(&mut self, subject: ClientId, msg: String) {
+        if self.shedder.shedding() {
+            self.shedder.lines += 1;
+            return;
+        }
+        self.publish(Some(subject), Audience::All, msg.into(), true, false);
+    }
+
+    fn publish(&mut self, from: Option<ClientId>, to: Audience, line: Bytes, flush: bool, event: bool) {
+        let line = self.protocol.encode(line);
+        self.feed_out(Fanout {
+            from,
+            echo: false,
+            to,
+            line,
+            flush,
+            event,
+            binary: false,
+            content_type: None,
+            compressed: None,
+            queued: Instant::now(),
+        });
+    }
+
+    /// Relays a binary message, byte for byte, to the framed clients in the
+    /// sender's room. It isn't logged, kept or offloaded: those are all
+    /// text.
+    fn relay_binary(&mut self, from: ClientId, payload: &[u8], flush: bool) {
+        let name = self.registry.name(from);
+        let mut msg = BytesMut::with_capacity("MESSAGE: \n".len() + name.len() + payload.len());
+        msg.put_slice(format!("MESSAGE:{name} ").as_bytes());
+        msg.put_slice(payload);
+        msg.put_u8(b'\n');
+        let to = Audience::Room(self.clients.get(&from).and_then(|c| c.room.clone()));
+        let line = msg.freeze();
+        let echo = self.clients.get(&from).is_some_and(|c| c.echo);
+        self.feed_out(Fanout {
+            from: Some(from),
+            echo,
+            to,
+            line,
+            flush,
+            event: false,
+            binary: true,
+            content_type: None,
+            compressed: None,
+            queued: Instant::now(),
+        });
+    }
+
+    fn feed_out(&mut self, fanout: Fanout) {
+        if self.send_direct(&fanout) {
+            self.counters.direct += 1;
+            self.fed_direct = true;
+            return;
+        }
+        self.fed_direct = false;
+        self.feed_unflushed |= !fanout.flush;
+        self.fed += 1;
+        // Only fails when nobody is connected
+        let _ = self.feed.send(fanout);
+    }
+
+    /// Sends a broadcast for a small room straight to its members' queues,
+    /// so they needn't take it off the feed behind everything else going
+    /// through; returns whether it did. Writers take their own queue first,
+    /// so a room only starts doing this once none of its members has lines
+    /// left on the feed, which would otherwise come after the ones queued.
+    /// Receipts are counted along the feed, so numbered messages from
+    /// clients wanting them go that way.
+    fn send_direct(&mut self, fanout: &Fanout) -> bool {
+        let Audience::Room(Some(name)) = &fanout.to else { return false };
+        let Some(room) = self.rooms.get_mut(name) else { return false };
+        if room.members.len() > self.tuning.small_room {
+            room.direct = false;
+            return false;
+        }
+        if fanout.from.is_some_and(|id| self.clients.get(&id).is_some_and(|c| c.receipts)) {
+            return false;
+        }
+        if !room.direct {
+            let caught_up = |id| self.clients.get(id).is_none_or(|c: &Client| self.fed == c.fed_before + c.writer.consumed());
+            if !room.members.iter().all(caught_up) {
+                return false;
+            }
+            room.direct = true;
+        }
+        let mut dead = Vec::new();
+        for &id in &room.members {
+            let Some(c) = self.clients.get_mut(&id) else { continue };
+            if let Err(e) = c.writer.send_fanout(id, c.framed, fanout) {
+                if !on_send_error(id, c, e, self.slow_consumer) {
+                    dead.push(id);
+                }
+            }
+        }
+        for id in dead {
+            self.remove_client(id, Reason::Writer);
+        }
+        true
+    }
+
+    /// Moves a client to another room (`None` is the lobby), keeping its
+    /// members in step.
+    fn set_room(&mut self, client_id: ClientId, room: Option<Arc<str>>) {
+        // What's left of the old room's replay is no use now
+        if let Some(replays) = &mut self.replays {
+            replays.cancel(client_id);
+        }
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        if c.room == room {
+            return;
+        }
+        if let Some(old) = c.room.take() {
+            info!("part {client_id} {old}");
+            if let Some(r) = self.rooms.get_mut(&old) {
+                r.members.remove(&client_id);
+                if r.members.is_empty() {
+                    self.rooms.remove(&old);
+                }
+            }
+        }
+        if let Some(new) = &room {
+            info!("join {client_id} {new}");
+            let limit = self.history_limit;
+            let r = self.rooms.entry(new.clone()).or_insert_with(|| Room::new(limit));
+            r.members.insert(client_id);
+            // The newcomer may still have lines on the feed
+            r.direct = false;
+        }
+        c.writer.set_room(room.clone());
+        c.room = room;
+    }
+
+    /// Stores a payload too large to broadcast, returning its blob id.
+    /// If the write fails the message goes out whole instead.
+    fn offload(&mut self, payload: &str) -> Option<String> {
+        let blobs = self.blobs.as_mut().filter(|blobs| blobs.wants(payload))?;
+        blobs.put(payload).inspect_err(|e| warn!("blob write failed, sending inline: {e}")).ok()
+    }
+
+    /// Keeps a broadcast message in a room's (or the lobby's) history.
+    fn keep(&mut self, room: Option<&Arc<str>>, line: Bytes) {
+        match room {
+            None => self.lobby_history.push(line),
+            Some(name) => match self.rooms.get_mut(name) {
+                Some(room) if room.modes.history => room.history.push(line),
+                _ => {}
+            },
+        }
+    }
+
+    /// Logs, keeps and fans out a message from `sender` to everyone else in
+    /// `room` (the lobby for `None`), by reference if it's large, and sends
+    /// it on to linked servers and the Redis bridge. A tagged message
+    /// carries its content type in the line.
+    fn publish_message(
+        &mut self,
+        sender: ClientId,
+        name: &str,
+        room: Option<Arc<str>>,
+        payload: &str,
+        content_type: Option<&str>,
+        flush: bool,
+    ) {
+        if let Some(cluster) = &mut self.cluster {
+            cluster.originate(room.as_deref(), content_type, name, payload);
+        }
+        if let Some(bridge) = &mut self.bridge {
+            bridge.publish(room.as_deref(), content_type, name, payload);
+        }
+        self.deliver_message(Some(sender), name, room, payload, content_type, flush);
+    }
+
+    /// Publishes a message from a linked server, or another instance on
+    /// the Redis bridge, to everyone in its room here, under its sender's
+    /// name there. It has been through its own server's hooks and limits,
+    /// so it only gets logged, kept and fanned out.
+    fn publish_relay(&mut self, relay: Relay) {
+        let room = relay.room.as_deref().map(Arc::from);
+        let payload = sanitize_payload(&relay.text);
+        self.deliver_message(None, &relay.sender(), room, &payload, relay.content_type.as_deref(), true);
+    }
+
+    /// The part of publishing a message that's the same wherever it came
+    /// from; `sender` is `None` for a message from a linked server.
+    fn deliver_message(
+        &mut self,
+        sender: Option<ClientId>,
+        name: &str,
+        room: Option<Arc<str>>,
+        payload: &str,
+        content_type: Option<&str>,
+        flush: bool,
+    ) {
+        let stamp = self.stamper.as_mut().map(|stamper| stamper.next(SystemTime::now()));
+        if let Some(journal) = &self.journal {
+            journal.record(sender, name, room.as_deref(), payload, content_type, stamp);
+        }
+        let now = Instant::now();
+        for id in &self.bridges {
+            let Some(c) = self.clients.get_mut(id).filter(|c| Some(*id) != sender && c.room == room) else { continue };
+            if let Some(echoes) = c.echoes.as_mut() {
+                echoes.sent(payload, now);
+            }
+        }
+        let attributes: Vec<String> = content_type
+            .map(|content_type| format!("ct={content_type}"))
+            .into_iter()
+            .chain(stamp.map(|stamp| stamp.attributes()))
+            .collect();
+        let tag = if attributes.is_empty() { String::new() } else { format!("[{}]", attributes.join(",")) };
+        let msg = Bytes::from(match self.offload(payload) {
+            Some(blob) => format!("BLOBREF{tag}:{name} {blob} {}\n", payload.len()),
+            None => format!("MESSAGE{tag}:{name} {payload}\n"),
+        });
+        self.keep(room.as_ref(), msg.clone());
+        let line = self.protocol.encode(msg);
+        // Compressed once for each algorithm some client asked for
+        let compress = self.compress_min_bytes.is_some_and(|min| payload.len() >= min) && !line.starts_with(b"BLOBREF");
+        let compressed = (compress && !self.compressing.is_empty()).then(|| {
+            let tag: String = attributes.iter().map(|attribute| format!("{attribute},")).collect();
+            self.compressing
+                .keys()
+                .map(|&alg| {
+                    let line = format!("MESSAGE[{tag}enc={}]:{name} {}\n", alg.name(), alg.compress(payload.as_bytes()));
+                    (alg, Bytes::from(line))
+                })
+                .collect()
+        });
+        let content_type = Bytes::copy_from_slice(content_type.unwrap_or(protocol::UNTAGGED).as_bytes());
+        let to = Audience::Room(room);
+        let queued = Instant::now();
+        let echo = sender.and_then(|id| self.clients.get(&id)).is_some_and(|c| c.echo);
+        self.feed_out(Fanout {
+            from: sender,
+            echo,
+            to,
+            line,
+            flush,
+            event: false,
+            binary: false,
+            content_type: Some(content_type),
+            compressed,
+            queued,
+        });
+    }
+
+    /// Whether the client is the moderator of the room it's in.
+    fn moderates(&self, client_id: ClientId) -> bool {
+        let room = self.clients.get(&client_id).and_then(|c| c.room.as_ref()).and_then(|r| self.rooms.get(r));
+        room.is_some_and(|room| room.moderator == Some(client_id))
+    }
+
+    /// Holds a message in the sender's moderated room until its moderator
+    /// approves or rejects it.
+    fn hold(&mut self, client_id: ClientId, text: String, content_type: Option<&str>) {
+        let Some(name) = self.clients.get(&client_id).and_then(|c| c.room.clone()) else { return };
+        let Some(room) = self.rooms.get_mut(&name) else { return };
+        if room.held.len() >= MAX_HELD {
+            self.reply(client_id, "ERROR:MODERATION_QUEUE_FULL\n");
+            return;
+        }
+        self.last_held += 1;
+        let id = self.last_held;
+        let sender = self.registry.name(client_id);
+        let pending = format!("PENDING:{id} {sender} {text}\n");
+        room.held.insert(id, Held { sender: client_id, name: sender, text, content_type: content_type.map(String::from) });
+        let moderator = room.moderator;
+        info!("held {client_id} {name} {id}");
+        self.reply(client_id, format!("HELD:{id}\n"));
+        if let Some(moderator) = moderator {
+            self.reply(moderator, pending);
+        }
+    }
+
+    /// Tells the room's moderator and every admin that a message from
+    /// `client_id` matched a `flag` filter.
+    fn flag(&mut self, client_id: ClientId, list: &str, text: &str) {
+        let room = self.clients.get(&client_id).and_then(|c| c.room.clone());
+        let moderator = room.as_ref().and_then(|name| self.rooms.get(name)).and_then(|room| room.moderator);
+        let sender = self.registry.name(client_id);
+        let line = format!("FLAGGED:{} {list} {sender} {}\n", room.as_deref().unwrap_or("-"), sanitize_payload(text));
+        let to: Vec<ClientId> =
+            self.clients.iter().filter(|(&id, c)| id != client_id && (c.admin || Some(id) == moderator)).map(|(&id, _)| id).collect();
+        info!("flagged {client_id} {list} to={}", to.len());
+        for id in to {
+            self.reply(id, line.clone());
+        }
+    }
+
+    /// Sends an alert that tripped to the admins in the alert room.
+    fn alert(&mut self, tripped: Option<Condition>) {
+        let (Some(condition), Some(room)) = (tripped, self.alerter.room()) else { return };
+        let to: Vec<ClientId> =
+            self.clients.iter().filter(|(_, c)| c.admin && c.room.as_deref() == Some(room)).map(|(&id, _)| id).collect();
+        let line = condition.line();
+        for id in to {
+            self.reply(id, line.clone());
+        }
+    }
+
+    /// `MSG:` with a binary payload, from a framed client to another: the
+    /// relay for when a direct connection can't be made.
+    fn relay_private_binary(&mut self, client_id: ClientId, msg: Bytes) {
+        if self.read_only(client_id) {
+            return;
+        }
+        let (to, payload) = match msg.iter().position(|&b| b == b' ') {
+            Some(space) => (msg.slice(..space), msg.slice(space + 1..)),
+            None => (msg.clone(), Bytes::new()),
+        };
+        let to = String::from_utf8_lossy(&to);
+        let Some(target) = self.resolve(&to) else {
+            self.reply(client_id, format!("ERROR:UNKNOWN_CLIENT {}\n", sanitize_payload(&to)));
+            return;
+        };
+        if !self.clients.get(&target).is_some_and(|t| t.framed) {
+            self.reply(client_id, format!("ERROR:NOT_FRAMED {to}\n"));
+            return;
+        }
+        info!("msg {client_id} {target} binary bytes={}", payload.len());
+        let from = self.registry.name(client_id);
+        let mut line = BytesMut::with_capacity("MSG: \n".len() + from.len() + payload.len());
+        line.put_slice(format!("MSG:{from} ").as_bytes());
+        line.put_slice(&payload);
+        line.put_u8(b'\n');
+        self.reply(target, line.freeze());
+        self.reply(client_id, "ACK:MSG\n");
+    }
+
+    /// Sends a room's (or the lobby's) recent messages to a client.
+    fn replay(&mut self, client_id: ClientId, room: Option<&Arc<str>>) {
+        let lines = self.history_lines(client_id, room);
+        self.replay_lines(client_id, lines);
+    }
+
+    /// Replays the messages stamped after `last` that the client's room (or
+    /// the lobby) still keeps, for a client picking up where it left off.
+    fn resume(&mut self, client_id: ClientId, last: &str) {
+        if self.stamper.is_none() {
+            return self.reply(client_id, "ERROR:RESUME_DISABLED\n");
+        }
+        let Ok(last) = last.parse::<u64>() else {
+            return self.reply(client_id, format!("ERROR:INVALID_RESUME {}\n", sanitize_payload(last)));
+        };
+        let room = self.clients.get(&client_id).and_then(|c| c.room.clone());
+        let history = match &room {
+            None => Some(&self.lobby_history),
+            Some(name) => self.rooms.get(name).map(|room| &room.history),
+        };
+        // Covered if nothing was lost, or the oldest line kept is one the client saw
+        let covered = history.is_none_or(|history| {
+            !history.wrapped() || history.since(0).next().and_then(|(_, line)| stamp::id(line)).is_some_and(|id| id <= last)
+        });
+        let mut lines = self.history_lines(client_id, room.as_ref());
+        lines.retain(|line| stamp::id(&line[b"HISTORY:".len()..]).is_some_and(|id| id > last));
+        if !covered {
+            self.reply(client_id, "RESUME:GAP\n");
+        }
+        self.reply(client_id, format!("ACK:RESUME {}\n", lines.len()));
+        self.replay_lines(client_id, lines);
+    }
+
+    /// A room's (or the lobby's) kept lines, as `HISTORY:` lines, of the
+    /// content types the client accepts.
+    fn history_lines(&self, client_id: ClientId, room: Option<&Arc<str>>) -> Vec<Bytes> {
+        let lines = match room {
+            None => self.lobby_history.replay(),
+            Some(name) => self.rooms.get(name).map(|room| room.history.replay()).unwrap_or_default(),
+        };
+        let Some(c) = self.clients.get(&client_id) else { return Vec::new() };
+        lines.into_iter().filter(|line| protocol::content_type(&line[b"HISTORY:".len()..]).is_none_or(|ct| c.writer.accepts(ct))).collect()
+    }
+
+    /// Sends replayed lines now, or queues them when replay is paced.
+    fn replay_lines(&mut self, client_id: ClientId, lines: Vec<Bytes>) {
+        match &mut self.replays {
+            Some(replays) => replays.start(client_id, lines),
+            None => lines.into_iter().for_each(|line| self.reply(client_id, line)),
+        }
+    }
+
+    /// Sends the pages of paced replays that are due.
+    fn send_replay_pages(&mut self) {
+        let Some(replays) = &mut self.replays else { return };
+        for (client_id, page) in replays.due(Instant::now()) {
+            for line in page {
+                self.reply(client_id, line);
+            }
+        }
+    }
+
+    fn report_repeats(&mut self, client_id: ClientId, n: u32, flush: bool) {
+        let msg = format!("REPEATED:{} {n}\n", self.registry.name(client_id));
+        self.fan_out(Some(client_id), msg, flush, false);
+    }
+
+    fn expire_repeats(&mut self, client_id: ClientId) {
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        let Some(n) = c.dedup.as_mut().and_then(|d| d.expire(Instant::now())) else { return };
+        let flush = self.tuning.batching == Batching::Never || (self.tuning.batching == Batching::Ingest && c.ingest.is_none());
+        self.report_repeats(client_id, n, flush);
+    }
+
+    /// Takes a line from the client's rate budget, when rate limiting is
+    /// on. A line over it is rejected, and the client disconnected if it
+    /// keeps on; returns whether it was.
+    fn rate_limited(&mut self, client_id: ClientId, received: Instant) -> bool {
+        let Some(c) = self.clients.get_mut(&client_id) else { return true };
+        if c.rate.as_mut().is_none_or(|rate| rate.try_take(received)) {
+            return false;
+        }
+        c.rate_strikes += 1;
+        let limit = self.rate_limit.as_ref().map_or(u32::MAX, |l| l.disconnect_after);
+        if c.rate_strikes < limit {
+            self.reply(client_id, "ERROR:RATE_LIMITED\n");
+            return true;
+        }
+        info!("rate limited {client_id} strikes={}", c.rate_strikes);
+        let line = Bytes::from_static(b"ERROR:RATE_LIMITED\n");
+        let _ = enqueue(client_id, c, line, true, self.slow_consumer, self.protocol);
+        self.remove_client(client_id, Reason::RateLimited);
+        true
+    }
+
+    /// Sends a line straight back to one client, dropping it on failure.
+    fn reply(&mut self, client_id: ClientId, line: impl Into<Bytes>) {
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        if !enqueue(client_id, c, line.into(), true, self.slow_consumer, self.protocol) {
+            self.remove_client(client_id, Reason::Writer);
+        }
+    }
+
+    /// Puts an empty line on the feed, which only asks every writer to
+    /// flush.
+    fn feed_flush(&mut self) {
+        let queued = Instant::now();
+        let line = Bytes::new();
+        self.feed_out(Fanout {
+            from: None,
+            echo: false,
+            to: Audience::All,
+            line,
+            flush: true,
+            event: false,
+            binary: false,
+            content_type: None,
+            compressed: None,
+            queued,
+        });
+    }
+
+    fn flush_batched(&mut self) {
+        let mut dead: Vec<ClientId> = Vec::new();
+        for (&id, c) in self.clients.iter_mut() {
+            let alive = match c.ingest.as_mut().and_then(IngestState::take_range) {
+                Some(ack) => enqueue(id, c, Bytes::from(ack), true, self.slow_consumer, self.protocol),
+                None => match c.writer.flush() {
+                    Ok(()) => true,
+                    Err(e) => on_send_error(id, c, e, self.slow_consumer),
+                },
+            };
+            if !alive {
+                dead.push(id);
+            }
+        }
+        if std::mem::take(&mut self.feed_unflushed) {
+            self.feed_flush();
+        }
+        for id in dead {
+            self.remove_client(id, Reason::Writer);
+        }
+    }
+
+    /// Logs, for each client that has missed lines to the slow-consumer
+    /// policy since the last housekeeping tick, how many.
+    fn report_dropped(&mut self) {
+        for c in self.clients.values_mut() {
+            let total = c.writer.dropped();
+            if total > c.dropped_reported {
+                let _entered = c.span.enter();
+                info!(dropped = total - c.dropped_reported, total, "slow consumer dropped lines");
+                c.dropped_reported = total;
+            }
+        }
+    }
+
+    /// Disconnects clients whose writer is stuck so far behind that the
+    /// feed has already overwritten lines it hasn't taken yet.
+    fn disconnect_stalled(&mut self) {
+        let capacity = self.send_queue as u64;
+        let stalled: Vec<ClientId> =
+            self.clients.iter().filter(|(_, c)| self.fed - c.fed_before - c.writer.consumed() > capacity).map(|(&id, _)| id).collect();
+        for id in stalled {
+            info!("slow consumer {id} stalled");
+            if let Some(c) = self.clients.get(&id) {
+                c.writer.abort_lagged();
+            }
+            self.remove_client(id, Reason::SlowConsumer);
+        }
+    }
+
+    /// Disconnects clients whose lines have taken over budget to deliver at
+    /// every check for the whole grace period. A network blip shorter than
+    /// that costs a client some dropped lines at worst, not its connection.
+    fn disconnect_late(&mut self, limit: LatencyBudget) {
+        let now = Instant::now();
+        let mut late = Vec::new();
+        for (&id, c) in &mut self.clients {
+            let latency = c.writer.take_latency();
+            if latency > limit.budget {
+                let since = *c.late_since.get_or_insert(now);
+                if now.duration_since(since) >= limit.grace {
+                    late.push((id, latency));
+                }
+            } else if latency < limit.budget / 2 {
+                c.late_since = None;
+            }
+        }
+        for (id, latency) in late {
+            let _entered = self.span(id).entered();
+            info!(latency_ms = latency.as_millis() as u64, "slow consumer over latency budget");
+            if let Some(c) = self.clients.get(&id) {
+                c.writer.abort_lagged();
+            }
+            self.remove_client(id, Reason::SlowConsumer);
+        }
+    }
+
+    /// Applies each client's idle policy: pings the quiet ones due a
+    /// `PING`, and disconnects those that didn't answer theirs or, without
+    /// pings, have been quiet too long.
+    fn reap_idle(&mut self) {
+        let now = Instant::now();
+        let mut quiet = Vec::new();
+        let mut dead = Vec::new();
+        for (&id, c) in &self.clients {
+            match c.idle.check(now, c.last_heard, c.pinged) {
+                Verdict::Keep => {}
+                Verdict::Ping => quiet.push(id),
+                verdict => dead.push((id, verdict)),
+            }
+        }
+        for id in quiet {
+            if let Some(c) = self.clients.get_mut(&id) {
+                c.pinged = Some(now);
+            }
+            self.reply(id, "PING\n");
+        }
+        for (id, verdict) in dead {
+            let Some(c) = self.clients.get(&id) else { continue };
+            let _entered = c.span.clone().entered();
+            let quiet_secs = now.duration_since(c.last_heard).as_secs();
+            let reason = match verdict {
+                Verdict::NoPong => {
+                    info!(quiet_secs, policy = %c.idle, "ping timeout");
+                    Reason::PingTimeout
+                }
+                _ => {
+                    info!(quiet_secs, policy = %c.idle, "idle timeout");
+                    Reason::IdleTimeout
+                }
+            };
+            c.writer.abort();
+            self.remove_client(id, reason);
+        }
+    }
+
+    /// Sends a keepalive to every client that has been sent nothing for the
+    /// NOOP interval. Unlike `PING` it asks for no answer: it's only
+    /// there so NATs and firewalls that forget quiet flows see traffic.
+    fn send_keepalives(&mut self) {
+        let Some(interval) = self.noop_interval else { return };
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for (&id, c) in &mut self.clients {
+            let sent = c.writer.sent_bytes();
+            if sent != c.sent.0 {
+                c.sent = (sent, now);
+            } else if now.duration_since(c.sent.1) >= interval {
+                due.push(id);
+            }
+        }
+        for id in due {
+            let Some(c) = self.clients.get_mut(&id) else { continue };
+            // The WebSocket writer sends an empty line as a ping frame
+            let line = match c.websocket {
+                true => Bytes::from_static(b"\n"),
+                false => self.protocol.encode(Bytes::from_static(b"NOOP\n")),
+            };
+            // Its own bytes don't count as traffic, or it would only go every other interval
+            c.sent = (c.sent.0 + line.len() as u64, now);
+            if let Err(e) = c.writer.send(line, true) {
+                if !on_send_error(id, c, e, self.slow_consumer) {
+                    self.remove_client(id, Reason::Writer);
+                }
+            }
+        }
+    }
+
+    /// Moves quiet clients to idle or away.
+    fn check_presence(&mut self) {
+        let now = Instant::now();
+        let changed: Vec<ClientId> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| c.authed && self.presence.state(now.duration_since(c.last_active)) != c.presence)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in changed {
+            self.update_presence(id, now);
+        }
+    }
+
+    /// A client by id or nickname, if it has been let in.
+    fn resolve(&self, name: &str) -> Option<ClientId> {
+        self.registry.resolve(name).filter(|id| self.clients.get(id).is_none_or(|c| c.authed))
+    }
+
+    fn mark_active(&mut self, client_id: ClientId, now: Instant) {
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        c.last_active = now;
+        self.update_presence(client_id, now);
+    }
+
+    /// Tells the client's room if its presence changed, budget permitting;
+    /// a change over budget, or while shedding load, is picked up again by
+    /// the next check.
+    fn update_presence(&mut self, client_id: ClientId, now: Instant) {
+        if !self.presence.enabled() || self.shedder.shedding() {
+            return;
+        }
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        let state = self.presence.state(now.duration_since(c.last_active));
+        if state == c.presence || !c.presence_budget.try_take(now) {
+            return;
+        }
+        c.presence = state;
+        let to = Audience::Room(c.room.clone());
+        let line = format!("PRESENCE:{} {state}\n", self.registry.name(client_id));
+        self.publish(Some(client_id), to, line.into(), true, false);
+    }
+
+    /// Starts or stops shedding load, going by how far behind the clients'
+    /// writers are altogether.
+    fn check_load(&mut self) {
+        let queued = self.clients.values().map(|c| self.fed - c.fed_before - c.writer.consumed()).sum();
+        match self.shedder.check(queued, Instant::now()) {
+            Some(Change::Started(load)) => match load.resident {
+                Some(resident) => warn!("load shedding on queued={} resident_bytes={resident}", load.queued),
+                None => warn!("load shedding on queued={}", load.queued),
+            },
+            Some(Change::Stopped { after }) => warn!("load shedding off after_secs={}", after.as_secs()),
+            None => {}
+        }
+    }
+
+    fn housekeeping(&mut self) {
+        self.detector.prune(Instant::now());
+        self.forgive_violations();
+        if let Some(summary) = self.sizes.take_summary() {
+            info!("{summary}");
+        }
+        if let Some(summary) = self.conn_log.take_summary() {
+            info!("{summary}");
+        }
+        if self.tuning.report_latency {
+            if let Some(summary) = self.latency.take_summary() {
+                info!("{summary}");
+            }
+        }
+        if self.alerter.enabled() {
+            let now = Instant::now();
+            let tripped = self.alerter.check_fds(now);
+            self.alert(tripped);
+            let tripped = self.alerter.check_memory(now);
+            self.alert(tripped);
+            let deepest =
+                self.clients.iter().map(|(&id, c)| (id, self.fed - c.fed_before - c.writer.consumed())).max_by_key(|&(_, queued)| queued);
+            let tripped = self.alerter.check_queue(deepest, self.send_queue as u64, now);
+            self.alert(tripped);
+        }
+        self.report_dropped();
+        if self.counters.panics > 0 {
+            info!("panics total={}", self.counters.panics);
+        }
+        let stats = &self.counters.tarpit;
+        if stats.pending + stats.active > 0 {
+            info!("tarpit stats pending={} active={} total={}", stats.pending, stats.active, stats.total);
+        }
+    }
+
+    /// Drops a client for `reason`; `Reason::Writer` is narrowed down to
+    /// what made its writer give up.
+    fn remove_client(&mut self, client_id: ClientId, reason: Reason) {
+        let _entered = self.span(client_id).entered();
+        self.set_room(client_id, None);
+        if let Some(c) = self.clients.remove(&client_id) {
+            self.report_session(client_id, &c, reason);
+            self.registry.unregister(client_id);
+            self.bridges.remove(&client_id);
+            self.count_compressing(c.compression, None);
+            if c.ingest.is_some() {
+                self.counters.ingesting -= 1;
+            }
+            if c.tarpitted {
+                self.counters.tarpit.active -= 1;
+            }
+            if c.writer.panicked() {
+                self.counters.panics += 1;
+            }
+            self.counters.disconnects += 1;
+            self.counters.bytes_out += c.writer.sent_bytes();
+            self.counters.write_errors += u64::from(c.writer.failed());
+            self.counters.slow_consumers += u64::from(c.writer.lagged());
+            self.counters.dropped_lines += c.writer.dropped();
+            if self.conn_log.sample(Instant::now()) {
+                info!(
+                    messages_in = c.messages_in,
+                    bytes_in = c.bytes_in,
+                    dropped = c.writer.dropped(),
+                    clients = self.clients.len(),
+                    "disconnected"
+                );
+            }
+            c.writer.close();
+            if c.authed {
+                self.announce(client_id, format!("LEFT:{client_id}\n"));
+            }
+        }
+        self.inputs.remove(&client_id);
+        self.parked.remove(&client_id);
+        self.fair.remove(client_id);
+    }
+
+    /// Sends the summary of a session ending for `reason` to the session
+    /// webhook, if there is one.
+    fn report_session(&self, client_id: ClientId, c: &Client, reason: Reason) {
+        let Some(sessions) = &self.sessions else { return };
+        let reason = match reason {
+            Reason::Writer if c.writer.panicked() => Reason::Panicked,
+            Reason::Writer if c.writer.lagged() => Reason::SlowConsumer,
+            Reason::Writer => Reason::WriteError,
+            reason => reason,
+        };
+        sessions.send(&Summary {
+            client_id,
+            name: self.registry.name(client_id),
+            identity: c.identity.clone(),
+            peer: self.registry.peer(client_id).unwrap_or(UNIX_PEER),
+            duration: c.connected.elapsed(),
+            reason,
+            messages_in: c.messages_in,
+            bytes_in: c.bytes_in,
+            bytes_out: c.writer.sent_bytes(),
+            dropped: c.writer.dropped(),
+        });
+    }
+
+    /// Queues a line for fair scheduling, and stops reading from its
+    /// sender once it has used up its read budget.
+    fn queue_fair(&mut self, client_id: ClientId, frame: Bytes) {
+        self.fair.push(client_id, frame, Instant::now());
+        if self.fair.queued(client_id) >= READ_BUDGET {
+            if let Some(input) = self.inputs.remove(&client_id) {
+                self.parked.insert(client_id, input);
+            }
+        }
+    }
+
+    /// Handles the next fair-queued line, reading from its sender again
+    /// once it's back under budget. Returns whether there was one.
+    fn handle_next_fair(&mut self) -> bool {
+        let Some((client_id, frame, received)) = self.fair.pop() else { return false };
+        if self.fair.queued(client_id) < READ_BUDGET {
+            if let Some(input) = self.parked.remove(&client_id) {
+                self.inputs.insert(client_id, input);
+            }
+        }
+        self.handle_frame(client_id, frame, received);
+        true
+    }
+
+    /// Drops a client whose input failed, telling it why if it sent a line
+    /// over the limit.
+    fn read_failed(&mut self, client_id: ClientId, e: io::Error) {
+        let _entered = self.span(client_id).entered();
+        let reason = if Panicked::is(&e) {
+            self.counters.panics += 1;
+            error!(error = %e, "reader panicked");
+            Reason::Panicked
+        } else if LineTooLong::is(&e) {
+            info!("line too long");
+            self.reply(client_id, format!("ERROR:LINE_TOO_LONG {}\n", self.max_line));
+            Reason::LineTooLong
+        } else {
+            warn!(error = %e, "read error");
+            Reason::ReadError
+        };
+        self.remove_client(client_id, reason);
+    }
+
+    /// The client closed its end: what it sent before that still counts.
+    fn client_closed(&mut self, client_id: ClientId) {
+        for (frame, received) in self.fair.remove(client_id) {
+            self.handle_frame(client_id, frame, received);
+        }
+        self.remove_client(client_id, Reason::Closed);
+    }
+
+    /// Counts a malformed frame against the client and applies the policy's
+    /// response: a `WARN:PROTOCOL` line, throttled reads, or disconnection.
+    fn record_violation(&mut self, client_id: ClientId, reason: &str) {
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        c.violations += 1;
+        let tarpitted = c.tarpitted;
+        info!("violation {client_id} {reason} count={}", c.violations);
+
+        let response = self.violations.respond(c.violations);
+        let line = match response {
+            Response::Disconnect => format!("ERROR:PROTOCOL_VIOLATION {reason}\n"),
+            Response::Warn | Response::Throttle => format!("WARN:PROTOCOL {reason}\n"),
+        };
+        let alive = enqueue(client_id, c, Bytes::from(line), true, self.slow_consumer, self.protocol);
+        match response {
+            Response::Disconnect => self.remove_client(client_id, Reason::ProtocolViolation),
+            _ if !alive => self.remove_client(client_id, Reason::Writer),
+            // A tarpitted client is already read slowly enough
+            Response::Throttle if !tarpitted => self.set_throttle(client_id, Some(self.violations.throttle_interval)),
+            Response::Throttle | Response::Warn => {}
+        }
+    }
+
+    /// Forgives one violation per client and lifts violation throttling from
+    /// clients that have dropped back under the threshold.
+    fn forgive_violations(&mut self) {
+        let mut unthrottle = Vec::new();
+        for (&id, c) in self.clients.iter_mut() {
+            c.rate_strikes = c.rate_strikes.saturating_sub(1);
+            if c.violations == 0 {
+                continue;
+            }
+            c.violations -= 1;
+            if c.violations + 1 == self.violations.throttle_at && !c.tarpitted {
+                unthrottle.push(id);
+            }
+        }
+        for id in unthrottle {
+            self.set_throttle(id, None);
+        }
+    }
+
+    fn set_throttle(&mut self, client_id: ClientId, interval: Option<Duration>) {
+        let input = match self.parked.get_mut(&client_id) {
+            Some(input) => Some(input),
+            None => self.inputs.iter_mut().find(|(id, _)| *id == client_id).map(|(_, input)| input),
+        };
+        if let Some(input) = input {
+            input.lines.get_mut().set_interval(interval);
+        }
+    }
+}
+
+/// The error for a setting that can't be made; `bad` is empty when there
+/// were none at all.
+fn invalid_setting(bad: &str) -> String {
+    match bad {
+        "" => "ERROR:INVALID_SETTING\n".to_string(),
+        bad => format!("ERROR:INVALID_SETTING {}\n", sanitize_payload(bad)),
+    }
+}
+
+/// Queues a line for a client in the server's protocol, applying the
+/// slow-consumer policy if its queue is full. Returns whether the client
+/// should be kept.
+fn enqueue(client_id: ClientId, c: &mut Client, line: Bytes, flush: bool, policy: SlowConsumer, protocol: Protocol) -> bool {
+    match c.writer.send(protocol.encode(line), flush) {
+        Ok(()) => true,
+        Err(e) => on_send_error(client_id, c, e, policy),
+    }
+}
+
+fn on_send_error(client_id: ClientId, c: &mut Client, e: SendError, policy: SlowConsumer) -> bool {
+    match (e, policy) {
+        (SendError::Full, SlowConsumer::DropNewest | SlowConsumer::Latency(_)) => {
+            c.writer.note_dropped();
+            true
+        }
+        // A full queue makes room under DropOldest rather than refusing
+        (SendError::Full, SlowConsumer::Disconnect | SlowConsumer::DropOldest) => {
+            info!("slow consumer {client_id} send queue full");
+            c.writer.abort_lagged();
+            false
+        }
+        // The writer task already logged the write error
+        (SendError::Closed, _) => false,
+    }
+}
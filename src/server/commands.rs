@@ -0,0 +1,474 @@
+//! Commands from clients, other than messages: rooms, nicknames,
+//! settings, private messages and the rest of the line protocol.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use tracing::{info, warn};
+
+use crate::compress::Compression;
+use crate::protocol::{self, sanitize_payload, Command, Setting};
+use crate::purge::Target;
+use crate::registry::{ClientId, NickTaken};
+use crate::unix::UNIX_PEER;
+
+use super::{invalid_setting, IngestState, Server};
+
+impl Server {
+    /// Carries out a command from a client.
+    pub(super) fn command(&mut self, client_id: ClientId, command: Command<'_>, received: Instant) {
+        match command {
+            // Producer negotiates batched acks; everything after this
+            // line is acknowledged via ACK_RANGE instead of ACK:MESSAGE.
+            Command::Ingest => {
+                let Some(c) = self.clients.get_mut(&client_id) else { return };
+                if c.ingest.is_none() {
+                    c.ingest = Some(IngestState::default());
+                    self.counters.ingesting += 1;
+                }
+                info!("ingest mode {client_id}");
+                self.reply(client_id, "ACK:INGEST\n");
+            }
+            Command::Events(_) | Command::Join(_) | Command::Part(_) | Command::Accept(_) if self.locked(client_id) => {}
+            Command::Events(on) => {
+                let Some(c) = self.clients.get_mut(&client_id) else { return };
+                c.writer.set_events(on);
+                self.reply(client_id, "ACK:EVENTS\n");
+            }
+            Command::Join(room) => {
+                let room: Arc<str> = room.into();
+                self.set_room(client_id, Some(room.clone()));
+                self.replay(client_id, Some(&room));
+                self.reply(client_id, format!("ACK:JOIN {room}\n"));
+            }
+            Command::Part(room) => {
+                let Some(c) = self.clients.get(&client_id) else { return };
+                if c.room.as_deref() != Some(room) {
+                    self.reply(client_id, format!("ERROR:NOT_IN_ROOM {room}\n"));
+                    return;
+                }
+                self.set_room(client_id, None);
+                self.reply(client_id, format!("ACK:PART {room}\n"));
+            }
+            Command::BadRoom(room) => {
+                self.reply(client_id, format!("ERROR:INVALID_ROOM {}\n", sanitize_payload(room)));
+            }
+            Command::Who => {
+                let mut ids: Vec<ClientId> = self.clients.iter().filter(|(_, c)| c.authed).map(|(&id, _)| id).collect();
+                ids.sort_unstable();
+                let ids: Vec<String> = ids.iter().map(ClientId::to_string).collect();
+                self.reply(client_id, format!("WHO:{}\n", ids.join(" ")));
+            }
+            Command::WhoRegions => {
+                self.who_regions(client_id);
+            }
+            Command::Rooms => {
+                let rooms: Vec<String> = self
+                    .rooms
+                    .iter()
+                    .map(|(name, room)| match room.modes.is_default() {
+                        true => format!("{name}={}", room.members.len()),
+                        false => format!("{name}={};{}", room.members.len(), room.modes.describe().replace(' ', ";")),
+                    })
+                    .collect();
+                self.reply(client_id, format!("ROOMS:{}\n", rooms.join(" ")));
+            }
+            Command::Mode(settings) => {
+                self.set_modes(client_id, settings);
+            }
+            Command::Nick(nick) => match self.registry.set_nick(client_id, nick) {
+                Ok(()) => {
+                    info!("nick {client_id} {nick}");
+                    self.reply(client_id, format!("ACK:NICK {nick}\n"));
+                }
+                Err(NickTaken) => self.reply(client_id, format!("ERROR:NICK_TAKEN {nick}\n")),
+            },
+            Command::BadNick(nick) => {
+                self.reply(client_id, format!("ERROR:INVALID_NICK {}\n", sanitize_payload(nick)));
+            }
+            // Private: straight to the target's queue, rooms don't matter
+            Command::Msg { to, text } => {
+                let Some(target) = self.resolve(to) else {
+                    self.reply(client_id, format!("ERROR:UNKNOWN_CLIENT {}\n", sanitize_payload(to)));
+                    return;
+                };
+                info!("msg {client_id} {target}");
+                let from = self.registry.name(client_id);
+                self.reply(target, format!("MSG:{from} {}\n", sanitize_payload(text)));
+                self.reply(client_id, "ACK:MSG\n");
+            }
+            Command::Approve(id) => {
+                self.moderate(client_id, id, true);
+            }
+            Command::Reject(id) => {
+                self.moderate(client_id, id, false);
+            }
+            Command::Direct(to) => {
+                self.offer_direct(client_id, to);
+            }
+            Command::DirectFailed(to) => match self.resolve(to) {
+                Some(peer) if self.direct => {
+                    info!("direct failed {client_id} {peer}");
+                    let from = self.registry.name(client_id);
+                    self.reply(peer, format!("DIRECT_FAILED:{from}\n"));
+                    self.reply(client_id, "ACK:DIRECT_FAILED\n");
+                }
+                Some(_) => self.reply(client_id, "ERROR:DIRECT_DISABLED\n"),
+                None => self.reply(client_id, format!("ERROR:UNKNOWN_CLIENT {}\n", sanitize_payload(to))),
+            },
+            Command::Maintenance(mode) => {
+                self.set_maintenance(client_id, mode);
+            }
+            Command::Kick(target) => {
+                self.kick(client_id, target);
+            }
+            Command::KickWhere(selector) => {
+                self.kick_where(client_id, selector);
+            }
+            Command::Set { target, settings } => {
+                self.set_client(client_id, target, settings);
+            }
+            Command::SetWhere { selector, settings } => {
+                self.set_where(client_id, selector, settings);
+            }
+            Command::SetOwn(_) if self.locked(client_id) => {}
+            Command::SetOwn(settings) => {
+                self.set_own(client_id, settings);
+            }
+            Command::Get(keys) => {
+                self.get_settings(client_id, keys);
+            }
+            Command::Broadcast(text) => {
+                self.notice(client_id, text);
+            }
+            Command::BroadcastWhere { selector, text } => {
+                self.notice_where(client_id, selector, text);
+            }
+            Command::Stats => {
+                self.stats(client_id);
+            }
+            Command::Info => {
+                self.reply(client_id, self.info.clone());
+            }
+            Command::History(limit) => {
+                let limit = match limit {
+                    "" => usize::MAX,
+                    n => match n.parse() {
+                        Ok(n) if n > 0 => n,
+                        _ => return self.reply(client_id, format!("ERROR:INVALID_HISTORY {}\n", sanitize_payload(n))),
+                    },
+                };
+                let room = self.clients.get(&client_id).and_then(|c| c.room.clone());
+                let mut lines = self.history_lines(client_id, room.as_ref());
+                lines.drain(..lines.len().saturating_sub(limit));
+                self.reply(client_id, format!("ACK:HISTORY {}\n", lines.len()));
+                self.replay_lines(client_id, lines);
+            }
+            Command::Resume(last) => {
+                self.resume(client_id, last);
+            }
+            Command::Shutdown => {
+                self.shutdown(client_id);
+            }
+            Command::PurgeUser(who) => {
+                // Whatever names a connected client has gone by
+                let mut names = vec![who.to_string()];
+                if let Some(id) = self.resolve(who) {
+                    names.extend([id.to_string(), self.registry.name(id)]);
+                }
+                names.sort();
+                names.dedup();
+                self.purge(client_id, Target::Identity(names));
+            }
+            Command::PurgeRoom(room) => {
+                self.purge(client_id, Target::Room(room.to_string()));
+            }
+            Command::Auth(_) => match self.auth.enabled() {
+                true => self.reply(client_id, "ERROR:ALREADY_AUTHENTICATED\n"),
+                false => self.reply(client_id, "ERROR:AUTH_DISABLED\n"),
+            },
+            Command::BadContentType(content_type) => {
+                self.reply(client_id, format!("ERROR:INVALID_CONTENT_TYPE {}\n", sanitize_payload(content_type)));
+            }
+            Command::Accept(types) => {
+                self.set_accept(client_id, types);
+            }
+            Command::Caps(caps) => {
+                self.set_caps(client_id, caps);
+            }
+            Command::Ping => {
+                self.reply(client_id, "PONG\n");
+            }
+            Command::Pong => {
+                self.measure_rtt(client_id, received);
+            }
+            Command::Fetch(id) => {
+                let found = match self.blobs.as_ref().map(|blobs| blobs.get(id)) {
+                    Some(Ok(found)) => found,
+                    Some(Err(e)) => {
+                        warn!("blob {id} read failed: {e}");
+                        None
+                    }
+                    None => None,
+                };
+                let reply = match found {
+                    Some(payload) => format!("BLOB:{id} {payload}\n"),
+                    None => format!("ERROR:UNKNOWN_BLOB {}\n", sanitize_payload(id)),
+                };
+                self.reply(client_id, reply);
+            }
+            // Ephemeral: fanned out to subscribed clients, never acked
+            Command::Event(name) => {
+                let Some(c) = self.clients.get_mut(&client_id) else { return };
+                if !c.event_budget.try_take(Instant::now()) {
+                    return;
+                }
+                if self.shedder.shedding() {
+                    self.shedder.lines += 1;
+                    return;
+                }
+                let msg = format!("EVENT:{} {name}\n", self.registry.name(client_id));
+                self.fan_out(Some(client_id), msg, true, true);
+            }
+            Command::BadSequence(seq) => {
+                self.reply(client_id, format!("ERROR:INVALID_SEQUENCE {}\n", sanitize_payload(seq)));
+            }
+            Command::Barrier => {
+                self.barrier(client_id);
+            }
+            Command::Receipts(on) => {
+                let Some(c) = self.clients.get_mut(&client_id) else { return };
+                c.receipts = on;
+                self.reply(client_id, if on { "ACK:RECEIPTS ON\n" } else { "ACK:RECEIPTS OFF\n" });
+            }
+            // Messages go on through handle_frame
+            Command::Pub { .. } | Command::Sequenced { .. } => {}
+        }
+    }
+
+    /// `SET:` from a client for itself. All settings must be valid, and
+    /// none an admin's to make, or none are applied.
+    pub(super) fn set_own(&mut self, client_id: ClientId, settings: &str) {
+        let bad = match protocol::parse_settings(settings) {
+            Ok(parsed) => match parsed.iter().position(Setting::admin_only) {
+                Some(i) => settings.split_whitespace().nth(i).unwrap_or_default(),
+                None => {
+                    let Some(c) = self.clients.get_mut(&client_id) else { return };
+                    parsed.into_iter().for_each(|setting| c.apply(setting));
+                    let settings = settings.split_whitespace().collect::<Vec<_>>().join(" ");
+                    return self.reply(client_id, format!("ACK:SET {settings}\n"));
+                }
+            },
+            Err(bad) => bad,
+        };
+        self.reply(client_id, invalid_setting(bad));
+    }
+
+    /// `GET:` from a client: the settings asked for, or all of them, as
+    /// `GET:<key>=<value> ...`.
+    pub(super) fn get_settings(&mut self, client_id: ClientId, keys: &str) {
+        let mut keys: Vec<&str> = keys.split_whitespace().collect();
+        if keys.is_empty() {
+            keys = protocol::SETTINGS.to_vec();
+        }
+        if let Some(bad) = keys.iter().find(|key| !protocol::SETTINGS.contains(key)) {
+            return self.reply(client_id, format!("ERROR:UNKNOWN_SETTING {}\n", sanitize_payload(bad)));
+        }
+        let Some(c) = self.clients.get(&client_id) else { return };
+        let values = keys.iter().map(|key| format!("{key}={}", c.setting(key))).collect::<Vec<_>>().join(" ");
+        self.reply(client_id, format!("GET:{values}\n"));
+    }
+
+    /// Whether an admin has locked the client's room and subscriptions,
+    /// telling it so.
+    pub(super) fn locked(&mut self, client_id: ClientId) -> bool {
+        if !self.clients.get(&client_id).is_some_and(|c| c.locked) {
+            return false;
+        }
+        self.reply(client_id, "ERROR:LOCKED\n");
+        true
+    }
+
+    /// `ACCEPT:` from a client: the content types of messages it wants, or
+    /// `*` for all of them.
+    pub(super) fn set_accept(&mut self, client_id: ClientId, types: &str) {
+        let accept = match types {
+            "*" => None,
+            types => match types.split(',').find(|t| !protocol::valid_content_type(t)) {
+                Some(bad) => {
+                    self.reply(client_id, format!("ERROR:INVALID_CONTENT_TYPE {}\n", sanitize_payload(bad)));
+                    return;
+                }
+                None => Some(types.split(',').map(|t| Bytes::copy_from_slice(t.as_bytes())).collect()),
+            },
+        };
+        let Some(c) = self.clients.get(&client_id) else { return };
+        c.writer.set_accept(accept);
+        self.reply(client_id, format!("ACK:ACCEPT {types}\n"));
+    }
+
+    /// `CAPS:` from a client: `compress=ALG` to get large messages
+    /// compressed, or `compress=none` to stop.
+    pub(super) fn set_caps(&mut self, client_id: ClientId, caps: &str) {
+        let compression = match caps.strip_prefix("compress=") {
+            Some("none") => None,
+            Some(name) if self.compress_min_bytes.is_some() => match Compression::parse(name) {
+                Some(compression) => Some(compression),
+                None => return self.reply(client_id, format!("ERROR:UNSUPPORTED_CAPS {}\n", sanitize_payload(caps))),
+            },
+            _ => return self.reply(client_id, format!("ERROR:UNSUPPORTED_CAPS {}\n", sanitize_payload(caps))),
+        };
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        let old = std::mem::replace(&mut c.compression, compression);
+        c.writer.set_compression(compression);
+        self.count_compressing(old, compression);
+        self.reply(client_id, format!("ACK:CAPS {caps}\n"));
+    }
+
+    /// Keeps `compressing` up to date as a client goes from `old` to `new`.
+    pub(super) fn count_compressing(&mut self, old: Option<Compression>, new: Option<Compression>) {
+        if let Some(old) = old {
+            let count = self.compressing.get_mut(&old).expect("counted when set");
+            *count -= 1;
+            if *count == 0 {
+                self.compressing.remove(&old);
+            }
+        }
+        if let Some(new) = new {
+            *self.compressing.entry(new).or_default() += 1;
+        }
+    }
+
+    /// Applies `MODE:` settings to the client's current room. All settings
+    /// must be valid or none are applied.
+    pub(super) fn set_modes(&mut self, client_id: ClientId, settings: &str) {
+        let Some(name) = self.clients.get(&client_id).and_then(|c| c.room.clone()) else {
+            self.reply(client_id, "ERROR:NOT_IN_ROOM\n");
+            return;
+        };
+        let Some(room) = self.rooms.get_mut(&name) else { return };
+        // A moderated room's modes are the moderator's to change
+        if room.modes.moderated && room.moderator != Some(client_id) {
+            self.reply(client_id, "ERROR:NOT_MODERATOR\n");
+            return;
+        }
+        let mut modes = room.modes.clone();
+        for setting in settings.split_whitespace() {
+            if modes.apply(setting).is_err() {
+                let setting = sanitize_payload(setting).into_owned();
+                self.reply(client_id, format!("ERROR:INVALID_MODE {setting}\n"));
+                return;
+            }
+        }
+        if modes.moderated && !room.modes.moderated {
+            room.moderator = Some(client_id);
+        }
+        // Ending moderation rejects whatever was still held
+        let mut rejected = BTreeMap::new();
+        if !modes.moderated {
+            room.moderator = None;
+            rejected = std::mem::take(&mut room.held);
+        }
+        room.modes = modes;
+        if !room.modes.history {
+            room.history.clear();
+        }
+        let described = room.modes.describe();
+        for (id, held) in rejected {
+            self.reply(held.sender, format!("REJECTED:{id}\n"));
+        }
+        info!("mode {client_id} {name} {described}");
+        self.reply(client_id, format!("ACK:MODE {name} {described}\n"));
+    }
+
+    /// `WHO:regions` from an admin: every client with its latency region,
+    /// as `WHO:<id>=<region> ...`.
+    pub(super) fn who_regions(&mut self, client_id: ClientId) {
+        if self.not_admin(client_id) {
+            return;
+        }
+        let mut clients: Vec<(ClientId, &str)> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| c.authed)
+            .map(|(&id, c)| (id, c.rtt.and_then(|rtt| self.regions.region(rtt)).unwrap_or("-")))
+            .collect();
+        clients.sort_unstable();
+        let clients: Vec<String> = clients.iter().map(|(id, region)| format!("{id}={region}")).collect();
+        self.reply(client_id, format!("WHO:{}\n", clients.join(" ")));
+    }
+
+    /// A `PONG` answering the `PING` sent at login times the client's
+    /// round trip, putting it in a latency region.
+    pub(super) fn measure_rtt(&mut self, client_id: ClientId, received: Instant) {
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        let Some(probe) = c.rtt_probe.take() else { return };
+        let rtt = received.saturating_duration_since(probe);
+        c.rtt = Some(rtt);
+        self.rtts.record(rtt);
+        info!("rtt {client_id} ms={} region={}", rtt.as_millis(), self.regions.region(rtt).unwrap_or("-"));
+    }
+
+    /// `APPROVE:` or `REJECT:` from `client_id` for a held message.
+    pub(super) fn moderate(&mut self, client_id: ClientId, id: &str, approve: bool) {
+        let Some(name) = self.clients.get(&client_id).and_then(|c| c.room.clone()) else {
+            self.reply(client_id, "ERROR:NOT_IN_ROOM\n");
+            return;
+        };
+        let Some(room) = self.rooms.get_mut(&name) else { return };
+        if room.moderator != Some(client_id) {
+            self.reply(client_id, "ERROR:NOT_MODERATOR\n");
+            return;
+        }
+        let Some((id, held)) = id.parse().ok().and_then(|id| room.held.remove_entry(&id)) else {
+            self.reply(client_id, format!("ERROR:UNKNOWN_PENDING {}\n", sanitize_payload(id)));
+            return;
+        };
+        let verdict = if approve { "APPROVE" } else { "REJECT" };
+        info!("{} {client_id} {name} {id}", verdict.to_ascii_lowercase());
+        if approve {
+            self.publish_message(held.sender, &held.name, Some(name), &held.text, held.content_type.as_deref(), true);
+            self.reply(held.sender, format!("APPROVED:{id}\n"));
+        } else {
+            self.reply(held.sender, format!("REJECTED:{id}\n"));
+        }
+        self.reply(client_id, format!("ACK:{verdict} {id}\n"));
+    }
+
+    /// `DIRECT:` from `client_id`. The first of two clients to ask only
+    /// makes an offer, which the other is told about; once both have asked
+    /// for each other, each gets `PUNCH:` with the other's address as the
+    /// server sees it, at the same moment, so they can try a simultaneous
+    /// open. Each client has one offer out at a time.
+    pub(super) fn offer_direct(&mut self, client_id: ClientId, to: &str) {
+        if !self.direct {
+            self.reply(client_id, "ERROR:DIRECT_DISABLED\n");
+            return;
+        }
+        let Some(target) = self.resolve(to).filter(|&target| target != client_id) else {
+            self.reply(client_id, format!("ERROR:UNKNOWN_CLIENT {}\n", sanitize_payload(to)));
+            return;
+        };
+        let (Some(addr), Some(own)) = (self.registry.peer(target), self.registry.peer(client_id)) else { return };
+        // Unix socket clients have no address to connect to
+        if addr == UNIX_PEER || own == UNIX_PEER {
+            self.reply(client_id, format!("ERROR:DIRECT_UNAVAILABLE {}\n", sanitize_payload(to)));
+            return;
+        }
+        let from = self.registry.name(client_id);
+        let offered = self.clients.get_mut(&target).is_some_and(|t| t.direct_offer.take_if(|o| *o == client_id).is_some());
+        if offered {
+            info!("direct {target} {client_id}");
+            let name = self.registry.name(target);
+            self.reply(target, format!("PUNCH:{from} {own}\n"));
+            self.reply(client_id, format!("PUNCH:{name} {addr}\n"));
+            return;
+        }
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        c.direct_offer = Some(target);
+        self.reply(target, format!("DIRECT:{from}\n"));
+        self.reply(client_id, "ACK:DIRECT\n");
+    }
+}
@@ -0,0 +1,81 @@
+//! Messages that don't come from a client's connection: an embedder's
+//! injectors, and UDP datagrams.
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use tracing::{info, warn};
+
+use crate::inject::Injected;
+use crate::protocol::{sanitize_payload, Maintenance};
+use crate::registry::ClientId;
+
+use super::{Budget, Server};
+
+/// Messages an internal identity (see `inject`) may burst...
+const INJECT_BURST: f64 = 20.0;
+
+/// ...and its sustained rate, per second.
+const INJECT_RATE: f64 = 5.0;
+
+/// Messages the UDP port may take in a burst, from all senders together...
+pub(super) const UDP_BURST: f64 = 200.0;
+
+/// ...and their sustained rate, per second.
+pub(super) const UDP_RATE: f64 = 100.0;
+
+/// An identity publishing from inside the process.
+pub(super) struct Internal {
+    id: ClientId,
+    budget: Budget,
+}
+
+impl Internal {
+    pub(super) fn new(id: ClientId) -> Self {
+        Self { id, budget: Budget::new(Instant::now(), INJECT_BURST, INJECT_RATE) }
+    }
+}
+
+impl Server {
+    /// Publishes a message from an internal identity, which stands in for
+    /// a sender: its own budget, then journal, history and fan-out like any
+    /// client's message. Room modes don't apply; slow mode and acks are
+    /// about clients.
+    pub(super) fn inject(&mut self, Injected { name, room, text }: Injected) {
+        let Some(internal) = self.internal.get_mut(&name) else { return };
+        if !internal.budget.try_take(Instant::now()) {
+            warn!("internal {name} over budget, message dropped");
+            return;
+        }
+        let id = internal.id;
+        let payload = sanitize_payload(&text);
+        let target = room.as_deref().unwrap_or("-");
+        info!("inject {name} {target} {payload}");
+        self.publish_message(id, &name, room, &payload, None, true);
+    }
+
+    /// Publishes each line of a datagram to the lobby, under the address it
+    /// came from. Datagrams share one budget, as they can't be told apart
+    /// by anything but an address that's easily forged. Read-only
+    /// maintenance drops them, as nobody sending them is an admin.
+    pub(super) fn ingest_datagram(&mut self, peer: SocketAddr, text: &str) {
+        if !self.access.permits(peer.ip()) {
+            return;
+        }
+        if self.maintenance == Maintenance::ReadOnly {
+            warn!("udp {peer} read-only, datagram dropped");
+            return;
+        }
+        let name = format!("udp:{peer}");
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            if !self.udp_budget.try_take(Instant::now()) {
+                warn!("udp {peer} over budget, message dropped");
+                return;
+            }
+            let payload = sanitize_payload(line);
+            info!("udp {peer} bytes={}", payload.len());
+            self.counters.messages_in += 1;
+            self.deliver_message(None, &name, None, &payload, None, true);
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Maintenance mode, and the other commands only admins may run: kicks,
+//! overrides of a client's settings, notices, stats, purges and shutdown.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use tracing::info;
+
+use crate::protocol::{self, sanitize_payload, Maintenance, Setting};
+use crate::purge::Target;
+use crate::registry::ClientId;
+use crate::selector::{InvalidSelector, Selector, Session};
+use crate::sessions::Reason;
+use crate::writer::Audience;
+
+use super::{invalid_setting, Server};
+
+impl Server {
+    /// Whether the server is read-only for this client, telling it so.
+    pub(super) fn read_only(&mut self, client_id: ClientId) -> bool {
+        if self.maintenance != Maintenance::ReadOnly || self.clients.get(&client_id).is_some_and(|c| c.admin) {
+            return false;
+        }
+        self.reply(client_id, "ERROR:READ_ONLY\n");
+        true
+    }
+
+    /// An admin switching maintenance mode; everyone is told.
+    pub(super) fn set_maintenance(&mut self, client_id: ClientId, mode: Maintenance) {
+        if self.not_admin(client_id) {
+            return;
+        }
+        let (name, notice) = match mode {
+            Maintenance::On => ("ON", "SERVER:MAINTENANCE\n"),
+            Maintenance::ReadOnly => ("READ_ONLY", "SERVER:MAINTENANCE_READ_ONLY\n"),
+            Maintenance::Off => ("OFF", "SERVER:MAINTENANCE_OVER\n"),
+        };
+        let was = std::mem::replace(&mut self.maintenance, mode);
+        info!("maintenance {client_id} {}", name.to_ascii_lowercase());
+        self.reply(client_id, format!("ACK:MAINTENANCE {name}\n"));
+        if was != mode {
+            self.publish(Some(client_id), Audience::All, Bytes::from_static(notice.as_bytes()), true, false);
+        }
+    }
+
+    /// Whether the client may not run operator commands, telling it so.
+    pub(super) fn not_admin(&mut self, client_id: ClientId) -> bool {
+        if self.clients.get(&client_id).is_some_and(|c| c.admin) {
+            return false;
+        }
+        self.reply(client_id, "ERROR:NOT_ADMIN\n");
+        true
+    }
+
+    /// An admin disconnecting a client, which is told why.
+    pub(super) fn kick(&mut self, client_id: ClientId, target: &str) {
+        if self.not_admin(client_id) {
+            return;
+        }
+        let Some(peer) = self.resolve(target).filter(|id| self.clients.contains_key(id)) else {
+            self.reply(client_id, format!("ERROR:UNKNOWN_CLIENT {}\n", sanitize_payload(target)));
+            return;
+        };
+        info!("kick {client_id} {peer}");
+        self.reply(client_id, format!("ACK:KICK {peer}\n"));
+        self.disconnect_with(peer, "ERROR:KICKED\n", Reason::Kicked);
+    }
+
+    /// `KICK[...]:` from an admin: every other client the selector matches
+    /// is disconnected, as with `KICK:`.
+    pub(super) fn kick_where(&mut self, client_id: ClientId, selector: &str) {
+        if self.not_admin(client_id) {
+            return;
+        }
+        let Some(matched) = self.select(client_id, selector) else { return };
+        info!("kick {client_id} matched={} {selector}", matched.len());
+        self.reply(client_id, format!("ACK:KICK matched={}\n", matched.len()));
+        for peer in matched {
+            self.disconnect_with(peer, "ERROR:KICKED\n", Reason::Kicked);
+        }
+    }
+
+    /// An admin overriding any of a client's settings, room and lock
+    /// included, with `SET:`. All settings must be valid or none are
+    /// applied; the client is told with `SET:` what changed.
+    pub(super) fn set_client(&mut self, client_id: ClientId, target: &str, settings: &str) {
+        if self.not_admin(client_id) {
+            return;
+        }
+        let Some(peer) = self.resolve(target).filter(|id| self.clients.contains_key(id)) else {
+            self.reply(client_id, format!("ERROR:UNKNOWN_CLIENT {}\n", sanitize_payload(target)));
+            return;
+        };
+        let parsed = match protocol::parse_settings(settings) {
+            Ok(parsed) => parsed,
+            Err(bad) => return self.reply(client_id, invalid_setting(bad)),
+        };
+        let settings = settings.split_whitespace().collect::<Vec<_>>().join(" ");
+        info!("set {client_id} {peer} {settings}");
+        self.reply(client_id, format!("ACK:SET {peer} {settings}\n"));
+        self.override_settings(peer, &parsed, &settings);
+    }
+
+    /// `SET[...]:` from an admin: the same, for every other client the
+    /// selector matches.
+    pub(super) fn set_where(&mut self, client_id: ClientId, selector: &str, settings: &str) {
+        if self.not_admin(client_id) {
+            return;
+        }
+        let parsed = match protocol::parse_settings(settings) {
+            Ok(parsed) => parsed,
+            Err(bad) => return self.reply(client_id, invalid_setting(bad)),
+        };
+        let Some(matched) = self.select(client_id, selector) else { return };
+        let settings = settings.split_whitespace().collect::<Vec<_>>().join(" ");
+        info!("set {client_id} matched={} {selector} {settings}", matched.len());
+        self.reply(client_id, format!("ACK:SET matched={} {settings}\n", matched.len()));
+        for peer in matched {
+            self.override_settings(peer, &parsed, &settings);
+        }
+    }
+
+    /// Applies settings an admin made for `peer`, telling it with `SET:`.
+    pub(super) fn override_settings(&mut self, peer: ClientId, parsed: &[Setting<'_>], settings: &str) {
+        let Some(c) = self.clients.get_mut(&peer) else { return };
+        let mut room = None;
+        for &setting in parsed {
+            match setting {
+                Setting::Room(name) => room = Some(name),
+                setting => c.apply(setting),
+            }
+        }
+        self.reply(peer, format!("SET:{settings}\n"));
+        // Moved as if it had sent JOIN or PART itself
+        if let Some(room) = room {
+            let room: Option<Arc<str>> = room.map(Into::into);
+            self.set_room(peer, room.clone());
+            if let Some(room) = &room {
+                self.replay(peer, Some(room));
+            }
+        }
+    }
+
+    /// The clients other than `client_id` that an admin's selector
+    /// matches, in id order; `None`, once the admin is told, if it
+    /// doesn't parse.
+    pub(super) fn select(&mut self, client_id: ClientId, selector: &str) -> Option<Vec<ClientId>> {
+        let selector: Selector = match selector.parse() {
+            Ok(selector) => selector,
+            Err(InvalidSelector(bad)) if bad.is_empty() => {
+                self.reply(client_id, "ERROR:INVALID_SELECTOR\n");
+                return None;
+            }
+            Err(InvalidSelector(bad)) => {
+                self.reply(client_id, format!("ERROR:INVALID_SELECTOR {}\n", sanitize_payload(&bad)));
+                return None;
+            }
+        };
+        let now = Instant::now();
+        let mut matched: Vec<ClientId> = self
+            .clients
+            .iter()
+            .filter(|&(&id, c)| {
+                id != client_id
+                    && selector.matches(&Session {
+                        id,
+                        name: &self.registry.name(id),
+                        room: c.room.as_deref(),
+                        lang: c.lang.as_deref(),
+                        admin: c.admin,
+                        idle: now.duration_since(c.last_active),
+                        age: now.duration_since(c.connected),
+                        messages: c.messages_in,
+                        queued: self.fed - c.fed_before - c.writer.consumed(),
+                        rtt: c.rtt,
+                        region: c.rtt.and_then(|rtt| self.regions.region(rtt)),
+                    })
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        matched.sort_unstable();
+        Some(matched)
+    }
+
+    /// An admin's notice, to every client in every room.
+    pub(super) fn notice(&mut self, client_id: ClientId, text: &str) {
+        if self.not_admin(client_id) {
+            return;
+        }
+        info!("broadcast {client_id} bytes={}", text.len());
+        self.reply(client_id, "ACK:BROADCAST\n");
+        let line = format!("NOTICE:{}\n", sanitize_payload(text));
+        self.publish(Some(client_id), Audience::All, Bytes::from(line), true, false);
+    }
+
+    /// An admin's notice, to every other client the selector matches.
+    pub(super) fn notice_where(&mut self, client_id: ClientId, selector: &str, text: &str) {
+        if self.not_admin(client_id) {
+            return;
+        }
+        let Some(matched) = self.select(client_id, selector) else { return };
+        info!("broadcast {client_id} matched={} {selector} bytes={}", matched.len(), text.len());
+        self.reply(client_id, format!("ACK:BROADCAST matched={}\n", matched.len()));
+        let line = Bytes::from(format!("NOTICE:{}\n", sanitize_payload(text)));
+        for peer in matched {
+            self.reply(peer, line.clone());
+        }
+    }
+
+    /// Health at a glance, for anyone: uptime, clients, messages relayed
+    /// and how many were the caller's. Admins get the rest of the counters.
+    pub(super) fn stats(&mut self, client_id: ClientId) {
+        let Some(c) = self.clients.get(&client_id) else { return };
+        let mut line = format!(
+            "STATS:uptime_secs={} clients={} messages={} own_messages={}",
+            self.started.elapsed().as_secs(),
+            self.clients.len(),
+            self.counters.messages_in,
+            c.messages_in,
+        );
+        if c.admin {
+            let maintenance = match self.maintenance {
+                Maintenance::Off => "off",
+                Maintenance::On => "on",
+                Maintenance::ReadOnly => "read_only",
+            };
+            line += &format!(
+                " rooms={} handshaking={} tarpitted={} broadcasts={} panics={} maintenance={maintenance}",
+                self.rooms.len(),
+                self.handshaking,
+                self.counters.tarpit.pending + self.counters.tarpit.active,
+                self.fed + self.counters.direct,
+                self.counters.panics,
+            );
+        }
+        line.push('\n');
+        self.reply(client_id, line);
+    }
+
+    /// An admin deleting stored messages: from history at once, and from
+    /// the message log once its task gets to it.
+    pub(super) fn purge(&mut self, client_id: ClientId, target: Target) {
+        if self.not_admin(client_id) {
+            return;
+        }
+        let mut removed = self.lobby_history.purge(|line| target.matches_line(None, line));
+        for (name, room) in &mut self.rooms {
+            removed += room.history.purge(|line| target.matches_line(Some(name), line));
+        }
+        let by = self.registry.name(client_id);
+        info!(by, target = %target, history = removed, "purge");
+        self.reply(client_id, format!("ACK:PURGE history={removed}\n"));
+        if let Some(journal) = &self.journal {
+            journal.purge(target, &by);
+        }
+    }
+
+    /// An admin stopping the server; clients are drained as on a signal.
+    pub(super) fn shutdown(&mut self, client_id: ClientId) {
+        if self.not_admin(client_id) {
+            return;
+        }
+        info!("shutdown requested by {client_id}");
+        self.reply(client_id, "ACK:SHUTDOWN\n");
+        self.stopping = true;
+    }
+}
@@ -0,0 +1,114 @@
+//! Telling senders how far their messages got: ingest-mode `ACK_RANGE`
+//! batches, delivery receipts and barriers.
+
+use crate::registry::ClientId;
+
+use super::Server;
+
+/// Ingest producers get one `ACK_RANGE` per this many messages at most,
+/// and at least one per `Tuning::flush_interval` while any are unacknowledged.
+const INGEST_ACK_BATCH: u64 = 1000;
+
+/// Ack bookkeeping for a client in ingest mode. Messages are numbered from 1
+/// in the order they were received after `INGEST`.
+#[derive(Default)]
+pub(super) struct IngestState {
+    received: u64,
+    acked: u64,
+}
+
+impl IngestState {
+    /// Counts a message in, returning an `ACK_RANGE` line if that makes a
+    /// full batch.
+    pub(super) fn received(&mut self) -> Option<String> {
+        self.received += 1;
+        if self.received - self.acked >= INGEST_ACK_BATCH {
+            self.take_range()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the `ACK_RANGE` line covering everything not yet acknowledged.
+    pub(super) fn take_range(&mut self) -> Option<String> {
+        if self.received == self.acked {
+            return None;
+        }
+        let from = self.acked + 1;
+        self.acked = self.received;
+        Some(format!("ACK_RANGE:{from}-{}\n", self.received))
+    }
+}
+
+/// A numbered message, or a `BARRIER`, waiting for every writer to get
+/// past it.
+pub(super) struct Receipt {
+    pub(super) client_id: ClientId,
+    /// `None` for a barrier.
+    pub(super) seq: Option<u64>,
+    /// Its place in the feed: the value of `fed` once it was sent.
+    pub(super) index: u64,
+}
+
+impl Server {
+    /// Tells senders which of their numbered messages and barriers every
+    /// client's writer has now got past.
+    pub(super) fn send_receipts(&mut self) {
+        let done = self.clients.values().map(|c| c.fed_before + c.writer.delivered()).min().unwrap_or(u64::MAX);
+        while self.receipts.front().is_some_and(|r| r.index <= done) {
+            let Some(Receipt { client_id, seq, .. }) = self.receipts.pop_front() else { break };
+            match seq {
+                Some(seq) => self.reply(client_id, format!("DELIVERED:{seq}\n")),
+                None => {
+                    if let Some(c) = self.clients.get_mut(&client_id) {
+                        c.barrier = false;
+                    }
+                    self.reply(client_id, "ACK:BARRIER\n");
+                }
+            }
+        }
+    }
+
+    /// `BARRIER`: waits, like a receipt, for every writer to get past a
+    /// flush put on the feed after everything the client has sent so far.
+    /// Writers take their own queue first, so that covers broadcasts to
+    /// small rooms queued directly as well, and anything left unflushed.
+    /// A client has one barrier at a time; another before it's answered,
+    /// or any while shedding load, is turned away.
+    pub(super) fn barrier(&mut self, client_id: ClientId) {
+        let Some(c) = self.clients.get_mut(&client_id) else { return };
+        if c.barrier || self.shedder.shedding() {
+            if self.shedder.shedding() {
+                self.shedder.lines += 1;
+            }
+            self.reply(client_id, "ERROR:BUSY BARRIER\n");
+            return;
+        }
+        c.barrier = true;
+        // The feed needs no flush if its last line was one, and no small
+        // room has been sent to since
+        if std::mem::take(&mut self.feed_unflushed) | self.fed_direct {
+            self.feed_flush();
+        }
+        self.receipts.push_back(Receipt { client_id, seq: None, index: self.fed });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acks_ingest_in_batches() {
+        let mut state = IngestState::default();
+        assert_eq!(state.take_range(), None);
+        for _ in 1..INGEST_ACK_BATCH {
+            assert_eq!(state.received(), None);
+        }
+        assert_eq!(state.received().as_deref(), Some("ACK_RANGE:1-1000\n"));
+        state.received();
+        state.received();
+        assert_eq!(state.take_range().as_deref(), Some("ACK_RANGE:1001-1002\n"));
+        assert_eq!(state.take_range(), None);
+    }
+}
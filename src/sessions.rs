@@ -0,0 +1,174 @@
+//! Session summaries, for tracking sessions outside the server.
+//!
+//! With a session webhook set, every client that goes away, for whatever
+//! reason, is reported to it as one JSON object: who it was, how long it
+//! stayed, why it left and what it sent and received. A tracker kept in
+//! sync this way needn't poll `WHO` or `STATS`. Summaries are POSTed one at
+//! a time from a background task, through a bounded queue, so a mass
+//! disconnect can't open thousands of requests at once; what doesn't fit is
+//! dropped with a warning.
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::warn;
+
+use crate::alert;
+use crate::registry::ClientId;
+
+/// Summaries waiting to be POSTed before new ones are dropped.
+const QUEUE: usize = 1024;
+/// Give up on one POST after this long.
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a client went away.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Reason {
+    /// It closed its end.
+    Closed,
+    ReadError,
+    LineTooLong,
+    /// Its writer gave up: a write error, falling behind, or a panic,
+    /// which `report_session` tells apart.
+    Writer,
+    WriteError,
+    SlowConsumer,
+    Panicked,
+    RateLimited,
+    ProtocolViolation,
+    AuthFailed,
+    AuthTimeout,
+    Kicked,
+    Redirected,
+    PingTimeout,
+    IdleTimeout,
+    /// The server shut down.
+    Shutdown,
+}
+
+impl Reason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reason::Closed => "closed",
+            Reason::ReadError => "read_error",
+            Reason::LineTooLong => "line_too_long",
+            Reason::Writer | Reason::WriteError => "write_error",
+            Reason::SlowConsumer => "slow_consumer",
+            Reason::Panicked => "panicked",
+            Reason::RateLimited => "rate_limited",
+            Reason::ProtocolViolation => "protocol_violation",
+            Reason::AuthFailed => "auth_failed",
+            Reason::AuthTimeout => "auth_timeout",
+            Reason::Kicked => "kicked",
+            Reason::Redirected => "redirected",
+            Reason::PingTimeout => "ping_timeout",
+            Reason::IdleTimeout => "idle_timeout",
+            Reason::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// One client's session, as it ended.
+pub(crate) struct Summary {
+    pub client_id: ClientId,
+    /// Nickname, or the id when it had none.
+    pub name: String,
+    /// How it authenticated (`user=alice`, `token`); `None` if it didn't.
+    pub identity: Option<String>,
+    pub peer: SocketAddr,
+    pub duration: Duration,
+    pub reason: Reason,
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub dropped: u64,
+}
+
+impl Summary {
+    fn to_json(&self, ended: SystemTime) -> String {
+        json!({
+            "type": "session_end",
+            "client_id": self.client_id,
+            "name": self.name,
+            "identity": self.identity,
+            "peer": self.peer.to_string(),
+            "ended_at": ended.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            "duration_ms": self.duration.as_millis() as u64,
+            "reason": self.reason.as_str(),
+            "messages_in": self.messages_in,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "dropped": self.dropped,
+        })
+        .to_string()
+    }
+}
+
+/// The queue to the task POSTing summaries.
+pub(crate) struct Webhook {
+    tx: mpsc::Sender<String>,
+    task: JoinHandle<()>,
+}
+
+impl Webhook {
+    /// Starts the task POSTing to `url`; it stops once this is dropped.
+    pub fn start(url: String) -> Self {
+        let (tx, mut rx) = mpsc::channel::<String>(QUEUE);
+        let task = tokio::spawn(async move {
+            while let Some(body) = rx.recv().await {
+                match time::timeout(POST_TIMEOUT, alert::post(&url, &body)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("session webhook failed: {e}"),
+                    Err(_) => warn!("session webhook failed: timed out"),
+                }
+            }
+        });
+        Self { tx, task }
+    }
+
+    pub fn send(&self, summary: &Summary) {
+        if self.tx.try_send(summary.to_json(SystemTime::now())).is_err() {
+            warn!(client_id = summary.client_id, "session webhook behind, summary dropped");
+        }
+    }
+
+    /// Waits, until `deadline` at the latest, for the summaries queued so
+    /// far to be sent.
+    pub async fn close(self, deadline: time::Instant) {
+        drop(self.tx);
+        if time::timeout_at(deadline, self.task).await.is_err() {
+            warn!("session webhook cut off at shutdown");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_is_one_json_object() {
+        let summary = Summary {
+            client_id: 7,
+            name: "alice".to_string(),
+            identity: Some("user=alice".to_string()),
+            peer: "10.0.0.7:51000".parse().unwrap(),
+            duration: Duration::from_millis(1500),
+            reason: Reason::Kicked,
+            messages_in: 3,
+            bytes_in: 42,
+            bytes_out: 100,
+            dropped: 0,
+        };
+        let json: serde_json::Value = serde_json::from_str(&summary.to_json(UNIX_EPOCH + Duration::from_secs(2))).unwrap();
+        assert_eq!(json["type"], "session_end");
+        assert_eq!(json["reason"], "kicked");
+        assert_eq!(json["duration_ms"], 1500);
+        assert_eq!(json["ended_at"], 2000);
+        assert_eq!(json["identity"], "user=alice");
+    }
+}
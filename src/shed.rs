@@ -0,0 +1,111 @@
+//! Load shedding, so an overloaded server gives up the least important
+//! work first instead of falling behind on everything.
+//!
+//! Once a second the server adds up the broadcast lines waiting in every
+//! client's queue, and reads its resident memory if there's a threshold
+//! for it. Past either threshold it sheds: new connections are turned away
+//! with `ERROR:OVERLOADED`, and events, typing and presence lines aren't
+//! sent, leaving the queues to messages. It stops once both are back under
+//! three quarters of their thresholds, so it doesn't flap around the line.
+
+use std::time::{Duration, Instant};
+
+use crate::alert;
+
+/// How often load is checked.
+pub(crate) const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Fraction of a threshold load must fall under before shedding stops.
+const RECOVERY: f64 = 0.75;
+
+#[derive(Default)]
+pub struct ShedConfig {
+    /// Broadcast lines waiting across all clients that start shedding;
+    /// `None` doesn't count them.
+    pub queue_threshold: Option<u64>,
+    /// Resident memory, in bytes, that starts shedding; `None` doesn't
+    /// check.
+    pub memory_threshold: Option<u64>,
+}
+
+/// Load as of the last check.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Load {
+    pub queued: u64,
+    pub resident: Option<u64>,
+}
+
+/// A change in whether the server is shedding.
+pub(crate) enum Change {
+    Started(Load),
+    Stopped { after: Duration },
+}
+
+pub(crate) struct Shedder {
+    config: ShedConfig,
+    /// Shedding since then.
+    since: Option<Instant>,
+    /// Totals since startup, for the metrics endpoint.
+    pub connections: u64,
+    pub lines: u64,
+}
+
+impl Shedder {
+    pub fn new(config: ShedConfig) -> Self {
+        Self { config, since: None, connections: 0, lines: 0 }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.queue_threshold.is_some() || self.config.memory_threshold.is_some()
+    }
+
+    pub fn shedding(&self) -> bool {
+        self.since.is_some()
+    }
+
+    /// Checks `queued` lines, and memory, against the thresholds.
+    pub fn check(&mut self, queued: u64, now: Instant) -> Option<Change> {
+        let resident = self.config.memory_threshold.and_then(|_| alert::resident_memory());
+        self.update(Load { queued, resident }, now)
+    }
+
+    fn update(&mut self, load: Load, now: Instant) -> Option<Change> {
+        let over = |fraction: f64| {
+            let past = |value: Option<u64>, threshold: Option<u64>| match (value, threshold) {
+                (Some(value), Some(threshold)) => value as f64 >= threshold as f64 * fraction,
+                _ => false,
+            };
+            past(Some(load.queued), self.config.queue_threshold) || past(load.resident, self.config.memory_threshold)
+        };
+        match self.since {
+            None if over(1.0) => {
+                self.since = Some(now);
+                Some(Change::Started(load))
+            }
+            Some(since) if !over(RECOVERY) => {
+                self.since = None;
+                Some(Change::Stopped { after: now.saturating_duration_since(since) })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_well_under_the_threshold() {
+        let mut shedder = Shedder::new(ShedConfig { queue_threshold: Some(1000), memory_threshold: None });
+        let now = Instant::now();
+        let at = |queued| Load { queued, resident: None };
+        assert!(shedder.update(at(999), now).is_none());
+        assert!(matches!(shedder.update(at(1000), now), Some(Change::Started(_))));
+        assert!(shedder.update(at(800), now).is_none());
+        assert!(shedder.shedding());
+        let later = now + Duration::from_secs(3);
+        assert!(matches!(shedder.update(at(749), later), Some(Change::Stopped { after }) if after == Duration::from_secs(3)));
+        assert!(!shedder.shedding());
+    }
+}
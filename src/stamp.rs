@@ -0,0 +1,134 @@
+//! Message IDs and timestamps.
+//!
+//! With `--stamp-messages`, every `MESSAGE:` and `BLOBREF:` line says when
+//! the server sent it and carries an ID, alongside any content type:
+//! `MESSAGE[id=…,ts=2026-10-15T10:17:47.427Z]:{ID} {TEXT}`. An ID is the
+//! time in microseconds since the Unix epoch, bumped past the last one
+//! when two land in the same microsecond or the clock steps back, with the
+//! server's node number (`PeerConfig::node_id`) in its low ten bits. So
+//! IDs only ever go up, across restarts too (short of a clock set back
+//! further than the downtime), and a consumer that reconnects can drop
+//! what it has seen and sort the rest. Servers with different node numbers
+//! never hand out the same ID, and IDs from a cluster sort by time.
+//!
+//! It's off by default because it changes the line format: clients that
+//! match on `MESSAGE:` don't expect a tag there.
+
+use std::borrow::Cow;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Low bits of an ID holding the node number of the server that gave it.
+const NODE_BITS: u32 = 10;
+/// Highest node number.
+pub const MAX_NODE: u16 = (1 << NODE_BITS) - 1;
+
+/// When a message went out, and its ID.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Stamp {
+    pub id: u64,
+    pub ts_ms: u64,
+}
+
+impl Stamp {
+    /// The `id=…,ts=…` attributes for a line's tag.
+    pub fn attributes(&self) -> String {
+        format!("id={},ts={}", self.id, rfc3339(self.ts_ms))
+    }
+}
+
+/// Hands out stamps.
+pub(crate) struct Stamper {
+    node: u64,
+    /// Microseconds of the last ID.
+    last: u64,
+}
+
+impl Stamper {
+    pub fn new(node: u16) -> Self {
+        debug_assert!(node <= MAX_NODE, "node {node} over {MAX_NODE}");
+        Self { node: u64::from(node), last: 0 }
+    }
+
+    pub fn next(&mut self, now: SystemTime) -> Stamp {
+        let micros = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+        self.last = micros.max(self.last + 1);
+        Stamp { id: self.last << NODE_BITS | self.node, ts_ms: micros / 1000 }
+    }
+}
+
+/// The node number of a server without one, from its id (FNV-1a), so it
+/// stays the same as long as the id does. Two ids can land on the same
+/// number, so a cluster that needs unique IDs numbers its servers itself.
+pub(crate) fn node(server_id: &str) -> u16 {
+    let hash = server_id.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| (hash ^ u64::from(b)).wrapping_mul(0x100_0000_01b3));
+    (hash % (u64::from(MAX_NODE) + 1)) as u16
+}
+
+/// Milliseconds since the epoch as `YYYY-MM-DDTHH:MM:SS.mmmZ`.
+pub(crate) fn rfc3339(ts_ms: u64) -> String {
+    let secs = ts_ms / 1000;
+    let (days, time) = (secs / 86_400, secs % 86_400);
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        ts_ms % 1000,
+    )
+}
+
+/// The ID in the tag of a stamped `MESSAGE:` or `BLOBREF:` line.
+pub(crate) fn id(line: &[u8]) -> Option<u64> {
+    let rest = line.strip_prefix(b"MESSAGE").or_else(|| line.strip_prefix(b"BLOBREF"))?.strip_prefix(b"[")?;
+    let tag = std::str::from_utf8(&rest[..rest.iter().position(|&b| b == b']')?]).ok()?;
+    tag.split(',').find_map(|attribute| attribute.strip_prefix("id="))?.parse().ok()
+}
+
+/// A text line with any `id=` and `ts=` taken out of its tag, for
+/// comparing against what an unstamped server would send.
+pub(crate) fn unstamped(line: &str) -> Cow<'_, str> {
+    let Some((kind, rest)) = line.split_once('[') else { return Cow::Borrowed(line) };
+    let Some((attributes, rest)) = rest.split_once("]:") else { return Cow::Borrowed(line) };
+    let kept: Vec<&str> = attributes.split(',').filter(|a| !a.starts_with("id=") && !a.starts_with("ts=")).collect();
+    match kept[..] {
+        [] => Cow::Owned(format!("{kind}:{rest}")),
+        _ => Cow::Owned(format!("{kind}[{}]:{rest}", kept.join(","))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn ids_only_go_up() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_792_059_467_427);
+        let mut stamper = Stamper::new(7);
+        let first = stamper.next(now);
+        assert_eq!(first, Stamp { id: 1_792_059_467_427_000 << 10 | 7, ts_ms: 1_792_059_467_427 });
+        assert_eq!(stamper.next(now).id, first.id + 1024);
+        assert_eq!(stamper.next(now - Duration::from_secs(1)).id, first.id + 2048);
+        // Another node's IDs at the same moment differ, and still sort by time
+        let other = Stamper::new(8).next(now).id;
+        assert_ne!(other, first.id);
+        assert!(other < first.id + 1024);
+        assert_eq!(node("a"), node("a"));
+        assert_eq!(first.attributes(), "id=1835068894645248007,ts=2026-10-15T10:17:47.427Z");
+        assert_eq!(rfc3339(951_782_400_000), "2000-02-29T00:00:00.000Z");
+        assert_eq!(unstamped("MESSAGE[ct=json,id=5,ts=x]:3 {}"), "MESSAGE[ct=json]:3 {}");
+        assert_eq!(unstamped("MESSAGE[id=5,ts=x]:3 hi"), "MESSAGE:3 hi");
+        assert_eq!(id(b"MESSAGE[ct=json,id=5,ts=x]:3 {}\n"), Some(5));
+        assert_eq!(id(b"MESSAGE:3 [id=5]\n"), None);
+    }
+}
@@ -0,0 +1,88 @@
+//! Listening sockets handed over by systemd.
+//!
+//! With socket activation (`Accept=no`) systemd binds the ports itself and
+//! passes them to the server as file descriptors 3 onwards, saying how many
+//! in `LISTEN_FDS` and for which process in `LISTEN_PID`. That lets the
+//! unit listen on privileged ports without the server ever holding the
+//! privilege, and keeps connections queueing in the kernel while the server
+//! restarts. The variables are cleared once read, so nothing the server
+//! starts mistakes the sockets for its own.
+
+use std::io;
+
+use tokio::net::TcpListener;
+
+/// The first descriptor systemd passes (`SD_LISTEN_FDS_START`).
+#[cfg(unix)]
+const FIRST_FD: i32 = 3;
+
+/// The TCP listeners systemd passed, in order, or `None` when the server
+/// wasn't socket-activated. Fails on a descriptor that isn't a TCP stream
+/// socket, which this server couldn't serve.
+#[cfg(unix)]
+pub fn listeners() -> io::Result<Option<Vec<TcpListener>>> {
+    use std::os::fd::{BorrowedFd, FromRawFd};
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let Some(count) = passed(pid.as_deref(), fds.as_deref(), std::process::id())? else { return Ok(None) };
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let mut listeners = Vec::new();
+    for fd in FIRST_FD..FIRST_FD + count {
+        // Checked before it's owned, so a descriptor that isn't open is an
+        // error rather than a close of someone else's
+        // SAFETY: only looked at, while nothing else can close it
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        let borrowed = socket2::SockRef::from(&borrowed);
+        let tcp = borrowed.r#type().is_ok_and(|ty| ty == socket2::Type::STREAM)
+            && borrowed.local_addr().is_ok_and(|addr| addr.as_socket().is_some());
+        if !tcp {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("socket-activated fd {fd} isn't a TCP socket")));
+        }
+        // SAFETY: systemd hands these descriptors to this process alone, and
+        // they're taken here exactly once, since the variables are now gone
+        let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+        listeners.push(TcpListener::from_std(socket.into())?);
+    }
+    Ok(Some(listeners))
+}
+
+/// Socket activation is a systemd thing; there's never anything to inherit.
+#[cfg(not(unix))]
+pub fn listeners() -> io::Result<Option<Vec<TcpListener>>> {
+    Ok(None)
+}
+
+/// How many descriptors were passed to the process `pid`, from the values
+/// of `LISTEN_PID` and `LISTEN_FDS`; `None` if they were meant for another
+/// process (the variables leak into children) or there are none.
+#[cfg(unix)]
+fn passed(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> io::Result<Option<i32>> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else { return Ok(None) };
+    if listen_pid.parse() != Ok(pid) {
+        return Ok(None);
+    }
+    match listen_fds.parse::<i32>() {
+        Ok(0) => Ok(None),
+        Ok(count) if count > 0 => Ok(Some(count)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid LISTEN_FDS {listen_fds:?}"))),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_only_its_own_sockets() {
+        assert_eq!(passed(Some("42"), Some("2"), 42).unwrap(), Some(2));
+        assert_eq!(passed(Some("41"), Some("2"), 42).unwrap(), None);
+        assert_eq!(passed(Some("42"), Some("0"), 42).unwrap(), None);
+        assert_eq!(passed(None, None, 42).unwrap(), None);
+        assert!(passed(Some("42"), Some("two"), 42).is_err());
+    }
+}
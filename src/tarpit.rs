@@ -0,0 +1,104 @@
+//! Tarpitting for greylisted IPs.
+//!
+//! A tarpitted connection is accepted but its `LOGIN` is held back for a
+//! while and its input is read at most one line per interval, which makes
+//! abuse expensive without the bluntness of a ban.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::time::{self, Sleep};
+use tokio_stream::Stream;
+
+pub struct TarpitConfig {
+    /// How long the handshake is delayed; `None` disables tarpitting.
+    pub delay: Option<Duration>,
+    /// Minimum time between lines read from a tarpitted client.
+    pub read_interval: Duration,
+}
+
+impl Default for TarpitConfig {
+    fn default() -> Self {
+        Self {
+            delay: None,
+            read_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Counters reported on the housekeeping tick.
+#[derive(Default)]
+pub struct TarpitStats {
+    /// Connections waiting for their delayed handshake.
+    pub pending: usize,
+    /// Connected clients whose reads are throttled.
+    pub active: usize,
+    pub total: u64,
+}
+
+/// A stream that yields at most one item per `interval`, or passes items
+/// straight through when there's no interval.
+pub struct Throttled<S> {
+    inner: S,
+    interval: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, interval: Option<Duration>) -> Self {
+        Self {
+            inner,
+            interval,
+            sleep: None,
+        }
+    }
+
+    /// Changes the pacing; takes effect after the current wait, if any.
+    pub fn set_interval(&mut self, interval: Option<Duration>) {
+        self.interval = interval;
+    }
+}
+
+impl<S: Stream + Unpin> Stream for Throttled<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        if let Some(sleep) = self.sleep.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.sleep = None;
+        }
+        let item = Pin::new(&mut self.inner).poll_next(cx);
+        if let (Poll::Ready(Some(_)), Some(interval)) = (&item, self.interval) {
+            self.sleep = Some(Box::pin(time::sleep(interval)));
+        }
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn yields_one_item_per_interval() {
+        let interval = Duration::from_millis(50);
+        let mut throttled = Throttled::new(tokio_stream::iter(0..3), Some(interval));
+        let start = Instant::now();
+        assert_eq!(throttled.next().await, Some(0));
+        assert!(start.elapsed() < interval);
+        assert_eq!(throttled.next().await, Some(1));
+        assert_eq!(throttled.next().await, Some(2));
+        assert!(start.elapsed() >= 2 * interval);
+
+        let start = Instant::now();
+        let unthrottled = Throttled::new(tokio_stream::iter(0..3), None);
+        assert_eq!(unthrottled.collect::<Vec<_>>().await, [0, 1, 2]);
+        assert!(start.elapsed() < interval);
+    }
+}
@@ -0,0 +1,252 @@
+//! Helpers for integration tests: a server on a free port, and clients
+//! that assert on what they're sent.
+//!
+//! [`TestServer`] runs a server in-process, on its own thread and runtime,
+//! so it suits both `#[test]` and `#[tokio::test]`, and stops it when
+//! dropped. [`TestClient`] connects to it, waits for `LOGIN:`, and panics
+//! with the line it got instead when an expectation isn't met, or when
+//! nothing comes within its timeout:
+//!
+//! ```no_run
+//! use tcp_broadcast::testing::{TestClient, TestServer};
+//! use tcp_broadcast::Config;
+//!
+//! # async fn broadcast() {
+//! let server = TestServer::start(Config::default());
+//! let mut a = TestClient::connect(server.addr()).await;
+//! let mut b = TestClient::connect(server.addr()).await;
+//! a.send("hello").await;
+//! a.expect("ACK:MESSAGE").await;
+//! b.expect(&format!("MESSAGE:{} hello", a.id())).await;
+//! # }
+//! ```
+//!
+//! Presence notices, pings and `NOOP`s can arrive at any point, so every
+//! expectation but [`TestClient::expect_presence`] skips them.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use futures::SinkExt;
+use tokio::net::TcpStream;
+use tokio::runtime;
+use tokio::sync::oneshot;
+use tokio::time;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::conformance::is_unsolicited;
+use crate::server::{BroadcastServer, Config};
+use crate::stamp;
+
+/// How long a client waits for an expected line by default.
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a client listens when expecting nothing.
+const QUIET_PERIOD: Duration = Duration::from_millis(300);
+
+/// A server on a free port of 127.0.0.1, stopped when dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl TestServer {
+    /// Starts a server with `config`.
+    pub fn start(config: Config) -> TestServer {
+        Self::start_with(config, |server| server)
+    }
+
+    /// Starts a server with `config`, letting `build` add hooks first. It
+    /// runs on the server's thread, as hooks needn't be `Send`.
+    pub fn start_with<F>(config: Config, build: F) -> TestServer
+    where
+        F: FnOnce(BroadcastServer) -> BroadcastServer + Send + 'static,
+    {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind a free port");
+        let addr = listener.local_addr().expect("listener address");
+        listener.set_nonblocking(true).expect("nonblocking listener");
+        let (stop, stopped) = oneshot::channel::<()>();
+        let thread = thread::spawn(move || {
+            let runtime = runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let server = BroadcastServer::bind(addr).config(config).shutdown_on(async {
+                    let _ = stopped.await;
+                });
+                build(server).serve(listener).await
+            })
+        });
+        TestServer { addr, stop: Some(stop), thread: Some(thread) }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Shuts the server down, draining its clients, and returns what
+    /// serving it came to: an error if it failed to start, say.
+    pub fn stop(mut self) -> io::Result<()> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> io::Result<()> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(served)) => served,
+            Some(Err(_)) => Err(io::Error::other("server thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.shut_down();
+    }
+}
+
+/// A connection that has logged in.
+pub struct TestClient {
+    conn: Framed<TcpStream, LinesCodec>,
+    id: String,
+    history: Vec<String>,
+    timeout: Duration,
+}
+
+impl TestClient {
+    /// Connects to `addr` and waits for `LOGIN:`, keeping any history
+    /// replayed before it.
+    pub async fn connect(addr: SocketAddr) -> TestClient {
+        Self::login(addr, None).await
+    }
+
+    /// Connects to a server that wants credentials, answering
+    /// `AUTH_REQUIRED` with `credentials` (a token, or `user password`).
+    pub async fn connect_with_auth(addr: SocketAddr, credentials: &str) -> TestClient {
+        Self::login(addr, Some(credentials)).await
+    }
+
+    async fn login(addr: SocketAddr, credentials: Option<&str>) -> TestClient {
+        let stream = TcpStream::connect(addr).await.unwrap_or_else(|e| panic!("connect to {addr}: {e}"));
+        let mut client =
+            TestClient { conn: Framed::new(stream, LinesCodec::new()), id: String::new(), history: Vec::new(), timeout: TIMEOUT };
+        loop {
+            let line = client.recv_any().await;
+            if let Some(id) = line.strip_prefix("LOGIN:") {
+                client.id = id.to_string();
+                return client;
+            }
+            match credentials {
+                Some(credentials) if line == "AUTH_REQUIRED" => client.send(&format!("AUTH:{credentials}")).await,
+                _ if line.starts_with("HISTORY:") => client.history.push(line),
+                _ => panic!("expected LOGIN:<id>, got {line:?}"),
+            }
+        }
+    }
+
+    /// The id the server gave this client.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The `HISTORY:` lines sent before `LOGIN:`.
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// How long to wait for each expected line from now on.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub async fn send(&mut self, line: &str) {
+        self.conn.send(line).await.unwrap_or_else(|e| panic!("client {} send: {e}", self.id));
+    }
+
+    /// Sends `BARRIER` and waits for its `ACK:BARRIER`: by then everything
+    /// this client sent before has been flushed to every client. Anything
+    /// else that comes first fails, as with [`TestClient::expect`].
+    pub async fn barrier(&mut self) {
+        self.send("BARRIER").await;
+        self.expect("ACK:BARRIER").await;
+    }
+
+    /// The next line past presence notices, pings and `NOOP`s.
+    pub async fn recv(&mut self) -> String {
+        loop {
+            let line = self.recv_any().await;
+            if !is_unsolicited(&line) {
+                return line;
+            }
+        }
+    }
+
+    /// Expects the next line to be `want`, once any `id=` and `ts=` a
+    /// server stamping messages adds are taken out.
+    pub async fn expect(&mut self, want: &str) {
+        let got = self.recv().await;
+        assert_eq!(stamp::unstamped(&got), want, "client {}", self.id);
+    }
+
+    /// Expects the next line to start with `prefix`, and returns it.
+    pub async fn expect_prefix(&mut self, prefix: &str) -> String {
+        let got = self.recv().await;
+        assert!(got.starts_with(prefix), "client {}: expected {prefix:?}…, got {got:?}", self.id);
+        got
+    }
+
+    /// Waits for one presence notice, `JOINED:{ID}` or `LEFT:{ID}`, among
+    /// any others; anything else on the way fails.
+    pub async fn expect_presence(&mut self, want: &str) {
+        loop {
+            let got = self.recv_any().await;
+            if got == want {
+                return;
+            }
+            assert!(is_unsolicited(&got), "client {}: expected {want:?}, got {got:?}", self.id);
+        }
+    }
+
+    /// Expects nothing but presence notices, pings and `NOOP`s for a moment.
+    pub async fn expect_quiet(&mut self) {
+        let deadline = time::Instant::now() + QUIET_PERIOD;
+        loop {
+            match time::timeout_at(deadline, self.conn.next()).await {
+                Err(_) => return,
+                Ok(Some(Ok(line))) if is_unsolicited(&line) => {}
+                Ok(Some(Ok(line))) => panic!("client {}: expected nothing, got {line:?}", self.id),
+                Ok(Some(Err(e))) => panic!("client {} read: {e}", self.id),
+                Ok(None) => panic!("client {}: expected nothing, but the server closed the connection", self.id),
+            }
+        }
+    }
+
+    /// Expects the server to close the connection, reading past whatever
+    /// it sent first; returns the last line, if any.
+    pub async fn expect_closed(&mut self) -> Option<String> {
+        let deadline = time::Instant::now() + self.timeout;
+        let mut last = None;
+        loop {
+            match time::timeout_at(deadline, self.conn.next()).await {
+                Err(_) => panic!("client {}: still connected after {:?}", self.id, self.timeout),
+                Ok(Some(Ok(line))) => last = Some(line),
+                // A reset counts: the server may close with lines unread
+                Ok(Some(Err(_)) | None) => return last,
+            }
+        }
+    }
+
+    async fn recv_any(&mut self) -> String {
+        match time::timeout(self.timeout, self.conn.next()).await {
+            Ok(Some(Ok(line))) => line,
+            Ok(Some(Err(e))) => panic!("client {} read: {e}", self.id),
+            Ok(None) => panic!("client {}: the server closed the connection", self.id),
+            Err(_) => panic!("client {}: nothing within {:?}", self.id, self.timeout),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Optional TLS on the listener.
+//!
+//! With a certificate and key configured, every accepted socket goes
+//! through a rustls handshake before it gets its `LOGIN`; the line protocol
+//! on top is unchanged (see `conn` for where the handshake runs).
+//! Optionally clients must present a certificate signed by a given CA.
+//! Without the `tls` feature there's no rustls, and a configuration with
+//! TLS is refused at startup.
+
+use std::io;
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+#[cfg(feature = "tls")]
+use rustls_pki_types::pem::PemObject;
+#[cfg(feature = "tls")]
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::{crypto, RootCertStore, ServerConfig};
+#[cfg(feature = "tls")]
+pub(crate) use tokio_rustls::TlsAcceptor;
+
+/// Built without TLS, so no acceptor can exist.
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub(crate) enum TlsAcceptor {}
+
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key for the leaf certificate.
+    pub key: PathBuf,
+    /// PEM CA certificates; when set, clients must present a certificate
+    /// signed by one of them.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self { cert: cert.into(), key: key.into(), client_ca: None }
+    }
+
+    /// Loads the files and builds the acceptor, failing on anything
+    /// unreadable or inconsistent so a bad setup is caught at startup.
+    #[cfg(feature = "tls")]
+    pub(crate) fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?;
+        let builder = match &self.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in certs(ca)? {
+                    roots.add(cert).map_err(invalid)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .build()
+                    .map_err(invalid)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let key = PrivateKeyDer::from_pem_file(&self.key).map_err(|e| pem_error(&self.key, e))?;
+        let config = builder.with_single_cert(certs(&self.cert)?, key).map_err(invalid)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub(crate) fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        Err(crate::info::not_built("tls"))
+    }
+
+    /// For the startup log.
+    pub fn describe(&self) -> String {
+        let clients = if self.client_ca.is_some() { "required" } else { "off" };
+        format!("client_certs={clients}")
+    }
+}
+
+#[cfg(feature = "tls")]
+fn certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no certificates", path.display())));
+    }
+    Ok(certs)
+}
+
+#[cfg(feature = "tls")]
+fn pem_error(path: &Path, e: rustls_pki_types::pem::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {e}", path.display()))
+}
+
+#[cfg(feature = "tls")]
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("tls: {e}"))
+}
@@ -0,0 +1,44 @@
+//! UDP ingestion, for devices that can only fire and forget.
+//!
+//! With `--udp-port`, each line of a datagram is published to the lobby as
+//! a message, `MESSAGE:udp:{ADDR} {TEXT}`: the sender's address stands in
+//! for a nickname, and since nicknames can't hold a colon it can't be
+//! passed off as a client's. Nothing is sent back, not even an ack, and
+//! nothing is authenticated beyond the access lists, so the port belongs
+//! on the devices' own network.
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+
+/// Largest payload a UDP datagram can carry.
+const MAX_DATAGRAM: usize = 65_507;
+
+pub(crate) struct UdpIngest {
+    socket: UdpSocket,
+    buf: Box<[u8]>,
+}
+
+impl UdpIngest {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(Self { socket, buf: vec![0; MAX_DATAGRAM].into_boxed_slice() })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+/// The next datagram, and who sent it, if there's a socket; never
+/// completes otherwise. Bytes that aren't UTF-8 come out as U+FFFD.
+pub(crate) async fn recv(udp: Option<&mut UdpIngest>) -> io::Result<(SocketAddr, String)> {
+    match udp {
+        Some(udp) => {
+            let (n, peer) = udp.socket.recv_from(&mut udp.buf).await?;
+            Ok((peer, String::from_utf8_lossy(&udp.buf[..n]).into_owned()))
+        }
+        None => std::future::pending().await,
+    }
+}
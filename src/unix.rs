@@ -0,0 +1,93 @@
+//! Unix domain socket listener, for clients on the same host.
+//!
+//! Clients on the socket speak the same line protocol as TCP ones and
+//! share their broadcast domain. They have no network address, so they
+//! are registered under [`UNIX_PEER`] and skip the per-IP abuse
+//! heuristics, which would otherwise lump them all together. The socket
+//! file is removed when the listener is dropped, on shutdown or on error,
+//! and a stale one left by a crashed server is replaced on startup.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+
+use crate::conn::Conn;
+
+/// Stands in for the peer address of a Unix socket client, which has none.
+pub const UNIX_PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// A peer address as the log shows it: `unix` for socket clients.
+pub fn describe(peer: SocketAddr) -> String {
+    if peer == UNIX_PEER {
+        "unix".to_string()
+    } else {
+        peer.to_string()
+    }
+}
+
+#[cfg(unix)]
+pub struct UnixSocket {
+    listener: tokio::net::UnixListener,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        // A socket nobody answers on is left over from a server that didn't
+        // get to clean up; anything else at the path is left alone
+        if std::fs::symlink_metadata(path).is_ok_and(|meta| std::os::unix::fs::FileTypeExt::is_socket(&meta.file_type()))
+            && std::os::unix::net::UnixStream::connect(path).is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+        {
+            std::fs::remove_file(path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)?;
+        Ok(Self { listener, path: path.to_path_buf() })
+    }
+
+    pub async fn accept(&self) -> io::Result<Conn> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(Conn::Unix(stream))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("can't remove unix socket {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Unix sockets don't exist here, so neither can a listener.
+#[cfg(not(unix))]
+pub enum UnixSocket {}
+
+#[cfg(not(unix))]
+impl UnixSocket {
+    pub fn bind(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "unix sockets aren't supported on this platform"))
+    }
+
+    pub async fn accept(&self) -> io::Result<Conn> {
+        match *self {}
+    }
+
+    pub fn path(&self) -> &Path {
+        match *self {}
+    }
+}
+
+/// The next connection on the socket, if there is one; never completes
+/// otherwise.
+pub async fn accept(socket: Option<&UnixSocket>) -> io::Result<Conn> {
+    match socket {
+        Some(socket) => socket.accept().await,
+        None => std::future::pending().await,
+    }
+}
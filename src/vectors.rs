@@ -0,0 +1,131 @@
+//! `vectors` subcommand: protocol test vectors, and a runner for them.
+//!
+//! `vectors/protocol.jsonl`, built in, holds one vector per line: the
+//! clients to connect, then steps, each a line one of them sends, the next
+//! line it should be sent, or a pause in which it should be sent nothing.
+//! `{a}` in a line stands for the id client `a` got at login. The expected
+//! lines are what a server with the default config sends. None of them
+//! depend on the time, so they hold whatever the clock says, and the `id=`
+//! and `ts=` a server stamping messages adds are ignored. Implementers of
+//! other servers can run them with `tcp-broadcast vectors HOST:PORT`; here
+//! they run in `cargo test`, so a change to any reply shows up there.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+use crate::conformance::{is_unsolicited, CheckResult, Probe};
+use crate::logging::LogLevel;
+use crate::server::{BroadcastServer, Config};
+
+/// The vectors shipped with the crate.
+pub const BUILTIN: &str = include_str!("../vectors/protocol.jsonl");
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Vector {
+    name: String,
+    /// Connected in this order before the first step.
+    clients: Vec<String>,
+    steps: Vec<Step>,
+}
+
+/// One of `send`, `expect` or `quiet`, for `client`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Step {
+    client: String,
+    send: Option<String>,
+    expect: Option<String>,
+    #[serde(default)]
+    quiet: bool,
+}
+
+/// Reads vectors, one JSON object per line; blank lines are skipped.
+pub fn parse(text: &str) -> io::Result<Vec<Vector>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {e}", i + 1)))
+        })
+        .collect()
+}
+
+/// Runs every vector against `target` and returns whether they all passed.
+pub async fn run(target: &str, vectors: &[Vector]) -> bool {
+    println!("vectors {target}");
+    let mut failed = 0;
+    for vector in vectors {
+        match check(target, vector).await {
+            Ok(()) => println!("PASS {}", vector.name),
+            Err(why) => {
+                failed += 1;
+                println!("FAIL {}: {why}", vector.name);
+            }
+        }
+    }
+    println!("{} passed, {failed} failed", vectors.len() - failed);
+    failed == 0
+}
+
+/// Runs every vector against a server of its own with the default config.
+pub async fn run_local(vectors: &[Vector]) -> io::Result<bool> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    let target = addr.to_string();
+    let config = Config { log_level: LogLevel::Error, ..Config::default() };
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = BroadcastServer::bind(addr).config(config).shutdown_on(async {
+        let _ = stopped.await;
+    });
+    let server = server.serve(listener);
+    tokio::pin!(server);
+    let passed = tokio::select! {
+        served = &mut server => {
+            served?;
+            return Err(io::Error::other("server stopped during the vectors"));
+        }
+        passed = run(&target, vectors) => passed,
+    };
+    let _ = stop.send(());
+    server.await?;
+    Ok(passed)
+}
+
+async fn check(target: &str, vector: &Vector) -> CheckResult {
+    let mut probes = HashMap::new();
+    for client in &vector.clients {
+        probes.insert(client.as_str(), Probe::connect(target).await?);
+    }
+    let ids: Vec<(String, String)> = probes.iter().map(|(client, probe)| (format!("{{{client}}}"), probe.id.clone())).collect();
+    let fill = |line: &str| ids.iter().fold(line.to_string(), |line, (client, id)| line.replace(client, id));
+    for (i, step) in vector.steps.iter().enumerate() {
+        let probe = probes.get_mut(step.client.as_str()).ok_or_else(|| format!("step {}: no client {}", i + 1, step.client))?;
+        let done = match (&step.send, &step.expect, step.quiet) {
+            (Some(line), None, false) => probe.send(&fill(line)).await,
+            // Presence notices are skipped while waiting for anything else
+            (None, Some(line), false) if is_unsolicited(line) => probe.expect_presence(&fill(line)).await,
+            (None, Some(line), false) => probe.expect(&fill(line)).await,
+            (None, None, true) => probe.expect_quiet().await,
+            _ => Err("not one of send, expect or quiet".to_string()),
+        };
+        done.map_err(|why| format!("step {}: {why}", i + 1))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shipped_vectors_pass() {
+        let vectors = parse(BUILTIN).unwrap();
+        assert!(run_local(&vectors).await.unwrap());
+    }
+}
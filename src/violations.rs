@@ -0,0 +1,49 @@
+//! Escalating responses to protocol violations.
+//!
+//! Each client has a violation count that grows with every malformed frame
+//! and is forgiven one step per housekeeping tick. The count decides the
+//! response: a warning, then throttled reads, then disconnection.
+
+use std::time::Duration;
+
+pub struct ViolationPolicy {
+    /// Violations at which the client is disconnected.
+    pub budget: u32,
+    /// Violations at which reads start being throttled.
+    pub throttle_at: u32,
+    /// Minimum time between lines read from a throttled client.
+    pub throttle_interval: Duration,
+}
+
+impl ViolationPolicy {
+    /// A budget of `budget` violations, throttling from halfway.
+    pub fn with_budget(budget: u32) -> Self {
+        Self {
+            budget,
+            throttle_at: budget / 2,
+            throttle_interval: Duration::from_millis(500),
+        }
+    }
+
+    pub fn respond(&self, violations: u32) -> Response {
+        if violations >= self.budget {
+            Response::Disconnect
+        } else if violations >= self.throttle_at {
+            Response::Throttle
+        } else {
+            Response::Warn
+        }
+    }
+}
+
+impl Default for ViolationPolicy {
+    fn default() -> Self {
+        Self::with_budget(10)
+    }
+}
+
+pub enum Response {
+    Warn,
+    Throttle,
+    Disconnect,
+}
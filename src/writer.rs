@@ -0,0 +1,839 @@
+//! Per-client writer tasks.
+//!
+//! Each client's write half is owned by its own task, spawned onto the
+//! runtime's worker threads. Broadcasts reach every task through one shared
+//! `broadcast` channel, and each task filters out what its client shouldn't
+//! see (its own messages, other rooms, events it opted out of). Lines meant for one
+//! client only (acks, `LOGIN`, warnings) go through a bounded per-client
+//! queue. The main loop never waits on a socket, so a client that stops
+//! reading only ever falls behind on its own; what happens then is up to the
+//! [`SlowConsumer`] policy. The feed is a ring shared by every task, so a
+//! task that falls a whole ring behind always loses the oldest lines; its
+//! own queue can give up either end. A task stuck in a write can't notice it has
+//! fallen behind, so the main loop also compares what it fed against what
+//! each task consumed. Lines carry the time they were queued, and each task
+//! notes how long they waited to be flushed, for the
+//! [`SlowConsumer::Latency`] policy.
+//!
+//! Lines are written as they are, or for a client on the framed listener
+//! as one length-prefixed frame each, newline dropped.
+//!
+//! A write that fails for the moment (the socket wasn't ready, a signal cut
+//! it short) is retried a few times, backing off, from where it stopped;
+//! any other error, or one that keeps coming back, ends the task. So does
+//! a write, flush or close taking longer than the write timeout, if one is
+//! set: a peer that stops reading without closing would otherwise hold the
+//! task until the queue overflows, and forever under the policies that
+//! drop lines rather than disconnect.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{FutureExt, SinkExt};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::codec::{FramedWrite, LengthDelimitedCodec};
+use tracing::{error, info, warn, Instrument, Span};
+
+use crate::compress::Compression;
+use crate::conn::{Transport, WriteHalf};
+use crate::panics;
+use crate::registry::ClientId;
+
+/// How long a departing client's writer may keep draining its queue.
+const CLOSE_GRACE: Duration = Duration::from_secs(5);
+/// Tries after the first at a write failing for the moment.
+const WRITE_RETRIES: u32 = 5;
+/// The wait before the first retry, doubled for each after it.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// What to do when a client can't keep up with its queue.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumer {
+    /// Make room for a line that doesn't fit by dropping the oldest one
+    /// queued, and carry on.
+    DropOldest,
+    /// Drop the lines that don't fit and carry on.
+    DropNewest,
+    /// Disconnect the client.
+    Disconnect,
+    /// Drop the lines that don't fit, as with `DropNewest`, and disconnect
+    /// the client only once its lines have taken too long to deliver for a
+    /// while.
+    Latency(LatencyBudget),
+}
+
+/// When a client is too slow under [`SlowConsumer::Latency`]: its lines
+/// took longer than `budget` from being queued to being flushed (or one
+/// still waits), at every check for `grace`. It's back in good standing
+/// once under half the budget; in between, the clock keeps running.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LatencyBudget {
+    pub budget: Duration,
+    pub grace: Duration,
+}
+
+impl Default for LatencyBudget {
+    fn default() -> Self {
+        Self { budget: Duration::from_secs(1), grace: Duration::from_secs(10) }
+    }
+}
+
+/// Who a broadcast is for.
+#[derive(Clone)]
+pub enum Audience {
+    /// Every client.
+    All,
+    /// Clients in the room, or in the lobby for `None`.
+    Room(Option<Arc<str>>),
+}
+
+/// A line for many clients, sent once through the shared channel.
+#[derive(Clone)]
+pub struct Fanout {
+    /// Sender, who doesn't get its own line back; `None` for server lines.
+    pub from: Option<ClientId>,
+    /// The sender asked for its own messages back, so gets this one too.
+    pub echo: bool,
+    pub to: Audience,
+    pub line: Bytes,
+    pub flush: bool,
+    /// Ephemeral event, skipped by clients that sent `EVENTS:OFF`.
+    pub event: bool,
+    /// Binary payload, which only a framed client can receive.
+    pub binary: bool,
+    /// A message's content type, which clients can filter on; `None` for
+    /// lines that aren't messages, which they can't.
+    pub content_type: Option<Bytes>,
+    /// The line compressed for clients that asked, by algorithm.
+    pub compressed: Option<Arc<[(Compression, Bytes)]>>,
+    pub queued: Instant,
+}
+
+struct Outbound {
+    line: Bytes,
+    flush: bool,
+    queued: Instant,
+}
+
+/// A client's own queue of lines, bounded at `capacity`.
+struct Queue {
+    lines: Mutex<VecDeque<Outbound>>,
+    capacity: usize,
+    /// Woken on every line queued, and when either end goes.
+    ready: Notify,
+    /// The main loop's end is gone: nothing more will be queued.
+    closed: AtomicBool,
+    /// The task's end is gone: nothing more will be written.
+    gone: AtomicBool,
+}
+
+impl Queue {
+    /// Queues a line, making room for it by dropping the oldest one with
+    /// `drop_oldest`. Returns whether a line was dropped.
+    fn push(&self, mut out: Outbound, drop_oldest: bool) -> Result<bool, SendError> {
+        if self.gone.load(Ordering::Acquire) {
+            return Err(SendError::Closed);
+        }
+        let mut lines = self.lines.lock().unwrap();
+        // An empty line only asks for a flush, which the last queued one can carry
+        if out.line.is_empty() {
+            if let Some(last) = lines.back_mut() {
+                last.flush |= out.flush;
+                return Ok(false);
+            }
+        }
+        let dropped = lines.len() >= self.capacity;
+        if dropped {
+            if !drop_oldest {
+                return Err(SendError::Full);
+            }
+            // Its flush still has to happen, for lines already written
+            let oldest = lines.pop_front().unwrap();
+            lines.front_mut().unwrap_or(&mut out).flush |= oldest.flush;
+        }
+        lines.push_back(out);
+        drop(lines);
+        self.ready.notify_one();
+        Ok(dropped)
+    }
+
+    fn try_recv(&self) -> Option<Outbound> {
+        self.lines.lock().unwrap().pop_front()
+    }
+
+    /// The next line, or `None` once the queue is closed and empty.
+    async fn recv(&self) -> Option<Outbound> {
+        loop {
+            if let Some(out) = self.try_recv() {
+                return Some(out);
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return self.try_recv();
+            }
+            self.ready.notified().await;
+        }
+    }
+}
+
+/// The main loop's end of a [`Queue`]; dropping it closes the queue.
+struct QueueSender(Arc<Queue>);
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::Release);
+        self.0.ready.notify_one();
+    }
+}
+
+/// The task's end of a [`Queue`].
+struct QueueReceiver(Arc<Queue>);
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.0.gone.store(true, Ordering::Release);
+    }
+}
+
+pub enum SendError {
+    /// The queue is full: the client isn't keeping up.
+    Full,
+    /// The writer task is gone, after a write error.
+    Closed,
+}
+
+/// Per-client state the writer task reads and the main loop updates.
+struct Shared {
+    events: AtomicBool,
+    /// Current room; `None` is the lobby.
+    room: Mutex<Option<Arc<str>>>,
+    /// Content types of messages the client wants; `None` for all.
+    accept: Mutex<Option<Vec<Bytes>>>,
+    /// How the client wants large messages compressed, if at all.
+    compression: Mutex<Option<Compression>>,
+    dropped: AtomicU64,
+    /// Broadcast lines taken off the feed, skipped and lagged ones included.
+    consumed: AtomicU64,
+    /// Of those, the ones the task is done with: written and flushed,
+    /// skipped, or lagged.
+    delivered: AtomicU64,
+    /// Broadcast lines at the start of the feed the client doesn't get:
+    /// those sent before it authenticated.
+    skip: AtomicU64,
+    /// The task died of a panic.
+    panicked: AtomicBool,
+    /// The task stopped on a write error.
+    failed: AtomicBool,
+    /// The client was dropped for falling behind, by the task or the main
+    /// loop.
+    lagged: AtomicBool,
+    /// Bytes of lines written, before any framing.
+    sent_bytes: AtomicU64,
+    /// Longest a flushed line waited, in microseconds, since the main
+    /// loop last looked.
+    worst_wait: AtomicU64,
+    /// When the oldest line written but not yet flushed was queued, as
+    /// microseconds after `epoch` plus one; 0 when there's none.
+    unflushed_since: AtomicU64,
+    epoch: Instant,
+}
+
+impl Shared {
+    fn accepts(&self, content_type: &[u8]) -> bool {
+        self.accept.lock().unwrap().as_ref().is_none_or(|types| types.iter().any(|t| t == content_type))
+    }
+
+    /// What the client gets of a broadcast, compressed if it asked: nothing
+    /// if it's the client's own line, in another room, or of a kind it
+    /// opted out of or can't take.
+    fn line_for(&self, client_id: ClientId, framed: bool, f: &Fanout) -> Option<Bytes> {
+        if (f.from == Some(client_id) && !f.echo) || (f.event && !self.events.load(Ordering::Relaxed)) {
+            return None;
+        }
+        if f.binary && !framed {
+            return None;
+        }
+        if f.content_type.as_ref().is_some_and(|content_type| !self.accepts(content_type)) {
+            return None;
+        }
+        if let Audience::Room(room) = &f.to {
+            if *self.room.lock().unwrap() != *room {
+                return None;
+            }
+        }
+        let compression = *self.compression.lock().unwrap();
+        let compressed = f.compressed.as_ref().zip(compression).and_then(|(lines, compression)| {
+            lines.iter().find(|(alg, _)| *alg == compression).map(|(_, line)| line.clone())
+        });
+        Some(compressed.unwrap_or_else(|| f.line.clone()))
+    }
+}
+
+/// Where a client's lines are written.
+pub enum Output {
+    Lines(BufWriter<WriteHalf>),
+    /// One length-prefixed frame per line.
+    Frames(FramedWrite<WriteHalf, LengthDelimitedCodec>),
+}
+
+impl Output {
+    /// Output for a client from `transport`, buffering up to `buffer`
+    /// bytes between flushes.
+    pub fn new(write_half: WriteHalf, transport: Transport, buffer: usize) -> Self {
+        match transport {
+            Transport::Framed => {
+                // Frames only need to fit the 4-byte length on the way out
+                let codec = LengthDelimitedCodec::builder().max_frame_length(u32::MAX as usize).new_codec();
+                let mut frames = FramedWrite::new(write_half, codec);
+                frames.set_backpressure_boundary(buffer);
+                Output::Frames(frames)
+            }
+            Transport::Tcp | Transport::WebSocket | Transport::Unix => Output::Lines(BufWriter::with_capacity(buffer, write_half)),
+        }
+    }
+
+    async fn write(&mut self, line: Bytes) -> io::Result<()> {
+        let mut retry = Retry::default();
+        match self {
+            // Written piece by piece rather than with `write_all`, so a retry
+            // doesn't repeat what already went
+            Output::Lines(lines) => {
+                let mut at = 0;
+                while at < line.len() {
+                    match lines.write(&line[at..]).await {
+                        Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                        Ok(n) => at += n,
+                        Err(e) => retry.after(e).await?,
+                    }
+                }
+                Ok(())
+            }
+            // An empty line only asks for a flush
+            Output::Frames(_) if line.is_empty() => Ok(()),
+            Output::Frames(frames) => {
+                let end = line.len() - usize::from(line.ends_with(b"\n"));
+                // A frame that failed wasn't taken, so it can be fed again
+                while let Err(e) = frames.feed(line.slice(..end)).await {
+                    retry.after(e).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        let mut retry = Retry::default();
+        loop {
+            let flushed = match self {
+                Output::Lines(lines) => lines.flush().await,
+                Output::Frames(frames) => SinkExt::<Bytes>::flush(frames).await,
+            };
+            match flushed {
+                Ok(()) => return Ok(()),
+                Err(e) => retry.after(e).await?,
+            }
+        }
+    }
+
+    /// Flushes, then closes cleanly (TLS close_notify, WebSocket close).
+    async fn shutdown(&mut self) -> io::Result<()> {
+        let mut retry = Retry::default();
+        loop {
+            let closed = match self {
+                Output::Lines(lines) => lines.shutdown().await,
+                Output::Frames(frames) => SinkExt::<Bytes>::close(frames).await,
+            };
+            match closed {
+                Ok(()) => return Ok(()),
+                Err(e) => retry.after(e).await?,
+            }
+        }
+    }
+}
+
+/// Retries left for one write, flush or close.
+#[derive(Default)]
+struct Retry {
+    tries: u32,
+}
+
+impl Retry {
+    /// Waits out `e` if it's worth another try, or hands it back.
+    async fn after(&mut self, e: io::Error) -> io::Result<()> {
+        if !is_transient(&e) || self.tries == WRITE_RETRIES {
+            return Err(e);
+        }
+        let wait = RETRY_BACKOFF * 2u32.pow(self.tries);
+        self.tries += 1;
+        info!(error = %e, attempt = self.tries, "write retry");
+        time::sleep(wait).await;
+        Ok(())
+    }
+}
+
+/// `op`, given up on as timed out after `timeout`, if there is one.
+async fn timed<T>(timeout: Option<Duration>, op: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, op)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out"))),
+        None => op.await,
+    }
+}
+
+/// Errors that say nothing about the connection, only about this attempt.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted)
+}
+
+pub struct ClientWriter {
+    tx: QueueSender,
+    /// Full queues drop their oldest line rather than refuse a new one.
+    drop_oldest: bool,
+    task: JoinHandle<()>,
+    shared: Arc<Shared>,
+    /// Lines were queued without a flush since the last one.
+    unflushed: bool,
+}
+
+impl ClientWriter {
+    /// Spawns the writer task. `closed` gets the client id once the task
+    /// gives up on the client.
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn(
+        client_id: ClientId,
+        out: Output,
+        write_timeout: Option<Duration>,
+        queue: usize,
+        feed: broadcast::Receiver<Fanout>,
+        policy: SlowConsumer,
+        closed: mpsc::UnboundedSender<ClientId>,
+        span: Span,
+    ) -> Self {
+        let queue = Arc::new(Queue {
+            lines: Mutex::new(VecDeque::new()),
+            capacity: queue.max(1),
+            ready: Notify::new(),
+            closed: AtomicBool::new(false),
+            gone: AtomicBool::new(false),
+        });
+        let (tx, rx) = (QueueSender(queue.clone()), QueueReceiver(queue));
+        let shared = Arc::new(Shared {
+            events: AtomicBool::new(true),
+            room: Mutex::new(None),
+            accept: Mutex::new(None),
+            compression: Mutex::new(None),
+            dropped: AtomicU64::new(0),
+            consumed: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            skip: AtomicU64::new(0),
+            panicked: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+            lagged: AtomicBool::new(false),
+            sent_bytes: AtomicU64::new(0),
+            worst_wait: AtomicU64::new(0),
+            unflushed_since: AtomicU64::new(0),
+            epoch: Instant::now(),
+        });
+        let task = Task {
+            client_id,
+            out,
+            write_timeout,
+            rx,
+            feed,
+            shared: shared.clone(),
+            policy,
+            unflushed: None,
+        };
+        let exit = shared.clone();
+        let task = tokio::spawn(async move {
+            // A panic (in a transport, say) ends only this client
+            match AssertUnwindSafe(task.run()).catch_unwind().await {
+                Ok(Ok(())) => return,
+                Ok(Err(Exit::Io(e))) => {
+                    exit.failed.store(true, Ordering::Relaxed);
+                    warn!(error = %e, "write error");
+                }
+                Ok(Err(Exit::Lagged(n))) => {
+                    exit.lagged.store(true, Ordering::Relaxed);
+                    info!(behind = n, "slow consumer");
+                }
+                Err(payload) => {
+                    exit.panicked.store(true, Ordering::Relaxed);
+                    error!(error = %panics::message(&*payload), "writer panicked");
+                }
+            }
+            let _ = closed.send(client_id);
+        }
+        .instrument(span));
+        Self { tx, drop_oldest: policy == SlowConsumer::DropOldest, task, shared, unflushed: false }
+    }
+
+    /// Queues a line for this client only; with `flush` it goes out as soon
+    /// as the task gets to it, otherwise it may wait for a later flush.
+    /// Under `SlowConsumer::DropOldest` a full queue makes room rather than
+    /// refusing the line, and the line it loses is counted as dropped.
+    pub fn send(&mut self, line: Bytes, flush: bool) -> Result<(), SendError> {
+        self.send_queued(line, flush, Instant::now())
+    }
+
+    /// Queues a broadcast for this client alone, passing over the feed, if
+    /// it's one the client would take off the feed; `framed` is whether
+    /// it's on the framed listener. Its wait is timed from `f.queued`.
+    pub fn send_fanout(&mut self, client_id: ClientId, framed: bool, f: &Fanout) -> Result<(), SendError> {
+        match self.shared.line_for(client_id, framed, f) {
+            Some(line) => self.send_queued(line, f.flush, f.queued),
+            None => Ok(()),
+        }
+    }
+
+    fn send_queued(&mut self, line: Bytes, flush: bool, queued: Instant) -> Result<(), SendError> {
+        let out = Outbound { line, flush, queued };
+        if self.tx.0.push(out, self.drop_oldest)? {
+            self.note_dropped();
+        }
+        self.unflushed = !flush;
+        Ok(())
+    }
+
+    /// Flushes lines queued without a flush, if there are any.
+    pub fn flush(&mut self) -> Result<(), SendError> {
+        if !self.unflushed {
+            return Ok(());
+        }
+        self.send(Bytes::new(), true)
+    }
+
+    pub fn set_events(&self, on: bool) {
+        self.shared.events.store(on, Ordering::Relaxed);
+    }
+
+    pub fn events(&self) -> bool {
+        self.shared.events.load(Ordering::Relaxed)
+    }
+
+    /// Skips the first `n` broadcast lines the task takes off the feed;
+    /// `u64::MAX` skips them all until this is called again.
+    pub fn skip_feed(&self, n: u64) {
+        self.shared.skip.store(n, Ordering::Relaxed);
+    }
+
+    pub fn set_room(&self, room: Option<Arc<str>>) {
+        *self.shared.room.lock().unwrap() = room;
+    }
+
+    pub fn set_accept(&self, types: Option<Vec<Bytes>>) {
+        *self.shared.accept.lock().unwrap() = types;
+    }
+
+    /// The content types it accepts, comma-separated, or `*` for all.
+    pub fn accept(&self) -> String {
+        match &*self.shared.accept.lock().unwrap() {
+            Some(types) => types.iter().map(|t| String::from_utf8_lossy(t)).collect::<Vec<_>>().join(","),
+            None => "*".to_string(),
+        }
+    }
+
+    pub fn set_compression(&self, compression: Option<Compression>) {
+        *self.shared.compression.lock().unwrap() = compression;
+    }
+
+    /// Whether the client wants messages of this content type.
+    pub fn accepts(&self, content_type: &[u8]) -> bool {
+        self.shared.accepts(content_type)
+    }
+
+    /// Counts a line dropped under one of the dropping policies.
+    pub fn note_dropped(&self) {
+        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Lines this client has missed under one of the dropping policies.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the task died of a panic.
+    pub fn panicked(&self) -> bool {
+        self.shared.panicked.load(Ordering::Relaxed)
+    }
+
+    /// Whether the task stopped on a write error.
+    pub fn failed(&self) -> bool {
+        self.shared.failed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the client was dropped for falling behind.
+    pub fn lagged(&self) -> bool {
+        self.shared.lagged.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written to the client so far.
+    pub fn sent_bytes(&self) -> u64 {
+        self.shared.sent_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Broadcast lines the task is done with so far, out of those it has
+    /// taken off the feed.
+    pub fn delivered(&self) -> u64 {
+        self.shared.delivered.load(Ordering::Relaxed)
+    }
+
+    /// The longest any line has taken from being queued to being flushed
+    /// since this was last called, counting one that's still waiting.
+    pub fn take_latency(&self) -> Duration {
+        let worst = self.shared.worst_wait.swap(0, Ordering::Relaxed);
+        let waiting = match self.shared.unflushed_since.load(Ordering::Relaxed) {
+            0 => 0,
+            since => (self.shared.epoch.elapsed().as_micros() as u64).saturating_sub(since - 1),
+        };
+        Duration::from_micros(worst.max(waiting))
+    }
+
+    /// Broadcast lines the task has taken off the feed so far.
+    pub fn consumed(&self) -> u64 {
+        self.shared.consumed.load(Ordering::Relaxed)
+    }
+
+    /// Stops the writer immediately, discarding anything still queued.
+    pub fn abort(&self) {
+        self.task.abort();
+    }
+
+    /// Stops the writer of a client that isn't keeping up, like `abort`.
+    pub fn abort_lagged(&self) {
+        self.shared.lagged.store(true, Ordering::Relaxed);
+        self.task.abort();
+    }
+
+    /// Waits, until `deadline` at the latest, for the writer to deliver
+    /// everything queued and broadcast so far; it finishes once the feed's
+    /// sender is gone and it has caught up. Returns whether it made it.
+    pub async fn drain(self, deadline: time::Instant) -> bool {
+        // Holding `tx` keeps the queue open, so the task stops on the
+        // closed feed rather than on an empty queue with the feed unread.
+        let Self { tx: _tx, mut task, .. } = self;
+        if time::timeout_at(deadline, &mut task).await.is_ok() {
+            return true;
+        }
+        task.abort();
+        false
+    }
+
+    /// Lets the writer deliver what's queued (a parting error line, say),
+    /// giving up after `CLOSE_GRACE` if the client isn't reading.
+    pub fn close(self) {
+        let Self { tx, task, .. } = self;
+        drop(tx);
+        if !task.is_finished() {
+            tokio::spawn(async move {
+                time::sleep(CLOSE_GRACE).await;
+                task.abort();
+            });
+        }
+    }
+}
+
+enum Exit {
+    Io(io::Error),
+    /// Fell this many broadcast lines behind under `SlowConsumer::Disconnect`.
+    Lagged(u64),
+}
+
+impl From<io::Error> for Exit {
+    fn from(e: io::Error) -> Self {
+        Exit::Io(e)
+    }
+}
+
+/// What to do with one item taken off either queue.
+enum Step {
+    /// A line, whether to flush it, and when it was queued.
+    Write(Bytes, bool, Instant),
+    Skip,
+    Stop,
+}
+
+struct Task {
+    client_id: ClientId,
+    out: Output,
+    write_timeout: Option<Duration>,
+    rx: QueueReceiver,
+    feed: broadcast::Receiver<Fanout>,
+    shared: Arc<Shared>,
+    policy: SlowConsumer,
+    /// When the oldest line written since the last flush was queued.
+    unflushed: Option<Instant>,
+}
+
+impl Task {
+    async fn run(mut self) -> Result<(), Exit> {
+        loop {
+            // Lines for this client alone go first, so LOGIN precedes any broadcast
+            let step = tokio::select! {
+                biased;
+                out = self.rx.0.recv() => match out {
+                    Some(out) => Step::Write(out.line, out.flush, out.queued),
+                    None => Step::Stop,
+                },
+                item = self.feed.recv() => self.fanout(item)?,
+            };
+            let mut flush = match step {
+                Step::Write(line, flush, queued) => {
+                    self.write(line, queued).await?;
+                    flush
+                }
+                Step::Skip => {
+                    // Nothing written is waiting on a flush, so it's all done
+                    if self.unflushed.is_none() {
+                        self.flushed();
+                    }
+                    continue;
+                }
+                Step::Stop => break,
+            };
+            // Whatever else is already queued goes out in the same flush
+            loop {
+                let step = match self.rx.0.try_recv() {
+                    Some(out) => Step::Write(out.line, out.flush, out.queued),
+                    None => match self.feed.try_recv() {
+                        Ok(item) => self.fanout(Ok(item))?,
+                        Err(broadcast::error::TryRecvError::Lagged(n)) => self.fanout(Err(RecvError::Lagged(n)))?,
+                        // Empty, or closed: the blocking receive above sorts that out
+                        Err(_) => break,
+                    },
+                };
+                if let Step::Write(line, more, queued) = step {
+                    self.write(line, queued).await?;
+                    flush |= more;
+                }
+            }
+            if flush {
+                timed(self.write_timeout, self.out.flush()).await?;
+                self.flushed();
+            }
+        }
+        timed(self.write_timeout, self.out.shutdown()).await?;
+        Ok(())
+    }
+
+    async fn write(&mut self, line: Bytes, queued: Instant) -> io::Result<()> {
+        let len = line.len() as u64;
+        if self.unflushed.is_none_or(|oldest| queued < oldest) {
+            self.unflushed = Some(queued);
+            let since = queued.saturating_duration_since(self.shared.epoch).as_micros() as u64 + 1;
+            self.shared.unflushed_since.store(since, Ordering::Relaxed);
+        }
+        timed(self.write_timeout, self.out.write(line)).await?;
+        self.shared.sent_bytes.fetch_add(len, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Notes how long the lines just flushed waited, and that every line
+    /// taken off the feed so far is delivered.
+    fn flushed(&mut self) {
+        if let Some(oldest) = self.unflushed.take() {
+            self.shared.unflushed_since.store(0, Ordering::Relaxed);
+            self.shared.worst_wait.fetch_max(oldest.elapsed().as_micros() as u64, Ordering::Relaxed);
+        }
+        self.shared.delivered.store(self.shared.consumed.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    fn fanout(&self, item: Result<Fanout, RecvError>) -> Result<Step, Exit> {
+        let taken = match &item {
+            Ok(_) => 1,
+            Err(RecvError::Lagged(n)) => *n,
+            Err(RecvError::Closed) => 0,
+        };
+        let index = self.shared.consumed.fetch_add(taken, Ordering::Relaxed);
+        match item {
+            Ok(f) if index >= self.shared.skip.load(Ordering::Relaxed) => {
+                let framed = matches!(self.out, Output::Frames(_));
+                match self.shared.line_for(self.client_id, framed, &f) {
+                    Some(line) => Ok(Step::Write(line, f.flush, f.queued)),
+                    None => Ok(Step::Skip),
+                }
+            }
+            Ok(_) => Ok(Step::Skip),
+            Err(RecvError::Lagged(n)) => match self.policy {
+                SlowConsumer::DropOldest | SlowConsumer::DropNewest | SlowConsumer::Latency(_) => {
+                    self.shared.dropped.fetch_add(n, Ordering::Relaxed);
+                    Ok(Step::Skip)
+                }
+                SlowConsumer::Disconnect => Err(Exit::Lagged(n)),
+            },
+            Err(RecvError::Closed) => Ok(Step::Stop),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    /// A writer to `out`, and the feed, which it stops with once dropped.
+    fn writer(out: tokio::io::DuplexStream, queue: usize, policy: SlowConsumer) -> (ClientWriter, broadcast::Sender<Fanout>) {
+        let (feed, rx) = broadcast::channel(4);
+        let (closed, _) = mpsc::unbounded_channel();
+        let out = Output::new(Box::new(out), Transport::Tcp, 64);
+        (ClientWriter::spawn(1, out, None, queue, rx, policy, closed, Span::none()), feed)
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_refuses_or_drops_by_policy() {
+        let (out, peer) = tokio::io::duplex(64);
+        let (mut disconnect, _feed) = writer(out, 2, SlowConsumer::Disconnect);
+        let mut lines = BufReader::new(peer).lines();
+        assert!(disconnect.send(Bytes::from("one\n"), false).is_ok());
+        assert!(disconnect.send(Bytes::from("two\n"), true).is_ok());
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("one"));
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("two"));
+
+        // Nobody reads on, so the queue fills up behind the task
+        let line = Bytes::from(format!("{}\n", "x".repeat(1024)));
+        let full = (0..8).map(|_| disconnect.send(line.clone(), true)).find_map(Result::err);
+        assert!(matches!(full, Some(SendError::Full)));
+
+        let (out, _peer) = tokio::io::duplex(64);
+        let (mut drop_oldest, _feed) = writer(out, 2, SlowConsumer::DropOldest);
+        assert!((0..8).all(|_| drop_oldest.send(line.clone(), true).is_ok()));
+        assert!(drop_oldest.dropped() > 0);
+    }
+    #[tokio::test]
+    async fn takes_what_it_should_off_the_feed() {
+        let (out, peer) = tokio::io::duplex(1024);
+        let (writer, feed) = writer(out, 2, SlowConsumer::Disconnect);
+        writer.set_room(Some("ops".into()));
+        let fanout = |from, to, line: &str| Fanout {
+            from,
+            echo: false,
+            to,
+            line: Bytes::from(format!("{line}\n")),
+            flush: true,
+            event: false,
+            binary: false,
+            content_type: None,
+            compressed: None,
+            queued: Instant::now(),
+        };
+        // Not its own line, nor another room's
+        let _ = feed.send(fanout(Some(1), Audience::All, "own"));
+        let _ = feed.send(fanout(Some(2), Audience::Room(None), "lobby"));
+        let _ = feed.send(fanout(Some(2), Audience::Room(Some("ops".into())), "ops"));
+        let _ = feed.send(fanout(None, Audience::All, "server"));
+        let mut lines = BufReader::new(peer).lines();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("ops"));
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("server"));
+        assert_eq!(writer.consumed(), 4);
+    }
+}
@@ -0,0 +1,168 @@
+//! WebSocket transport, so browsers can join.
+//!
+//! A WebSocket client speaks the same line protocol, one line per message:
+//! each text (or binary) message it sends is read as a line, and each line
+//! the server writes goes out as a text message without its newline, but
+//! for an empty line, a keepalive, which goes out as a ping. The
+//! adapters here turn the socket back into a byte stream and a byte sink,
+//! so the reader and writer treat it like any other connection. Without
+//! the `websocket` feature there's no WebSocket listener.
+
+use std::io;
+#[cfg(feature = "websocket")]
+use std::pin::Pin;
+#[cfg(feature = "websocket")]
+use std::task::{ready, Context, Poll};
+
+#[cfg(feature = "websocket")]
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(feature = "websocket")]
+use futures::{future, SinkExt, StreamExt};
+#[cfg(feature = "websocket")]
+use futures::stream::SplitSink;
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::tungstenite::{Error as WsError, Message, Utf8Bytes};
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::WebSocketStream;
+#[cfg(feature = "websocket")]
+use tokio_util::io::StreamReader;
+
+use crate::conn::{ReadHalf, WriteHalf};
+
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// The stream under the WebSocket, plain TCP or TLS.
+pub type BoxedIo = Box<dyn Io>;
+#[cfg(feature = "websocket")]
+pub type WsStream = WebSocketStream<BoxedIo>;
+
+/// Built without WebSockets, so no stream can exist.
+#[cfg(not(feature = "websocket"))]
+pub enum WsStream {}
+
+/// Runs the server side of the HTTP upgrade.
+#[cfg(feature = "websocket")]
+pub async fn accept(io: BoxedIo) -> io::Result<WsStream> {
+    tokio_tungstenite::accept_async(io).await.map_err(io::Error::other)
+}
+
+#[cfg(not(feature = "websocket"))]
+pub async fn accept(_io: BoxedIo) -> io::Result<WsStream> {
+    Err(crate::info::not_built("websocket"))
+}
+
+#[cfg(not(feature = "websocket"))]
+pub fn split(ws: WsStream) -> (ReadHalf, WriteHalf) {
+    match ws {}
+}
+
+#[cfg(feature = "websocket")]
+pub fn split(ws: WsStream) -> (ReadHalf, WriteHalf) {
+    let (sink, stream) = ws.split();
+    let lines = stream.filter_map(|msg| {
+        future::ready(match msg {
+            Ok(Message::Text(text)) => Some(Ok(line(text.into()))),
+            Ok(Message::Binary(data)) => Some(Ok(line(data))),
+            // Pings are answered by tungstenite; a close ends the stream
+            Ok(_) | Err(WsError::ConnectionClosed) => None,
+            Err(e) => Some(Err(io::Error::other(e))),
+        })
+    });
+    let writer = LineWriter { sink, pending: BytesMut::new() };
+    (Box::new(StreamReader::new(lines)), Box::new(writer))
+}
+
+/// A message's payload as one newline-terminated line; a trailing newline
+/// the client added itself isn't doubled.
+#[cfg(feature = "websocket")]
+fn line(payload: Bytes) -> Bytes {
+    let payload = payload.strip_suffix(b"\n").unwrap_or(&payload);
+    let mut line = BytesMut::with_capacity(payload.len() + 1);
+    line.put_slice(payload);
+    line.put_u8(b'\n');
+    line.freeze()
+}
+
+/// Byte sink that sends each complete line as a text message.
+#[cfg(feature = "websocket")]
+struct LineWriter {
+    sink: SplitSink<WsStream, Message>,
+    /// Written bytes not yet sent, at most one partial line once drained.
+    pending: BytesMut,
+}
+
+#[cfg(feature = "websocket")]
+impl LineWriter {
+    /// Hands complete lines to the socket while it has room.
+    fn poll_send_lines(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            ready!(self.sink.poll_ready_unpin(cx)).map_err(io::Error::other)?;
+            let line = self.pending.split_to(end).freeze();
+            self.pending.advance(1);
+            // The server never sends an empty line, so one is a keepalive
+            if line.is_empty() {
+                self.sink.start_send_unpin(Message::Ping(Bytes::new())).map_err(io::Error::other)?;
+                continue;
+            }
+            let text = Utf8Bytes::try_from(line)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "line is not valid UTF-8"))?;
+            self.sink.start_send_unpin(Message::Text(text)).map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl AsyncWrite for LineWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Backpressure: nothing new is taken while earlier lines are stuck
+        ready!(this.poll_send_lines(cx))?;
+        this.pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_lines(cx))?;
+        this.sink.poll_flush_unpin(cx).map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        self.get_mut().sink.poll_close_unpin(cx).map_err(io::Error::other)
+    }
+}
+
+#[cfg(all(test, feature = "websocket"))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    #[tokio::test]
+    async fn carries_one_line_per_message() {
+        let (server, client) = tokio::io::duplex(4096);
+        let (ws, client) = tokio::join!(accept(Box::new(server)), tokio_tungstenite::client_async("ws://localhost/", client));
+        let (read, mut write) = split(ws.unwrap());
+        let (mut client, _) = client.unwrap();
+
+        client.send(Message::text("hello")).await.unwrap();
+        client.send(Message::binary(&b"ends in a newline\n"[..])).await.unwrap();
+        let mut lines = BufReader::new(read).lines();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("hello"));
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("ends in a newline"));
+
+        // An empty line is a keepalive, and a partial one waits for its end
+        write.write_all(b"one\n\ntwo\npart").await.unwrap();
+        write.flush().await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("one"));
+        assert!(matches!(client.next().await.unwrap().unwrap(), Message::Ping(ping) if ping.is_empty()));
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("two"));
+        write.write_all(b"ial\n").await.unwrap();
+        write.flush().await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("partial"));
+    }
+}
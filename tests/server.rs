@@ -0,0 +1,755 @@
+//! The server end to end, through the in-process harness.
+
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use tcp_broadcast::testing::{TestClient, TestServer};
+#[cfg(feature = "persistence")]
+use tcp_broadcast::BlobConfig;
+#[cfg(feature = "tls")]
+use tcp_broadcast::TlsConfig;
+use tcp_broadcast::{
+    AuthConfig, Config, IdleConfig, IdlePolicy, LogLevel, RateLimit, SlowConsumer, SocketOptions, Tuning, ViolationPolicy,
+};
+
+fn quiet() -> Config {
+    Config { log_level: LogLevel::Error, ..Config::default() }
+}
+
+/// A path in the temp dir for this test run, with nothing there yet.
+#[cfg(feature = "persistence")]
+fn scratch(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("tcp-broadcast-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// A UDP port nothing was bound to a moment ago.
+fn free_udp_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0").and_then(|socket| socket.local_addr()).expect("bind a free udp port").port()
+}
+
+#[tokio::test]
+async fn broadcasts_to_everyone_but_the_sender() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+    let mut c = TestClient::connect(server.addr()).await;
+
+    a.send("hello").await;
+    a.expect("ACK:MESSAGE").await;
+    b.expect(&format!("MESSAGE:{} hello", a.id())).await;
+    c.expect(&format!("MESSAGE:{} hello", a.id())).await;
+    a.expect_quiet().await;
+}
+
+#[tokio::test]
+async fn acks_and_messages_keep_their_order() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+
+    for n in 0..50 {
+        a.send(&format!("message {n}")).await;
+    }
+    // A command's reply comes after the acks for everything sent before it
+    a.send("WHO").await;
+    for _ in 0..50 {
+        a.expect("ACK:MESSAGE").await;
+    }
+    a.expect(&format!("WHO:{} {}", a.id(), b.id())).await;
+    for n in 0..50 {
+        b.expect(&format!("MESSAGE:{} message {n}", a.id())).await;
+    }
+}
+
+#[tokio::test]
+async fn a_client_that_leaves_is_forgotten() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let b = TestClient::connect(server.addr()).await;
+    let b_id = b.id().to_string();
+
+    drop(b);
+    a.expect_presence(&format!("LEFT:{b_id}")).await;
+    a.send("WHO").await;
+    a.expect(&format!("WHO:{}", a.id())).await;
+    a.send(&format!("MSG:{b_id} still there?")).await;
+    a.expect_prefix("ERROR:").await;
+}
+
+#[tokio::test]
+async fn private_messages_are_rate_limited_too() {
+    let rate_limit = RateLimit { rate: 0.1, burst: 2.0, disconnect_after: 3 };
+    let server = TestServer::start(Config { rate_limit: Some(rate_limit), ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+
+    // Past the burst, then enough strikes to outlast any forgiven, in one
+    // write so none is left to send once the server hangs up
+    let spam: Vec<String> = (0..10).map(|n| format!("MSG:{} spam {n}", b.id())).collect();
+    a.send(&spam.join("\n")).await;
+    a.expect("ACK:MSG").await;
+    a.expect("ACK:MSG").await;
+    a.expect("ERROR:RATE_LIMITED").await;
+    assert_eq!(a.expect_closed().await.as_deref(), Some("ERROR:RATE_LIMITED"));
+    b.expect(&format!("MSG:{} spam 0", a.id())).await;
+    b.expect(&format!("MSG:{} spam 1", a.id())).await;
+    b.expect_presence(&format!("LEFT:{}", a.id())).await;
+}
+
+#[tokio::test]
+async fn ingest_mode_acks_in_ranges() {
+    let server = TestServer::start(quiet());
+    let mut producer = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+
+    producer.send("INGEST").await;
+    producer.expect("ACK:INGEST").await;
+    for n in 0..5 {
+        producer.send(&format!("reading {n}")).await;
+    }
+    // The flush tick may split them, but the ranges follow on from 1
+    let mut acked = 0;
+    while acked < 5 {
+        let range = producer.expect_prefix("ACK_RANGE:").await;
+        let (from, to) = range["ACK_RANGE:".len()..].split_once('-').expect("ACK_RANGE:FROM-TO");
+        assert_eq!(from.parse::<u32>().unwrap(), acked + 1);
+        acked = to.parse().unwrap();
+    }
+    assert_eq!(acked, 5);
+    for n in 0..5 {
+        b.expect(&format!("MESSAGE:{} reading {n}", producer.id())).await;
+    }
+}
+
+#[tokio::test]
+async fn events_are_fanned_out_but_limited_and_optional() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+    let mut c = TestClient::connect(server.addr()).await;
+
+    c.send("EVENTS:OFF").await;
+    c.expect("ACK:EVENTS").await;
+    // A burst of 5, and the rest dropped without a word
+    a.send("TYPING").await;
+    for _ in 0..6 {
+        a.send("EVENT:wave").await;
+    }
+    a.send("hello").await;
+    a.expect("ACK:MESSAGE").await;
+    b.expect(&format!("EVENT:{} TYPING", a.id())).await;
+    for _ in 0..4 {
+        b.expect(&format!("EVENT:{} wave", a.id())).await;
+    }
+    b.expect(&format!("MESSAGE:{} hello", a.id())).await;
+    c.expect(&format!("MESSAGE:{} hello", a.id())).await;
+}
+
+#[tokio::test]
+async fn passes_its_own_conformance_checks() {
+    let server = TestServer::start(quiet());
+    assert!(tcp_broadcast::conformance::run(&server.addr().to_string()).await.unwrap());
+}
+
+#[tokio::test]
+async fn the_throughput_profile_leaves_writes_to_the_flush_tick() {
+    let server = TestServer::start(Config { tuning: Tuning::throughput(), ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+
+    for n in 0..20 {
+        a.send(&format!("message {n}")).await;
+    }
+    // Nothing else is sent to push them out; the tick has to
+    for n in 0..20 {
+        a.expect("ACK:MESSAGE").await;
+        b.expect(&format!("MESSAGE:{} message {n}", a.id())).await;
+    }
+}
+
+#[tokio::test]
+async fn protocol_violations_escalate_to_a_disconnect() {
+    let server = TestServer::start(Config { violations: ViolationPolicy::with_budget(3), ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+
+    // Halfway, here the first, its reads are throttled too
+    a.send("nul\0byte").await;
+    a.expect("WARN:PROTOCOL nul byte").await;
+    a.send("nul\0byte").await;
+    a.expect("WARN:PROTOCOL nul byte").await;
+    a.send("nul\0byte").await;
+    assert_eq!(a.expect_closed().await.as_deref(), Some("ERROR:PROTOCOL_VIOLATION nul byte"));
+}
+
+#[tokio::test]
+async fn hooks_see_logins_and_messages() {
+    let (seen, hooked) = mpsc::channel();
+    let server = TestServer::start_with(quiet(), move |server| {
+        let connected = seen.clone();
+        server
+            .on_connect(move |id, peer| connected.send(format!("connect {id} {}", peer.ip())).unwrap())
+            .on_message(move |frame| seen.send(format!("message {} {}", frame.sender, frame.text)).unwrap())
+    });
+    let mut a = TestClient::connect(server.addr()).await;
+    let b = TestClient::connect(server.addr()).await;
+
+    // Commands aren't messages
+    a.send("WHO").await;
+    a.expect_prefix("WHO:").await;
+    a.send("hello").await;
+    a.expect("ACK:MESSAGE").await;
+    let hooked: Vec<String> = hooked.try_iter().collect();
+    let want = [format!("connect {} 127.0.0.1", a.id()), format!("connect {} 127.0.0.1", b.id()), format!("message {} hello", a.id())];
+    assert_eq!(hooked, want);
+}
+
+#[tokio::test]
+async fn rooms_keep_their_messages_to_themselves() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+    let mut c = TestClient::connect(server.addr()).await;
+
+    for client in [&mut a, &mut b] {
+        client.send("JOIN:dev").await;
+        client.expect("ACK:JOIN dev").await;
+    }
+    a.send("in dev").await;
+    a.expect("ACK:MESSAGE").await;
+    b.expect(&format!("MESSAGE:{} in dev", a.id())).await;
+    c.send("ROOMS").await;
+    c.expect("ROOMS:dev=2").await;
+
+    a.send("PART:ops").await;
+    a.expect("ERROR:NOT_IN_ROOM ops").await;
+    a.send("JOIN:no room").await;
+    a.expect("ERROR:INVALID_ROOM no room").await;
+    a.send("PART:dev").await;
+    a.expect("ACK:PART dev").await;
+    c.send("in the lobby").await;
+    c.expect("ACK:MESSAGE").await;
+    a.expect(&format!("MESSAGE:{} in the lobby", c.id())).await;
+    b.expect_quiet().await;
+}
+
+#[tokio::test]
+async fn room_modes_turn_off_acks_and_slow_members_down() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+
+    a.send("MODE:slow=5").await;
+    a.expect("ERROR:NOT_IN_ROOM").await;
+    for client in [&mut a, &mut b] {
+        client.send("JOIN:dev").await;
+        client.expect("ACK:JOIN dev").await;
+    }
+    a.send("MODE:acks=off slow=5").await;
+    a.expect("ACK:MODE dev acks=off slow=5 history=on moderated=off").await;
+    a.send("MODE:slow=soon").await;
+    a.expect("ERROR:INVALID_MODE slow=soon").await;
+
+    a.send("first").await;
+    b.expect(&format!("MESSAGE:{} first", a.id())).await;
+    a.send("too soon").await;
+    a.expect("ERROR:SLOW_MODE 5").await;
+    a.send("ROOMS").await;
+    a.expect("ROOMS:dev=2;acks=off;slow=5;history=on;moderated=off").await;
+    b.expect_quiet().await;
+}
+
+#[tokio::test]
+async fn nicknames_are_unique_and_stand_in_for_ids() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+
+    a.send("NICK:alice").await;
+    a.expect("ACK:NICK alice").await;
+    b.send("NICK:Alice").await;
+    b.expect("ERROR:NICK_TAKEN Alice").await;
+    b.send("NICK:1234").await;
+    b.expect("ERROR:INVALID_NICK 1234").await;
+    a.send("hello").await;
+    a.expect("ACK:MESSAGE").await;
+    b.expect("MESSAGE:alice hello").await;
+
+    // Renaming frees the old name
+    a.send("NICK:al").await;
+    a.expect("ACK:NICK al").await;
+    b.send("NICK:alice").await;
+    b.expect("ACK:NICK alice").await;
+}
+
+#[tokio::test]
+async fn private_messages_reach_one_client_in_any_room() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+    let mut c = TestClient::connect(server.addr()).await;
+
+    b.send("JOIN:dev").await;
+    b.expect("ACK:JOIN dev").await;
+    b.send("NICK:bob").await;
+    b.expect("ACK:NICK bob").await;
+    a.send("MSG:bob psst").await;
+    a.expect("ACK:MSG").await;
+    b.expect(&format!("MSG:{} psst", a.id())).await;
+    b.send(&format!("MSG:{} hi", a.id())).await;
+    b.expect("ACK:MSG").await;
+    a.expect("MSG:bob hi").await;
+    a.send("MSG:nobody hello?").await;
+    a.expect("ERROR:UNKNOWN_CLIENT nobody").await;
+    c.expect_quiet().await;
+}
+
+#[tokio::test]
+async fn everyone_hears_who_comes_and_goes() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let b = TestClient::connect(server.addr()).await;
+    let b_id = b.id().to_string();
+
+    a.expect_presence(&format!("JOINED:{b_id}")).await;
+    a.send("WHO").await;
+    a.expect(&format!("WHO:{} {b_id}", a.id())).await;
+    drop(b);
+    a.expect_presence(&format!("LEFT:{b_id}")).await;
+}
+
+#[tokio::test]
+async fn newcomers_get_the_recent_history_first() {
+    let server = TestServer::start(Config { history: 2, ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+
+    for n in 0..3 {
+        a.send(&format!("message {n}")).await;
+        a.expect("ACK:MESSAGE").await;
+    }
+    let late = TestClient::connect(server.addr()).await;
+    let kept = [1, 2].map(|n| format!("HISTORY:MESSAGE:{} message {n}", a.id()));
+    assert_eq!(late.history(), kept);
+}
+
+#[cfg(feature = "persistence")]
+#[tokio::test]
+async fn the_message_log_seeds_history_after_a_restart() {
+    let log = scratch("journal.log");
+    let config = || Config { history: 5, log_file: Some(log.clone()), ..quiet() };
+    let server = TestServer::start(config());
+    let mut a = TestClient::connect(server.addr()).await;
+    a.send("JOIN:dev").await;
+    a.expect("ACK:JOIN dev").await;
+    a.send("in a room").await;
+    a.expect("ACK:MESSAGE").await;
+    a.send("PART:dev").await;
+    a.expect("ACK:PART dev").await;
+    a.send("in the lobby").await;
+    a.expect("ACK:MESSAGE").await;
+    let a_id = a.id().to_string();
+    drop(a);
+    server.stop().unwrap();
+
+    // Only the lobby's messages come back
+    let server = TestServer::start(config());
+    let late = TestClient::connect(server.addr()).await;
+    assert_eq!(late.history(), [format!("HISTORY:MESSAGE:{a_id} in the lobby")]);
+    let _ = std::fs::remove_file(log);
+}
+
+#[tokio::test]
+async fn injectors_publish_under_their_own_name() {
+    let server = TestServer::start_with(quiet(), |mut server| {
+        let greeter = server.injector("greeter");
+        server.on_connect(move |id, _| greeter.publish_in("dev", format!("welcome, {id}")))
+    });
+    let mut a = TestClient::connect(server.addr()).await;
+    a.send("JOIN:dev").await;
+    a.expect("ACK:JOIN dev").await;
+    let b = TestClient::connect(server.addr()).await;
+    a.expect(&format!("MESSAGE:greeter welcome, {}", b.id())).await;
+    a.send("NICK:Greeter").await;
+    a.expect("ERROR:NICK_TAKEN Greeter").await;
+}
+
+#[cfg(feature = "persistence")]
+#[tokio::test]
+async fn large_payloads_go_by_reference() {
+    let dir = scratch("blobs");
+    let blobs = BlobConfig { threshold: 16, ..BlobConfig::new(&dir) };
+    let server = TestServer::start(Config { blobs: Some(blobs), ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+
+    let large = "x".repeat(17);
+    a.send(&large).await;
+    a.expect("ACK:MESSAGE").await;
+    let blobref = b.expect_prefix(&format!("BLOBREF:{} ", a.id())).await;
+    let (blob_id, size) = blobref.rsplit_once(' ').map(|(rest, size)| (rest.rsplit(' ').next().unwrap(), size)).unwrap();
+    assert_eq!(size, "17");
+    b.send(&format!("FETCH:{blob_id}")).await;
+    b.expect(&format!("BLOB:{blob_id} {large}")).await;
+    b.send("FETCH:nothing").await;
+    b.expect("ERROR:UNKNOWN_BLOB nothing").await;
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn clients_that_dont_answer_a_ping_are_evicted() {
+    let policy = IdlePolicy::Ping { interval: Duration::from_secs(1), timeout: Duration::from_secs(1) };
+    let server = TestServer::start(Config { idle: IdleConfig::all(policy), ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+    a.set_timeout(Duration::from_secs(5));
+    b.set_timeout(Duration::from_secs(5));
+
+    a.expect_presence("PING").await;
+    b.expect_presence("PING").await;
+    b.send("PONG").await;
+    // a says nothing, so it goes, and b, having answered, hears it
+    assert_eq!(a.expect_closed().await, None);
+    b.expect_presence(&format!("LEFT:{}", a.id())).await;
+    b.send("PING").await;
+    b.expect("PONG").await;
+}
+
+#[tokio::test]
+async fn shutting_down_drains_then_says_so() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+
+    a.send("last words").await;
+    a.expect("ACK:MESSAGE").await;
+    server.stop().unwrap();
+    // What was broadcast before comes first, and the notice last
+    b.expect(&format!("MESSAGE:{} last words", a.id())).await;
+    assert_eq!(b.expect_closed().await.as_deref(), Some("SERVER:SHUTDOWN"));
+    assert_eq!(a.expect_closed().await.as_deref(), Some("SERVER:SHUTDOWN"));
+}
+
+#[tokio::test]
+async fn connections_past_the_limit_are_turned_away() {
+    use tokio::io::AsyncReadExt;
+
+    let server = TestServer::start(Config { max_clients: Some(1), ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+
+    let mut extra = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    let mut said = String::new();
+    tokio::time::timeout(Duration::from_secs(2), extra.read_to_string(&mut said)).await.unwrap().unwrap();
+    assert_eq!(said, "ERROR:SERVER_FULL\n");
+    a.send("WHO").await;
+    a.expect(&format!("WHO:{}", a.id())).await;
+}
+
+#[tokio::test]
+async fn a_firehose_neither_holds_up_others_nor_loses_lines() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+    let mut c = TestClient::connect(server.addr()).await;
+
+    // Far past the 16 lines a sender may have queued before it's parked
+    for n in 0..100 {
+        a.send(&format!("flood {n}")).await;
+    }
+    c.send("quiet").await;
+    c.expect("ACK:MESSAGE").await;
+    // The quiet line gets through while the flood is still being read
+    let mut acks = 0;
+    loop {
+        match a.recv().await {
+            line if line == format!("MESSAGE:{} quiet", c.id()) => break,
+            line => assert_eq!(line, "ACK:MESSAGE"),
+        }
+        acks += 1;
+    }
+    assert!(acks < 100, "the quiet line waited for the whole flood");
+    for _ in acks..100 {
+        a.expect("ACK:MESSAGE").await;
+    }
+    let mut flood = 0;
+    for _ in 0..101 {
+        let line = b.recv().await;
+        if line != format!("MESSAGE:{} quiet", c.id()) {
+            assert_eq!(line, format!("MESSAGE:{} flood {flood}", a.id()));
+            flood += 1;
+        }
+    }
+    assert_eq!(flood, 100);
+}
+
+#[tokio::test]
+async fn lines_over_the_limit_get_their_sender_disconnected() {
+    let server = TestServer::start(Config { max_line: 8, ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+
+    a.send("eight!!!").await;
+    a.expect("ACK:MESSAGE").await;
+    b.expect(&format!("MESSAGE:{} eight!!!", a.id())).await;
+    a.send("and nine!").await;
+    assert_eq!(a.expect_closed().await.as_deref(), Some("ERROR:LINE_TOO_LONG 8"));
+    b.expect_presence(&format!("LEFT:{}", a.id())).await;
+}
+
+#[tokio::test]
+async fn a_slow_consumer_is_disconnected() {
+    let config = Config { send_queue: 8, slow_consumer: SlowConsumer::Disconnect, ..quiet() };
+    let server = TestServer::start(config);
+    let mut fast = TestClient::connect(server.addr()).await;
+    let mut slow = TestClient::connect(server.addr()).await;
+    let mut producer = TestClient::connect(server.addr()).await;
+    let slow_id = slow.id().to_string();
+    slow.set_timeout(Duration::from_secs(10));
+
+    // Far more than the socket buffers hold, while `slow` reads nothing
+    let line = "x".repeat(64 * 1024);
+    for _ in 0..512 {
+        producer.send(&line).await;
+        producer.expect("ACK:MESSAGE").await;
+        fast.expect(&format!("MESSAGE:{} {line}", producer.id())).await;
+    }
+    slow.expect_closed().await;
+    // Its writer hangs up before the server gets round to removing it, and
+    // the LEFT may have gone by among the acks, so ask until it's gone
+    let who = format!("WHO:{} {}", fast.id(), producer.id());
+    for attempt in 1.. {
+        producer.send("WHO").await;
+        if producer.recv().await == who {
+            break;
+        }
+        assert!(attempt < 20, "client {slow_id} still listed");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // Everyone else carries on
+    producer.send("after").await;
+    producer.expect("ACK:MESSAGE").await;
+    fast.expect(&format!("MESSAGE:{} after", producer.id())).await;
+}
+
+#[tokio::test]
+async fn a_stalled_write_times_out_even_when_dropping_lines() {
+    let config = Config { slow_consumer: SlowConsumer::DropNewest, write_timeout: Some(Duration::from_secs(1)), ..quiet() };
+    let server = TestServer::start(config);
+    let mut stalled = TestClient::connect(server.addr()).await;
+    let mut producer = TestClient::connect(server.addr()).await;
+    stalled.set_timeout(Duration::from_secs(10));
+
+    let line = "x".repeat(64 * 1024);
+    for _ in 0..256 {
+        producer.send(&line).await;
+        producer.expect("ACK:MESSAGE").await;
+    }
+    // Reading now would unstall the write, so give it time to run out first
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    producer.send("WHO").await;
+    producer.expect(&format!("WHO:{}", producer.id())).await;
+    stalled.expect_closed().await;
+}
+
+#[tokio::test]
+async fn small_rooms_keep_their_order_off_the_feed() {
+    let tuning = Tuning { small_room: 2, ..Tuning::default() };
+    let server = TestServer::start(Config { tuning, ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+    let mut c = TestClient::connect(server.addr()).await;
+
+    for client in [&mut a, &mut b] {
+        client.send("JOIN:dev").await;
+        client.expect_prefix("ACK:JOIN").await;
+    }
+    for n in 0..50 {
+        a.send(&format!("message {n}")).await;
+    }
+    for _ in 0..50 {
+        a.expect("ACK:MESSAGE").await;
+    }
+    for n in 0..50 {
+        b.expect(&format!("MESSAGE:{} message {n}", a.id())).await;
+    }
+    c.expect_quiet().await;
+}
+
+#[tokio::test]
+async fn a_barrier_waits_for_everything_before_it() {
+    // Nagle's algorithm would hold lines back past their flush
+    let socket = SocketOptions { nodelay: true, ..SocketOptions::default() };
+    // Everything is batched, and the flush tick never comes
+    let tuning = Tuning { small_room: 8, flush_interval: Duration::from_secs(3600), ..Tuning::throughput() };
+    let server = TestServer::start(Config { socket, tuning, ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+
+    // Broadcasts in a small room skip the feed, but a barrier covers them
+    // all the same
+    for client in [&mut a, &mut b] {
+        client.send("JOIN:dev").await;
+        client.expect_prefix("ACK:JOIN").await;
+    }
+    for n in 0..20 {
+        a.send(&format!("message {n}")).await;
+    }
+    a.send("BARRIER").await;
+    for _ in 0..20 {
+        a.expect("ACK:MESSAGE").await;
+    }
+    a.expect("ACK:BARRIER").await;
+    // Already there: nothing left to wait for
+    b.set_timeout(Duration::ZERO);
+    for n in 0..20 {
+        b.expect(&format!("MESSAGE:{} message {n}", a.id())).await;
+    }
+}
+
+#[tokio::test]
+async fn a_barrier_waits_for_a_stalled_reader() {
+    let server = TestServer::start(quiet());
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut stalled = TestClient::connect(server.addr()).await;
+
+    // Far more than the socket buffers hold, while `stalled` reads nothing
+    let line = "x".repeat(64 * 1024);
+    for _ in 0..256 {
+        a.send(&line).await;
+        a.expect("ACK:MESSAGE").await;
+    }
+    a.send("BARRIER").await;
+    a.send("BARRIER").await;
+    a.expect("ERROR:BUSY BARRIER").await;
+    a.expect_quiet().await;
+    for _ in 0..256 {
+        stalled.expect(&format!("MESSAGE:{} {line}", a.id())).await;
+    }
+    a.expect("ACK:BARRIER").await;
+    a.barrier().await;
+}
+
+#[tokio::test]
+async fn datagrams_reach_the_lobby_within_their_budget() {
+    let udp_port = free_udp_port();
+    let server = TestServer::start(Config { udp_port: Some(udp_port), ..quiet() });
+    let mut a = TestClient::connect(server.addr()).await;
+    let mut b = TestClient::connect(server.addr()).await;
+    let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let from = device.local_addr().unwrap();
+
+    device.send_to(b"temp=21.5\n\ntemp=\x1b21.6", ("127.0.0.1", udp_port)).unwrap();
+    b.expect(&format!("MESSAGE:udp:{from} temp=21.5")).await;
+    b.expect(&format!("MESSAGE:udp:{from} temp=21.6")).await;
+
+    // Far past the burst of 200: the rest of the datagram is dropped
+    let flood: String = (0..300).map(|n| format!("line {n}\n")).collect();
+    device.send_to(flood.as_bytes(), ("127.0.0.1", udp_port)).unwrap();
+    // A datagram is taken in all at once, so once its first line is out
+    // the rest are ahead of anything sent after
+    b.expect(&format!("MESSAGE:udp:{from} line 0")).await;
+    a.send("after").await;
+    while a.recv().await != "ACK:MESSAGE" {}
+    let mut taken = 1;
+    loop {
+        let line = b.recv().await;
+        if line == format!("MESSAGE:{} after", a.id()) {
+            break;
+        }
+        assert_eq!(line, format!("MESSAGE:udp:{from} line {taken}"));
+        taken += 1;
+    }
+    assert!((190..=200).contains(&taken), "{taken} datagram lines let through");
+}
+
+#[tokio::test]
+async fn read_only_maintenance_drops_datagrams() {
+    let udp_port = free_udp_port();
+    let auth = AuthConfig {
+        users: [("root".to_string(), "secret".to_string()), ("alice".to_string(), "hunter2".to_string())].into(),
+        admins: vec!["root".to_string()],
+        ..AuthConfig::default()
+    };
+    let server = TestServer::start(Config { udp_port: Some(udp_port), auth, ..quiet() });
+    let mut admin = TestClient::connect_with_auth(server.addr(), "root secret").await;
+    let mut alice = TestClient::connect_with_auth(server.addr(), "alice hunter2").await;
+    let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    admin.send("MAINTENANCE:READ_ONLY").await;
+    admin.expect("ACK:MAINTENANCE READ_ONLY").await;
+    alice.expect("SERVER:MAINTENANCE_READ_ONLY").await;
+    device.send_to(b"temp=21.5", ("127.0.0.1", udp_port)).unwrap();
+    alice.expect_quiet().await;
+
+    admin.send("MAINTENANCE:OFF").await;
+    admin.expect("ACK:MAINTENANCE OFF").await;
+    alice.expect("SERVER:MAINTENANCE_OVER").await;
+    device.send_to(b"temp=21.6", ("127.0.0.1", udp_port)).unwrap();
+    alice.expect(&format!("MESSAGE:udp:{} temp=21.6", device.local_addr().unwrap())).await;
+}
+
+#[cfg(feature = "tls")]
+type TlsConn = tokio_util::codec::Framed<tokio_rustls::client::TlsStream<tokio::net::TcpStream>, tokio_util::codec::LinesCodec>;
+
+/// Logs in over TLS to a server using the test certificate in tests/tls,
+/// returning the connection and its id.
+#[cfg(feature = "tls")]
+async fn tls_login(addr: std::net::SocketAddr) -> (TlsConn, String) {
+    use std::sync::Arc;
+
+    use rustls_pki_types::pem::PemObject;
+    use rustls_pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{crypto, ClientConfig, RootCertStore};
+
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file(tls_file("cert.pem")).unwrap()).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let localhost = ServerName::try_from("localhost").unwrap();
+    let stream = tokio_rustls::TlsConnector::from(Arc::new(config)).connect(localhost, stream).await.unwrap();
+    let mut conn = tokio_util::codec::Framed::new(stream, tokio_util::codec::LinesCodec::new());
+    let login = tls_recv(&mut conn).await;
+    let id = login.strip_prefix("LOGIN:").unwrap_or_else(|| panic!("expected LOGIN:<id>, got {login:?}"));
+    let id = id.to_string();
+    (conn, id)
+}
+
+/// The next line past presence notices.
+#[cfg(feature = "tls")]
+async fn tls_recv(conn: &mut TlsConn) -> String {
+    use tokio_stream::StreamExt;
+
+    loop {
+        let line = tokio::time::timeout(Duration::from_secs(2), conn.next()).await.expect("a line within 2s").unwrap().unwrap();
+        if !line.starts_with("JOINED:") && !line.starts_with("LEFT:") {
+            return line;
+        }
+    }
+}
+
+/// A self-signed certificate for localhost, or its key, good for a century.
+#[cfg(feature = "tls")]
+fn tls_file(name: &str) -> String {
+    format!("{}/tests/tls/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn tls_clients_speak_the_same_protocol() {
+    use futures::SinkExt;
+
+    let tls = TlsConfig::new(tls_file("cert.pem"), tls_file("key.pem"));
+    let server = TestServer::start(Config { tls: Some(tls), ..quiet() });
+    let (mut a, a_id) = tls_login(server.addr()).await;
+    let (mut b, _) = tls_login(server.addr()).await;
+
+    a.send("over tls").await.unwrap();
+    assert_eq!(tls_recv(&mut a).await, "ACK:MESSAGE");
+    assert_eq!(tls_recv(&mut b).await, format!("MESSAGE:{a_id} over tls"));
+}